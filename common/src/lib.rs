@@ -117,9 +117,286 @@ pub const SYS_GETCHAR: u32 = 2;
 pub const SYS_EXIT: u32 = 3;
 pub const SYS_READFILE: u32 = 4;
 pub const SYS_WRITEFILE: u32 = 5;
-pub const SYS_PING : u32 = 6;
+pub const SYS_PING: u32 = 6;
+pub const SYS_PIPE: u32 = 7;
+pub const SYS_READ: u32 = 8;
+pub const SYS_WRITE: u32 = 9;
+pub const SYS_CLOSE: u32 = 10;
+pub const SYS_SPAWN: u32 = 11;
+pub const SYS_WAIT: u32 = 12;
+pub const SYS_OPEN: u32 = 13;
+pub const SYS_UNLINK: u32 = 14;
+pub const SYS_TRUNCATE: u32 = 15;
+pub const SYS_MKDIR: u32 = 16;
+pub const SYS_RMDIR: u32 = 17;
+pub const SYS_GETDENTS: u32 = 18;
+pub const SYS_CHDIR: u32 = 19;
+pub const SYS_GETCWD: u32 = 20;
+pub const SYS_STAT: u32 = 21;
+pub const SYS_LSTAT: u32 = 22;
+pub const SYS_SYMLINK: u32 = 23;
+pub const SYS_LINK: u32 = 24;
+pub const SYS_READLINK: u32 = 25;
+pub const SYS_FSCK: u32 = 26;
+pub const SYS_SYNC: u32 = 27;
+pub const SYS_CACHESTAT: u32 = 28;
+pub const SYS_MOUNT: u32 = 29;
+pub const SYS_UMOUNT: u32 = 30;
+pub const SYS_GETRANDOM: u32 = 31;
+pub const SYS_IFADDR: u32 = 32;
+pub const SYS_ROUTE_ADD: u32 = 33;
+pub const SYS_ROUTE_DEL: u32 = 34;
+pub const SYS_ROUTE_LIST: u32 = 35;
+pub const SYS_SOCKET: u32 = 36;
+pub const SYS_BIND: u32 = 37;
+pub const SYS_CONNECT: u32 = 38;
+pub const SYS_SETSOCKOPT: u32 = 39;
+pub const SYS_SYSCTL: u32 = 40;
+pub const SYS_RECVERR: u32 = 41;
 
-pub const VIRTIO_BLK_PADDR: usize = 0x10001000;
+// システムコールのエラーは負のerrnoをu32にキャストして返す
+pub const EPERM: i32 = 1;
+pub const ENOENT: i32 = 2;
+pub const ESRCH: i32 = 3;
+pub const EIO: i32 = 5;
+pub const ENXIO: i32 = 6;
+pub const EBADF: i32 = 9;
+pub const ECHILD: i32 = 10;
+pub const EAGAIN: i32 = 11;
+pub const EACCES: i32 = 13;
+pub const EBUSY: i32 = 16;
+pub const EEXIST: i32 = 17;
+pub const EXDEV: i32 = 18;
+pub const ENODEV: i32 = 19;
+pub const ENOTDIR: i32 = 20;
+pub const EISDIR: i32 = 21;
+pub const EINVAL: i32 = 22;
+pub const EMFILE: i32 = 24;
+pub const EFBIG: i32 = 27;
+pub const ENOSPC: i32 = 28;
+pub const EROFS: i32 = 30;
+pub const EPIPE: i32 = 32;
+pub const ERANGE: i32 = 34;
+pub const ENAMETOOLONG: i32 = 36;
+pub const ENOTEMPTY: i32 = 39;
+pub const ELOOP: i32 = 40;
+pub const EPROTO: i32 = 71;
+pub const ENOTSOCK: i32 = 88;
+pub const EDESTADDRREQ: i32 = 89;
+pub const EMSGSIZE: i32 = 90;
+pub const ENOPROTOOPT: i32 = 92;
+pub const EPROTONOSUPPORT: i32 = 93;
+pub const EADDRINUSE: i32 = 98;
+pub const EADDRNOTAVAIL: i32 = 99;
+pub const ENETUNREACH: i32 = 101;
+pub const ENOBUFS: i32 = 105;
+pub const ETIMEDOUT: i32 = 110;
+pub const ECONNREFUSED: i32 = 111;
+pub const EHOSTUNREACH: i32 = 113;
+
+// SYS_OPENのフラグ
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_ACCMODE: u32 = 3;
+pub const O_CREAT: u32 = 0x40;
+pub const O_TRUNC: u32 = 0x200;
+pub const O_APPEND: u32 = 0x400;
+
+pub const FD_STDIN: u32 = 0;
+pub const FD_STDOUT: u32 = 1;
+pub const FD_STDERR: u32 = 2;
+
+// SYS_GETDENTSが返すディレクトリエントリ
+pub const DT_CHR: u32 = 2;
+pub const DT_DIR: u32 = 4;
+pub const DT_BLK: u32 = 6;
+pub const DT_REG: u32 = 8;
+pub const DT_LNK: u32 = 10;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Dirent {
+    pub kind: u32,
+    pub name: [u8; 124],
+}
+
+impl Dirent {
+    pub const fn new() -> Self {
+        Self {
+            kind: 0,
+            name: [0; 124],
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+// SYS_STAT・SYS_LSTATが返すファイルの属性
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Stat {
+    pub kind: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub size: u64,
+    pub mtime: u64,
+}
+
+impl Stat {
+    pub const fn new() -> Self {
+        Self {
+            kind: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            nlink: 0,
+            size: 0,
+            mtime: 0,
+        }
+    }
+}
+
+// SYS_FSCKが返す、起動時にファイルシステムを読み込んだ結果
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct FsckReport {
+    // 読み込めたエントリの数
+    pub entries: u32,
+    // チェックサムかマジックが不正なヘッダ (連続したものは1つと数える)
+    pub corrupt: u32,
+    // 壊れたヘッダとして読み飛ばしたバイト数
+    pub quarantined: u32,
+    // 名前や種類が扱えずに読み飛ばしたエントリの数
+    pub skipped: u32,
+    // ディスクの末尾で途切れたエントリがあったか
+    pub truncated: u32,
+    // 終端のゼロブロックが見つかったか
+    pub end_marker: u32,
+}
+
+impl FsckReport {
+    pub const fn new() -> Self {
+        Self {
+            entries: 0,
+            corrupt: 0,
+            quarantined: 0,
+            skipped: 0,
+            truncated: 0,
+            end_marker: 0,
+        }
+    }
+}
+
+// SYS_CACHESTATが返すブロックキャッシュの統計 (単位はセクタ)
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct CacheStats {
+    pub hits: u32,
+    pub misses: u32,
+    // ディスクに書き戻した数
+    pub writebacks: u32,
+    // 書き戻しを行った回数
+    pub syncs: u32,
+    // 書き戻していない数
+    pub dirty: u32,
+}
+
+impl CacheStats {
+    pub const fn new() -> Self {
+        Self {
+            hits: 0,
+            misses: 0,
+            writebacks: 0,
+            syncs: 0,
+            dirty: 0,
+        }
+    }
+}
+
+// SYS_SOCKETの種類。今はUDPだけ
+pub const SOCK_DGRAM: u32 = 2;
+
+// SYS_SETSOCKOPTのオプション
+// IPヘッダのDSCP (上位6bit) とECN (下位2bit)
+pub const IP_TOS: u32 = 1;
+pub const IP_TTL: u32 = 2;
+// IP_PMTUDISC_DOならDFを立て、MTUを超えるデータグラムはEMSGSIZEになる
+pub const IP_MTU_DISCOVER: u32 = 10;
+pub const IP_PMTUDISC_DONT: u32 = 0;
+pub const IP_PMTUDISC_DO: u32 = 2;
+
+// SYS_RECVERRで受け取る、ソケットが送ったデータグラムへのICMPのエラー
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct SockError {
+    // エラーを送ってきたホスト (ビッグエンディアン)
+    pub origin: u32,
+    pub icmp_type: u8,
+    pub code: u8,
+    // 最後に送ってからエラーが届くまでの時間 (マイクロ秒)
+    pub rtt_us: u32,
+}
+
+// SockErrorのicmp_type
+pub const ICMP_DEST_UNREACH: u8 = 3;
+pub const ICMP_TIME_EXCEEDED: u8 = 11;
+
+// SYS_ROUTE_ADD・SYS_ROUTE_DEL・SYS_ROUTE_LISTでやり取りする経路 (アドレスはビッグエンディアン)
+pub const IFNAMSIZ: usize = 16;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct RouteEntry {
+    pub dst: u32,
+    pub prefix_len: u32,
+    // 0ならゲートウェイを経由せずインターフェースから直接届ける
+    pub gateway: u32,
+    // この経路で送るときの送信元アドレス (SYS_ROUTE_LISTのみ)
+    pub src: u32,
+    // 追加時に空ならゲートウェイへの経路から決める
+    pub iface: [u8; IFNAMSIZ],
+}
+
+impl RouteEntry {
+    pub const fn new() -> Self {
+        Self {
+            dst: 0,
+            prefix_len: 0,
+            gateway: 0,
+            src: 0,
+            iface: [0; IFNAMSIZ],
+        }
+    }
+
+    pub fn iface(&self) -> &str {
+        let len = self
+            .iface
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(self.iface.len());
+        core::str::from_utf8(&self.iface[..len]).unwrap_or("")
+    }
+
+    pub fn set_iface(&mut self, name: &str) {
+        let len = name.len().min(IFNAMSIZ - 1);
+        self.iface = [0; IFNAMSIZ];
+        self.iface[..len].copy_from_slice(&name.as_bytes()[..len]);
+    }
+}
+
+// spawnしたプロセスに渡すコマンドライン (ユーザー空間の固定アドレス)
+pub const USER_ARGS: usize = 0x1800000;
+pub const ARGS_MAX: usize = 128;
 
 pub fn ascii_len(buf: *const u8) -> usize {
     let len;
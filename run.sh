@@ -8,7 +8,22 @@ USER=user/target/riscv32i-unknown-none-elf/release/user
 mkdir -p disk
 echo "Lorem ipsum dolor sit amet, consectetur adipiscing elit. In ut magna consequat, cursus velit aliquam, scelerisque odio. Ut lorem eros, feugiat quis bibendum vitae, malesuada ac orci. Praesent eget quam non nunc fringilla cursus imperdiet non tellus. Aenean dictum lobortis turpis, non interdum leo rhoncus sed. Cras in tellus auctor, faucibus tortor ut, maximus metus. Praesent placerat ut magna non tristique. Pellentesque at nunc quis dui tempor vulputate. Vestibulum vitae massa orci. Mauris et tellus quis risus sagittis placerat. Integer lorem leo, feugiat sed molestie non, viverra a tellus." > disk/lorem.txt
 echo "hello world!!" > disk/hello.txt
-(cd disk && tar cf ../disk.tar --format=ustar ./*)
+if [ "${DISK_FORMAT:-ext2}" = tar ]; then
+    DISK=disk.tar
+    (cd disk && tar cf ../$DISK --format=ustar ./*)
+    # ファイルを作成・拡張できるようにディスクに空き領域を確保する
+    truncate -s 1M $DISK
+elif [ "${DISK_FORMAT:-ext2}" = fat32 ]; then
+    DISK=disk.img
+    rm -f $DISK
+    # FAT32には65525個以上のクラスタが要るので、512バイトのクラスタで64MiBにする
+    mkfs.vfat -F 32 -s 1 -C $DISK 65536
+    mcopy -i $DISK disk/* ::/
+else
+    DISK=disk.img
+    rm -f $DISK
+    mkfs.ext2 -q -b 1024 -d disk $DISK 4M
+fi
 
 (cd user && cargo build --release)
 rust-objcopy --set-section-flags .bss=alloc,contents -O binary $USER shell.bin
@@ -16,8 +31,34 @@ rust-objcopy -Ibinary -Oelf32-littleriscv shell.bin shell.bin.o
 
 cargo build --release
 
+# QEMUのvirtio-mmioは既定でレガシー (バージョン1) なので、モダンなデバイスにする
+VIRTIO_MODE="-global virtio-mmio.force-legacy=false"
+if [ "${VIRTIO_LEGACY:-0}" = 1 ]; then
+    VIRTIO_MODE=
+fi
+
+# CONSOLE_SOCKETを指定すると、virtio-consoleのポートをUNIXソケットに繋ぐ。
+# ポート0 (hvc0) ではシェルが動き、ポート1 (hvc1) は`.log`の付いたソケットに出力するだけのポート
+CONSOLE=
+if [ -n "${CONSOLE_SOCKET:-}" ]; then
+    CONSOLE="-device virtio-serial-device,bus=virtio-mmio-bus.2 \
+        -chardev socket,id=hvc0,path=$CONSOLE_SOCKET,server=on,wait=off \
+        -device virtconsole,chardev=hvc0,nr=0 \
+        -chardev socket,id=hvc1,path=$CONSOLE_SOCKET.log,server=on,wait=off \
+        -device virtserialport,chardev=hvc1,nr=1,name=log"
+fi
+
+# SHARE_DIRを指定すると、ホストのディレクトリをvirtio-9pで共有して/mntにマウントする。
+# -virtfsはPCIのデバイスを作るので、virtio-mmioのデバイスを直接指定する
+SHARE=
+if [ -n "${SHARE_DIR:-}" ]; then
+    SHARE="-fsdev local,id=fsdev0,path=$SHARE_DIR,security_model=none \
+        -device virtio-9p-device,fsdev=fsdev0,mount_tag=host,bus=virtio-mmio-bus.3"
+fi
+
 $QEMU -machine virt -bios default -nographic -serial mon:stdio --no-reboot \
-    -d unimp,guest_errors,int,cpu_reset -D qemu.log \
-    -drive id=drive0,file=disk.tar,format=raw,if=none \
+    -d unimp,guest_errors,int,cpu_reset -D qemu.log $VIRTIO_MODE \
+    -drive id=drive0,file=$DISK,format=raw,if=none \
     -device virtio-blk-device,drive=drive0,bus=virtio-mmio-bus.0 \
+    -device virtio-rng-device,bus=virtio-mmio-bus.1 $CONSOLE $SHARE \
     -kernel $KERNEL
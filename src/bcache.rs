@@ -0,0 +1,370 @@
+// ブロックキャッシュ: ディスクを`BLOCK_SIZE`ごとに区切り、決まった数のブロックだけをメモリに置く。
+// ブロックはセクタ番号で探し、足りなくなれば最も長く使われていないものを追い出す。
+// セクタ単位で読み込み済み・変更済みを管理し、書き戻しは変更されたセクタだけを対象にする
+
+use core::mem::{size_of, MaybeUninit};
+
+use common::{align_up, println, CacheStats, EIO, PAGE_SIZE};
+
+use crate::{
+    memory::alloc_pages,
+    timer::{now, timebase_freq},
+    virtio::blk::VirtioBlk,
+    VIRTIO,
+};
+
+const SECTOR_SIZE: usize = VirtioBlk::SECTOR_SIZE as usize;
+// キャッシュの1ブロックのセクタ数と、キャッシュするブロックの数
+const BLOCK_SECTORS: usize = 8;
+const BLOCK_SIZE: usize = BLOCK_SECTORS * SECTOR_SIZE;
+const BLOCKS: usize = 256;
+// 最初に変更されてからこの秒数が経ったら書き戻す
+const WRITEBACK_INTERVAL_SECS: u64 = 1;
+
+/// キャッシュしているブロック。`loaded`と`dirty`はブロック内のセクタごとのビット
+#[derive(Copy, Clone)]
+struct Buf {
+    // ディスク上の先頭のセクタ。使われていなければNone
+    sector: Option<u64>,
+    loaded: u8,
+    dirty: u8,
+    // 最後に使われた時の`CLOCK`。使われていなければ0
+    last_used: u64,
+}
+
+static mut BUFS: [Buf; BLOCKS] = [Buf {
+    sector: None,
+    loaded: 0,
+    dirty: 0,
+    last_used: 0,
+}; BLOCKS];
+static mut DATA: &mut [u8] = &mut [];
+// キャッシュが扱うディスクのバイト数
+static mut DISK_SIZE: usize = 0;
+static mut CLOCK: u64 = 0;
+// 最後に使ったブロック。同じブロックへの連続した操作で探すのを省く
+static mut LAST: usize = 0;
+// 書き戻していない変更が最初に発生した時刻
+static mut DIRTY_SINCE: Option<u64> = None;
+static mut STATS: CacheStats = CacheStats::new();
+
+fn bufs() -> &'static mut [Buf; BLOCKS] {
+    unsafe { &mut *core::ptr::addr_of_mut!(BUFS) }
+}
+
+fn data(i: usize) -> &'static mut [u8] {
+    let data = unsafe { &mut **core::ptr::addr_of_mut!(DATA) };
+    &mut data[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE]
+}
+
+fn stats() -> &'static mut CacheStats {
+    unsafe { &mut *core::ptr::addr_of_mut!(STATS) }
+}
+
+/// ブロック内の`start`バイト目から`len`バイトを含むセクタのビット
+fn sector_mask(start: usize, len: usize) -> u8 {
+    let first = start / SECTOR_SIZE;
+    let end = align_up(start + len, SECTOR_SIZE) / SECTOR_SIZE;
+    ((1u32 << end) - (1u32 << first)) as u8
+}
+
+/// `mask`のうち連続して立っているビットの範囲を順に返す
+fn runs(mask: u8) -> impl Iterator<Item = core::ops::Range<usize>> {
+    let mut s = 0;
+    core::iter::from_fn(move || {
+        while s < BLOCK_SECTORS && mask & (1 << s) == 0 {
+            s += 1;
+        }
+        let start = s;
+        while s < BLOCK_SECTORS && mask & (1 << s) != 0 {
+            s += 1;
+        }
+        (start < s).then_some(start..s)
+    })
+}
+
+/// ディスクの先頭`capacity`バイトのキャッシュを用意する。
+/// オフセットは`usize`で表すので、それを超える部分は扱わない
+pub unsafe fn bcache_init(capacity: u64) {
+    let size = capacity.min(usize::MAX as u64) as usize;
+    DISK_SIZE = size - size % SECTOR_SIZE;
+    let pages = BLOCKS * BLOCK_SIZE / PAGE_SIZE;
+    DATA = core::slice::from_raw_parts_mut(alloc_pages(pages) as *mut u8, BLOCKS * BLOCK_SIZE);
+}
+
+/// キャッシュが扱うディスクのバイト数
+pub fn bcache_size() -> usize {
+    unsafe { DISK_SIZE }
+}
+
+/// ブロック`i`の変更されたセクタを書き戻す
+fn write_back(i: usize) -> Result<(), i32> {
+    let buf = &mut bufs()[i];
+    let sector = buf.sector.unwrap();
+    let virtio = unsafe { VIRTIO.as_mut().unwrap() };
+    for run in runs(buf.dirty) {
+        let data = &mut data(i)[run.start * SECTOR_SIZE..run.end * SECTOR_SIZE];
+        let start = sector + run.start as u64;
+        if let Err(e) = virtio.read_write_disk(data, start, true) {
+            println!(
+                "bcache: failed to write sectors {}..{}: {}",
+                start,
+                sector + run.end as u64,
+                e
+            );
+            return Err(EIO);
+        }
+        stats().writebacks += run.len() as u32;
+    }
+    buf.dirty = 0;
+    Ok(())
+}
+
+/// `sector`から始まるブロックを探す。なければ最も長く使われていないブロックを追い出して割り当てる
+fn lookup(sector: u64) -> Result<usize, i32> {
+    let bufs = bufs();
+    let last = unsafe { LAST };
+    let found = if bufs[last].sector == Some(sector) {
+        Some(last)
+    } else {
+        bufs.iter().position(|b| b.sector == Some(sector))
+    };
+    let i = match found {
+        Some(i) => i,
+        None => {
+            let i = (0..BLOCKS).min_by_key(|i| bufs[*i].last_used).unwrap();
+            // 書き戻せなければ追い出さない
+            if bufs[i].dirty != 0 {
+                write_back(i)?;
+            }
+            bufs[i] = Buf {
+                sector: Some(sector),
+                loaded: 0,
+                dirty: 0,
+                last_used: 0,
+            };
+            i
+        }
+    };
+
+    unsafe {
+        CLOCK += 1;
+        bufs[i].last_used = CLOCK;
+        LAST = i;
+    }
+    Ok(i)
+}
+
+/// ブロック`i`の`mask`のセクタのうち、読み込んでいないものをディスクから読み込む
+fn load(i: usize, mask: u8) -> Result<(), i32> {
+    let buf = &mut bufs()[i];
+    let sector = buf.sector.unwrap();
+    stats().hits += (mask & buf.loaded).count_ones();
+    for run in runs(mask & !buf.loaded) {
+        stats().misses += run.len() as u32;
+        let virtio = unsafe { VIRTIO.as_mut().unwrap() };
+        let data = &mut data(i)[run.start * SECTOR_SIZE..run.end * SECTOR_SIZE];
+        let start = sector + run.start as u64;
+        if let Err(e) = virtio.read_write_disk(data, start, false) {
+            println!(
+                "bcache: failed to read sectors {}..{}: {}",
+                start,
+                sector + run.end as u64,
+                e
+            );
+            return Err(EIO);
+        }
+        buf.loaded |= sector_mask(run.start * SECTOR_SIZE, run.len() * SECTOR_SIZE);
+    }
+    Ok(())
+}
+
+/// `off`から`len`バイトをブロックごとに分け、キャッシュ上の該当部分と`len`の中での位置を`f`に渡す。
+/// `write`なら変更済みにする。書き込みで全体を上書きするセクタはディスクから読まない
+fn access(
+    off: usize,
+    len: usize,
+    write: bool,
+    mut f: impl FnMut(&mut [u8], usize),
+) -> Result<(), i32> {
+    if off.checked_add(len).is_none_or(|end| end > bcache_size()) {
+        return Err(EIO);
+    }
+
+    let mut done = 0;
+    while done < len {
+        let pos = off + done;
+        let start = pos % BLOCK_SIZE;
+        let n = (BLOCK_SIZE - start).min(len - done);
+        let i = lookup((pos / BLOCK_SIZE * BLOCK_SECTORS) as u64)?;
+
+        let mask = sector_mask(start, n);
+        let covered = if write {
+            // 先頭と末尾のセクタは一部しか書かないことがある
+            let first = start.div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+            let end = (start + n) / SECTOR_SIZE * SECTOR_SIZE;
+            if first < end {
+                sector_mask(first, end - first)
+            } else {
+                0
+            }
+        } else {
+            0
+        };
+        load(i, mask & !covered)?;
+        f(&mut data(i)[start..start + n], done);
+
+        let buf = &mut bufs()[i];
+        buf.loaded |= mask;
+        if write {
+            buf.dirty |= mask;
+            unsafe {
+                if DIRTY_SINCE.is_none() {
+                    DIRTY_SINCE = Some(now());
+                }
+            }
+        }
+        done += n;
+    }
+    Ok(())
+}
+
+/// `off`から`buf`の長さ分をディスクから読む
+pub fn bcache_read(off: usize, buf: &mut [u8]) -> Result<(), i32> {
+    access(off, buf.len(), false, |data, done| {
+        buf[done..done + data.len()].copy_from_slice(data)
+    })
+}
+
+/// `off`から`buf`を書き込み、変更済みにする
+pub fn bcache_write(off: usize, buf: &[u8]) -> Result<(), i32> {
+    access(off, buf.len(), true, |data, done| {
+        data.copy_from_slice(&buf[done..done + data.len()])
+    })
+}
+
+/// `off`から`len`バイトをゼロで埋める
+pub fn bcache_zero(off: usize, len: usize) -> Result<(), i32> {
+    access(off, len, true, |data, _| data.fill(0))
+}
+
+/// `off`にある`T`を読む。`T`はどのようなバイト列でも正しい値になる型でなければならない
+pub fn bcache_get<T: Copy>(off: usize) -> Result<T, i32> {
+    let mut value = MaybeUninit::<T>::zeroed();
+    let bytes =
+        unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    bcache_read(off, bytes)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// `off`に`value`を書き込む
+pub fn bcache_put<T: Copy>(off: usize, value: &T) -> Result<(), i32> {
+    let bytes =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    bcache_write(off, bytes)
+}
+
+/// `src`から`len`バイトを`dst`に移す。範囲は重なっていてもよい
+pub fn bcache_copy(src: usize, dst: usize, len: usize) -> Result<(), i32> {
+    let mut chunk = [0u8; SECTOR_SIZE];
+    let mut done = 0;
+    while done < len {
+        let n = chunk.len().min(len - done);
+        // 後ろに移す場合は、まだ移していない部分を上書きしないよう末尾から移す
+        let pos = if dst > src { len - done - n } else { done };
+        bcache_read(src + pos, &mut chunk[..n])?;
+        bcache_write(dst + pos, &chunk[..n])?;
+        done += n;
+    }
+    Ok(())
+}
+
+/// 書き込み要求の完了を待ち、成功したら変更済みの印を消して書き込んだセクタ数を返す
+fn finish_write(virtio: &mut VirtioBlk, id: u16, i: usize, run: core::ops::Range<usize>) -> usize {
+    let buf = &mut bufs()[i];
+    let sector = buf.sector.unwrap();
+    if let Err(e) = virtio.wait(id) {
+        // 変更済みのまま残し、次の書き戻しで再び試す
+        println!(
+            "bcache: failed to write sectors {}..{}: {}",
+            sector + run.start as u64,
+            sector + run.end as u64,
+            e
+        );
+        return 0;
+    }
+
+    buf.dirty &= !sector_mask(run.start * SECTOR_SIZE, run.len() * SECTOR_SIZE);
+    run.len()
+}
+
+/// 変更されたセクタをディスクに書き戻し、書き込んだセクタ数を返す。
+/// ブロック内で連続したセクタは1つの要求にまとめ、複数の要求を同時に発行する
+pub fn bcache_sync(virtio: &mut VirtioBlk) -> usize {
+    let mut inflight: [Option<(u16, usize, core::ops::Range<usize>)>; VirtioBlk::MAX_REQUESTS] =
+        [const { None }; VirtioBlk::MAX_REQUESTS];
+    let mut next = 0;
+    let mut written = 0;
+    for i in 0..BLOCKS {
+        let buf = bufs()[i];
+        let Some(sector) = buf.sector else {
+            continue;
+        };
+
+        for run in runs(buf.dirty) {
+            // 空きがなければ最も古い要求の完了を待つ
+            if let Some((id, i, run)) = inflight[next].take() {
+                written += finish_write(virtio, id, i, run);
+            }
+            let data = data(i)[run.start * SECTOR_SIZE..].as_mut_ptr();
+            let len = run.len() * SECTOR_SIZE;
+            let start = sector + run.start as u64;
+            match unsafe { virtio.submit(data, len, start, true) } {
+                Ok(id) => {
+                    inflight[next] = Some((id, i, run));
+                    next = (next + 1) % inflight.len();
+                }
+                Err(e) => println!(
+                    "bcache: failed to write sectors {}..{}: {}",
+                    start,
+                    sector + run.end as u64,
+                    e
+                ),
+            }
+        }
+    }
+
+    for slot in inflight.iter_mut() {
+        if let Some((id, i, run)) = slot.take() {
+            written += finish_write(virtio, id, i, run);
+        }
+    }
+
+    let stats = stats();
+    stats.writebacks += written as u32;
+    stats.syncs += 1;
+    // 書き込めなかったセクタは次の書き戻しの時期に再び試す
+    unsafe {
+        DIRTY_SINCE = if bufs().iter().any(|b| b.dirty != 0) {
+            Some(now())
+        } else {
+            None
+        };
+    }
+    written
+}
+
+/// 書き戻していない変更が`WRITEBACK_INTERVAL_SECS`以上前からあるか
+pub fn bcache_writeback_due() -> bool {
+    match unsafe { DIRTY_SINCE } {
+        Some(since) => now() - since >= WRITEBACK_INTERVAL_SECS * timebase_freq(),
+        None => false,
+    }
+}
+
+pub fn bcache_stats() -> CacheStats {
+    CacheStats {
+        dirty: bufs().iter().map(|b| b.dirty.count_ones()).sum(),
+        ..*stats()
+    }
+}
@@ -0,0 +1,189 @@
+// devfs: デバイスをファイルディスクリプタから読み書きできるファイルとして見せる
+
+use common::{Stat, EISDIR, ENOENT, ENOSPC, ENOTDIR};
+
+use crate::{
+    bcache::{bcache_read, bcache_size, bcache_sync, bcache_write},
+    file::{console_read, console_write},
+    path::PathBuf,
+    random::random_fill,
+    tty::{tty_exists, tty_read, tty_write, TTYS_MAX},
+    vfs::{DirEntry, FileKind, FileSystem, Node},
+    VIRTIO,
+};
+
+#[derive(Copy, Clone, PartialEq)]
+enum Device {
+    Console,
+    Null,
+    Zero,
+    Random,
+    /// virtio-blkのディスク。ブロックキャッシュを通して読み書きする
+    Vda,
+    /// virtio-consoleのポート
+    Hvc(usize),
+}
+
+// `Node`はこの配列の添字
+const DEVICES: [(&str, Device); 5 + TTYS_MAX] = [
+    ("console", Device::Console),
+    ("null", Device::Null),
+    ("zero", Device::Zero),
+    ("random", Device::Random),
+    ("vda", Device::Vda),
+    ("hvc0", Device::Hvc(0)),
+    ("hvc1", Device::Hvc(1)),
+    ("hvc2", Device::Hvc(2)),
+    ("hvc3", Device::Hvc(3)),
+];
+
+impl Device {
+    fn kind(&self) -> FileKind {
+        match self {
+            Device::Vda => FileKind::BlockDevice,
+            _ => FileKind::CharDevice,
+        }
+    }
+
+    /// statで見せる大きさ。ディスクはオフセットで扱えない部分も含めた容量
+    fn capacity(&self) -> u64 {
+        match self {
+            Device::Vda => unsafe { VIRTIO.as_ref().unwrap() }.blk_capacity(),
+            _ => 0,
+        }
+    }
+
+    /// virtio-consoleのポートはデバイスが追加したものだけがある
+    fn exists(&self) -> bool {
+        match self {
+            Device::Hvc(port) => tty_exists(*port),
+            _ => true,
+        }
+    }
+}
+
+/// `/`ならNone、デバイスならその番号
+fn parse(path: &PathBuf) -> Result<Option<Node>, i32> {
+    if path.is_root() {
+        return Ok(None);
+    }
+
+    DEVICES
+        .iter()
+        .position(|(name, device)| *name == path.relative() && device.exists())
+        .map(Some)
+        .ok_or(if path.parent().is_root() {
+            ENOENT
+        } else {
+            ENOTDIR
+        })
+}
+
+/// VFSから見たdevfs
+pub struct DevFs;
+
+pub static DEVFS: DevFs = DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn kind(&self, path: &PathBuf) -> Result<FileKind, i32> {
+        Ok(match parse(path)? {
+            Some(node) => DEVICES[node].1.kind(),
+            None => FileKind::Directory,
+        })
+    }
+
+    fn stat(&self, path: &PathBuf) -> Result<Stat, i32> {
+        let (kind, mode, size) = match parse(path)? {
+            Some(node) => (DEVICES[node].1.kind(), 0o666, DEVICES[node].1.capacity()),
+            None => (FileKind::Directory, 0o755, 0),
+        };
+        Ok(Stat {
+            kind: kind.dirent_type(),
+            mode,
+            nlink: 1,
+            size,
+            ..Stat::new()
+        })
+    }
+
+    fn readdir(&self, dir: &PathBuf, cursor: &mut usize) -> Option<DirEntry> {
+        if !dir.is_root() {
+            return None;
+        }
+
+        loop {
+            let (name, device) = DEVICES.get(*cursor)?;
+            *cursor += 1;
+            if device.exists() {
+                return Some(DirEntry::new(name, device.kind()));
+            }
+        }
+    }
+
+    fn lookup(&self, path: &PathBuf) -> Result<Node, i32> {
+        parse(path)?.ok_or(EISDIR)
+    }
+
+    fn read(&self, node: Node, offset: usize, buf: &mut [u8]) -> Result<usize, i32> {
+        match DEVICES[node].1 {
+            Device::Console => Ok(console_read(buf)),
+            Device::Hvc(port) => tty_read(port, buf),
+            Device::Null => Ok(0),
+            Device::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+            Device::Random => {
+                random_fill(buf);
+                Ok(buf.len())
+            }
+            Device::Vda => {
+                if offset >= bcache_size() {
+                    return Ok(0);
+                }
+
+                let len = buf.len().min(bcache_size() - offset);
+                bcache_read(offset, &mut buf[..len])?;
+                Ok(len)
+            }
+        }
+    }
+
+    fn write(&self, node: Node, offset: usize, buf: &[u8]) -> Result<usize, i32> {
+        match DEVICES[node].1 {
+            Device::Console => Ok(console_write(buf)),
+            Device::Hvc(port) => tty_write(port, buf),
+            Device::Null | Device::Zero | Device::Random => Ok(buf.len()),
+            Device::Vda => {
+                // ディスクの末尾を超えては書けない
+                if offset >= bcache_size() {
+                    return Err(ENOSPC);
+                }
+
+                let len = buf.len().min(bcache_size() - offset);
+                bcache_write(offset, &buf[..len])?;
+                Ok(len)
+            }
+        }
+    }
+
+    fn size(&self, node: Node) -> usize {
+        match DEVICES[node].1 {
+            Device::Vda => bcache_size(),
+            _ => 0,
+        }
+    }
+
+    /// `O_TRUNC`付きで開けるよう、デバイスの切り詰めは何もしない
+    fn truncate(&self, _node: Node, _len: usize) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn sync(&self) {
+        unsafe { bcache_sync(VIRTIO.as_mut().unwrap()) };
+    }
+}
@@ -0,0 +1,1044 @@
+// ext2ファイルシステム: ブロックキャッシュを通してディスクを読み書きする。
+// 構造体はキャッシュから写して使い、変更したら書き戻す。ディスクへの書き戻しはブロックキャッシュに任せる
+
+use core::mem::{offset_of, size_of};
+
+use common::{
+    println, Stat, EBUSY, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENODEV, ENOENT, ENOSPC,
+    ENOTDIR, ENOTEMPTY, EPERM, EROFS,
+};
+
+use crate::{
+    bcache::{
+        bcache_get, bcache_put, bcache_read, bcache_size, bcache_sync, bcache_write, bcache_zero,
+    },
+    path::PathBuf,
+    vfs::{DirEntry, FileKind, FileSystem, Node},
+    VIRTIO,
+};
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+const ROOT_INO: u32 = 2;
+// i_blockのうち直接ブロックの数。続く3つは1重・2重・3重間接ブロック
+const DIRECT_BLOCKS: usize = 12;
+// リビジョン0のinodeのサイズと最初の未予約inode
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+// ディレクトリエントリにファイルの種類を持つ
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+// スーパーブロックの予備を一部のグループにだけ置く・4GiB以上のファイルがある。どちらも書き込みに影響しない
+const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x2;
+// ハッシュ化されたディレクトリ。索引を更新しないので、ディレクトリを変更したら落とす
+const INDEX_FL: u32 = 0x1000;
+const NAME_MAX: usize = 255;
+// 同時に開かれるinodeの数の上限 (プロセス数 × ディスクリプタ数)
+const OPEN_MAX: usize = 64;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xa000;
+const S_IFCHR: u16 = 0x2000;
+const S_IFBLK: u16 = 0x6000;
+
+/// スーパーブロックの先頭部分
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Superblock {
+    inodes_count: u32,
+    blocks_count: u32,
+    r_blocks_count: u32,
+    free_blocks_count: u32,
+    free_inodes_count: u32,
+    first_data_block: u32,
+    log_block_size: u32,
+    log_frag_size: u32,
+    blocks_per_group: u32,
+    frags_per_group: u32,
+    inodes_per_group: u32,
+    mtime: u32,
+    wtime: u32,
+    mnt_count: u16,
+    max_mnt_count: u16,
+    magic: u16,
+    state: u16,
+    errors: u16,
+    minor_rev_level: u16,
+    lastcheck: u32,
+    checkinterval: u32,
+    creator_os: u32,
+    rev_level: u32,
+    def_resuid: u16,
+    def_resgid: u16,
+    first_ino: u32,
+    inode_size: u16,
+    block_group_nr: u16,
+    feature_compat: u32,
+    feature_incompat: u32,
+    feature_ro_compat: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct GroupDesc {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks_count: u16,
+    free_inodes_count: u16,
+    used_dirs_count: u16,
+    pad: u16,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Inode {
+    mode: u16,
+    uid: u16,
+    size: u32,
+    atime: u32,
+    ctime: u32,
+    mtime: u32,
+    dtime: u32,
+    gid: u16,
+    links_count: u16,
+    // 512バイト単位
+    blocks: u32,
+    flags: u32,
+    osd1: u32,
+    block: [u32; 15],
+    generation: u32,
+    file_acl: u32,
+    dir_acl: u32,
+    faddr: u32,
+    osd2: [u8; 12],
+}
+
+impl Inode {
+    fn kind(&self) -> FileKind {
+        match self.mode & S_IFMT {
+            S_IFDIR => FileKind::Directory,
+            S_IFLNK => FileKind::Symlink,
+            S_IFCHR => FileKind::CharDevice,
+            S_IFBLK => FileKind::BlockDevice,
+            _ => FileKind::Regular,
+        }
+    }
+
+    /// リンク先を`block`に直接持つシンボリックリンク
+    fn is_fast_symlink(&self) -> bool {
+        let acl_blocks = if self.file_acl != 0 {
+            block_size() / 512
+        } else {
+            0
+        };
+        self.kind() == FileKind::Symlink && self.blocks as usize == acl_blocks
+    }
+
+    /// デバイスファイル。`block[0]`はデバイス番号で、データブロックを指さない
+    fn is_device(&self) -> bool {
+        matches!(self.kind(), FileKind::CharDevice | FileKind::BlockDevice)
+    }
+}
+
+/// 起動時にスーパーブロックから読み取る値
+#[derive(Copy, Clone)]
+struct Geometry {
+    block_size: usize,
+    groups: usize,
+    inode_size: usize,
+    first_ino: u32,
+    filetype: bool,
+    // 知らないro_compat機能があれば読み込み専用で使う
+    read_only: bool,
+}
+
+static mut GEOMETRY: Geometry = Geometry {
+    block_size: 1024,
+    groups: 0,
+    inode_size: GOOD_OLD_INODE_SIZE,
+    first_ino: GOOD_OLD_FIRST_INO,
+    filetype: false,
+    read_only: false,
+};
+// 開かれているinodeとその参照数
+static mut OPEN: [(u32, usize); OPEN_MAX] = [(0, 0); OPEN_MAX];
+
+fn geometry() -> &'static Geometry {
+    unsafe { &*core::ptr::addr_of!(GEOMETRY) }
+}
+
+fn check_writable() -> Result<(), i32> {
+    if geometry().read_only {
+        return Err(EROFS);
+    }
+    Ok(())
+}
+
+fn block_size() -> usize {
+    geometry().block_size
+}
+
+/// ディスクの`off`にある構造体を読み、`f`で書き換えて書き戻す
+fn update<T: Copy>(off: usize, f: impl FnOnce(&mut T)) -> Result<(), i32> {
+    let mut value = bcache_get::<T>(off)?;
+    f(&mut value);
+    bcache_put(off, &value)
+}
+
+fn sb() -> Result<Superblock, i32> {
+    bcache_get(SUPERBLOCK_OFFSET)
+}
+
+fn update_sb(f: impl FnOnce(&mut Superblock)) -> Result<(), i32> {
+    update(SUPERBLOCK_OFFSET, f)
+}
+
+/// グループディスクリプタはスーパーブロックの次のブロックから並ぶ
+fn gd_offset(group: usize) -> Result<usize, i32> {
+    Ok((sb()?.first_data_block as usize + 1) * block_size() + group * size_of::<GroupDesc>())
+}
+
+fn gd(group: usize) -> Result<GroupDesc, i32> {
+    bcache_get(gd_offset(group)?)
+}
+
+fn update_gd(group: usize, f: impl FnOnce(&mut GroupDesc)) -> Result<(), i32> {
+    update(gd_offset(group)?, f)
+}
+
+fn inode_offset(ino: u32) -> Result<usize, i32> {
+    let per_group = sb()?.inodes_per_group as usize;
+    let index = ino as usize - 1;
+    let table = gd(index / per_group)?.inode_table as usize;
+    Ok(table * block_size() + (index % per_group) * geometry().inode_size)
+}
+
+fn inode(ino: u32) -> Result<Inode, i32> {
+    bcache_get(inode_offset(ino)?)
+}
+
+fn update_inode(ino: u32, f: impl FnOnce(&mut Inode)) -> Result<(), i32> {
+    update(inode_offset(ino)?, f)
+}
+
+fn block_offset(b: u32) -> usize {
+    b as usize * block_size()
+}
+
+/// ブロック`b`の`off`バイト目にある値
+fn read_u32(b: u32, off: usize) -> Result<u32, i32> {
+    bcache_get::<[u8; 4]>(block_offset(b) + off).map(u32::from_le_bytes)
+}
+
+fn write_u32(b: u32, off: usize, value: u32) -> Result<(), i32> {
+    bcache_put(block_offset(b) + off, &value.to_le_bytes())
+}
+
+/// ディスクの先頭をext2として読めるか調べ、読めれば以降の操作に使う値を設定する。
+/// ext2の印がなければ`Ok(false)`、印があるのに使えなければエラーを返す
+pub fn ext2_probe() -> Result<bool, i32> {
+    let disk_size = bcache_size();
+    if disk_size < SUPERBLOCK_OFFSET + size_of::<Superblock>() {
+        return Ok(false);
+    }
+    let sb = sb()?;
+    if sb.magic != EXT2_MAGIC {
+        return Ok(false);
+    }
+
+    // ブロックサイズは64KiBまで
+    if sb.log_block_size > 6 || sb.blocks_per_group == 0 || sb.inodes_per_group == 0 {
+        println!("ext2: corrupt superblock");
+        return Err(EINVAL);
+    }
+    let block_size = 1024 << sb.log_block_size;
+    if sb.feature_incompat & !FEATURE_INCOMPAT_FILETYPE != 0 {
+        println!("ext2: unsupported features {:#x}", sb.feature_incompat);
+        return Err(EINVAL);
+    }
+    let fits = (sb.blocks_count as usize)
+        .checked_mul(block_size)
+        .is_some_and(|size| size <= disk_size);
+    if !fits || sb.first_data_block >= sb.blocks_count {
+        println!("ext2: filesystem is larger than the disk");
+        return Err(EINVAL);
+    }
+
+    let ro_compat =
+        sb.feature_ro_compat & !(FEATURE_RO_COMPAT_SPARSE_SUPER | FEATURE_RO_COMPAT_LARGE_FILE);
+    if ro_compat != 0 {
+        println!(
+            "ext2: unsupported features {:#x}, mounting read-only",
+            ro_compat
+        );
+    }
+
+    let (inode_size, first_ino) = if sb.rev_level == 0 {
+        (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INO)
+    } else {
+        (sb.inode_size as usize, sb.first_ino)
+    };
+    let data_blocks = (sb.blocks_count - sb.first_data_block) as usize;
+    unsafe {
+        GEOMETRY = Geometry {
+            block_size,
+            groups: data_blocks.div_ceil(sb.blocks_per_group as usize),
+            inode_size,
+            first_ino,
+            filetype: sb.feature_incompat & FEATURE_INCOMPAT_FILETYPE != 0,
+            read_only: ro_compat != 0,
+        };
+    }
+
+    println!(
+        "ext2: {} blocks of {} bytes, {} inodes, {} groups",
+        sb.blocks_count,
+        block_size,
+        sb.inodes_count,
+        geometry().groups
+    );
+    Ok(true)
+}
+
+/// ビットマップのブロックで`start`以降の最初の空きビットを立て、その番号を返す
+fn alloc_bit(bitmap: u32, start: usize, count: usize) -> Result<Option<usize>, i32> {
+    // ビットマップは少しずつ読む
+    let mut chunk = [0u8; 64];
+    let mut i = start;
+    while i < count {
+        let base = i / 8;
+        let len = chunk.len().min(count.div_ceil(8) - base);
+        bcache_read(block_offset(bitmap) + base, &mut chunk[..len])?;
+        let end = count.min((base + len) * 8);
+        if let Some(i) = (i..end).find(|i| chunk[i / 8 - base] & (1 << (i % 8)) == 0) {
+            let byte = chunk[i / 8 - base] | (1 << (i % 8));
+            bcache_put(block_offset(bitmap) + i / 8, &byte)?;
+            return Ok(Some(i));
+        }
+        i = end;
+    }
+    Ok(None)
+}
+
+fn free_bit(bitmap: u32, i: usize) -> Result<(), i32> {
+    let off = block_offset(bitmap) + i / 8;
+    let byte = bcache_get::<u8>(off)?;
+    bcache_put(off, &(byte & !(1 << (i % 8))))
+}
+
+/// 空きブロックを確保し、ゼロで埋める
+fn alloc_block() -> Result<u32, i32> {
+    let sb = sb()?;
+    let per_group = sb.blocks_per_group as usize;
+    let first = sb.first_data_block as usize;
+    for group in 0..geometry().groups {
+        let gd = gd(group)?;
+        if gd.free_blocks_count == 0 {
+            continue;
+        }
+
+        // 最後のグループはブロック数が少ないことがある
+        let count = per_group.min(sb.blocks_count as usize - first - group * per_group);
+        if let Some(i) = alloc_bit(gd.block_bitmap, 0, count)? {
+            update_gd(group, |gd| gd.free_blocks_count -= 1)?;
+            update_sb(|sb| sb.free_blocks_count -= 1)?;
+            let b = (first + group * per_group + i) as u32;
+            bcache_zero(block_offset(b), block_size())?;
+            return Ok(b);
+        }
+    }
+    Err(ENOSPC)
+}
+
+fn free_block(b: u32) -> Result<(), i32> {
+    let sb = sb()?;
+    let per_group = sb.blocks_per_group as usize;
+    let index = b as usize - sb.first_data_block as usize;
+    let group = index / per_group;
+    free_bit(gd(group)?.block_bitmap, index % per_group)?;
+    update_gd(group, |gd| gd.free_blocks_count += 1)?;
+    update_sb(|sb| sb.free_blocks_count += 1)
+}
+
+/// 空きinodeを確保し、ゼロで初期化する
+fn alloc_inode(mode: u16) -> Result<u32, i32> {
+    let sb = sb()?;
+    let per_group = sb.inodes_per_group as usize;
+    for group in 0..geometry().groups {
+        let gd = gd(group)?;
+        if gd.free_inodes_count == 0 {
+            continue;
+        }
+
+        // 最初のグループの先頭には予約済みのinodeがある
+        let start = if group == 0 {
+            geometry().first_ino as usize - 1
+        } else {
+            0
+        };
+        let count = per_group.min(sb.inodes_count as usize - group * per_group);
+        let Some(i) = alloc_bit(gd.inode_bitmap, start, count)? else {
+            continue;
+        };
+        let ino = (group * per_group + i + 1) as u32;
+
+        update_gd(group, |gd| {
+            gd.free_inodes_count -= 1;
+            if mode & S_IFMT == S_IFDIR {
+                gd.used_dirs_count += 1;
+            }
+        })?;
+        update_sb(|sb| sb.free_inodes_count -= 1)?;
+
+        bcache_zero(inode_offset(ino)?, geometry().inode_size)?;
+        update_inode(ino, |inode| {
+            inode.mode = mode;
+            inode.links_count = 1;
+        })?;
+        return Ok(ino);
+    }
+    Err(ENOSPC)
+}
+
+/// リンクがなくなったinodeのブロックとinode自体を解放する
+fn free_inode(ino: u32) -> Result<(), i32> {
+    let inode = inode(ino)?;
+    if !inode.is_fast_symlink() && !inode.is_device() {
+        truncate_blocks(ino, 0)?;
+    }
+
+    let sb = sb()?;
+    update_inode(ino, |inode| {
+        inode.links_count = 0;
+        // 削除時刻を持つinodeは削除済みとみなされる。時計がないので最後にスーパーブロックが
+        // 書かれた時刻を使う。inode数より小さい値は孤立inodeのリストと解釈されるので避ける
+        inode.dtime = sb.wtime.max(sb.inodes_count);
+    })?;
+
+    let per_group = sb.inodes_per_group as usize;
+    let index = ino as usize - 1;
+    let group = index / per_group;
+    free_bit(gd(group)?.inode_bitmap, index % per_group)?;
+    update_gd(group, |gd| {
+        gd.free_inodes_count += 1;
+        if inode.kind() == FileKind::Directory {
+            gd.used_dirs_count -= 1;
+        }
+    })?;
+    update_sb(|sb| sb.free_inodes_count += 1)
+}
+
+/// ファイルの`index`番目のブロックの番号。穴なら0を返すが、`alloc`なら確保する
+fn bmap(ino: u32, index: usize, alloc: bool) -> Result<u32, i32> {
+    let per = block_size() / 4;
+    let (slot, depth, mut index) = if index < DIRECT_BLOCKS {
+        (index, 0, 0)
+    } else if index - DIRECT_BLOCKS < per {
+        (DIRECT_BLOCKS, 1, index - DIRECT_BLOCKS)
+    } else if index - DIRECT_BLOCKS - per < per * per {
+        (DIRECT_BLOCKS + 1, 2, index - DIRECT_BLOCKS - per)
+    } else if index - DIRECT_BLOCKS - per - per * per < per * per * per {
+        (
+            DIRECT_BLOCKS + 2,
+            3,
+            index - DIRECT_BLOCKS - per - per * per,
+        )
+    } else {
+        return Err(EFBIG);
+    };
+
+    let mut b = inode(ino)?.block[slot];
+    if b == 0 {
+        if !alloc {
+            return Ok(0);
+        }
+        b = alloc_block()?;
+        update_inode(ino, |inode| {
+            inode.block[slot] = b;
+            inode.blocks += (block_size() / 512) as u32;
+        })?;
+    }
+
+    for level in (0..depth).rev() {
+        let span = per.pow(level);
+        let entry = index / span * 4;
+        index %= span;
+        let mut next = read_u32(b, entry)?;
+        if next == 0 {
+            if !alloc {
+                return Ok(0);
+            }
+            next = alloc_block()?;
+            write_u32(b, entry, next)?;
+            update_inode(ino, |inode| inode.blocks += (block_size() / 512) as u32)?;
+        }
+        b = next;
+    }
+    Ok(b)
+}
+
+/// `b`を根とする深さ`depth`の間接ブロックの木で、先頭`keep`ブロックより後ろを解放する。
+/// 木が空になれば`b`自体も解放して真を返す
+fn truncate_tree(b: u32, depth: u32, keep: usize, freed: &mut usize) -> Result<bool, i32> {
+    if depth > 0 {
+        let per = block_size() / 4;
+        let span = per.pow(depth - 1);
+        for entry in 0..per {
+            let child = read_u32(b, entry * 4)?;
+            let child_keep = keep.saturating_sub(entry * span);
+            if child == 0 || child_keep >= span {
+                continue;
+            }
+            if truncate_tree(child, depth - 1, child_keep, freed)? {
+                write_u32(b, entry * 4, 0)?;
+            }
+        }
+    }
+
+    if keep > 0 {
+        return Ok(false);
+    }
+    free_block(b)?;
+    *freed += 1;
+    Ok(true)
+}
+
+/// 先頭`len`バイトを含むブロックより後ろを解放する
+fn truncate_blocks(ino: u32, len: usize) -> Result<(), i32> {
+    let per = block_size() / 4;
+    let keep = len.div_ceil(block_size());
+    let mut freed = 0;
+    let mut start = 0;
+    for (slot, depth) in (0..DIRECT_BLOCKS)
+        .map(|i| (i, 0))
+        .chain([(12, 1), (13, 2), (14, 3)])
+    {
+        let span = per.pow(depth);
+        let b = inode(ino)?.block[slot];
+        if b != 0 && truncate_tree(b, depth, keep.saturating_sub(start), &mut freed)? {
+            update_inode(ino, |inode| inode.block[slot] = 0)?;
+        }
+        start += span;
+    }
+    update_inode(ino, |inode| {
+        inode.blocks -= (freed * block_size() / 512) as u32;
+    })
+}
+
+fn read_inode(ino: u32, offset: usize, buf: &mut [u8]) -> Result<usize, i32> {
+    let size = inode(ino)?.size as usize;
+    if offset >= size {
+        return Ok(0);
+    }
+
+    let len = buf.len().min(size - offset);
+    let mut done = 0;
+    while done < len {
+        let pos = offset + done;
+        let block_off = pos % block_size();
+        let n = (block_size() - block_off).min(len - done);
+        match bmap(ino, pos / block_size(), false)? {
+            0 => buf[done..done + n].fill(0),
+            b => bcache_read(block_offset(b) + block_off, &mut buf[done..done + n])?,
+        }
+        done += n;
+    }
+    Ok(len)
+}
+
+fn write_inode(ino: u32, offset: usize, buf: &[u8]) -> Result<usize, i32> {
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done;
+        let block_off = pos % block_size();
+        let n = (block_size() - block_off).min(buf.len() - done);
+        let written = bmap(ino, pos / block_size(), true)
+            .and_then(|b| bcache_write(block_offset(b) + block_off, &buf[done..done + n]));
+        match written {
+            Ok(()) => done += n,
+            Err(_) if done > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    update_inode(ino, |inode| {
+        inode.size = inode.size.max((offset + done) as u32)
+    })?;
+    Ok(done)
+}
+
+fn truncate_inode(ino: u32, len: usize) -> Result<(), i32> {
+    let size = inode(ino)?.size as usize;
+    if len < size {
+        truncate_blocks(ino, len)?;
+        // 後で伸ばしたときに読めるよう、最後のブロックの残りをゼロにする
+        let block_off = len % block_size();
+        if block_off > 0 {
+            if let b @ 1.. = bmap(ino, len / block_size(), false)? {
+                bcache_zero(block_offset(b) + block_off, block_size() - block_off)?;
+            }
+        }
+    }
+    update_inode(ino, |inode| inode.size = len as u32)
+}
+
+/// ディレクトリエントリのうち名前を除いた部分の長さ
+const DIRENT_HEADER_SIZE: usize = 8;
+
+fn dirent_size(name_len: usize) -> usize {
+    (DIRENT_HEADER_SIZE + name_len + 3) & !3
+}
+
+/// ディレクトリ内の`pos`バイト目にあるエントリ
+struct Slot {
+    block: u32,
+    off: usize,
+    ino: u32,
+    rec_len: usize,
+    name_len: usize,
+    name: [u8; NAME_MAX],
+}
+
+impl Slot {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+fn dir_slot(dir: u32, pos: usize) -> Result<Slot, i32> {
+    let b = bmap(dir, pos / block_size(), false)?;
+    if b == 0 {
+        return Err(EIO);
+    }
+
+    let off = pos % block_size();
+    let header = bcache_get::<[u8; DIRENT_HEADER_SIZE]>(block_offset(b) + off)?;
+    let mut slot = Slot {
+        block: b,
+        off,
+        ino: u32::from_le_bytes(header[..4].try_into().unwrap()),
+        rec_len: u16::from_le_bytes([header[4], header[5]]) as usize,
+        name_len: header[6] as usize,
+        name: [0; NAME_MAX],
+    };
+    if slot.rec_len < DIRENT_HEADER_SIZE
+        || off + slot.rec_len > block_size()
+        || DIRENT_HEADER_SIZE + slot.name_len > slot.rec_len
+    {
+        println!("ext2: corrupt directory entry in inode {}", dir);
+        return Err(EIO);
+    }
+    bcache_read(
+        block_offset(b) + off + DIRENT_HEADER_SIZE,
+        &mut slot.name[..slot.name_len],
+    )?;
+    Ok(slot)
+}
+
+fn set_rec_len(b: u32, off: usize, rec_len: usize) -> Result<(), i32> {
+    bcache_put(block_offset(b) + off + 4, &(rec_len as u16).to_le_bytes())
+}
+
+fn write_dirent(
+    b: u32,
+    off: usize,
+    ino: u32,
+    rec_len: usize,
+    name: &str,
+    kind: FileKind,
+) -> Result<(), i32> {
+    let mut header = [0u8; DIRENT_HEADER_SIZE];
+    header[..4].copy_from_slice(&ino.to_le_bytes());
+    header[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+    header[6] = name.len() as u8;
+    header[7] = if geometry().filetype {
+        match kind {
+            FileKind::Regular => 1,
+            FileKind::Directory => 2,
+            FileKind::CharDevice => 3,
+            FileKind::BlockDevice => 4,
+            FileKind::Symlink => 7,
+        }
+    } else {
+        0
+    };
+    bcache_write(block_offset(b) + off, &header)?;
+    bcache_write(block_offset(b) + off + DIRENT_HEADER_SIZE, name.as_bytes())
+}
+
+/// ディレクトリのエントリを先頭から返す。`pos`は次のエントリの位置
+fn next_slot(dir: u32, pos: &mut usize) -> Result<Option<Slot>, i32> {
+    if *pos >= inode(dir)?.size as usize {
+        return Ok(None);
+    }
+    let slot = dir_slot(dir, *pos)?;
+    *pos += slot.rec_len;
+    Ok(Some(slot))
+}
+
+fn find_entry(dir: u32, name: &str) -> Result<u32, i32> {
+    let mut pos = 0;
+    while let Some(slot) = next_slot(dir, &mut pos)? {
+        if slot.ino != 0 && slot.name() == name.as_bytes() {
+            return Ok(slot.ino);
+        }
+    }
+    Err(ENOENT)
+}
+
+fn add_entry(dir: u32, name: &str, ino: u32, kind: FileKind) -> Result<(), i32> {
+    if name.len() > NAME_MAX {
+        return Err(ENAMETOOLONG);
+    }
+
+    update_inode(dir, |inode| inode.flags &= !INDEX_FL)?;
+    let needed = dirent_size(name.len());
+    let mut pos = 0;
+    while let Some(slot) = next_slot(dir, &mut pos)? {
+        // 使われていないエントリか、エントリの後ろの余りに入れる
+        let used = if slot.ino == 0 {
+            0
+        } else {
+            dirent_size(slot.name_len)
+        };
+        if slot.rec_len - used < needed {
+            continue;
+        }
+
+        if used > 0 {
+            set_rec_len(slot.block, slot.off, used)?;
+        }
+        return write_dirent(
+            slot.block,
+            slot.off + used,
+            ino,
+            slot.rec_len - used,
+            name,
+            kind,
+        );
+    }
+
+    // 空きがなければブロックを足す
+    let size = inode(dir)?.size as usize;
+    let b = bmap(dir, size / block_size(), true)?;
+    write_dirent(b, 0, ino, block_size(), name, kind)?;
+    update_inode(dir, |inode| inode.size = (size + block_size()) as u32)
+}
+
+fn remove_entry(dir: u32, name: &str) -> Result<(), i32> {
+    update_inode(dir, |inode| inode.flags &= !INDEX_FL)?;
+    let mut pos = 0;
+    let mut prev: Option<Slot> = None;
+    while let Some(slot) = next_slot(dir, &mut pos)? {
+        if slot.off == 0 {
+            prev = None;
+        }
+        if slot.ino == 0 || slot.name() != name.as_bytes() {
+            prev = Some(slot);
+            continue;
+        }
+
+        // ブロックの先頭なら空きにし、それ以外は直前のエントリに吸収させる
+        return match prev {
+            Some(prev) => set_rec_len(prev.block, prev.off, prev.rec_len + slot.rec_len),
+            None => write_u32(slot.block, slot.off, 0),
+        };
+    }
+    Err(ENOENT)
+}
+
+fn is_empty_dir(dir: u32) -> Result<bool, i32> {
+    let mut pos = 0;
+    while let Some(slot) = next_slot(dir, &mut pos)? {
+        if slot.ino != 0 && slot.name() != b"." && slot.name() != b".." {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// ルートからディレクトリを辿って`path`のinodeを探す
+fn lookup_path(path: &PathBuf) -> Result<u32, i32> {
+    let mut ino = ROOT_INO;
+    for component in path.relative().split('/').filter(|c| !c.is_empty()) {
+        if inode(ino)?.kind() != FileKind::Directory {
+            return Err(ENOTDIR);
+        }
+        ino = find_entry(ino, component)?;
+    }
+    Ok(ino)
+}
+
+/// 作成するエントリの親ディレクトリ。既に存在すれば`EEXIST`
+fn lookup_parent(path: &PathBuf) -> Result<u32, i32> {
+    if path.is_root() {
+        return Err(EEXIST);
+    }
+
+    let parent = lookup_path(&path.parent())?;
+    if inode(parent)?.kind() != FileKind::Directory {
+        return Err(ENOTDIR);
+    }
+    match find_entry(parent, path.file_name()) {
+        Ok(_) => Err(EEXIST),
+        Err(ENOENT) => Ok(parent),
+        Err(e) => Err(e),
+    }
+}
+
+/// 新しいinodeを作って`path`に置く。置けなければinodeを解放する
+fn create_inode(path: &PathBuf, mode: u16) -> Result<u32, i32> {
+    let parent = lookup_parent(path)?;
+    let ino = alloc_inode(mode)?;
+    if let Err(e) =
+        inode(ino).and_then(|inode| add_entry(parent, path.file_name(), ino, inode.kind()))
+    {
+        free_inode(ino)?;
+        return Err(e);
+    }
+    Ok(ino)
+}
+
+fn open_refs(ino: u32) -> usize {
+    unsafe { &*core::ptr::addr_of!(OPEN) }
+        .iter()
+        .find(|(i, _)| *i == ino)
+        .map_or(0, |(_, refs)| *refs)
+}
+
+/// VFSから見たext2。`Node`はinode番号
+pub struct Ext2Fs;
+
+pub static EXT2FS: Ext2Fs = Ext2Fs;
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn kind(&self, path: &PathBuf) -> Result<FileKind, i32> {
+        Ok(inode(lookup_path(path)?)?.kind())
+    }
+
+    fn stat(&self, path: &PathBuf) -> Result<Stat, i32> {
+        let inode = inode(lookup_path(path)?)?;
+        Ok(Stat {
+            kind: inode.kind().dirent_type(),
+            mode: (inode.mode & 0o7777) as u32,
+            uid: inode.uid as u32,
+            gid: inode.gid as u32,
+            nlink: inode.links_count as u32,
+            size: inode.size as u64,
+            mtime: inode.mtime as u64,
+        })
+    }
+
+    fn readdir(&self, dir: &PathBuf, cursor: &mut usize) -> Option<DirEntry> {
+        let dir = lookup_path(dir).ok()?;
+        if inode(dir).ok()?.kind() != FileKind::Directory {
+            return None;
+        }
+
+        while let Some(slot) = next_slot(dir, cursor).ok()? {
+            let name = slot.name();
+            if slot.ino == 0 || name == b"." || name == b".." {
+                continue;
+            }
+            if let Ok(name) = core::str::from_utf8(name) {
+                return Some(DirEntry::new(name, inode(slot.ino).ok()?.kind()));
+            }
+        }
+        None
+    }
+
+    fn readlink(&self, path: &PathBuf, buf: &mut [u8]) -> Result<usize, i32> {
+        let ino = lookup_path(path)?;
+        let inode = inode(ino)?;
+        if inode.kind() != FileKind::Symlink {
+            return Err(EINVAL);
+        }
+
+        if inode.is_fast_symlink() {
+            let len = (inode.size as usize).min(buf.len());
+            let off = inode_offset(ino)? + offset_of!(Inode, block);
+            bcache_read(off, &mut buf[..len])?;
+            Ok(len)
+        } else {
+            read_inode(ino, 0, buf)
+        }
+    }
+
+    fn lookup(&self, path: &PathBuf) -> Result<Node, i32> {
+        let ino = lookup_path(path)?;
+        if inode(ino)?.kind() == FileKind::Directory {
+            return Err(EISDIR);
+        }
+        Ok(ino as Node)
+    }
+
+    fn open(&self, node: Node) {
+        let open = unsafe { &mut *core::ptr::addr_of_mut!(OPEN) };
+        let entry = match open.iter().position(|(i, _)| *i == node as u32) {
+            Some(i) => &mut open[i],
+            // 表が埋まっていれば開いていることを記録しない
+            None => match open.iter_mut().find(|(i, _)| *i == 0) {
+                Some(entry) => entry,
+                None => return,
+            },
+        };
+        entry.0 = node as u32;
+        entry.1 += 1;
+    }
+
+    fn close(&self, node: Node) {
+        let open = unsafe { &mut *core::ptr::addr_of_mut!(OPEN) };
+        if let Some(entry) = open.iter_mut().find(|(i, _)| *i == node as u32) {
+            entry.1 -= 1;
+            if entry.1 == 0 {
+                entry.0 = 0;
+            }
+        }
+    }
+
+    // デバイスファイルに対応するドライバはないので、中身は読み書きできない
+    fn read(&self, node: Node, offset: usize, buf: &mut [u8]) -> Result<usize, i32> {
+        if inode(node as u32)?.is_device() {
+            return Err(ENODEV);
+        }
+        read_inode(node as u32, offset, buf)
+    }
+
+    fn write(&self, node: Node, offset: usize, buf: &[u8]) -> Result<usize, i32> {
+        check_writable()?;
+        if inode(node as u32)?.is_device() {
+            return Err(ENODEV);
+        }
+        write_inode(node as u32, offset, buf)
+    }
+
+    fn size(&self, node: Node) -> usize {
+        inode(node as u32).map_or(0, |inode| inode.size as usize)
+    }
+
+    fn truncate(&self, node: Node, len: usize) -> Result<(), i32> {
+        check_writable()?;
+        if inode(node as u32)?.is_device() {
+            return Err(EINVAL);
+        }
+        truncate_inode(node as u32, len)
+    }
+
+    fn create(&self, path: &PathBuf) -> Result<(), i32> {
+        check_writable()?;
+        create_inode(path, S_IFREG | 0o644).map(|_| ())
+    }
+
+    fn mkdir(&self, path: &PathBuf) -> Result<(), i32> {
+        check_writable()?;
+        let parent = lookup_parent(path)?;
+        let ino = create_inode(path, S_IFDIR | 0o755)?;
+        let b = match bmap(ino, 0, true) {
+            Ok(b) => b,
+            Err(e) => {
+                remove_entry(parent, path.file_name())?;
+                free_inode(ino)?;
+                return Err(e);
+            }
+        };
+
+        let dot_len = dirent_size(1);
+        write_dirent(b, 0, ino, dot_len, ".", FileKind::Directory)?;
+        write_dirent(
+            b,
+            dot_len,
+            parent,
+            block_size() - dot_len,
+            "..",
+            FileKind::Directory,
+        )?;
+        update_inode(ino, |inode| {
+            inode.size = block_size() as u32;
+            inode.links_count = 2;
+        })?;
+        update_inode(parent, |inode| inode.links_count += 1)
+    }
+
+    fn unlink(&self, path: &PathBuf) -> Result<(), i32> {
+        check_writable()?;
+        let ino = lookup_path(path)?;
+        let inode = inode(ino)?;
+        if inode.kind() == FileKind::Directory {
+            return Err(EISDIR);
+        }
+        if inode.links_count <= 1 && open_refs(ino) > 0 {
+            return Err(EBUSY);
+        }
+
+        remove_entry(lookup_path(&path.parent())?, path.file_name())?;
+        update_inode(ino, |inode| inode.links_count -= 1)?;
+        if inode.links_count == 1 {
+            free_inode(ino)?;
+        }
+        Ok(())
+    }
+
+    fn rmdir(&self, path: &PathBuf) -> Result<(), i32> {
+        check_writable()?;
+        if path.is_root() {
+            return Err(EBUSY);
+        }
+        let ino = lookup_path(path)?;
+        if inode(ino)?.kind() != FileKind::Directory {
+            return Err(ENOTDIR);
+        }
+        if !is_empty_dir(ino)? {
+            return Err(ENOTEMPTY);
+        }
+
+        let parent = lookup_path(&path.parent())?;
+        remove_entry(parent, path.file_name())?;
+        update_inode(parent, |inode| inode.links_count -= 1)?;
+        free_inode(ino)
+    }
+
+    fn symlink(&self, target: &str, path: &PathBuf) -> Result<(), i32> {
+        check_writable()?;
+        let ino = create_inode(path, S_IFLNK | 0o777)?;
+        // 短いリンク先はブロックを確保せずinodeに入れる
+        if target.len() < size_of::<[u32; 15]>() {
+            let off = inode_offset(ino)? + offset_of!(Inode, block);
+            bcache_write(off, target.as_bytes())?;
+            return update_inode(ino, |inode| inode.size = target.len() as u32);
+        }
+
+        match write_inode(ino, 0, target.as_bytes()) {
+            Ok(len) if len == target.len() => Ok(()),
+            result => {
+                remove_entry(lookup_path(&path.parent())?, path.file_name())?;
+                free_inode(ino)?;
+                Err(result.err().unwrap_or(ENOSPC))
+            }
+        }
+    }
+
+    fn link(&self, old: &PathBuf, path: &PathBuf) -> Result<(), i32> {
+        check_writable()?;
+        let ino = lookup_path(old)?;
+        let inode = inode(ino)?;
+        if inode.kind() == FileKind::Directory {
+            return Err(EPERM);
+        }
+
+        let parent = lookup_parent(path)?;
+        add_entry(parent, path.file_name(), ino, inode.kind())?;
+        update_inode(ino, |inode| inode.links_count += 1)
+    }
+
+    fn sync(&self) {
+        unsafe { bcache_sync(VIRTIO.as_mut().unwrap()) };
+    }
+}
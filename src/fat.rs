@@ -0,0 +1,1082 @@
+// FAT32ファイルシステム (VFATの長いファイル名を含む): ブロックキャッシュを通してディスクを読み書きする。
+// inodeがないので、ファイルはディレクトリエントリ (短い名前のエントリ) のディスク上の位置で識別する
+
+use common::{
+    println, Stat, EACCES, EBUSY, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC,
+    ENOTDIR, ENOTEMPTY, EPERM,
+};
+
+use crate::{
+    bcache::{
+        bcache_get, bcache_put, bcache_read, bcache_size, bcache_sync, bcache_write, bcache_zero,
+    },
+    path::{PathBuf, PATH_MAX},
+    vfs::{DirEntry, FileKind, FileSystem, Node},
+    VIRTIO,
+};
+
+const DIRENT_SIZE: usize = 32;
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+// 長い名前のエントリはこの属性の組み合わせを持つ
+const ATTR_LONG_NAME: u8 = 0x0f;
+const LAST_LONG_ENTRY: u8 = 0x40;
+// 長い名前のエントリ1つに入るUTF-16の文字数
+const LFN_CHARS: usize = 13;
+const NAME_MAX: usize = 255;
+// 削除済み・ここで終わりを表すエントリの先頭バイト
+const DELETED: u8 = 0xe5;
+const END_OF_DIR: u8 = 0x00;
+// 短い名前の小文字フラグ (Windows NT)
+const NTRES_LOWER_BASE: u8 = 0x08;
+const NTRES_LOWER_EXT: u8 = 0x10;
+
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+// この値以上ならチェーンの終わり
+const END_OF_CHAIN_MIN: u32 = 0x0fff_fff8;
+const END_OF_CHAIN: u32 = 0x0fff_ffff;
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+// 同時に開かれるファイルの数の上限
+const OPEN_MAX: usize = 64;
+
+/// 起動時にBPB (BIOS Parameter Block) から読み取る値
+#[derive(Copy, Clone)]
+struct Geometry {
+    cluster_size: usize,
+    // 先頭のFATの位置とFAT1つ分のバイト数
+    fat_offset: usize,
+    fat_size: usize,
+    num_fats: usize,
+    data_offset: usize,
+    // 有効なクラスタ番号は2..cluster_end
+    cluster_end: u32,
+    root_cluster: u32,
+    // FSInfoセクタの位置。ない場合は0
+    fsinfo_offset: usize,
+}
+
+static mut GEOMETRY: Geometry = Geometry {
+    cluster_size: 512,
+    fat_offset: 0,
+    fat_size: 0,
+    num_fats: 0,
+    data_offset: 0,
+    cluster_end: 0,
+    root_cluster: 0,
+    fsinfo_offset: 0,
+};
+// 開かれているファイル (短い名前のエントリの位置) とその参照数
+static mut OPEN: [(usize, usize); OPEN_MAX] = [(0, 0); OPEN_MAX];
+
+fn geometry() -> &'static Geometry {
+    unsafe { &*core::ptr::addr_of!(GEOMETRY) }
+}
+
+fn read_u8(off: usize) -> Result<u8, i32> {
+    bcache_get(off)
+}
+
+fn read_u16(off: usize) -> Result<u16, i32> {
+    bcache_get::<[u8; 2]>(off).map(u16::from_le_bytes)
+}
+
+fn read_u32(off: usize) -> Result<u32, i32> {
+    bcache_get::<[u8; 4]>(off).map(u32::from_le_bytes)
+}
+
+fn write_u8(off: usize, value: u8) -> Result<(), i32> {
+    bcache_put(off, &value)
+}
+
+fn write_u16(off: usize, value: u16) -> Result<(), i32> {
+    bcache_put(off, &value.to_le_bytes())
+}
+
+fn write_u32(off: usize, value: u32) -> Result<(), i32> {
+    bcache_put(off, &value.to_le_bytes())
+}
+
+/// ディスクの先頭をFAT32として読めるか調べ、読めれば以降の操作に使う値を設定する。
+/// FATの印がなければ`Ok(false)`、印があるのに使えなければエラーを返す
+pub fn fat_probe() -> Result<bool, i32> {
+    let disk_size = bcache_size();
+    if disk_size < 512 {
+        return Ok(false);
+    }
+    // BPBは先頭のセクタにある。ブートセクタの署名に加えて、FAT12/16とFAT32の
+    // それぞれの位置にある種類の文字列で、FATのボリュームかを見分ける
+    let bpb = bcache_get::<[u8; 512]>(0)?;
+    if bpb[510..] != [0x55, 0xaa] || (&bpb[54..57] != b"FAT" && &bpb[82..85] != b"FAT") {
+        return Ok(false);
+    }
+
+    let u16_at = |off: usize| u16::from_le_bytes([bpb[off], bpb[off + 1]]);
+    let u32_at = |off: usize| u32::from_le_bytes(bpb[off..off + 4].try_into().unwrap());
+    let bytes_per_sector = u16_at(11) as usize;
+    let sectors_per_cluster = bpb[13] as usize;
+    let reserved = u16_at(14) as usize;
+    let num_fats = bpb[16] as usize;
+    let root_entries = u16_at(17);
+    let total_sectors = match u16_at(19) {
+        0 => u32_at(32) as usize,
+        n => n as usize,
+    };
+    let fat_sectors = u32_at(36) as usize;
+    // FAT12/16はルートディレクトリの領域とFATのサイズをBPBの前半に持つ
+    if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        || !sectors_per_cluster.is_power_of_two()
+        || num_fats == 0
+        || root_entries != 0
+        || u16_at(22) != 0
+        || fat_sectors == 0
+    {
+        println!("fat: not a FAT32 filesystem");
+        return Err(EINVAL);
+    }
+    let fits = total_sectors
+        .checked_mul(bytes_per_sector)
+        .is_some_and(|size| size <= disk_size);
+    if !fits {
+        println!("fat: filesystem is larger than the disk");
+        return Err(EINVAL);
+    }
+
+    // 以降の位置はボリュームの大きさに収まるので、掛け算は溢れない
+    let data_sector = num_fats
+        .checked_mul(fat_sectors)
+        .and_then(|sectors| sectors.checked_add(reserved))
+        .filter(|sector| *sector < total_sectors)
+        .ok_or_else(|| {
+            println!("fat: no room for the data region");
+            EINVAL
+        })?;
+    let clusters = (total_sectors - data_sector) / sectors_per_cluster;
+    let fsinfo_offset = match u16_at(48) as usize {
+        0 | 0xffff => 0,
+        sector => sector * bytes_per_sector,
+    };
+    let fsinfo_offset = if fsinfo_offset != 0
+        && read_u32(fsinfo_offset) == Ok(FSINFO_LEAD_SIG)
+        && read_u32(fsinfo_offset + 484) == Ok(FSINFO_STRUCT_SIG)
+    {
+        fsinfo_offset
+    } else {
+        0
+    };
+    let root_cluster = u32_at(44);
+    unsafe {
+        GEOMETRY = Geometry {
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_offset: reserved * bytes_per_sector,
+            fat_size: fat_sectors * bytes_per_sector,
+            num_fats,
+            data_offset: data_sector * bytes_per_sector,
+            // FATに入りきらないクラスタは使わない
+            cluster_end: (clusters + 2).min(fat_sectors * bytes_per_sector / 4) as u32,
+            root_cluster,
+            fsinfo_offset,
+        };
+    }
+    if !is_valid_cluster(root_cluster) {
+        println!("fat: invalid root cluster {}", root_cluster);
+        return Err(EINVAL);
+    }
+
+    println!(
+        "fat: {} clusters of {} bytes, {} FATs",
+        clusters,
+        geometry().cluster_size,
+        num_fats
+    );
+    Ok(true)
+}
+
+fn is_valid_cluster(cluster: u32) -> bool {
+    (2..geometry().cluster_end).contains(&cluster)
+}
+
+fn cluster_offset(cluster: u32) -> usize {
+    geometry().data_offset + (cluster as usize - 2) * geometry().cluster_size
+}
+
+fn fat_get(cluster: u32) -> Result<u32, i32> {
+    Ok(read_u32(geometry().fat_offset + cluster as usize * 4)? & FAT_ENTRY_MASK)
+}
+
+/// 全てのFATを更新する。上位4ビットは予約されているので残す
+fn fat_set(cluster: u32, value: u32) -> Result<(), i32> {
+    let g = geometry();
+    for i in 0..g.num_fats {
+        let off = g.fat_offset + i * g.fat_size + cluster as usize * 4;
+        write_u32(off, (read_u32(off)? & !FAT_ENTRY_MASK) | value)?;
+    }
+    Ok(())
+}
+
+/// 次のクラスタ。チェーンの終わりならNone
+fn next_cluster(cluster: u32) -> Result<Option<u32>, i32> {
+    match fat_get(cluster)? {
+        next if is_valid_cluster(next) => Ok(Some(next)),
+        next if next >= END_OF_CHAIN_MIN => Ok(None),
+        _ => {
+            println!("fat: broken cluster chain at {}", cluster);
+            Err(EIO)
+        }
+    }
+}
+
+/// FSInfoの空きクラスタ数を更新する。値が不明なら不明のままにする
+fn adjust_free_count(delta: i32) -> Result<(), i32> {
+    let off = geometry().fsinfo_offset;
+    if off == 0 {
+        return Ok(());
+    }
+    let free = read_u32(off + 488)?;
+    if free != FSINFO_UNKNOWN {
+        write_u32(off + 488, free.wrapping_add_signed(delta))?;
+    }
+    Ok(())
+}
+
+/// 空きクラスタを確保し、チェーンの終わりとしてゼロで埋める。`prev`があればその後ろに繋ぐ
+fn alloc_cluster(prev: Option<u32>) -> Result<u32, i32> {
+    let g = geometry();
+    // FSInfoの次の空きクラスタの候補から探す
+    let hint = match g.fsinfo_offset {
+        0 => 2,
+        off => read_u32(off + 492)?,
+    };
+    let hint = if is_valid_cluster(hint) { hint } else { 2 };
+    let mut cluster = None;
+    for c in (hint..g.cluster_end).chain(2..hint) {
+        if fat_get(c)? == 0 {
+            cluster = Some(c);
+            break;
+        }
+    }
+    let cluster = cluster.ok_or(ENOSPC)?;
+
+    fat_set(cluster, END_OF_CHAIN)?;
+    if let Some(prev) = prev {
+        fat_set(prev, cluster)?;
+    }
+    adjust_free_count(-1)?;
+    if g.fsinfo_offset != 0 {
+        write_u32(g.fsinfo_offset + 492, cluster + 1)?;
+    }
+
+    bcache_zero(cluster_offset(cluster), g.cluster_size)?;
+    Ok(cluster)
+}
+
+/// `cluster`から始まるチェーンを全て解放する
+fn free_chain(mut cluster: u32) -> Result<(), i32> {
+    while is_valid_cluster(cluster) {
+        let next = fat_get(cluster)?;
+        fat_set(cluster, 0)?;
+        adjust_free_count(1)?;
+        cluster = next;
+    }
+    Ok(())
+}
+
+/// チェーンの`index`番目のクラスタ。`alloc`なら足りない分を確保する
+fn nth_cluster(start: u32, index: usize, alloc: bool) -> Result<Option<u32>, i32> {
+    let mut cluster = start;
+    for _ in 0..index {
+        cluster = match next_cluster(cluster)? {
+            Some(next) => next,
+            None if alloc => alloc_cluster(Some(cluster))?,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(cluster))
+}
+
+/// 短い名前のエントリの各フィールド
+fn entry_attr(off: usize) -> Result<u8, i32> {
+    read_u8(off + 11)
+}
+
+fn entry_cluster(off: usize) -> Result<u32, i32> {
+    Ok(((read_u16(off + 20)? as u32) << 16) | read_u16(off + 26)? as u32)
+}
+
+fn set_entry_cluster(off: usize, cluster: u32) -> Result<(), i32> {
+    write_u16(off + 20, (cluster >> 16) as u16)?;
+    write_u16(off + 26, cluster as u16)
+}
+
+fn entry_size(off: usize) -> Result<usize, i32> {
+    Ok(read_u32(off + 28)? as usize)
+}
+
+fn entry_kind(off: usize) -> Result<FileKind, i32> {
+    Ok(if entry_attr(off)? & ATTR_DIRECTORY != 0 {
+        FileKind::Directory
+    } else {
+        FileKind::Regular
+    })
+}
+
+/// FATの日付と時刻 (ローカル時刻だがUTCとみなす) をUNIX時間に変換する
+fn entry_mtime(off: usize) -> Result<u64, i32> {
+    let time = read_u16(off + 22)? as i64;
+    let date = read_u16(off + 24)? as i64;
+    let (year, month, day) = (1980 + (date >> 9), (date >> 5) & 0xf, date & 0x1f);
+    if !(1..=12).contains(&month) || day == 0 {
+        return Ok(0);
+    }
+
+    // 1970年1月1日からの日数 (3月始まりの暦で計算する)
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let days = era * 146097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719468;
+    let secs = (time >> 11) * 3600 + ((time >> 5) & 0x3f) * 60 + (time & 0x1f) * 2;
+    Ok((days * 86400 + secs) as u64)
+}
+
+/// ディレクトリのエントリを先頭から1つずつ辿る。`index`は次のエントリの番号
+#[derive(Copy, Clone)]
+struct DirIter {
+    cluster: Option<u32>,
+    index: usize,
+}
+
+impl DirIter {
+    fn new(dir: u32) -> Self {
+        Self {
+            cluster: Some(dir),
+            index: 0,
+        }
+    }
+
+    /// `index`番目のエントリから辿る
+    fn at(dir: u32, index: usize) -> Result<Self, i32> {
+        let per_cluster = geometry().cluster_size / DIRENT_SIZE;
+        Ok(Self {
+            cluster: nth_cluster(dir, index / per_cluster, false)?,
+            index,
+        })
+    }
+
+    /// 次のエントリの位置。ディレクトリの最後のクラスタを過ぎたらNone
+    fn next(&mut self) -> Result<Option<usize>, i32> {
+        let per_cluster = geometry().cluster_size / DIRENT_SIZE;
+        let Some(cluster) = self.cluster else {
+            return Ok(None);
+        };
+
+        let off = cluster_offset(cluster) + (self.index % per_cluster) * DIRENT_SIZE;
+        self.index += 1;
+        if self.index.is_multiple_of(per_cluster) {
+            self.cluster = next_cluster(cluster)?;
+        }
+        Ok(Some(off))
+    }
+}
+
+/// 長い名前を含めたディレクトリのエントリ
+struct Found {
+    // 短い名前のエントリの位置
+    off: usize,
+    // 長い名前のエントリの位置
+    lfn: [usize; NAME_MAX.div_ceil(LFN_CHARS)],
+    lfn_count: usize,
+    name: [u8; PATH_MAX],
+    len: usize,
+}
+
+impl Found {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.len]).unwrap_or("")
+    }
+}
+
+fn lfn_checksum(short: &[u8]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b))
+}
+
+/// 長い名前のエントリで`ord`番目の13文字の位置
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// 短い名前を`BASE.EXT`の形にする
+fn format_short_name(entry: &[u8], name: &mut [u8; PATH_MAX]) -> usize {
+    let ntres = entry[12];
+    let base_len = entry[..8]
+        .iter()
+        .rposition(|c| *c != b' ')
+        .map_or(0, |i| i + 1);
+    let ext_len = entry[8..11]
+        .iter()
+        .rposition(|c| *c != b' ')
+        .map_or(0, |i| i + 1);
+    let mut len = 0;
+    for (i, c) in entry[..base_len].iter().enumerate() {
+        let c = if i == 0 && *c == 0x05 { DELETED } else { *c };
+        name[len] = if ntres & NTRES_LOWER_BASE != 0 {
+            c.to_ascii_lowercase()
+        } else {
+            c
+        };
+        len += 1;
+    }
+    if ext_len > 0 {
+        name[len] = b'.';
+        len += 1;
+        for c in &entry[8..8 + ext_len] {
+            name[len] = if ntres & NTRES_LOWER_EXT != 0 {
+                c.to_ascii_lowercase()
+            } else {
+                *c
+            };
+            len += 1;
+        }
+    }
+    // ASCII以外の文字はコードページに依存するので置き換える
+    for c in &mut name[..len] {
+        if !c.is_ascii() {
+            *c = b'_';
+        }
+    }
+    len
+}
+
+/// 削除済みのエントリとボリュームラベルを飛ばし、長い名前をまとめて次のファイルを返す
+fn next_found(it: &mut DirIter) -> Result<Option<Found>, i32> {
+    let mut found = Found {
+        off: 0,
+        lfn: [0; NAME_MAX.div_ceil(LFN_CHARS)],
+        lfn_count: 0,
+        name: [0; PATH_MAX],
+        len: 0,
+    };
+    let mut utf16 = [0u16; NAME_MAX.div_ceil(LFN_CHARS) * LFN_CHARS];
+    // 次に来るべき長い名前のエントリの番号と、長い名前のチェックサム
+    let mut expected = 0;
+    let mut checksum = 0;
+
+    while let Some(off) = it.next()? {
+        let entry = bcache_get::<[u8; DIRENT_SIZE]>(off)?;
+        match entry[0] {
+            END_OF_DIR => return Ok(None),
+            DELETED => {
+                expected = 0;
+                found.lfn_count = 0;
+                continue;
+            }
+            _ => {}
+        }
+
+        if entry[11] & 0x3f == ATTR_LONG_NAME {
+            let ord = (entry[0] & !LAST_LONG_ENTRY) as usize;
+            // 番号は1から始まる。範囲外の壊れたエントリは、最初のエントリでも続きでも無視する
+            if ord == 0 || ord > found.lfn.len() {
+                expected = 0;
+                found.lfn_count = 0;
+                continue;
+            }
+            if entry[0] & LAST_LONG_ENTRY != 0 {
+                expected = ord;
+                checksum = entry[13];
+                found.lfn_count = 0;
+                utf16.fill(0xffff);
+            } else if ord != expected || entry[13] != checksum {
+                // 順番の崩れた長い名前は無視する
+                expected = 0;
+                found.lfn_count = 0;
+                continue;
+            }
+
+            for (i, pos) in LFN_OFFSETS.iter().enumerate() {
+                utf16[(ord - 1) * LFN_CHARS + i] =
+                    u16::from_le_bytes([entry[*pos], entry[pos + 1]]);
+            }
+            found.lfn[found.lfn_count] = off;
+            found.lfn_count += 1;
+            expected -= 1;
+            continue;
+        }
+
+        if entry[11] & ATTR_VOLUME_ID != 0 {
+            expected = 0;
+            found.lfn_count = 0;
+            continue;
+        }
+
+        found.off = off;
+        let has_lfn =
+            found.lfn_count > 0 && expected == 0 && lfn_checksum(&entry[..11]) == checksum;
+        if has_lfn {
+            let end = utf16.iter().position(|c| *c == 0 || *c == 0xffff);
+            let units = &utf16[..end.unwrap_or(utf16.len())];
+            for ch in char::decode_utf16(units.iter().copied()) {
+                let ch = ch.unwrap_or(char::REPLACEMENT_CHARACTER);
+                if found.len + ch.len_utf8() > PATH_MAX {
+                    break;
+                }
+                found.len += ch.encode_utf8(&mut found.name[found.len..]).len();
+            }
+        } else {
+            found.lfn_count = 0;
+            found.len = format_short_name(&entry, &mut found.name);
+        }
+        return Ok(Some(found));
+    }
+    Ok(None)
+}
+
+/// ディレクトリ (ルートか、ディレクトリのエントリ) の先頭クラスタ
+fn dir_cluster(dir: Option<usize>) -> Result<u32, i32> {
+    match dir {
+        Some(off) => entry_cluster(off),
+        None => Ok(geometry().root_cluster),
+    }
+}
+
+/// `.`と`..`を除き、大文字・小文字を区別せずに名前を探す
+fn find_entry(dir: Option<usize>, name: &str) -> Result<Found, i32> {
+    let mut it = DirIter::new(dir_cluster(dir)?);
+    while let Some(found) = next_found(&mut it)? {
+        if found.name().eq_ignore_ascii_case(name) && !matches!(found.name(), "." | "..") {
+            return Ok(found);
+        }
+    }
+    Err(ENOENT)
+}
+
+/// `path`のエントリの位置。ルートならNone
+fn lookup_path(path: &PathBuf) -> Result<Option<usize>, i32> {
+    let mut entry = None;
+    for component in path.relative().split('/').filter(|c| !c.is_empty()) {
+        if let Some(off) = entry {
+            if entry_kind(off)? != FileKind::Directory {
+                return Err(ENOTDIR);
+            }
+        }
+        entry = Some(find_entry(entry, component)?.off);
+    }
+    Ok(entry)
+}
+
+/// 短い名前に使える文字 (大文字にした後)
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+/// 長い名前から短い名前の基になる部分を作り、(情報が失われたか, 小文字を含むか) を返す
+fn short_name_basis(name: &str, short: &mut [u8; 11]) -> (bool, bool) {
+    short.fill(b' ');
+    let (base, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    };
+
+    let mut lossy = false;
+    for (start, part, max) in [(0, base, 8), (8, ext, 3)] {
+        let mut len = 0;
+        for c in part.bytes() {
+            if c == b' ' || c == b'.' {
+                lossy = true;
+                continue;
+            }
+            if len == max {
+                lossy = true;
+                break;
+            }
+            let upper = c.to_ascii_uppercase();
+            short[start + len] = if is_short_name_char(upper) {
+                upper
+            } else {
+                lossy = true;
+                b'_'
+            };
+            len += 1;
+        }
+    }
+    if short[0] == b' ' {
+        short[0] = b'_';
+        lossy = true;
+    }
+    (lossy, name.bytes().any(|c| c.is_ascii_lowercase()))
+}
+
+fn short_name_exists(dir: Option<usize>, short: &[u8; 11]) -> Result<bool, i32> {
+    let mut it = DirIter::new(dir_cluster(dir)?);
+    while let Some(found) = next_found(&mut it)? {
+        if bcache_get::<[u8; 11]>(found.off)? == *short {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// 重ならないよう`BASIS~N`の形の短い名前を作る
+fn unique_short_name(dir: Option<usize>, short: &mut [u8; 11]) -> Result<(), i32> {
+    let basis = *short;
+    let base_len = basis[..8]
+        .iter()
+        .rposition(|c| *c != b' ')
+        .map_or(0, |i| i + 1);
+    for n in 1..1_000_000u32 {
+        let mut tail = [0u8; 8];
+        let mut tail_len = 0;
+        let mut m = n;
+        while m > 0 {
+            tail[7 - tail_len] = b'0' + (m % 10) as u8;
+            tail_len += 1;
+            m /= 10;
+        }
+        tail[7 - tail_len] = b'~';
+        tail_len += 1;
+
+        let keep = base_len.min(8 - tail_len);
+        short[..8].fill(b' ');
+        short[..keep].copy_from_slice(&basis[..keep]);
+        short[keep..keep + tail_len].copy_from_slice(&tail[8 - tail_len..]);
+        if !short_name_exists(dir, short)? {
+            return Ok(());
+        }
+    }
+    Err(EEXIST)
+}
+
+/// ディレクトリに連続した`count`個の空きエントリを探す。足りなければディレクトリを伸ばす
+fn alloc_entries(dir: Option<usize>, count: usize, slots: &mut [usize]) -> Result<(), i32> {
+    let start = dir_cluster(dir)?;
+    let per_cluster = geometry().cluster_size / DIRENT_SIZE;
+    let mut it = DirIter::new(start);
+    let mut run = 0;
+    loop {
+        let Some(off) = it.next()? else {
+            // 最後のクラスタの後ろに新しいクラスタを繋ぐ
+            let last = nth_cluster(start, it.index / per_cluster - 1, false)?.ok_or(EIO)?;
+            it.cluster = Some(alloc_cluster(Some(last))?);
+            continue;
+        };
+
+        if matches!(read_u8(off)?, END_OF_DIR | DELETED) {
+            slots[run] = off;
+            run += 1;
+            if run == count {
+                return Ok(());
+            }
+        } else {
+            run = 0;
+        }
+    }
+}
+
+/// `dir`に`name`のエントリを作り、短い名前のエントリの位置を返す
+fn create_entry(dir: Option<usize>, name: &str, attr: u8, cluster: u32) -> Result<usize, i32> {
+    let chars = name.encode_utf16().count();
+    if chars > NAME_MAX {
+        return Err(ENAMETOOLONG);
+    }
+    match find_entry(dir, name) {
+        Ok(_) => return Err(EEXIST),
+        Err(ENOENT) => {}
+        Err(e) => return Err(e),
+    }
+
+    // 短い名前で表せなければ`~N`を付けた短い名前と長い名前を作る
+    let mut short = [0u8; 11];
+    let (lossy, lower) = short_name_basis(name, &mut short);
+    let needs_lfn = if lossy || short_name_exists(dir, &short)? {
+        unique_short_name(dir, &mut short)?;
+        true
+    } else {
+        lower
+    };
+    let lfn_count = if needs_lfn {
+        chars.div_ceil(LFN_CHARS)
+    } else {
+        0
+    };
+
+    let mut slots = [0usize; NAME_MAX.div_ceil(LFN_CHARS) + 1];
+    alloc_entries(dir, lfn_count + 1, &mut slots)?;
+
+    // 長い名前は最後の部分から順に並べる
+    let checksum = lfn_checksum(&short);
+    for (i, off) in slots[..lfn_count].iter().enumerate() {
+        let ord = lfn_count - i;
+        let mut entry = [0u8; DIRENT_SIZE];
+        entry[0] = ord as u8 | if i == 0 { LAST_LONG_ENTRY } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let mut units = name.encode_utf16().skip((ord - 1) * LFN_CHARS);
+        // 名前の後ろは0を1つ置き、残りは0xffffで埋める
+        let mut ended = false;
+        for pos in LFN_OFFSETS {
+            let unit = match units.next() {
+                Some(unit) if !ended => unit,
+                _ if !ended => {
+                    ended = true;
+                    0
+                }
+                _ => 0xffff,
+            };
+            entry[pos..pos + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        bcache_write(*off, &entry)?;
+    }
+
+    let off = slots[lfn_count];
+    let mut entry = [0u8; DIRENT_SIZE];
+    entry[..11].copy_from_slice(&short);
+    entry[11] = attr;
+    bcache_write(off, &entry)?;
+    set_entry_cluster(off, cluster)?;
+    Ok(off)
+}
+
+fn remove_found(found: &Found) -> Result<(), i32> {
+    for off in &found.lfn[..found.lfn_count] {
+        write_u8(*off, DELETED)?;
+    }
+    write_u8(found.off, DELETED)
+}
+
+fn is_empty_dir(dir: usize) -> Result<bool, i32> {
+    let mut it = DirIter::new(entry_cluster(dir)?);
+    while let Some(found) = next_found(&mut it)? {
+        if !matches!(found.name(), "." | "..") {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// 親ディレクトリと、そこで作る名前。既に存在すれば`EEXIST`
+fn lookup_parent(path: &PathBuf) -> Result<Option<usize>, i32> {
+    if path.is_root() {
+        return Err(EEXIST);
+    }
+    let parent = lookup_path(&path.parent())?;
+    if let Some(off) = parent {
+        if entry_kind(off)? != FileKind::Directory {
+            return Err(ENOTDIR);
+        }
+    }
+    Ok(parent)
+}
+
+fn open_refs(off: usize) -> usize {
+    unsafe { &*core::ptr::addr_of!(OPEN) }
+        .iter()
+        .find(|(o, _)| *o == off)
+        .map_or(0, |(_, refs)| *refs)
+}
+
+fn read_file(off: usize, offset: usize, buf: &mut [u8]) -> Result<usize, i32> {
+    let size = entry_size(off)?;
+    if offset >= size {
+        return Ok(0);
+    }
+
+    let cluster_size = geometry().cluster_size;
+    let len = buf.len().min(size - offset);
+    let mut cluster = nth_cluster(entry_cluster(off)?, offset / cluster_size, false)?;
+    let mut done = 0;
+    while done < len {
+        let Some(c) = cluster else {
+            return Err(EIO);
+        };
+        let pos = (offset + done) % cluster_size;
+        let n = (cluster_size - pos).min(len - done);
+        bcache_read(cluster_offset(c) + pos, &mut buf[done..done + n])?;
+        done += n;
+        if done < len {
+            cluster = next_cluster(c)?;
+        }
+    }
+    Ok(len)
+}
+
+fn write_file(off: usize, offset: usize, buf: &[u8]) -> Result<usize, i32> {
+    // 読み取り専用の属性は書き込みを禁じる。削除や名前の変更は妨げない
+    if entry_attr(off)? & ATTR_READ_ONLY != 0 {
+        return Err(EACCES);
+    }
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    if offset + buf.len() > u32::MAX as usize {
+        return Err(EFBIG);
+    }
+    // ファイルの終わりより後ろに書く場合は間をゼロで埋める
+    let size = entry_size(off)?;
+    if offset > size {
+        truncate_file(off, offset)?;
+    }
+
+    let cluster_size = geometry().cluster_size;
+    if entry_cluster(off)? == 0 {
+        set_entry_cluster(off, alloc_cluster(None)?)?;
+    }
+    let mut cluster = nth_cluster(entry_cluster(off)?, offset / cluster_size, true)?;
+    let mut done = 0;
+    while done < buf.len() {
+        let Some(c) = cluster else {
+            break;
+        };
+        let pos = (offset + done) % cluster_size;
+        let n = (cluster_size - pos).min(buf.len() - done);
+        match bcache_write(cluster_offset(c) + pos, &buf[done..done + n]) {
+            Ok(()) => done += n,
+            Err(_) if done > 0 => break,
+            Err(e) => return Err(e),
+        }
+        if done < buf.len() {
+            cluster = match next_cluster(c)? {
+                Some(next) => Some(next),
+                None => alloc_cluster(Some(c)).ok(),
+            };
+        }
+    }
+
+    if done == 0 {
+        return Err(ENOSPC);
+    }
+    write_u32(off + 28, entry_size(off)?.max(offset + done) as u32)?;
+    // 変更されたことを示す
+    write_u8(off + 11, entry_attr(off)? | ATTR_ARCHIVE)?;
+    Ok(done)
+}
+
+fn truncate_file(off: usize, len: usize) -> Result<(), i32> {
+    let size = entry_size(off)?;
+    let cluster_size = geometry().cluster_size;
+    if len > size {
+        // 伸ばした部分をゼロで埋める
+        let zeros = [0u8; 512];
+        let mut pos = size;
+        while pos < len {
+            let n = (len - pos).min(zeros.len());
+            write_file(off, pos, &zeros[..n])?;
+            pos += n;
+        }
+        return Ok(());
+    }
+
+    let start = entry_cluster(off)?;
+    if len == 0 {
+        free_chain(start)?;
+        set_entry_cluster(off, 0)?;
+    } else if let Some(last) = nth_cluster(start, (len - 1) / cluster_size, false)? {
+        free_chain(fat_get(last)?)?;
+        fat_set(last, END_OF_CHAIN)?;
+    }
+    write_u32(off + 28, len as u32)
+}
+
+/// VFSから見たFAT32。`Node`は短い名前のエントリのディスク上の位置
+pub struct FatFs;
+
+pub static FATFS: FatFs = FatFs;
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn kind(&self, path: &PathBuf) -> Result<FileKind, i32> {
+        match lookup_path(path)? {
+            Some(off) => entry_kind(off),
+            None => Ok(FileKind::Directory),
+        }
+    }
+
+    fn stat(&self, path: &PathBuf) -> Result<Stat, i32> {
+        let Some(off) = lookup_path(path)? else {
+            return Ok(Stat {
+                kind: FileKind::Directory.dirent_type(),
+                mode: 0o755,
+                nlink: 1,
+                ..Stat::new()
+            });
+        };
+
+        let kind = entry_kind(off)?;
+        let mode = match kind {
+            FileKind::Directory => 0o755,
+            _ => 0o644,
+        };
+        // 読み込み専用の属性は書き込みの権限を落として表す
+        let mode = if entry_attr(off)? & ATTR_READ_ONLY != 0 {
+            mode & !0o222
+        } else {
+            mode
+        };
+        Ok(Stat {
+            kind: kind.dirent_type(),
+            mode,
+            nlink: 1,
+            size: if kind == FileKind::Directory {
+                0
+            } else {
+                entry_size(off)? as u64
+            },
+            mtime: entry_mtime(off)?,
+            ..Stat::new()
+        })
+    }
+
+    fn readdir(&self, dir: &PathBuf, cursor: &mut usize) -> Option<DirEntry> {
+        let dir = lookup_path(dir).ok()?;
+        if let Some(off) = dir {
+            if entry_kind(off).ok()? != FileKind::Directory {
+                return None;
+            }
+        }
+
+        let mut it = DirIter::at(dir_cluster(dir).ok()?, *cursor).ok()?;
+        while let Some(found) = next_found(&mut it).ok()? {
+            *cursor = it.index;
+            if !matches!(found.name(), "." | "..") {
+                return Some(DirEntry::new(found.name(), entry_kind(found.off).ok()?));
+            }
+        }
+        *cursor = it.index;
+        None
+    }
+
+    fn lookup(&self, path: &PathBuf) -> Result<Node, i32> {
+        match lookup_path(path)? {
+            Some(off) if entry_kind(off)? == FileKind::Regular => Ok(off),
+            _ => Err(EISDIR),
+        }
+    }
+
+    fn open(&self, node: Node) {
+        let open = unsafe { &mut *core::ptr::addr_of_mut!(OPEN) };
+        let entry = match open.iter().position(|(o, _)| *o == node) {
+            Some(i) => &mut open[i],
+            // 表が埋まっていれば開いていることを記録しない
+            None => match open.iter_mut().find(|(o, _)| *o == 0) {
+                Some(entry) => entry,
+                None => return,
+            },
+        };
+        entry.0 = node;
+        entry.1 += 1;
+    }
+
+    fn close(&self, node: Node) {
+        let open = unsafe { &mut *core::ptr::addr_of_mut!(OPEN) };
+        if let Some(entry) = open.iter_mut().find(|(o, _)| *o == node) {
+            entry.1 -= 1;
+            if entry.1 == 0 {
+                entry.0 = 0;
+            }
+        }
+    }
+
+    fn read(&self, node: Node, offset: usize, buf: &mut [u8]) -> Result<usize, i32> {
+        read_file(node, offset, buf)
+    }
+
+    fn write(&self, node: Node, offset: usize, buf: &[u8]) -> Result<usize, i32> {
+        write_file(node, offset, buf)
+    }
+
+    fn size(&self, node: Node) -> usize {
+        entry_size(node).unwrap_or(0)
+    }
+
+    fn truncate(&self, node: Node, len: usize) -> Result<(), i32> {
+        if entry_attr(node)? & ATTR_READ_ONLY != 0 {
+            return Err(EACCES);
+        }
+        if len > u32::MAX as usize {
+            return Err(EFBIG);
+        }
+        truncate_file(node, len)
+    }
+
+    fn create(&self, path: &PathBuf) -> Result<(), i32> {
+        let parent = lookup_parent(path)?;
+        create_entry(parent, path.file_name(), ATTR_ARCHIVE, 0).map(|_| ())
+    }
+
+    fn mkdir(&self, path: &PathBuf) -> Result<(), i32> {
+        let parent = lookup_parent(path)?;
+        let cluster = alloc_cluster(None)?;
+        if let Err(e) = create_entry(parent, path.file_name(), ATTR_DIRECTORY, cluster) {
+            free_chain(cluster)?;
+            return Err(e);
+        }
+
+        // `..`がルートを指す場合はクラスタ番号を0にする
+        let dots = [
+            (b".          ", cluster),
+            (b"..         ", parent.map_or(Ok(0), entry_cluster)?),
+        ];
+        for (i, (name, target)) in dots.into_iter().enumerate() {
+            let off = cluster_offset(cluster) + i * DIRENT_SIZE;
+            let mut entry = [0u8; DIRENT_SIZE];
+            entry[..11].copy_from_slice(name);
+            entry[11] = ATTR_DIRECTORY;
+            bcache_write(off, &entry)?;
+            set_entry_cluster(off, target)?;
+        }
+        Ok(())
+    }
+
+    fn unlink(&self, path: &PathBuf) -> Result<(), i32> {
+        let parent = lookup_path(&path.parent())?;
+        let found = find_entry(parent, path.file_name())?;
+        if entry_kind(found.off)? == FileKind::Directory {
+            return Err(EISDIR);
+        }
+        if open_refs(found.off) > 0 {
+            return Err(EBUSY);
+        }
+
+        free_chain(entry_cluster(found.off)?)?;
+        remove_found(&found)
+    }
+
+    fn rmdir(&self, path: &PathBuf) -> Result<(), i32> {
+        if path.is_root() {
+            return Err(EBUSY);
+        }
+        let parent = lookup_path(&path.parent())?;
+        let found = find_entry(parent, path.file_name())?;
+        if entry_kind(found.off)? != FileKind::Directory {
+            return Err(ENOTDIR);
+        }
+        if !is_empty_dir(found.off)? {
+            return Err(ENOTEMPTY);
+        }
+
+        free_chain(entry_cluster(found.off)?)?;
+        remove_found(&found)
+    }
+
+    /// FATにはシンボリックリンクとハードリンクがない
+    fn symlink(&self, _target: &str, _path: &PathBuf) -> Result<(), i32> {
+        Err(EPERM)
+    }
+
+    fn link(&self, _old: &PathBuf, _path: &PathBuf) -> Result<(), i32> {
+        Err(EPERM)
+    }
+
+    fn sync(&self) {
+        unsafe { bcache_sync(VIRTIO.as_mut().unwrap()) };
+    }
+}
@@ -0,0 +1,337 @@
+// フラットデバイスツリー (FDT): OpenSBIが渡すハードウェア構成からメモリとデバイスを調べる
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+// 辿るノードの深さの上限
+const DEPTH_MAX: usize = 8;
+const MEMORY_MAX: usize = 4;
+const RESERVED_MAX: usize = 8;
+const VIRTIO_MMIO_MAX: usize = 8;
+
+/// 物理メモリの範囲
+#[derive(Copy, Clone, Debug)]
+pub struct Region {
+    pub base: usize,
+    pub size: usize,
+}
+
+/// MMIOで操作するデバイス
+#[derive(Copy, Clone, Debug)]
+pub struct Device {
+    pub base: usize,
+    pub size: usize,
+    /// PLICの割り込み番号。なければ0
+    pub irq: u32,
+}
+
+/// デバイスツリーから読み取った情報
+pub struct BootInfo {
+    memory: [Region; MEMORY_MAX],
+    memory_count: usize,
+    reserved: [Region; RESERVED_MAX],
+    reserved_count: usize,
+    pub timebase_freq: u64,
+    pub uart: Option<Device>,
+    pub plic: Option<Device>,
+    virtio_mmio: [Device; VIRTIO_MMIO_MAX],
+    virtio_mmio_count: usize,
+}
+
+impl BootInfo {
+    const fn new() -> Self {
+        const EMPTY: Region = Region { base: 0, size: 0 };
+        const NO_DEVICE: Device = Device {
+            base: 0,
+            size: 0,
+            irq: 0,
+        };
+        Self {
+            memory: [EMPTY; MEMORY_MAX],
+            memory_count: 0,
+            reserved: [EMPTY; RESERVED_MAX],
+            reserved_count: 0,
+            // timebase-frequencyがなければQEMU virtの値とみなす
+            timebase_freq: 10_000_000,
+            uart: None,
+            plic: None,
+            virtio_mmio: [NO_DEVICE; VIRTIO_MMIO_MAX],
+            virtio_mmio_count: 0,
+        }
+    }
+
+    pub fn memory(&self) -> &[Region] {
+        &self.memory[..self.memory_count]
+    }
+
+    /// メモリ予約ブロックと/reserved-memoryで使用禁止とされた範囲
+    pub fn reserved(&self) -> &[Region] {
+        &self.reserved[..self.reserved_count]
+    }
+
+    /// `virtio,mmio`のノード。ツリーに現れた順に並ぶ
+    pub fn virtio_mmio(&self) -> &[Device] {
+        &self.virtio_mmio[..self.virtio_mmio_count]
+    }
+
+    fn add_memory(&mut self, region: Region) {
+        if self.memory_count < MEMORY_MAX {
+            self.memory[self.memory_count] = region;
+            self.memory_count += 1;
+        }
+    }
+
+    fn add_reserved(&mut self, region: Region) {
+        if self.reserved_count < RESERVED_MAX {
+            self.reserved[self.reserved_count] = region;
+            self.reserved_count += 1;
+        }
+    }
+}
+
+static mut BOOT_INFO: BootInfo = BootInfo::new();
+
+pub fn boot_info() -> &'static BootInfo {
+    unsafe { &*core::ptr::addr_of!(BOOT_INFO) }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    Other,
+    Memory,
+    Reserved,
+    Uart,
+    Plic,
+    VirtioMmio,
+}
+
+/// 解析中のノード。`reg`は終端で親の#address-cells・#size-cellsを使って読む
+#[derive(Copy, Clone)]
+struct NodeState {
+    kind: Kind,
+    // 子ノードのregの形式
+    address_cells: u32,
+    size_cells: u32,
+    // /reserved-memoryなら子ノードが予約領域
+    reserved_memory: bool,
+    // 構造ブロック内のregの値の位置
+    reg: Option<(usize, usize)>,
+    irq: u32,
+}
+
+impl NodeState {
+    const fn new() -> Self {
+        Self {
+            kind: Kind::Other,
+            address_cells: 2,
+            size_cells: 1,
+            reserved_memory: false,
+            reg: None,
+            irq: 0,
+        }
+    }
+}
+
+fn be32(data: &[u8], off: usize) -> Option<u32> {
+    let bytes = data.get(off..off + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn be64(data: &[u8], off: usize) -> Option<u64> {
+    Some(((be32(data, off)? as u64) << 32) | be32(data, off + 4)? as u64)
+}
+
+/// `off`からの`cells`個のセルを1つの値として読む
+fn read_cells(data: &[u8], off: usize, cells: u32) -> Option<u64> {
+    let mut value = 0u64;
+    for i in 0..cells as usize {
+        value = (value << 32) | be32(data, off + i * 4)? as u64;
+    }
+    Some(value)
+}
+
+/// `off`から始まるNUL終端文字列
+fn cstr(data: &[u8], off: usize) -> Option<&str> {
+    let bytes = data.get(off..)?;
+    let len = bytes.iter().position(|b| *b == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// 32bitのアドレス空間に収まる範囲だけを返す
+fn region(base: u64, size: u64) -> Option<Region> {
+    let base = usize::try_from(base).ok()?;
+    let size = size.min((usize::MAX - base) as u64) as usize;
+    Some(Region { base, size })
+}
+
+fn kind_of(compatible: &[u8]) -> Kind {
+    for name in compatible.split(|b| *b == 0) {
+        match name {
+            b"ns16550a" => return Kind::Uart,
+            b"riscv,plic0" | b"sifive,plic-1.0.0" => return Kind::Plic,
+            b"virtio,mmio" => return Kind::VirtioMmio,
+            _ => {}
+        }
+    }
+    Kind::Other
+}
+
+/// ノードの終端で、集めたプロパティから情報を登録する
+fn finish_node(info: &mut BootInfo, structs: &[u8], node: &NodeState, parent: &NodeState) {
+    let Some((off, len)) = node.reg else {
+        return;
+    };
+
+    let stride = (parent.address_cells + parent.size_cells) as usize * 4;
+    if stride == 0 {
+        return;
+    }
+    let mut regions = (0..len / stride).filter_map(|i| {
+        let base = read_cells(structs, off + i * stride, parent.address_cells)?;
+        let size = read_cells(
+            structs,
+            off + i * stride + parent.address_cells as usize * 4,
+            parent.size_cells,
+        )?;
+        region(base, size)
+    });
+
+    match node.kind {
+        Kind::Memory => regions.for_each(|r| info.add_memory(r)),
+        Kind::Reserved => regions.for_each(|r| info.add_reserved(r)),
+        Kind::Uart | Kind::Plic | Kind::VirtioMmio => {
+            let Some(r) = regions.next() else {
+                return;
+            };
+            let device = Device {
+                base: r.base,
+                size: r.size,
+                irq: node.irq,
+            };
+            match node.kind {
+                Kind::Uart => info.uart = info.uart.or(Some(device)),
+                Kind::Plic => info.plic = info.plic.or(Some(device)),
+                _ => {
+                    if info.virtio_mmio_count < VIRTIO_MMIO_MAX {
+                        info.virtio_mmio[info.virtio_mmio_count] = device;
+                        info.virtio_mmio_count += 1;
+                    }
+                }
+            }
+        }
+        Kind::Other => {}
+    }
+}
+
+/// `dtb`のデバイスツリーを読み、`boot_info`で参照できるようにする。
+/// 読み終えた後はデバイスツリーを参照しないので、その領域は上書きしてよい
+pub unsafe fn fdt_parse(dtb: *const u8) -> Result<&'static BootInfo, ()> {
+    if dtb.is_null() {
+        return Err(());
+    }
+
+    let header = core::slice::from_raw_parts(dtb, 40);
+    if be32(header, 0) != Some(FDT_MAGIC) {
+        return Err(());
+    }
+    let fdt = core::slice::from_raw_parts(dtb, be32(header, 4).ok_or(())? as usize);
+    let off_struct = be32(fdt, 8).ok_or(())? as usize;
+    let off_strings = be32(fdt, 12).ok_or(())? as usize;
+    let off_rsvmap = be32(fdt, 16).ok_or(())? as usize;
+    let structs = fdt.get(off_struct..).ok_or(())?;
+    let strings = fdt.get(off_strings..).ok_or(())?;
+
+    let info = &mut *core::ptr::addr_of_mut!(BOOT_INFO);
+    *info = BootInfo::new();
+
+    // メモリ予約ブロックはアドレスとサイズが共に0のエントリで終わる
+    let mut off = off_rsvmap;
+    loop {
+        let base = be64(fdt, off).ok_or(())?;
+        let size = be64(fdt, off + 8).ok_or(())?;
+        if base == 0 && size == 0 {
+            break;
+        }
+        if let Some(r) = region(base, size) {
+            info.add_reserved(r);
+        }
+        off += 16;
+    }
+
+    // stack[0]はルートの親の代わり。ルートの子の既定値を持つ
+    let mut stack = [NodeState::new(); DEPTH_MAX + 1];
+    let mut depth = 0;
+    let mut off = 0;
+    loop {
+        let token = be32(structs, off).ok_or(())?;
+        off += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = cstr(structs, off).ok_or(())?;
+                off = align4(off + name.len() + 1);
+                if depth == DEPTH_MAX {
+                    return Err(());
+                }
+
+                let parent = stack[depth];
+                depth += 1;
+                let node = &mut stack[depth];
+                *node = NodeState::new();
+                if parent.reserved_memory {
+                    node.kind = Kind::Reserved;
+                } else if name == "memory" || name.starts_with("memory@") {
+                    node.kind = Kind::Memory;
+                }
+                node.reserved_memory = depth == 2 && name == "reserved-memory";
+            }
+            FDT_END_NODE => {
+                if depth == 0 {
+                    return Err(());
+                }
+                let node = stack[depth];
+                depth -= 1;
+                finish_node(info, structs, &node, &stack[depth]);
+            }
+            FDT_PROP => {
+                let len = be32(structs, off).ok_or(())? as usize;
+                let name = cstr(strings, be32(structs, off + 4).ok_or(())? as usize).ok_or(())?;
+                let value_off = off + 8;
+                let value = structs.get(value_off..value_off + len).ok_or(())?;
+                off = align4(value_off + len);
+                if depth == 0 {
+                    return Err(());
+                }
+
+                let node = &mut stack[depth];
+                match name {
+                    "#address-cells" => node.address_cells = be32(value, 0).ok_or(())?,
+                    "#size-cells" => node.size_cells = be32(value, 0).ok_or(())?,
+                    "reg" => node.reg = Some((value_off, len)),
+                    "interrupts" => node.irq = be32(value, 0).unwrap_or(0),
+                    "device_type" if value.starts_with(b"memory\0") => node.kind = Kind::Memory,
+                    "compatible" if node.kind == Kind::Other => node.kind = kind_of(value),
+                    // /cpusか各cpuノードにある
+                    "timebase-frequency" => {
+                        if let Some(freq) = read_cells(value, 0, len as u32 / 4).filter(|f| *f > 0)
+                        {
+                            info.timebase_freq = freq;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => break,
+            _ => return Err(()),
+        }
+    }
+
+    Ok(info)
+}
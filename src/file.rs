@@ -0,0 +1,233 @@
+use common::{
+    Dirent, EBADF, EISDIR, ELOOP, EMFILE, ENOTDIR, O_ACCMODE, O_APPEND, O_CREAT, O_RDONLY, O_TRUNC,
+    O_WRONLY,
+};
+
+use crate::{
+    net::udp::{udp_close, udp_dup, udp_read, udp_write},
+    path::PathBuf,
+    pipe::{pipe_close, pipe_dup, pipe_read, pipe_write},
+    sbi::{getchar, putchar},
+    tty::{tty_read, tty_write},
+    vfs::{
+        vfs_close, vfs_create, vfs_dup, vfs_kind, vfs_open, vfs_read, vfs_readdir, vfs_size,
+        vfs_truncate, vfs_write, FileKind, Node,
+    },
+    PM,
+};
+
+/// コンソールから1文字読み込む。入力があるまで他のプロセスに譲る
+pub fn console_read(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    loop {
+        let ch = getchar();
+        if ch >= 0 {
+            buf[0] = ch as u8;
+            return 1;
+        }
+
+        unsafe { PM.yield_() };
+    }
+}
+
+pub fn console_write(buf: &[u8]) -> usize {
+    for ch in buf {
+        putchar(*ch);
+    }
+    buf.len()
+}
+
+// 同時に開けるディレクトリの数
+const DIRS_MAX: usize = 16;
+
+/// 開いたディレクトリ。パスは大きいので`OpenFile`には番号だけを持たせる。
+/// `cursor`は次に返すエントリの位置で、dupしたディスクリプタの間で共有する
+#[derive(Copy, Clone)]
+struct OpenDir {
+    refs: usize,
+    path: PathBuf,
+    cursor: usize,
+}
+
+static mut DIRS: [OpenDir; DIRS_MAX] = [OpenDir {
+    refs: 0,
+    path: PathBuf::root(),
+    cursor: 0,
+}; DIRS_MAX];
+
+fn dir(id: usize) -> &'static mut OpenDir {
+    unsafe { &mut (*core::ptr::addr_of_mut!(DIRS))[id] }
+}
+
+/// ファイルディスクリプタが指すオブジェクト
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OpenFile {
+    Console,
+    /// virtio-consoleのポート
+    Tty(usize),
+    PipeRead(usize),
+    PipeWrite(usize),
+    /// UDPソケット
+    Socket(usize),
+    /// `mnt`と`node`はマウントとその中のファイル、`offset`は次に読み書きする位置
+    File {
+        mnt: usize,
+        node: Node,
+        offset: usize,
+        flags: u32,
+    },
+    /// 開いたディレクトリの表の番号
+    Dir(usize),
+}
+
+impl OpenFile {
+    /// ファイルシステム上のファイルかディレクトリを開く。`path`はシンボリックリンクを辿った後のパス
+    pub fn open(path: &PathBuf, flags: u32) -> Result<Self, i32> {
+        match vfs_kind(path) {
+            Ok(FileKind::Directory) => {
+                if flags & O_ACCMODE != O_RDONLY {
+                    return Err(EISDIR);
+                }
+                let dirs = unsafe { &mut *core::ptr::addr_of_mut!(DIRS) };
+                let (id, dir) = dirs
+                    .iter_mut()
+                    .enumerate()
+                    .find(|(_, d)| d.refs == 0)
+                    .ok_or(EMFILE)?;
+                *dir = OpenDir {
+                    refs: 1,
+                    path: *path,
+                    cursor: 0,
+                };
+                return Ok(OpenFile::Dir(id));
+            }
+            // vfs_realpathで辿りきれなかったシンボリックリンク
+            Ok(FileKind::Symlink) => return Err(ELOOP),
+            Err(e) if flags & O_CREAT == 0 => return Err(e),
+            _ => {}
+        }
+
+        if vfs_kind(path).is_err() {
+            vfs_create(path)?;
+        }
+        let (mnt, node) = vfs_open(path)?;
+        let file = OpenFile::File {
+            mnt,
+            node,
+            offset: 0,
+            flags,
+        };
+        if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY {
+            vfs_truncate(mnt, node, 0).inspect_err(|_| file.close())?;
+        }
+        Ok(file)
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, i32> {
+        match self {
+            OpenFile::File {
+                mnt,
+                node,
+                offset,
+                flags,
+            } => {
+                if *flags & O_ACCMODE == O_WRONLY {
+                    return Err(EBADF);
+                }
+                let len = vfs_read(*mnt, *node, *offset, buf)?;
+                *offset += len;
+                Ok(len)
+            }
+            OpenFile::Console => Ok(console_read(buf)),
+            OpenFile::Tty(port) => tty_read(*port, buf),
+            OpenFile::PipeRead(id) => pipe_read(*id, buf),
+            OpenFile::Socket(id) => udp_read(*id, buf),
+            OpenFile::PipeWrite(_) => Err(EBADF),
+            OpenFile::Dir(_) => Err(EISDIR),
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, i32> {
+        match self {
+            OpenFile::File {
+                mnt,
+                node,
+                offset,
+                flags,
+            } => {
+                if *flags & O_ACCMODE == O_RDONLY {
+                    return Err(EBADF);
+                }
+                if *flags & O_APPEND != 0 {
+                    *offset = vfs_size(*mnt, *node);
+                }
+                let len = vfs_write(*mnt, *node, *offset, buf)?;
+                *offset += len;
+                Ok(len)
+            }
+            OpenFile::Console => Ok(console_write(buf)),
+            OpenFile::Tty(port) => tty_write(*port, buf),
+            OpenFile::PipeWrite(id) => pipe_write(*id, buf),
+            OpenFile::Socket(id) => udp_write(*id, buf),
+            OpenFile::PipeRead(_) | OpenFile::Dir(_) => Err(EBADF),
+        }
+    }
+
+    /// ディレクトリのエントリを`buf`に詰め、詰めた数を返す。末尾に達したら0を返す
+    pub fn getdents(&mut self, buf: &mut [Dirent]) -> Result<usize, i32> {
+        let dir = match self {
+            OpenFile::Dir(id) => self::dir(*id),
+            _ => return Err(ENOTDIR),
+        };
+
+        let mut n = 0;
+        while n < buf.len() {
+            let entry = match vfs_readdir(&dir.path, &mut dir.cursor) {
+                Some(entry) => entry,
+                None => break,
+            };
+
+            buf[n] = Dirent::new();
+            buf[n].kind = entry.kind.dirent_type();
+            let len = entry.len.min(buf[n].name.len() - 1);
+            buf[n].name[..len].copy_from_slice(&entry.name[..len]);
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// ディレクトリなら、そのパス
+    pub fn dir_path(&self) -> Option<PathBuf> {
+        match *self {
+            OpenFile::Dir(id) => Some(dir(id).path),
+            _ => None,
+        }
+    }
+
+    /// 別のディスクリプタから同じオブジェクトを参照する際に呼ぶ
+    pub fn dup(&self) -> Self {
+        match *self {
+            OpenFile::Console | OpenFile::Tty(_) => {}
+            OpenFile::Dir(id) => dir(id).refs += 1,
+            OpenFile::PipeRead(id) => pipe_dup(id, false),
+            OpenFile::PipeWrite(id) => pipe_dup(id, true),
+            OpenFile::Socket(id) => udp_dup(id),
+            OpenFile::File { mnt, node, .. } => vfs_dup(mnt, node),
+        }
+        *self
+    }
+
+    pub fn close(&self) {
+        match *self {
+            OpenFile::Console | OpenFile::Tty(_) => {}
+            OpenFile::Dir(id) => dir(id).refs -= 1,
+            OpenFile::PipeRead(id) => pipe_close(id, false),
+            OpenFile::PipeWrite(id) => pipe_close(id, true),
+            OpenFile::Socket(id) => udp_close(id),
+            // 変更は各ファイルシステムの定期的な書き戻しかSYS_SYNCでディスクに反映される
+            OpenFile::File { mnt, node, .. } => vfs_close(mnt, node),
+        }
+    }
+}
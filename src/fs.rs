@@ -1,8 +1,21 @@
-use common::{align_up, ascii_len, oct2int, println};
+use common::{
+    align_up, println, FsckReport, Stat, DT_DIR, EBUSY, EEXIST, EINVAL, EISDIR, ENAMETOOLONG,
+    ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, EPERM,
+};
 
-use crate::virtio::Virtio;
+use crate::{
+    bcache::{
+        bcache_copy, bcache_get, bcache_put, bcache_read, bcache_size, bcache_sync, bcache_write,
+        bcache_zero,
+    },
+    path::{PathBuf, PATH_MAX},
+    vfs::{DirEntry, FileKind, FileSystem, Node},
+    virtio::blk::VirtioBlk,
+    VIRTIO,
+};
 
 #[repr(C, packed)]
+#[derive(Copy, Clone)]
 struct TarHeader {
     name: [u8; 100],
     mode: [u8; 8],
@@ -24,179 +37,924 @@ struct TarHeader {
     data: [u8; 0],
 }
 
+impl TarHeader {
+    /// GNU形式のヘッダは`prefix`を別の用途に使う
+    fn is_gnu(&self) -> bool {
+        self.magic == *b"ustar "
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, HEADER_SIZE) }
+    }
+
+    /// `checksum`フィールドを空白とみなしたヘッダ全体のバイトの和
+    fn checksum(&self) -> usize {
+        let field = core::mem::offset_of!(TarHeader, checksum);
+        self.bytes()
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                if (field..field + 8).contains(&i) {
+                    b' ' as usize
+                } else {
+                    *b as usize
+                }
+            })
+            .sum()
+    }
+
+    /// 記録されたチェックサムと一致するか。古いtarが使う符号付きの和も受け付ける
+    fn is_valid(&self) -> bool {
+        if !self.magic.starts_with(b"ustar") {
+            return false;
+        }
+
+        let expected = parse_numeric(&self.checksum) as usize;
+        let signed_diff: usize = self.bytes().iter().filter(|b| **b >= 0x80).count() * 0x100;
+        expected == self.checksum() || expected + signed_diff == self.checksum()
+    }
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<TarHeader>();
+// アーカイブの終端を表す2つのゼロブロック
+const END_OF_ARCHIVE_SIZE: usize = 2 * HEADER_SIZE;
+
+// ファイルの中身はディスク上のtarイメージに直接置く。`offset`はヘッダの位置
+// `name`は先頭の`./`や末尾の`/`を取り除いた、ルートからの相対パス
+// GNUのロングネームやPAXの拡張ヘッダは`offset`の直前の`ext_size`バイトに置かれたまま保持する
 #[derive(Copy, Clone)]
 pub struct File {
     pub in_use: bool,
-    pub name: [u8; 100],
-    pub data: [u8; 1024],
+    pub name: [u8; PATH_MAX],
+    pub kind: FileKind,
     pub size: usize,
+    offset: usize,
+    ext_size: usize,
+    refs: usize,
+    // ハードリンクの場合はリンク先のエントリ
+    link: Option<usize>,
+    // シンボリックリンクの場合はリンク先のパス
+    linkname: [u8; PATH_MAX],
 }
 
 impl File {
     const fn new() -> Self {
         Self {
             in_use: false,
-            name: [0; 100],
-            data: [0; 1024],
+            name: [0; PATH_MAX],
+            kind: FileKind::Regular,
             size: 0,
+            offset: 0,
+            ext_size: 0,
+            refs: 0,
+            link: None,
+            linkname: [0; PATH_MAX],
         }
     }
+
+    pub fn name(&self) -> &str {
+        field_str(&self.name)
+    }
+
+    pub fn linkname(&self) -> &str {
+        field_str(&self.linkname)
+    }
+
+    fn entry_size(&self) -> usize {
+        entry_size(self.size)
+    }
+
+    /// ヘッダの名前を`name`に書き換えられるか。拡張ヘッダで名前を与えている場合は書き換えられない
+    fn can_rename(&self, name: &str) -> Result<bool, i32> {
+        if self.ext_size != 0 {
+            return Ok(false);
+        }
+        Ok(if self.header()?.is_gnu() {
+            name.len() <= 100
+        } else {
+            split_name(name.as_bytes()).is_some()
+        })
+    }
+
+    fn header(&self) -> Result<TarHeader, i32> {
+        bcache_get(self.offset)
+    }
 }
 
-const FILES_MAX: usize = 2;
-const DISK_MAX_SIZE: usize = align_up(
-    core::mem::size_of::<File>() * FILES_MAX,
-    Virtio::SECTOR_SIZE as usize,
-);
+fn entry_size(size: usize) -> usize {
+    align_up(HEADER_SIZE + size, VirtioBlk::SECTOR_SIZE as usize)
+}
 
-static mut FILES: [File; FILES_MAX] = [File::new(); FILES_MAX];
-static mut DISK: [u8; DISK_MAX_SIZE] = [0; DISK_MAX_SIZE];
-
-pub unsafe fn fs_init(virtio: &mut Virtio) {
-    let mut sector = 0;
-    while sector < core::mem::size_of_val(&DISK) / Virtio::SECTOR_SIZE as usize {
-        // println!(
-        //     "{:?}, {}  {}",
-        //     sector,
-        //     core::mem::size_of_val(&DISK),
-        //     Virtio::SECTOR_SIZE
-        // );
-        virtio.read_write_disk(
-            &mut DISK[(sector * Virtio::SECTOR_SIZE as usize)..],
-            sector as u64,
-            false,
-        );
-        sector += 1;
+/// NULで終わる (あるいはフィールドいっぱいの) 文字列
+fn field_str(field: &[u8]) -> &str {
+    let len = field.iter().position(|c| *c == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).unwrap_or("")
+}
+
+/// 8進数のフィールドを読む。先頭ビットが立っていればGNUの256進数表記
+fn parse_numeric(field: &[u8]) -> u64 {
+    if field[0] & 0x80 != 0 {
+        return field[1..]
+            .iter()
+            .fold((field[0] & 0x7f) as u64, |n, b| (n << 8) | *b as u64);
+    }
+
+    field
+        .iter()
+        .skip_while(|c| **c == b' ')
+        .take_while(|c| (b'0'..=b'7').contains(*c))
+        .fold(0, |n, c| n * 8 + (c - b'0') as u64)
+}
+
+fn write_octal(field: &mut [u8], mut value: usize) {
+    for i in 0..(field.len() - 1) {
+        field[(field.len() - 2) - i] = (value % 8) as u8 + b'0';
+        value /= 8;
+    }
+    field[field.len() - 1] = b'\0';
+}
+
+/// ustarの`prefix`と`name`に分けて格納できる位置で分割する
+fn split_name(name: &[u8]) -> Option<(&[u8], &[u8])> {
+    if name.len() <= 100 {
+        return Some((&[], name));
     }
 
+    (0..name.len())
+        .filter(|i| name[*i] == b'/')
+        .find(|i| *i <= 155 && name.len() - i - 1 <= 100 && name.len() - i - 1 > 0)
+        .map(|i| (&name[..i], &name[i + 1..]))
+}
+
+/// 拡張ヘッダの値を`dst`にコピーする。長すぎる場合は末尾に印を付ける
+fn copy_ext_value(dst: &mut [u8; PATH_MAX + 1], value: &[u8]) {
+    dst.fill(0);
+    let len = value.len().min(PATH_MAX);
+    dst[..len].copy_from_slice(&value[..len]);
+    if value.len() > PATH_MAX {
+        dst[PATH_MAX] = b'!';
+    }
+}
+
+const FILES_MAX: usize = 64;
+// 拡張ヘッダの中身はこの長さまで読む
+const EXT_MAX: usize = 8192;
+
+static mut FILES: [File; FILES_MAX] = [File::new(); FILES_MAX];
+// アーカイブ終端 (ゼロブロック) の位置
+static mut DISK_END: usize = 0;
+static mut DIRTY: bool = false;
+static mut FSCK: FsckReport = FsckReport::new();
+static mut EXT_DATA: [u8; EXT_MAX] = [0; EXT_MAX];
+
+fn file(index: usize) -> &'static mut File {
+    unsafe { &mut (*core::ptr::addr_of_mut!(FILES))[index] }
+}
+
+/// ディスクをtarアーカイブとして読み込む
+pub unsafe fn fs_init() -> Result<(), i32> {
+    let disk_size = bcache_size();
+
+    // 直前の拡張ヘッダで与えられた長い名前とリンク先
+    let mut long_name = [0u8; PATH_MAX + 1];
+    let mut long_link = [0u8; PATH_MAX + 1];
+    let mut ext_start = None;
+
+    let report = &mut *core::ptr::addr_of_mut!(FSCK);
+    *report = FsckReport::new();
+    // 壊れたヘッダが続いている間は1度だけ報告する
+    let mut in_corrupt = false;
+
     let mut off = 0;
-    for file in FILES.iter_mut().take(FILES_MAX) {
-        let header = (&mut DISK[off] as *mut _ as *mut TarHeader)
-            .as_mut()
-            .unwrap();
+    let mut files = (*core::ptr::addr_of_mut!(FILES)).iter_mut();
+    while off + END_OF_ARCHIVE_SIZE <= disk_size {
+        let header = bcache_get::<TarHeader>(off)?;
+        if header.bytes().iter().all(|b| *b == 0) {
+            report.end_marker = 1;
+            break;
+        }
+
+        // 壊れたヘッダは`size`も信用できないので、次の正しいヘッダまで1ブロックずつ読み飛ばす。
+        // 読み飛ばした領域はそのまま残し、書き戻す際も変更しない
+        if !header.is_valid() {
+            if !in_corrupt {
+                println!("fs: corrupt tar header at {}", off);
+                report.corrupt += 1;
+            }
+            in_corrupt = true;
+            report.quarantined += HEADER_SIZE as u32;
+            long_name.fill(0);
+            long_link.fill(0);
+            ext_start = None;
+            off += HEADER_SIZE;
+            continue;
+        }
+        in_corrupt = false;
 
-        let name = &core::str::from_utf8(&header.name).unwrap();
-        if name.is_empty() {
+        let filesz = parse_numeric(&header.size) as usize;
+        if filesz > disk_size || off + entry_size(filesz) + END_OF_ARCHIVE_SIZE > disk_size {
+            println!("fs: truncated tar entry at {}: size={}", off, filesz);
+            report.truncated = 1;
             break;
         }
+        // ファイルの中身は読み書きする時に読み込む。拡張ヘッダだけはここで読む
+        if matches!(header.type_, b'L' | b'K' | b'x') {
+            let data = &mut (&mut *core::ptr::addr_of_mut!(EXT_DATA))[..filesz.min(EXT_MAX)];
+            bcache_read(off + HEADER_SIZE, data)?;
+            match header.type_ {
+                // GNUのロングネーム・ロングリンク
+                b'L' => copy_ext_value(&mut long_name, field_str(data).as_bytes()),
+                b'K' => copy_ext_value(&mut long_link, field_str(data).as_bytes()),
+                // PAXの拡張ヘッダ
+                _ => parse_pax(data, &mut long_name, &mut long_link),
+            }
+            ext_start.get_or_insert(off);
+            off += entry_size(filesz);
+            continue;
+        }
 
-        let magic = &core::str::from_utf8(&header.magic).unwrap()
-            [0..(ascii_len(&header.magic as *const u8) - 1)];
-        if magic != "ustar" {
-            panic!("invalid tar header: magic={magic}");
+        let ext_size = off - ext_start.take().unwrap_or(off);
+        let mut name = [0u8; PATH_MAX + 1];
+        let name_len = if long_name[0] != 0 {
+            name.copy_from_slice(&long_name);
+            name.iter().position(|c| *c == 0).unwrap_or(name.len())
+        } else {
+            let prefix = field_str(&header.prefix).as_bytes();
+            let base = field_str(&header.name).as_bytes();
+            let mut len = 0;
+            if !header.is_gnu() && !prefix.is_empty() {
+                name[..prefix.len()].copy_from_slice(prefix);
+                name[prefix.len()] = b'/';
+                len = prefix.len() + 1;
+            }
+            name[len..len + base.len()].copy_from_slice(base);
+            len + base.len()
+        };
+        let mut linkname = [0u8; PATH_MAX + 1];
+        if long_link[0] != 0 {
+            linkname.copy_from_slice(&long_link);
+        } else {
+            copy_ext_value(&mut linkname, field_str(&header.linkname).as_bytes());
         }
+        long_name.fill(0);
+        long_link.fill(0);
+
+        let entry_off = off;
+        off += entry_size(filesz);
+
+        // 名前がUTF-8でない場合も空文字列になり、ここで弾かれる
+        let path = if name_len > PATH_MAX || linkname[PATH_MAX] != 0 {
+            Err(ENAMETOOLONG)
+        } else {
+            PathBuf::resolve(
+                &PathBuf::root(),
+                core::str::from_utf8(&name[..name_len]).unwrap_or(""),
+            )
+        };
+        let path = match path {
+            Ok(path) if !path.is_root() => path,
+            _ => {
+                println!("fs: skipped entry with invalid name at {}", entry_off);
+                report.skipped += 1;
+                continue;
+            }
+        };
+
+        let (kind, link) = match header.type_ {
+            b'0' | b'\0' | b'7' => (FileKind::Regular, None),
+            b'5' => (FileKind::Directory, None),
+            b'2' => (FileKind::Symlink, None),
+            b'1' => {
+                let target = PathBuf::resolve(&PathBuf::root(), field_str(&linkname))
+                    .ok()
+                    .and_then(|target| lookup_entry(&target).ok());
+                match target {
+                    Some(target) => (FileKind::Regular, Some(resolve_hardlink(target))),
+                    None => {
+                        println!("fs: skipped hard link with missing target: {:?}", path);
+                        report.skipped += 1;
+                        continue;
+                    }
+                }
+            }
+            t => {
+                println!(
+                    "fs: skipped unsupported entry type '{}': {:?}",
+                    t as char, path
+                );
+                report.skipped += 1;
+                continue;
+            }
+        };
 
-        let filesz = oct2int(
-            &header.size as *const [u8] as *const u8,
-            core::mem::size_of_val(&header.size),
-        );
+        let file = match files.next() {
+            Some(file) => file,
+            None => {
+                println!("fs: too many files, skipped {:?}", path);
+                report.skipped += 1;
+                continue;
+            }
+        };
+        *file = File::new();
         file.in_use = true;
-        file.name[0..name.len()].copy_from_slice(name.as_bytes());
-        if filesz > 0 {
-            file.data[0..filesz].copy_from_slice(core::slice::from_raw_parts(
-                &header.data as *const [u8] as *const u8,
-                filesz,
-            ))
-        }
+        file.name[0..path.relative().len()].copy_from_slice(path.relative().as_bytes());
+        file.kind = kind;
         file.size = filesz;
-        println!(
-            "file: {}, size={}",
-            &core::str::from_utf8(&file.name).unwrap()[0..(ascii_len(&file.name as *const u8) - 1)],
-            file.size,
-        );
+        file.offset = entry_off;
+        file.ext_size = ext_size;
+        file.link = link;
+        file.linkname.copy_from_slice(&linkname[..PATH_MAX]);
+        report.entries += 1;
+        println!("file: {}, size={}", file.name(), file.size);
+    }
 
-        off += align_up(
-            core::mem::size_of::<TarHeader>() + filesz,
-            Virtio::SECTOR_SIZE as usize,
-        );
+    if report.end_marker == 0 && report.truncated == 0 {
+        println!("fs: end-of-archive marker not found");
     }
+    println!(
+        "fs: {} entries, {} corrupt headers ({} bytes quarantined), {} skipped",
+        report.entries, report.corrupt, report.quarantined, report.skipped
+    );
+
+    // 終端のゼロブロックは、次にエントリを追加する時にこの位置に書く
+    DISK_END = core::cmp::min(off, disk_size - END_OF_ARCHIVE_SIZE);
+    Ok(())
 }
 
-pub unsafe fn fs_flush(virtio: &mut Virtio) {
-    let mut off: usize = 0;
-    for file in FILES.iter_mut().take(FILES_MAX) {
-        if !file.in_use {
+/// 起動時にアーカイブを読み込んだ結果
+pub fn fs_fsck() -> FsckReport {
+    unsafe { FSCK }
+}
+
+/// PAXの拡張ヘッダ (`"<長さ> <キー>=<値>\n"`の繰り返し) から`path`と`linkpath`を取り出す
+fn parse_pax(mut data: &[u8], name: &mut [u8; PATH_MAX + 1], link: &mut [u8; PATH_MAX + 1]) {
+    while let Some(sp) = data.iter().position(|c| *c == b' ') {
+        let len = core::str::from_utf8(&data[..sp])
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(0);
+        if len <= sp + 1 || len > data.len() {
+            break;
+        }
+
+        let record = &data[sp + 1..len - 1];
+        data = &data[len..];
+        match record.iter().position(|c| *c == b'=') {
+            Some(eq) if &record[..eq] == b"path" => copy_ext_value(name, &record[eq + 1..]),
+            Some(eq) if &record[..eq] == b"linkpath" => copy_ext_value(link, &record[eq + 1..]),
+            _ => {}
+        }
+    }
+}
+
+/// ヘッダを更新し、変更されたセクタをディスクに書き戻す
+pub unsafe fn fs_flush(virtio: &mut VirtioBlk) {
+    // ヘッダを更新できなかったら次の書き戻しで再び試す
+    let mut failed = false;
+    for file in (*core::ptr::addr_of_mut!(FILES)).iter_mut() {
+        if !file.in_use || !DIRTY {
             continue;
         }
 
-        let header = (&mut DISK[off] as *mut u8 as *mut TarHeader)
-            .as_mut()
-            .unwrap();
-        let name = &file.name;
-        header.name[0..file.name.len()].copy_from_slice(name);
-        let mode = b"0000644\0";
-        header.mode[0..mode.len()].copy_from_slice(mode);
-        let magic = b"ustar\0";
-        header.magic[0..magic.len()].copy_from_slice(magic);
-        let version = b"00";
-        header.version[0..version.len()].copy_from_slice(version);
-        header.type_ = b'0';
+        // モード・所有者・更新時刻などは読み込んだヘッダの値をそのまま残す
+        let Ok(mut header) = file.header() else {
+            failed = true;
+            continue;
+        };
+        let before = header;
+
+        // 拡張ヘッダで名前を与えている場合はヘッダの名前を書き換えない
+        if file.ext_size == 0 && !file.name().is_empty() {
+            let mut name = [b'/'; PATH_MAX + 1];
+            let len = file.name().len();
+            name[..len].copy_from_slice(file.name().as_bytes());
+            let len = if file.kind == FileKind::Directory {
+                len + 1
+            } else {
+                len
+            };
 
-        let mut filesz = file.size;
-        for i in 0..(header.size.len() - 1) {
-            header.size[(header.size.len() - 2) - i] = (filesz % 8) as u8 + b'0';
-            filesz /= 8;
+            let split = if header.is_gnu() {
+                Some((&[][..], &name[..len])).filter(|(_, n)| n.len() <= 100)
+            } else {
+                split_name(&name[..len])
+            };
+            if let Some((prefix, base)) = split {
+                header.name.fill(0);
+                header.name[..base.len()].copy_from_slice(base);
+                if !header.is_gnu() {
+                    header.prefix.fill(0);
+                    header.prefix[..prefix.len()].copy_from_slice(prefix);
+                }
+            }
         }
-        header.size[header.size.len() - 1] = b'\0';
 
-        // チェックサムを計算
-        let mut checksum = b' ' as usize * core::mem::size_of_val(&header.checksum);
-        checksum += header.name.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.mode.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.uid.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.gid.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.size.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.mtime.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.type_ as usize;
-        checksum += header.linkname.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.magic.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.version.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.uname.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.gname.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.devmajor.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.devminor.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.prefix.iter().fold(0, |sum, i| sum + *i as usize);
+        let (type_, size) = match (file.kind, file.link) {
+            (FileKind::Regular, Some(target)) => {
+                let target = self::file(target).name();
+                if target.len() <= 100 {
+                    header.linkname.fill(0);
+                    header.linkname[..target.len()].copy_from_slice(target.as_bytes());
+                }
+                (b'1', 0)
+            }
+            (FileKind::Regular, None) if matches!(header.type_, b'0' | b'\0' | b'7') => {
+                (header.type_, file.size)
+            }
+            (FileKind::Regular, None) => (b'0', file.size),
+            (FileKind::Directory, _) => (b'5', 0),
+            (FileKind::Symlink, _) => {
+                let target = file.linkname();
+                if target.len() <= 100 && file.ext_size == 0 {
+                    header.linkname.fill(0);
+                    header.linkname[..target.len()].copy_from_slice(target.as_bytes());
+                }
+                (b'2', 0)
+            }
+            // tarfsにはデバイスファイルを作れない
+            (FileKind::CharDevice | FileKind::BlockDevice, _) => unreachable!(),
+        };
+        header.type_ = type_;
+        write_octal(&mut header.size, size);
 
+        // チェックサムを計算
+        let mut checksum = header.checksum();
         for i in 0..6 {
             header.checksum[(header.checksum.len() - 3) - i] = (checksum % 8) as u8 + b'0';
             checksum /= 8;
         }
+        header.checksum[6] = b'\0';
+        header.checksum[7] = b' ';
 
-        let header_data =
-            core::slice::from_raw_parts_mut(&mut header.data as *mut [u8] as *mut u8, file.size);
-        if file.size > 0 {
-            header_data.copy_from_slice(&file.data[0..file.size]);
+        if header.bytes() != before.bytes() && bcache_put(file.offset, &header).is_err() {
+            failed = true;
         }
+    }
 
-        off += align_up(
-            core::mem::size_of::<TarHeader>() + file.size,
-            Virtio::SECTOR_SIZE as usize,
-        );
+    let written = bcache_sync(virtio);
+    DIRTY = failed;
+    if written > 0 {
+        println!("wrote {} sectors to disk", written);
     }
+}
+
+fn files() -> impl Iterator<Item = (usize, &'static File)> {
+    unsafe { (*core::ptr::addr_of!(FILES)).iter() }
+        .enumerate()
+        .filter(|(_, f)| f.in_use && !f.name().is_empty())
+}
 
-    for sector in 0..(core::mem::size_of_val(&DISK) / Virtio::SECTOR_SIZE as usize) {
-        virtio.read_write_disk(
-            &mut DISK[(sector * Virtio::SECTOR_SIZE as usize)..],
-            sector as u64,
-            true,
-        );
+/// `path`のエントリそのもの。ハードリンクを辿らない
+fn lookup_entry(path: &PathBuf) -> Result<usize, i32> {
+    files()
+        .find(|(_, f)| f.name() == path.relative())
+        .map(|(i, _)| i)
+        .ok_or(ENOENT)
+}
+
+fn resolve_hardlink(index: usize) -> usize {
+    file(index).link.unwrap_or(index)
+}
+
+/// `path`の中身を持つエントリを探す。ハードリンクはリンク先を返し、
+/// ヘッダを持たない暗黙のディレクトリは含まない
+pub fn fs_lookup(path: &PathBuf) -> Result<usize, i32> {
+    lookup_entry(path).map(resolve_hardlink)
+}
+
+/// `path`の種類を返す。ルートと、他のエントリのパスの途中に現れるディレクトリも含む
+pub fn fs_kind(path: &PathBuf) -> Result<FileKind, i32> {
+    if path.is_root() {
+        return Ok(FileKind::Directory);
+    }
+    if let Ok(index) = fs_lookup(path) {
+        return Ok(file(index).kind);
     }
 
-    println!("wrote {} bytes to disk", core::mem::size_of_val(&DISK));
+    let dir = path.relative();
+    let is_prefix = files().any(|(_, f)| {
+        f.name().len() > dir.len()
+            && f.name().starts_with(dir)
+            && f.name().as_bytes()[dir.len()] == b'/'
+    });
+    if is_prefix {
+        Ok(FileKind::Directory)
+    } else {
+        Err(ENOENT)
+    }
+}
+
+/// `dir`の直下にある要素を1つ返し、`cursor`を進める。重複した暗黙のディレクトリは1度だけ返す
+pub fn fs_readdir(dir: &PathBuf, cursor: &mut usize) -> Option<DirEntry> {
+    let prefix = dir.relative();
+    let child = |f: &'static File| -> Option<(&'static str, FileKind)> {
+        let name = f.name();
+        let rest = if prefix.is_empty() {
+            name
+        } else if name.len() > prefix.len()
+            && name.starts_with(prefix)
+            && name.as_bytes()[prefix.len()] == b'/'
+        {
+            &name[prefix.len() + 1..]
+        } else {
+            return None;
+        };
+
+        match rest.split_once('/') {
+            Some((component, _)) => Some((component, FileKind::Directory)),
+            None => Some((rest, f.kind)),
+        }
+    };
+
+    for (i, f) in files() {
+        if i < *cursor {
+            continue;
+        }
+        *cursor = i + 1;
+
+        let (name, kind) = match child(f) {
+            Some(c) => c,
+            None => continue,
+        };
+        if files().any(|(j, g)| j < i && child(g).map(|(n, _)| n) == Some(name)) {
+            continue;
+        }
+
+        return Some(DirEntry::new(name, kind));
+    }
+    None
 }
 
-pub fn fs_lookup(filename: &str) -> Result<*mut File, ()> {
-    for file in unsafe { FILES.iter().take(FILES_MAX) } {
-        // println!(
-        //     "file: {}, size={}",
-        //     &core::str::from_utf8(&file.name).unwrap()[0..(ascii_len(&file.name as *const u8) - 1)],
-        //     file.size,
-        // );
-        let name =
-            &core::str::from_utf8(&file.name).unwrap()[0..(ascii_len(&file.name as *const u8) - 1)];
-        if name == filename {
-            return Ok(file as *const File as *mut File);
+/// `path`の属性を返す。最後の要素がシンボリックリンクならリンクそのものの属性
+pub fn fs_stat(path: &PathBuf) -> Result<Stat, i32> {
+    let index = match lookup_entry(path) {
+        Ok(index) => index,
+        // ルートやヘッダを持たないディレクトリ
+        Err(_) => {
+            fs_kind(path)?;
+            return Ok(Stat {
+                kind: DT_DIR,
+                mode: 0o755,
+                nlink: 1,
+                ..Stat::new()
+            });
         }
+    };
+
+    let data = resolve_hardlink(index);
+    let file = file(data);
+    let header = file.header()?;
+    let nlink = 1 + files().filter(|(_, f)| f.link == Some(data)).count();
+    Ok(Stat {
+        kind: file.kind.dirent_type(),
+        mode: parse_numeric(&header.mode) as u32 & 0o7777,
+        uid: parse_numeric(&header.uid) as u32,
+        gid: parse_numeric(&header.gid) as u32,
+        nlink: nlink as u32,
+        size: match file.kind {
+            FileKind::Regular => file.size as u64,
+            FileKind::Symlink => file.linkname().len() as u64,
+            FileKind::Directory | FileKind::CharDevice | FileKind::BlockDevice => 0,
+        },
+        mtime: parse_numeric(&header.mtime),
+    })
+}
+
+/// シンボリックリンクのリンク先
+pub fn fs_readlink(path: &PathBuf) -> Result<&'static str, i32> {
+    let file = file(lookup_entry(path)?);
+    if file.kind != FileKind::Symlink {
+        return Err(EINVAL);
+    }
+    Ok(file.linkname())
+}
+
+pub fn fs_size(index: usize) -> usize {
+    file(index).size
+}
+
+/// 空のファイルをアーカイブの末尾に作る
+pub fn fs_create(path: &PathBuf) -> Result<usize, i32> {
+    create_entry(path, FileKind::Regular)
+}
+
+pub fn fs_mkdir(path: &PathBuf) -> Result<(), i32> {
+    create_entry(path, FileKind::Directory).map(|_| ())
+}
+
+pub fn fs_symlink(target: &str, path: &PathBuf) -> Result<(), i32> {
+    // 拡張ヘッダは作らないので、リンク先はlinknameフィールドに収まる必要がある
+    if target.is_empty() || target.len() > 100 {
+        return Err(ENAMETOOLONG);
+    }
+
+    let file = file(create_entry(path, FileKind::Symlink)?);
+    file.linkname[..target.len()].copy_from_slice(target.as_bytes());
+    Ok(())
+}
+
+/// `path`を`old`のハードリンクとして作る
+pub fn fs_link(old: &PathBuf, path: &PathBuf) -> Result<(), i32> {
+    let target = fs_lookup(old)?;
+    if file(target).kind != FileKind::Regular {
+        return Err(EPERM);
+    }
+    // リンク先はlinknameフィールドに収まる必要がある
+    if file(target).name().len() > 100 {
+        return Err(ENAMETOOLONG);
+    }
+
+    let index = create_entry(path, FileKind::Regular)?;
+    file(index).link = Some(target);
+    Ok(())
+}
+
+/// 空のディレクトリを削除する
+pub fn fs_rmdir(path: &PathBuf) -> Result<(), i32> {
+    if fs_kind(path)? != FileKind::Directory {
+        return Err(ENOTDIR);
+    }
+    if path.is_root() || fs_readdir(path, &mut 0).is_some() {
+        return Err(ENOTEMPTY);
+    }
+
+    remove_entry(lookup_entry(path)?)
+}
+
+fn create_entry(path: &PathBuf, kind: FileKind) -> Result<usize, i32> {
+    if lookup_entry(path).is_ok() || fs_kind(path).is_ok() {
+        return Err(EEXIST);
+    }
+    if fs_kind(&path.parent())? != FileKind::Directory {
+        return Err(ENOTDIR);
+    }
+
+    // ディレクトリは末尾の`/`も含めてprefixとnameに収まる必要がある
+    let filename = path.relative();
+    let slash = if kind == FileKind::Directory { 1 } else { 0 };
+    let mut name = [b'/'; PATH_MAX + 1];
+    name[..filename.len()].copy_from_slice(filename.as_bytes());
+    if split_name(&name[..filename.len() + slash]).is_none() {
+        return Err(ENAMETOOLONG);
+    }
+
+    let (index, file) = unsafe { (*core::ptr::addr_of_mut!(FILES)).iter_mut() }
+        .enumerate()
+        .find(|(_, f)| !f.in_use)
+        .ok_or(ENOSPC)?;
+
+    let end = unsafe { DISK_END };
+    if end + HEADER_SIZE + END_OF_ARCHIVE_SIZE > bcache_size() {
+        return Err(ENOSPC);
+    }
+
+    let mut header: TarHeader = unsafe { core::mem::zeroed() };
+    let mode = match kind {
+        FileKind::Regular | FileKind::CharDevice | FileKind::BlockDevice => b"0000644\0",
+        FileKind::Directory => b"0000755\0",
+        FileKind::Symlink => b"0000777\0",
+    };
+    header.mode.copy_from_slice(mode);
+    header.uid.copy_from_slice(b"0000000\0");
+    header.gid.copy_from_slice(b"0000000\0");
+    header.mtime.copy_from_slice(b"00000000000\0");
+    header.magic.copy_from_slice(b"ustar\0");
+    header.version.copy_from_slice(b"00");
+    // 新しいヘッダと、後ろにずれた終端のゼロブロック
+    bcache_put(end, &header)?;
+    bcache_zero(end + HEADER_SIZE, END_OF_ARCHIVE_SIZE)?;
+
+    *file = File::new();
+    file.in_use = true;
+    file.name[0..filename.len()].copy_from_slice(filename.as_bytes());
+    file.kind = kind;
+    file.offset = end;
+    unsafe {
+        DISK_END += HEADER_SIZE;
+        DIRTY = true;
+    }
+    Ok(index)
+}
+
+/// ファイルを削除し、後ろのエントリを詰める。開かれている場合は`EBUSY`
+pub fn fs_unlink(path: &PathBuf) -> Result<(), i32> {
+    let index = lookup_entry(path)?;
+    if file(index).kind == FileKind::Directory {
+        return Err(EISDIR);
+    }
+
+    // ハードリンクが残っていれば、最初のリンクの名前を中身を持つエントリに移す
+    let link = files().find(|(_, f)| f.link == Some(index)).map(|(i, _)| i);
+    if let Some(link) = link {
+        // 移す名前をヘッダか、残りのリンクのlinknameに書けなければ消さない
+        let new_name = file(link).name();
+        let relinkable = files()
+            .filter(|(i, f)| *i != link && f.link == Some(index))
+            .all(|(_, f)| f.ext_size == 0 && new_name.len() <= 100);
+        if !file(index).can_rename(new_name)? || !relinkable {
+            return Err(ENAMETOOLONG);
+        }
+
+        let name = file(link).name;
+        remove_entry(link)?;
+        file(index).name = name;
+        unsafe { DIRTY = true };
+        return Ok(());
+    }
+
+    remove_entry(index)
+}
+
+fn remove_entry(index: usize) -> Result<(), i32> {
+    let file = file(index);
+    if file.refs > 0 {
+        return Err(EBUSY);
+    }
+
+    resize_entry(
+        file.offset - file.ext_size,
+        file.ext_size + file.entry_size(),
+        0,
+    )?;
+    file.in_use = false;
+    unsafe { DIRTY = true };
+    Ok(())
+}
+
+/// ファイルサイズを変更する。伸ばした部分はゼロで埋める
+pub fn fs_truncate(index: usize, len: usize) -> Result<(), i32> {
+    let file = file(index);
+    // ディレクトリなどはヘッダだけのエントリで、書き戻すときに中身を持たない
+    if file.kind != FileKind::Regular {
+        return Err(EISDIR);
+    }
+    let old_size = file.size;
+    let new_entry_size = entry_size(len);
+    if new_entry_size > file.entry_size()
+        && unsafe { DISK_END } + (new_entry_size - file.entry_size()) + END_OF_ARCHIVE_SIZE
+            > bcache_size()
+    {
+        return Err(ENOSPC);
+    }
+
+    resize_entry(file.offset, file.entry_size(), new_entry_size)?;
+    file.size = len;
+
+    // 伸ばした部分と、縮めた後のブロック末尾のパディングをゼロにする
+    let data = file.offset + HEADER_SIZE;
+    let start = data + core::cmp::min(old_size, len);
+    let end = file.offset + new_entry_size;
+    unsafe { DIRTY = true };
+    bcache_zero(start, end - start)
+}
+
+pub fn fs_read(index: usize, offset: usize, buf: &mut [u8]) -> Result<usize, i32> {
+    let file = file(index);
+    if offset >= file.size {
+        return Ok(0);
+    }
+
+    let len = core::cmp::min(buf.len(), file.size - offset);
+    bcache_read(file.offset + HEADER_SIZE + offset, &mut buf[..len])?;
+    Ok(len)
+}
+
+/// 必要に応じてファイルを伸ばしてから書き込む
+pub fn fs_write(index: usize, offset: usize, buf: &[u8]) -> Result<usize, i32> {
+    let file = file(index);
+    if file.kind != FileKind::Regular {
+        return Err(EISDIR);
+    }
+    if offset + buf.len() > file.size {
+        fs_truncate(index, offset + buf.len())?;
+    }
+
+    bcache_write(file.offset + HEADER_SIZE + offset, buf)?;
+    unsafe { DIRTY = true };
+    Ok(buf.len())
+}
+
+pub fn fs_open(index: usize) {
+    file(index).refs += 1;
+}
+
+pub fn fs_close(index: usize) {
+    file(index).refs -= 1;
+}
+
+/// `offset`にある長さ`old_size`のエントリを`new_size`に変え、後続のエントリを移動する
+fn resize_entry(offset: usize, old_size: usize, new_size: usize) -> Result<(), i32> {
+    if old_size == new_size {
+        return Ok(());
+    }
+
+    let end = unsafe { DISK_END };
+    bcache_copy(
+        offset + old_size,
+        offset + new_size,
+        end - offset - old_size,
+    )?;
+    for file in unsafe { (*core::ptr::addr_of_mut!(FILES)).iter_mut() } {
+        if file.in_use && file.offset > offset {
+            file.offset = file.offset + new_size - old_size;
+        }
+    }
+
+    // 詰めて空いた部分と終端のゼロブロック
+    let new_end = end + new_size - old_size;
+    unsafe { DISK_END = new_end };
+    bcache_zero(
+        new_end,
+        core::cmp::max(end, new_end) + END_OF_ARCHIVE_SIZE - new_end,
+    )
+}
+
+/// VFSから見たtarファイルシステム。`Node`は`FILES`の添字
+pub struct TarFs;
+
+pub static TARFS: TarFs = TarFs;
+
+impl FileSystem for TarFs {
+    fn name(&self) -> &'static str {
+        "tarfs"
+    }
+
+    fn kind(&self, path: &PathBuf) -> Result<FileKind, i32> {
+        fs_kind(path)
+    }
+
+    fn stat(&self, path: &PathBuf) -> Result<Stat, i32> {
+        fs_stat(path)
+    }
+
+    fn readdir(&self, dir: &PathBuf, cursor: &mut usize) -> Option<DirEntry> {
+        fs_readdir(dir, cursor)
+    }
+
+    fn readlink(&self, path: &PathBuf, buf: &mut [u8]) -> Result<usize, i32> {
+        let target = fs_readlink(path)?;
+        let len = target.len().min(buf.len());
+        buf[..len].copy_from_slice(&target.as_bytes()[..len]);
+        Ok(len)
+    }
+
+    fn lookup(&self, path: &PathBuf) -> Result<Node, i32> {
+        let index = fs_lookup(path)?;
+        if file(index).kind == FileKind::Directory {
+            return Err(EISDIR);
+        }
+        Ok(index)
+    }
+
+    fn open(&self, node: Node) {
+        fs_open(node)
+    }
+
+    fn close(&self, node: Node) {
+        fs_close(node)
+    }
+
+    fn read(&self, node: Node, offset: usize, buf: &mut [u8]) -> Result<usize, i32> {
+        fs_read(node, offset, buf)
+    }
+
+    fn write(&self, node: Node, offset: usize, buf: &[u8]) -> Result<usize, i32> {
+        fs_write(node, offset, buf)
+    }
+
+    fn size(&self, node: Node) -> usize {
+        fs_size(node)
+    }
+
+    fn truncate(&self, node: Node, len: usize) -> Result<(), i32> {
+        fs_truncate(node, len)
+    }
+
+    fn create(&self, path: &PathBuf) -> Result<(), i32> {
+        fs_create(path).map(|_| ())
+    }
+
+    fn mkdir(&self, path: &PathBuf) -> Result<(), i32> {
+        fs_mkdir(path)
+    }
+
+    fn unlink(&self, path: &PathBuf) -> Result<(), i32> {
+        fs_unlink(path)
+    }
+
+    fn rmdir(&self, path: &PathBuf) -> Result<(), i32> {
+        fs_rmdir(path)
+    }
+
+    fn symlink(&self, target: &str, path: &PathBuf) -> Result<(), i32> {
+        fs_symlink(target, path)
+    }
+
+    fn link(&self, old: &PathBuf, path: &PathBuf) -> Result<(), i32> {
+        fs_link(old, path)
+    }
+
+    fn sync(&self) {
+        unsafe { fs_flush(VIRTIO.as_mut().unwrap()) };
     }
-    Err(())
 }
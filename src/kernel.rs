@@ -1,27 +1,70 @@
 #![no_std]
 #![no_main]
 
+mod bcache;
+mod devfs;
+mod ext2;
+mod fat;
+mod fdt;
+mod file;
 mod fs;
 mod memory;
 mod net;
+mod p9fs;
+mod path;
+mod pipe;
+mod plic;
 mod process;
+mod procfs;
+mod random;
 mod sbi;
+mod sysctl;
+mod timer;
+mod tty;
+mod vfs;
 mod virtio;
 
+use bcache::{bcache_init, bcache_stats};
 use common::{
-    ascii_len, println, read_csr, write_csr, TrapFrame, SYS_EXIT, SYS_GETCHAR, SYS_PING,
-    SYS_PUTCHAR, SYS_READFILE, SYS_WRITEFILE,
+    ascii_len, println, read_csr, write_csr, CacheStats, Dirent, FsckReport, RouteEntry, SockError,
+    Stat, TrapFrame, EAGAIN, ELOOP, EMFILE, ENOENT, ENOTDIR, ENOTSOCK, EPROTONOSUPPORT, ERANGE,
+    SOCK_DGRAM, SYS_BIND, SYS_CACHESTAT, SYS_CHDIR, SYS_CLOSE, SYS_CONNECT, SYS_EXIT, SYS_FSCK,
+    SYS_GETCHAR, SYS_GETCWD, SYS_GETDENTS, SYS_GETRANDOM, SYS_IFADDR, SYS_LINK, SYS_LSTAT,
+    SYS_MKDIR, SYS_MOUNT, SYS_OPEN, SYS_PING, SYS_PIPE, SYS_PUTCHAR, SYS_READ, SYS_READFILE,
+    SYS_READLINK, SYS_RECVERR, SYS_RMDIR, SYS_ROUTE_ADD, SYS_ROUTE_DEL, SYS_ROUTE_LIST,
+    SYS_SETSOCKOPT, SYS_SOCKET, SYS_SPAWN, SYS_STAT, SYS_SYMLINK, SYS_SYNC, SYS_SYSCTL,
+    SYS_TRUNCATE, SYS_UMOUNT, SYS_UNLINK, SYS_WAIT, SYS_WRITE, SYS_WRITEFILE,
 };
 use core::arch::{asm, naked_asm};
 use core::panic::PanicInfo;
 use core::ptr;
-use fs::fs_flush;
-use process::ProcessManager;
+use devfs::DEVFS;
+use ext2::{ext2_probe, EXT2FS};
+use fat::{fat_probe, FATFS};
+use fdt::fdt_parse;
+use file::OpenFile;
+use fs::{fs_fsck, TARFS};
+use memory::memory_init;
+use net::udp::{udp_alloc, udp_bind, udp_connect, udp_recverr, udp_setsockopt};
+use p9fs::{p9fs_handle_interrupt, p9fs_init, P9FS};
+use path::PathBuf;
+use pipe::pipe_alloc;
+use plic::{plic_claim, plic_complete, plic_init};
+use process::{console_fds, tty_fds, ProcessManager, FDS_MAX};
+use procfs::PROCFS;
+use random::{random_fill, random_handle_interrupt, random_init, random_u32};
 use sbi::{getchar, putchar};
+use sysctl::sysctl;
+use tty::{tty_handle_interrupt, tty_init, tty_is_console, TTYS_MAX};
+use vfs::{
+    vfs_create, vfs_kind, vfs_link, vfs_lookup, vfs_mkdir, vfs_mount, vfs_mount_by_name, vfs_read,
+    vfs_readlink, vfs_realpath, vfs_rmdir, vfs_stat, vfs_symlink, vfs_sync, vfs_truncate,
+    vfs_umount, vfs_unlink, vfs_write, FileKind, FileSystem,
+};
 
 use crate::{
-    fs::{fs_init, fs_lookup},
-    virtio::Virtio,
+    fs::fs_init,
+    virtio::{blk::VirtioBlk, virtio_probe},
 };
 
 extern "C" {
@@ -33,12 +76,14 @@ extern "C" {
 }
 
 const SCAUSE_ECALL: u32 = 8;
+const SCAUSE_EXTERNAL_INTERRUPT: u32 = 0x80000009;
 
 static mut PM: ProcessManager = ProcessManager::new();
-static mut VIRTIO: *mut Virtio = core::ptr::null_mut();
+static mut VIRTIO: *mut VirtioBlk = core::ptr::null_mut();
 
+/// OpenSBIから`a0`にhart ID、`a1`にデバイスツリーのアドレスが渡される
 #[no_mangle]
-fn kernel_main() {
+extern "C" fn kernel_main(_hartid: usize, dtb: *const u8) {
     unsafe {
         let bss = ptr::addr_of_mut!(__bss);
         let bss_end = ptr::addr_of!(__bss_end);
@@ -47,11 +92,52 @@ fn kernel_main() {
 
     write_csr!("stvec", kernel_entry);
 
-    let mut virtio = Virtio::new();
+    // デバイスツリーは空き領域に置かれていることがあるので、ページを確保する前に読む
+    let info = unsafe { fdt_parse(dtb) }.expect("invalid device tree");
+    memory_init(info);
+    for region in info.memory() {
+        println!(
+            "[kernel] memory: {:#x}-{:#x}",
+            region.base,
+            region.base + region.size
+        );
+    }
+    println!("[kernel] timebase: {} Hz", info.timebase_freq);
+    if let Some(uart) = info.uart {
+        println!("[kernel] uart: {:#x} irq {}", uart.base, uart.irq);
+    }
+    plic_init(info.plic.expect("PLIC not found in device tree").base);
+
+    let devices = virtio_probe(info.virtio_mmio());
+    let mut virtio = devices.blk.expect("virtio-blk not found");
+    random_init(devices.rng);
+    tty_init(devices.console);
     unsafe {
         VIRTIO = core::ptr::addr_of_mut!(virtio);
     }
-    unsafe { fs_init(&mut virtio) };
+    unsafe { bcache_init(virtio.blk_capacity()) };
+    // ext2やFATの印があるのに使えないディスクは、tarとして上書きしないよう起動を止める
+    let root: &'static dyn FileSystem = if ext2_probe().expect("unusable ext2 filesystem") {
+        &EXT2FS
+    } else if fat_probe().expect("unusable FAT filesystem") {
+        &FATFS
+    } else {
+        unsafe { fs_init() }.expect("failed to read the tar archive");
+        &TARFS
+    };
+    vfs_mount(&PathBuf::root(), root).expect("failed to mount root filesystem");
+    PathBuf::resolve(&PathBuf::root(), "/proc")
+        .and_then(|path| vfs_mount(&path, &PROCFS))
+        .expect("failed to mount procfs");
+    PathBuf::resolve(&PathBuf::root(), "/dev")
+        .and_then(|path| vfs_mount(&path, &DEVFS))
+        .expect("failed to mount devfs");
+    // ホストと共有するディレクトリ
+    if p9fs_init(devices.p9) {
+        PathBuf::resolve(&PathBuf::root(), "/mnt")
+            .and_then(|path| vfs_mount(&path, &P9FS))
+            .expect("failed to mount 9p");
+    }
 
     net::ip::init();
     net::icmp::init();
@@ -62,7 +148,14 @@ fn kernel_main() {
         let size = ptr::addr_of!(_binary_shell_bin_size) as usize;
 
         PM.init();
-        PM.create(start, size);
+        PM.create(start, size, &[], console_fds())
+            .expect("no free process slots");
+        // コンソールとして繋がれたvirtio-consoleのポートでもシェルを動かす
+        for port in (0..TTYS_MAX).filter(|port| tty_is_console(*port)) {
+            if PM.create(start, size, &[], tty_fds(port)).is_err() {
+                println!("[kernel] no free process slots for hvc{}", port);
+            }
+        }
         PM.yield_();
     }
 
@@ -71,6 +164,7 @@ fn kernel_main() {
     loop {
         net::ip::process_packets();
         unsafe { asm!("wfi") };
+        handle_interrupt();
     }
 }
 
@@ -179,6 +273,8 @@ fn handle_trap(f: *mut TrapFrame) {
     if scause == SCAUSE_ECALL {
         handle_syscall(f);
         user_pc += 4;
+    } else if scause == SCAUSE_EXTERNAL_INTERRUPT {
+        handle_interrupt();
     } else {
         panic!("unexpected trap scause={scause:x}, stval={stval:x}, sepc={user_pc:x}");
     }
@@ -186,6 +282,71 @@ fn handle_trap(f: *mut TrapFrame) {
     write_csr!("sepc", user_pc);
 }
 
+/// 保留中の外部割り込みを処理する
+fn handle_interrupt() {
+    let irq = plic_claim();
+    if irq == 0 {
+        return;
+    }
+
+    let blk = unsafe { VIRTIO.as_mut().unwrap() };
+    if irq == blk.irq() {
+        blk.handle_interrupt();
+    } else {
+        handle_device_interrupt(irq);
+    }
+    plic_complete(irq);
+}
+
+/// virtio-blk以外のデバイスの割り込みを処理する。virtio-blkの完了待ちの間にも呼ばれる
+pub fn handle_device_interrupt(irq: u32) {
+    random_handle_interrupt(irq);
+    tty_handle_interrupt(irq);
+    p9fs_handle_interrupt(irq);
+}
+
+fn errno(e: i32) -> u32 {
+    (-e) as u32
+}
+
+/// ソケットを指すファイルディスクリプタ
+fn socket_id(fd: u32) -> Result<usize, i32> {
+    match unsafe { PM.fd_get(fd) }? {
+        OpenFile::Socket(id) => Ok(*id),
+        _ => Err(ENOTSOCK),
+    }
+}
+
+/// ユーザー空間のNUL終端文字列
+fn user_str<'a>(ptr: u32) -> &'a str {
+    let ptr = ptr as *const u8;
+    let len = ascii_len(ptr);
+    unsafe { core::str::from_utf8(core::slice::from_raw_parts(ptr, len - 1)).unwrap_or("") }
+}
+
+/// ユーザー空間のパスを作業ディレクトリから解決し、シンボリックリンクを辿る
+fn user_path(ptr: u32) -> Result<PathBuf, i32> {
+    let path = PathBuf::resolve(unsafe { &PM.cwd() }, user_str(ptr))?;
+    vfs_realpath(&path, true)
+}
+
+/// `user_path`と同じだが、最後の要素がシンボリックリンクでも辿らない
+fn user_path_nofollow(ptr: u32) -> Result<PathBuf, i32> {
+    let path = PathBuf::resolve(unsafe { &PM.cwd() }, user_str(ptr))?;
+    vfs_realpath(&path, false)
+}
+
+/// ファイルシステムを変更する操作が成功したらディスクに書き戻す
+fn flush_result(result: Result<(), i32>) -> u32 {
+    match result {
+        Ok(()) => {
+            vfs_sync();
+            0
+        }
+        Err(e) => errno(e),
+    }
+}
+
 fn handle_syscall(f: *mut TrapFrame) {
     let f = unsafe { f.as_mut().unwrap() };
     match f.a3 {
@@ -203,75 +364,286 @@ fn handle_syscall(f: *mut TrapFrame) {
             unsafe { PM.exit() };
         }
         SYS_READFILE => {
-            let filename = f.a0 as *const u8;
-            let filename_len = ascii_len(filename);
-            let filename = unsafe {
-                core::str::from_utf8(core::slice::from_raw_parts(filename, filename_len - 1))
-                    .unwrap()
-            };
+            let filename = user_str(f.a0);
+            let buf = unsafe { core::slice::from_raw_parts_mut(f.a1 as *mut u8, f.a2 as usize) };
 
-            let buf = f.a1 as *const u8;
-            let mut len = f.a2 as usize;
-
-            let file = if let Ok(f) = fs_lookup(filename) {
-                unsafe { f.as_mut().unwrap() }
-            } else {
-                println!("file not found: {}", filename);
-                f.a0 = 0xffff_fffe_u32;
-                return;
+            let (mnt, node) = match user_path(f.a0).and_then(|path| vfs_lookup(&path)) {
+                Ok(file) => file,
+                Err(e) => {
+                    println!("file not found: {}", filename);
+                    f.a0 = errno(e);
+                    return;
+                }
             };
 
-            if len > file.size {
-                len = file.size;
-            }
-
-            unsafe { ptr::copy(file.data.as_ptr(), buf as *mut _, len) };
-            f.a0 = len as u32;
+            f.a0 = match vfs_read(mnt, node, 0, buf) {
+                Ok(len) => len as u32,
+                Err(e) => errno(e),
+            };
         }
         SYS_WRITEFILE => {
-            let filename = f.a0 as *const u8;
-            let filename_len = ascii_len(filename);
-            let filename = unsafe {
-                core::str::from_utf8(core::slice::from_raw_parts(filename, filename_len - 1))
-                    .unwrap()
+            let buf = unsafe { core::slice::from_raw_parts(f.a1 as *const u8, f.a2 as usize) };
+
+            // ファイルの中身を置き換える。存在しなければ作成する
+            let result = user_path(f.a0).and_then(|path| {
+                let (mnt, node) = vfs_lookup(&path).or_else(|e| {
+                    if e != ENOENT {
+                        return Err(e);
+                    }
+                    vfs_create(&path)?;
+                    vfs_lookup(&path)
+                })?;
+                vfs_truncate(mnt, node, 0)?;
+                vfs_write(mnt, node, 0, buf)
+            });
+            vfs_sync();
+            f.a0 = match result {
+                Ok(len) => len as u32,
+                Err(e) => errno(e),
             };
-
-            let buf = f.a1 as *const u8;
-            let mut len = f.a2 as usize;
-
-            let file = if let Ok(f) = fs_lookup(filename) {
-                unsafe { f.as_mut().unwrap() }
+        }
+        SYS_OPEN => {
+            let result = user_path(f.a0).and_then(|path| OpenFile::open(&path, f.a1));
+            f.a0 = match result
+                .and_then(|file| unsafe { PM.fd_alloc(file) }.inspect_err(|_| file.close()))
+            {
+                Ok(fd) => fd,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_UNLINK => {
+            let result = user_path_nofollow(f.a0).and_then(|path| vfs_unlink(&path));
+            f.a0 = flush_result(result);
+        }
+        SYS_TRUNCATE => {
+            let result = user_path(f.a0)
+                .and_then(|path| vfs_lookup(&path))
+                .and_then(|(mnt, node)| vfs_truncate(mnt, node, f.a1 as usize));
+            f.a0 = flush_result(result);
+        }
+        SYS_MKDIR => {
+            let result = user_path_nofollow(f.a0).and_then(|path| vfs_mkdir(&path));
+            f.a0 = flush_result(result);
+        }
+        SYS_RMDIR => {
+            let result = user_path_nofollow(f.a0).and_then(|path| vfs_rmdir(&path));
+            f.a0 = flush_result(result);
+        }
+        SYS_GETDENTS => {
+            let buf = unsafe {
+                core::slice::from_raw_parts_mut(
+                    f.a1 as *mut Dirent,
+                    f.a2 as usize / core::mem::size_of::<Dirent>(),
+                )
+            };
+            f.a0 = match unsafe { PM.fd_get(f.a0) }.and_then(|file| file.getdents(buf)) {
+                Ok(n) => (n * core::mem::size_of::<Dirent>()) as u32,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_CHDIR => {
+            let result = user_path(f.a0).and_then(|path| match vfs_kind(&path)? {
+                FileKind::Directory => Ok(path),
+                FileKind::Symlink => Err(ELOOP),
+                _ => Err(ENOTDIR),
+            });
+            f.a0 = match result {
+                Ok(path) => {
+                    unsafe { PM.set_cwd(path) };
+                    0
+                }
+                Err(e) => errno(e),
+            };
+        }
+        SYS_STAT | SYS_LSTAT => {
+            let path = if f.a3 == SYS_STAT {
+                user_path(f.a0)
             } else {
-                println!("file not found: {}", filename);
-                f.a0 = 0xffff_fffe_u32;
-                return;
+                user_path_nofollow(f.a0)
             };
-
-            if len > file.size {
-                len = file.size;
+            f.a0 = match path.and_then(|path| vfs_stat(&path)) {
+                Ok(stat) => {
+                    unsafe { ptr::write(f.a1 as *mut Stat, stat) };
+                    0
+                }
+                Err(e) => errno(e),
+            };
+        }
+        SYS_SYMLINK => {
+            let result =
+                user_path_nofollow(f.a1).and_then(|path| vfs_symlink(user_str(f.a0), &path));
+            f.a0 = flush_result(result);
+        }
+        SYS_LINK => {
+            let result = user_path(f.a0).and_then(|old| {
+                let new = user_path_nofollow(f.a1)?;
+                vfs_link(&old, &new)
+            });
+            f.a0 = flush_result(result);
+        }
+        SYS_READLINK => {
+            let buf = unsafe { core::slice::from_raw_parts_mut(f.a1 as *mut u8, f.a2 as usize) };
+            // readlink(2)と同様にNUL終端しない
+            f.a0 = match user_path_nofollow(f.a0).and_then(|path| vfs_readlink(&path, buf)) {
+                Ok(len) => len as u32,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_SYNC => {
+            vfs_sync();
+            f.a0 = 0;
+        }
+        SYS_MOUNT => {
+            let fstype = user_str(f.a0);
+            let result = user_path(f.a1).and_then(|path| vfs_mount_by_name(fstype, &path));
+            f.a0 = match result {
+                Ok(()) => 0,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_UMOUNT => {
+            f.a0 = match user_path(f.a0).and_then(|path| vfs_umount(&path)) {
+                Ok(()) => 0,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_GETRANDOM => {
+            // 起動時にシード済みなので、フラグ (GRND_NONBLOCK等) に関わらずブロックしない
+            let buf = unsafe { core::slice::from_raw_parts_mut(f.a0 as *mut u8, f.a1 as usize) };
+            random_fill(buf);
+            f.a0 = buf.len() as u32;
+        }
+        SYS_IFADDR => {
+            let addr = net::ip::IpV4Addr::from_be_u32(f.a1);
+            f.a0 = match net::ip::set_iface_addr(
+                user_str(f.a0),
+                addr,
+                u8::try_from(f.a2).unwrap_or(u8::MAX),
+            ) {
+                Ok(()) => 0,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_ROUTE_ADD => {
+            let entry = unsafe { ptr::read(f.a0 as *const RouteEntry) };
+            let result = net::ip::Route::from_entry(&entry)
+                .and_then(|(route, iface)| net::ip::route_add(route, iface));
+            f.a0 = match result {
+                Ok(()) => 0,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_ROUTE_DEL => {
+            let entry = unsafe { ptr::read(f.a0 as *const RouteEntry) };
+            let result = net::ip::Route::from_entry(&entry)
+                .and_then(|(route, _)| net::ip::route_del(route.dst, route.prefix_len));
+            f.a0 = match result {
+                Ok(()) => 0,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_ROUTE_LIST => {
+            // 書き込んだ経路の数を返す
+            let buf =
+                unsafe { core::slice::from_raw_parts_mut(f.a0 as *mut RouteEntry, f.a1 as usize) };
+            let mut count = 0;
+            for (slot, route) in buf.iter_mut().zip(net::ip::routes()) {
+                *slot = route.to_entry();
+                count += 1;
             }
-
-            unsafe { ptr::copy(buf as *mut _, file.data.as_mut_ptr(), len) };
-            file.size = len;
-            unsafe {
-                let virtio = VIRTIO.as_mut().unwrap();
-                fs_flush(virtio);
+            f.a0 = count;
+        }
+        SYS_SOCKET => {
+            if f.a0 != SOCK_DGRAM {
+                f.a0 = errno(EPROTONOSUPPORT);
+                return;
             }
-            f.a0 = len as u32;
+            let Some(id) = udp_alloc() else {
+                f.a0 = errno(EMFILE);
+                return;
+            };
+            f.a0 = match unsafe { PM.fd_alloc(OpenFile::Socket(id)) } {
+                Ok(fd) => fd,
+                Err(e) => {
+                    OpenFile::Socket(id).close();
+                    errno(e)
+                }
+            };
+        }
+        SYS_BIND | SYS_CONNECT => {
+            let addr = net::ip::IpV4Addr::from_be_u32(f.a1);
+            let port = f.a2 as u16;
+            let result = socket_id(f.a0).and_then(|id| {
+                if f.a3 == SYS_BIND {
+                    udp_bind(id, addr, port)
+                } else {
+                    udp_connect(id, addr, port)
+                }
+            });
+            f.a0 = match result {
+                Ok(()) => 0,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_SETSOCKOPT => {
+            let result = socket_id(f.a0).and_then(|id| udp_setsockopt(id, f.a1, f.a2));
+            f.a0 = match result {
+                Ok(()) => 0,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_RECVERR => {
+            // a2はエラーを待つ時間 (ミリ秒)
+            let result = socket_id(f.a0).and_then(|id| udp_recverr(id, f.a2));
+            f.a0 = match result {
+                Ok(error) => {
+                    unsafe { ptr::write(f.a1 as *mut SockError, error) };
+                    0
+                }
+                Err(e) => errno(e),
+            };
+        }
+        SYS_SYSCTL => {
+            // a2が0なら読むだけ。書き換える前の値を返す
+            let value = (f.a2 != 0).then_some(f.a1);
+            f.a0 = match sysctl(user_str(f.a0), value) {
+                Ok(old) => old,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_CACHESTAT => {
+            unsafe { ptr::write(f.a0 as *mut CacheStats, bcache_stats()) };
+            f.a0 = 0;
+        }
+        SYS_FSCK => {
+            unsafe { ptr::write(f.a0 as *mut FsckReport, fs_fsck()) };
+            f.a0 = 0;
+        }
+        SYS_GETCWD => {
+            let buf = unsafe { core::slice::from_raw_parts_mut(f.a0 as *mut u8, f.a1 as usize) };
+            let cwd = unsafe { PM.cwd() };
+            let cwd = cwd.as_str().as_bytes();
+            f.a0 = if cwd.len() < buf.len() {
+                buf[..cwd.len()].copy_from_slice(cwd);
+                buf[cwd.len()] = b'\0';
+                cwd.len() as u32
+            } else {
+                errno(ERANGE)
+            };
         }
         SYS_PING => {
             let dst_ip_be = f.a0 as u32;
             let seq = f.a1 as u16;
+            let data_len = f.a2 as usize;
 
             let dst = net::ip::IpV4Addr::from_be_u32(dst_ip_be);
 
-            println!("[syscall] ping {} seq={}", dst, seq);
+            println!("[syscall] ping {} seq={} size={}", dst, seq, data_len);
 
             // Echo Request 送信
-            let id = 0x1234u16; // TODO: プロセスIDから生成
-            let data = [0u8; 32];
+            let id = random_u32() as u16;
 
-            match net::icmp::send_echo_request(dst, id, seq, &data) {
+            match net::icmp::send_echo_request(dst, id, seq, data_len) {
                 Ok(_) => {
                     println!("[syscall] Echo Request sent successfully");
                     net::ip::process_packets();
@@ -283,6 +655,96 @@ fn handle_syscall(f: *mut TrapFrame) {
                 }
             }
         }
+        SYS_PIPE => {
+            let fds = f.a0 as *mut u32;
+            let id = match pipe_alloc() {
+                Some(id) => id,
+                None => {
+                    f.a0 = errno(EMFILE);
+                    return;
+                }
+            };
+
+            let read_fd = match unsafe { PM.fd_alloc(OpenFile::PipeRead(id)) } {
+                Ok(fd) => fd,
+                Err(e) => {
+                    OpenFile::PipeRead(id).close();
+                    OpenFile::PipeWrite(id).close();
+                    f.a0 = errno(e);
+                    return;
+                }
+            };
+            let write_fd = match unsafe { PM.fd_alloc(OpenFile::PipeWrite(id)) } {
+                Ok(fd) => fd,
+                Err(e) => {
+                    let _ = unsafe { PM.fd_close(read_fd) };
+                    OpenFile::PipeWrite(id).close();
+                    f.a0 = errno(e);
+                    return;
+                }
+            };
+
+            unsafe {
+                *fds = read_fd;
+                *fds.add(1) = write_fd;
+            }
+            f.a0 = 0;
+        }
+        SYS_READ => {
+            let buf = unsafe { core::slice::from_raw_parts_mut(f.a1 as *mut u8, f.a2 as usize) };
+            f.a0 = match unsafe { PM.fd_get(f.a0) }.and_then(|file| file.read(buf)) {
+                Ok(len) => len as u32,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_WRITE => {
+            let buf = unsafe { core::slice::from_raw_parts(f.a1 as *const u8, f.a2 as usize) };
+            f.a0 = match unsafe { PM.fd_get(f.a0) }.and_then(|file| file.write(buf)) {
+                Ok(len) => len as u32,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_CLOSE => {
+            f.a0 = match unsafe { PM.fd_close(f.a0) } {
+                Ok(()) => 0,
+                Err(e) => errno(e),
+            };
+        }
+        SYS_SPAWN => {
+            let args = f.a0 as *const u8;
+            let args = unsafe { core::slice::from_raw_parts(args, ascii_len(args) - 1) };
+
+            let stdin = unsafe { PM.fd_get(f.a1).map(|file| *file) };
+            let stdout = unsafe { PM.fd_get(f.a2).map(|file| *file) };
+            let (stdin, stdout) = match (stdin, stdout) {
+                (Ok(stdin), Ok(stdout)) => (stdin, stdout),
+                (Err(e), _) | (_, Err(e)) => {
+                    f.a0 = errno(e);
+                    return;
+                }
+            };
+
+            let mut fds: [Option<OpenFile>; FDS_MAX] = [None; FDS_MAX];
+            fds[0] = Some(stdin.dup());
+            fds[1] = Some(stdout.dup());
+            // 標準エラー出力は親のものを引き継ぐ
+            fds[2] = unsafe { PM.fd_get(2) }.ok().map(|file| file.dup());
+
+            unsafe {
+                let start = ptr::addr_of!(_binary_shell_bin_start);
+                let size = ptr::addr_of!(_binary_shell_bin_size) as usize;
+                f.a0 = match PM.create(start, size, args, fds) {
+                    Ok(pid) => pid,
+                    Err(()) => errno(EAGAIN),
+                };
+            }
+        }
+        SYS_WAIT => {
+            f.a0 = match unsafe { PM.wait(f.a0) } {
+                Ok(pid) => pid,
+                Err(e) => errno(e),
+            };
+        }
         _ => panic!("unexpected syscall a3={:x}", { f.a3 }),
     }
 }
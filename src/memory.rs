@@ -1,10 +1,11 @@
 use core::ptr;
 
-use common::{is_aligned, PAddr, VAddr, PAGE_SIZE};
+use common::{align_up, is_aligned, PAddr, VAddr, PAGE_SIZE};
+
+use crate::fdt::BootInfo;
 
 extern "C" {
     static mut __free_ram: u8;
-    static mut __free_ram_end: u8;
 }
 
 pub const SATP_SV32: u32 = 1 << 31;
@@ -15,13 +16,55 @@ pub const PAGE_X: u32 = 1 << 3;
 pub const PAGE_U: u32 = 1 << 4;
 
 static mut NEXT_PADDR: *mut u8 = ptr::addr_of_mut!(__free_ram);
+// 解放された1ページずつの連結リスト。各ページの先頭のワードに次のページのアドレスを入れる
+static mut FREE_LIST: PAddr = 0;
+static mut FREE_COUNT: usize = 0;
+// `memory_init`でデバイスツリーのメモリ領域から決める
+static mut FREE_RAM_END: *mut u8 = ptr::null_mut();
+
+/// カーネルを含むメモリ領域のうち、カーネルより後ろで予約されていない部分を空き領域にする
+pub fn memory_init(info: &BootInfo) {
+    let start = ptr::addr_of_mut!(__free_ram) as usize;
+    let region = info
+        .memory()
+        .iter()
+        .find(|r| r.base <= start && start - r.base < r.size)
+        .expect("no memory region contains the kernel");
+
+    // 空き領域の途中から予約されていれば、その手前までにする
+    let mut end = region.base + region.size;
+    for reserved in info.reserved() {
+        if reserved.base < end && reserved.base + reserved.size > start {
+            end = end.min(reserved.base);
+        }
+    }
+    let end = end & !(PAGE_SIZE - 1);
+    if end <= align_up(start, PAGE_SIZE) {
+        panic!("no free memory after the kernel");
+    }
+
+    unsafe { FREE_RAM_END = end as *mut u8 };
+}
+
+/// 空き領域の終端。カーネルはここまでを恒等写像する
+pub fn free_ram_end() -> usize {
+    unsafe { FREE_RAM_END as usize }
+}
 
 pub fn alloc_pages(n: usize) -> PAddr {
     unsafe {
+        if n == 1 && FREE_LIST != 0 {
+            let paddr = FREE_LIST;
+            FREE_LIST = *(paddr as *const PAddr);
+            FREE_COUNT -= 1;
+            ptr::write_bytes(paddr as *mut u8, 0, PAGE_SIZE);
+            return paddr;
+        }
+
         let paddr = NEXT_PADDR as PAddr;
         NEXT_PADDR = NEXT_PADDR.add(n * PAGE_SIZE);
 
-        if NEXT_PADDR > ptr::addr_of_mut!(__free_ram_end) {
+        if NEXT_PADDR > FREE_RAM_END {
             panic!("out of memory");
         }
 
@@ -30,6 +73,28 @@ pub fn alloc_pages(n: usize) -> PAddr {
     }
 }
 
+/// `alloc_pages(1)`で確保したページを返す
+pub fn free_page(paddr: PAddr) {
+    unsafe {
+        *(paddr as *mut PAddr) = FREE_LIST;
+        FREE_LIST = paddr;
+        FREE_COUNT += 1;
+    }
+}
+
+/// 確保できるページの総数と、そのうちまだ確保されていないページ数
+pub fn page_stats() -> (usize, usize) {
+    unsafe {
+        let start = ptr::addr_of_mut!(__free_ram) as usize;
+        let end = FREE_RAM_END as usize;
+        let next = NEXT_PADDR as usize;
+        (
+            (end - start) / PAGE_SIZE,
+            (end - next) / PAGE_SIZE + FREE_COUNT,
+        )
+    }
+}
+
 pub fn map_page(table1: u32, vaddr: VAddr, paddr: PAddr, flags: u32) {
     if !is_aligned(vaddr as usize, PAGE_SIZE) {
         panic!("unaligned vaddr {vaddr}");
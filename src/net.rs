@@ -1,11 +1,27 @@
+use common::{EMSGSIZE, ENETUNREACH, ENOBUFS};
+
 pub mod checksum;
 pub mod icmp;
 pub mod ip;
 pub mod loopback;
+pub mod reassembly;
+pub mod udp;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NetError {
     NoRoute,
     InvalidPacket,
+    /// DFが立っていてMTUを超える
+    MessageTooLong,
     Timeout,
 }
+
+impl NetError {
+    pub fn errno(&self) -> i32 {
+        match self {
+            NetError::NoRoute => ENETUNREACH,
+            NetError::InvalidPacket | NetError::MessageTooLong => EMSGSIZE,
+            NetError::Timeout => ENOBUFS,
+        }
+    }
+}
@@ -1,10 +1,23 @@
-#[repr(packed)]
+#[repr(transparent)]
 #[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
 pub struct InternetChecksum([u8; 2]);
 impl InternetChecksum {
     pub fn calc(data: &[u8]) -> Self {
         InternetChecksumGenerator::new().feed(data).checksum()
     }
+
+    pub fn bytes(&self) -> [u8; 2] {
+        self.0
+    }
+
+    /// 16bitの語が`old`から`new`に変わった後のチェックサムを、全体を計算し直さずに求める (RFC 1624)
+    pub fn adjust(&self, old: u16, new: u16) -> Self {
+        let mut sum = !u16::from_be_bytes(self.0) as u32 + !old as u32 + new as u32;
+        while (sum >> 16) != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        Self((!(sum as u16)).to_be_bytes())
+    }
 }
 
 #[derive(Copy, Clone, Default)]
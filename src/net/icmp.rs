@@ -1,5 +1,8 @@
-use super::checksum::InternetChecksum;
-use super::ip::{ip_send, IpV4Addr, IpV4Protocol};
+use super::checksum::{InternetChecksum, InternetChecksumGenerator};
+use super::ip::{ip_send, is_broadcast, IpV4Addr, IpV4Header, IpV4Protocol, IP_PAYLOAD_MAX};
+use super::udp::udp_handle_error;
+use crate::timer::{now, timebase_freq};
+use common::{ECONNREFUSED, EHOSTUNREACH, EMSGSIZE, ENETUNREACH, ENOPROTOOPT, EPROTO};
 use core::mem::size_of;
 
 #[repr(transparent)]
@@ -12,79 +15,225 @@ impl IcmpType {
     pub fn echo_request() -> Self {
         Self(8)
     }
+    pub fn destination_unreachable() -> Self {
+        Self(3)
+    }
+    pub fn redirect() -> Self {
+        Self(5)
+    }
+    pub fn time_exceeded() -> Self {
+        Self(11)
+    }
+    pub fn parameter_problem() -> Self {
+        Self(12)
+    }
+    pub fn number(&self) -> u8 {
+        self.0
+    }
+    /// エラーメッセージか。エラーに対してエラーは返さない
+    pub fn is_error(&self) -> bool {
+        matches!(self.0, 3 | 4 | 5 | 11 | 12)
+    }
+}
+
+// Destination Unreachableのコード
+pub const ICMP_NET_UNREACHABLE: u8 = 0;
+pub const ICMP_PROTOCOL_UNREACHABLE: u8 = 2;
+pub const ICMP_PORT_UNREACHABLE: u8 = 3;
+// DFが立っていて転送先のMTUを超える。`rest`の後半にMTUを入れる
+pub const ICMP_FRAG_NEEDED: u8 = 4;
+// Redirectのコード
+pub const ICMP_REDIRECT_HOST: u8 = 1;
+// Time Exceededのコード
+pub const ICMP_TTL_EXCEEDED: u8 = 0;
+pub const ICMP_FRAG_TIME_EXCEEDED: u8 = 1;
+
+/// ソケットに知らせる、受け取ったICMPのエラー
+#[derive(Copy, Clone, Debug)]
+pub struct IcmpError {
+    /// エラーを送ってきたホスト
+    pub origin: IpV4Addr,
+    pub icmp_type: IcmpType,
+    pub code: u8,
+    /// 届いた時刻 (タイマーのカウント)
+    pub received: u64,
+}
+
+impl IcmpError {
+    pub fn errno(&self) -> i32 {
+        match (self.icmp_type.0, self.code) {
+            (3, ICMP_NET_UNREACHABLE) => ENETUNREACH,
+            (3, ICMP_PROTOCOL_UNREACHABLE) => ENOPROTOOPT,
+            (3, ICMP_PORT_UNREACHABLE) => ECONNREFUSED,
+            (3, ICMP_FRAG_NEEDED) => EMSGSIZE,
+            (12, _) => EPROTO,
+            _ => EHOSTUNREACH,
+        }
+    }
+}
+
+/// ICMPの送受信の統計
+#[derive(Copy, Clone, Default)]
+pub struct IcmpStats {
+    pub in_msgs: u32,
+    /// 短すぎるかチェックサムが合わない
+    pub in_errors: u32,
+    pub in_dest_unreachs: u32,
+    pub in_time_excds: u32,
+    pub in_parm_probs: u32,
+    pub in_redirects: u32,
+    pub out_msgs: u32,
+    pub out_dest_unreachs: u32,
+    pub out_time_excds: u32,
+    pub out_parm_probs: u32,
+    pub out_redirects: u32,
+    /// 送る間隔の制限で送らなかったエラー
+    pub out_rate_limited: u32,
 }
 
-#[repr(packed)]
+static mut ICMP_STATS: IcmpStats = IcmpStats {
+    in_msgs: 0,
+    in_errors: 0,
+    in_dest_unreachs: 0,
+    in_time_excds: 0,
+    in_parm_probs: 0,
+    in_redirects: 0,
+    out_msgs: 0,
+    out_dest_unreachs: 0,
+    out_time_excds: 0,
+    out_parm_probs: 0,
+    out_redirects: 0,
+    out_rate_limited: 0,
+};
+
+fn stats_mut() -> &'static mut IcmpStats {
+    unsafe { &mut *core::ptr::addr_of_mut!(ICMP_STATS) }
+}
+
+pub fn icmp_stats() -> IcmpStats {
+    *stats_mut()
+}
+
+/// ICMPのヘッダ。`rest`の使い道は種類ごとに違う (エコーなら識別子と順番、Redirectならゲートウェイ)
+#[repr(C, packed)]
 #[derive(Copy, Clone)]
-pub struct IcmpEchoHeader {
+pub struct IcmpHeader {
     icmp_type: IcmpType,
     code: u8,
     checksum: InternetChecksum,
-    identifier: u16,
-    sequence: u16,
+    rest: [u8; 4],
 }
-impl IcmpEchoHeader {
-    pub fn new_request(id: u16, seq: u16) -> Self {
+impl IcmpHeader {
+    pub fn new(icmp_type: IcmpType, code: u8, rest: [u8; 4]) -> Self {
         Self {
-            icmp_type: IcmpType::echo_request(),
-            code: 0,
+            icmp_type,
+            code,
             checksum: InternetChecksum::default(),
-            identifier: id.to_be(),
-            sequence: seq.to_be(),
+            rest,
         }
     }
 
-    pub fn new_reply(id: u16, seq: u16) -> Self {
-        Self {
-            icmp_type: IcmpType::echo_reply(),
-            code: 0,
-            checksum: InternetChecksum::default(),
-            identifier: id.to_be(),
-            sequence: seq.to_be(),
-        }
+    pub fn echo(icmp_type: IcmpType, id: u16, seq: u16) -> Self {
+        let (id, seq) = (id.to_be_bytes(), seq.to_be_bytes());
+        Self::new(icmp_type, 0, [id[0], id[1], seq[0], seq[1]])
     }
-}
-pub fn send_echo_request(
-    dst: IpV4Addr,
-    id: u16,
-    seq: u16,
-    data: &[u8],
-) -> Result<(), super::NetError> {
-    const MAX_ICMP_PACKET: usize = 1480; // IP MTU 1500 - IPv4ヘッダ20
-    let mut packet = [0u8; MAX_ICMP_PACKET];
-    let header_size = size_of::<IcmpEchoHeader>();
 
-    if header_size + data.len() > MAX_ICMP_PACKET {
-        return Err(super::NetError::InvalidPacket);
+    pub fn identifier(&self) -> u16 {
+        u16::from_be_bytes([self.rest[0], self.rest[1]])
+    }
+
+    pub fn sequence(&self) -> u16 {
+        u16::from_be_bytes([self.rest[2], self.rest[3]])
     }
+}
+
+// 送るメッセージを組み立てるバッファ。大きなエコーはカーネルスタックに収まらないので静的に確保する
+static mut TX_BUF: [u8; IP_PAYLOAD_MAX] = [0; IP_PAYLOAD_MAX];
 
-    let mut header = IcmpEchoHeader::new_request(id, seq);
+fn tx_buf() -> &'static mut [u8; IP_PAYLOAD_MAX] {
+    unsafe { &mut *core::ptr::addr_of_mut!(TX_BUF) }
+}
 
+/// `TX_BUF`の先頭に`header`を書き込む
+fn write_header<T>(header: &T) {
     unsafe {
         core::ptr::copy_nonoverlapping(
-            &header as *const _ as *const u8,
-            packet.as_mut_ptr(),
-            header_size,
+            header as *const _ as *const u8,
+            tx_buf().as_mut_ptr(),
+            size_of::<T>(),
         );
     }
-    packet[header_size..header_size + data.len()].copy_from_slice(data);
+}
+
+/// `TX_BUF`の先頭`len`バイトのメッセージにチェックサムを付けて送る
+fn send_message(dst: IpV4Addr, len: usize) -> Result<(), super::NetError> {
+    let packet = &mut tx_buf()[..len];
+    // チェックサムは2バイト目から。計算する間は0にしておく
+    packet[2..4].fill(0);
+    let checksum = InternetChecksum::calc(packet);
+    packet[2..4].copy_from_slice(&checksum.bytes());
 
-    let checksum = InternetChecksum::calc(&packet[..header_size + data.len()]);
-    header.checksum = checksum;
+    stats_mut().out_msgs += 1;
+    ip_send(dst, IpV4Protocol::icmp(), packet)
+}
+
+// エラーはトークンを1つ使って送り、トークンはnet.ipv4.icmp_ratelimit (ミリ秒) ごとに1つ増える。
+// 間隔が0なら制限しない
+const RATE_BURST: u32 = 10;
+static mut RATE_INTERVAL_MS: u32 = 100;
+static mut RATE_TOKENS: u32 = RATE_BURST;
+static mut RATE_LAST: u64 = 0;
+
+pub fn icmp_ratelimit() -> u32 {
+    unsafe { RATE_INTERVAL_MS }
+}
+
+pub fn icmp_set_ratelimit(ms: u32) -> Result<(), i32> {
+    unsafe { RATE_INTERVAL_MS = ms };
+    Ok(())
+}
 
+/// エラーを送ってよいか。送れるならトークンを1つ使う
+fn rate_allow() -> bool {
+    let interval = unsafe { RATE_INTERVAL_MS } as u64 * timebase_freq() / 1000;
+    if interval == 0 {
+        return true;
+    }
     unsafe {
-        core::ptr::copy_nonoverlapping(
-            &header as *const _ as *const u8,
-            packet.as_mut_ptr(),
-            header_size,
-        );
+        let now = now();
+        let refill = (now - RATE_LAST) / interval;
+        if refill > 0 {
+            RATE_TOKENS = (RATE_TOKENS as u64 + refill).min(RATE_BURST as u64) as u32;
+            RATE_LAST = now;
+        }
+        if RATE_TOKENS == 0 {
+            return false;
+        }
+        RATE_TOKENS -= 1;
+    }
+    true
+}
+
+/// `data_len`バイトのデータを付けたエコー要求を送る。データは0から増えていくバイト列
+pub fn send_echo_request(
+    dst: IpV4Addr,
+    id: u16,
+    seq: u16,
+    data_len: usize,
+) -> Result<(), super::NetError> {
+    let header_size = size_of::<IcmpHeader>();
+    if header_size + data_len > IP_PAYLOAD_MAX {
+        return Err(super::NetError::InvalidPacket);
     }
 
-    ip_send(
-        dst,
-        IpV4Protocol::icmp(),
-        &packet[..header_size + data.len()],
-    )
+    for (i, byte) in tx_buf()[header_size..header_size + data_len]
+        .iter_mut()
+        .enumerate()
+    {
+        *byte = i as u8;
+    }
+    write_header(&IcmpHeader::echo(IcmpType::echo_request(), id, seq));
+    send_message(dst, header_size + data_len)
 }
 
 pub fn send_echo_reply(
@@ -93,55 +242,121 @@ pub fn send_echo_reply(
     seq: u16,
     data: &[u8],
 ) -> Result<(), super::NetError> {
-    const MAX_ICMP_PACKET: usize = 1480;
-    let mut packet = [0u8; MAX_ICMP_PACKET];
-    let header_size = size_of::<IcmpEchoHeader>();
-
-    if header_size + data.len() > MAX_ICMP_PACKET {
+    let header_size = size_of::<IcmpHeader>();
+    if header_size + data.len() > IP_PAYLOAD_MAX {
         return Err(super::NetError::InvalidPacket);
     }
 
-    let mut header = IcmpEchoHeader::new_reply(id, seq);
+    tx_buf()[header_size..header_size + data.len()].copy_from_slice(data);
+    write_header(&IcmpHeader::echo(IcmpType::echo_reply(), id, seq));
+    send_message(dst, header_size + data.len())
+}
 
-    unsafe {
-        core::ptr::copy_nonoverlapping(
-            &header as *const _ as *const u8,
-            packet.as_mut_ptr(),
-            header_size,
-        );
+/// 受け取ったデータグラムの送信元にエラーを返す。`header`はオプションを含むIPヘッダで、
+/// それとデータの先頭8バイトを引用する
+pub fn send_error(icmp_type: IcmpType, code: u8, rest: [u8; 4], header: &[u8], data: &[u8]) {
+    let Some(ip_header) = IpV4Header::parse(header) else {
+        return;
+    };
+    // エラーへのエラー、2つ目以降のフラグメント、1つのホストでない送信元・宛先には返さない
+    if ip_header.protocol() == IpV4Protocol::icmp()
+        && data.first().is_some_and(|t| IcmpType(*t).is_error())
+    {
+        return;
+    }
+    let src = ip_header.src();
+    if ip_header.fragment_offset() != 0
+        || src == IpV4Addr::ANY
+        || is_broadcast(src)
+        || is_broadcast(ip_header.dst())
+    {
+        return;
+    }
+    if !rate_allow() {
+        stats_mut().out_rate_limited += 1;
+        return;
     }
-    packet[header_size..header_size + data.len()].copy_from_slice(data);
 
-    let checksum = InternetChecksum::calc(&packet[..header_size + data.len()]);
-    header.checksum = checksum;
+    let header_size = size_of::<IcmpHeader>();
+    let data = &data[..data.len().min(8)];
+    let buf = tx_buf();
+    buf[header_size..header_size + header.len()].copy_from_slice(header);
+    buf[header_size + header.len()..header_size + header.len() + data.len()].copy_from_slice(data);
+    write_header(&IcmpHeader::new(icmp_type, code, rest));
 
-    unsafe {
-        core::ptr::copy_nonoverlapping(
-            &header as *const _ as *const u8,
-            packet.as_mut_ptr(),
-            header_size,
-        );
+    let stats = stats_mut();
+    match icmp_type.0 {
+        3 => stats.out_dest_unreachs += 1,
+        5 => stats.out_redirects += 1,
+        11 => stats.out_time_excds += 1,
+        12 => stats.out_parm_probs += 1,
+        _ => {}
+    }
+    if let Err(e) = send_message(src, header_size + header.len() + data.len()) {
+        crate::println!("[icmp] Failed to send error to {}: {:?}", src, e);
     }
+}
 
-    ip_send(
-        dst,
-        IpV4Protocol::icmp(),
-        &packet[..header_size + data.len()],
-    )
+/// 受け取ったエラーが引用したデータグラムを調べ、それを送ったソケットに知らせる
+fn handle_error(src: IpV4Addr, header: &IcmpHeader, quoted: &[u8]) {
+    let stats = stats_mut();
+    match header.icmp_type.0 {
+        3 => stats.in_dest_unreachs += 1,
+        5 => stats.in_redirects += 1,
+        11 => stats.in_time_excds += 1,
+        12 => stats.in_parm_probs += 1,
+        _ => {}
+    }
+    // Redirectは経路が1つしかない今の構成では従うことがないので、数えるだけ
+    if header.icmp_type == IcmpType::redirect() {
+        return;
+    }
+
+    let Some(ip_header) = IpV4Header::parse(quoted) else {
+        return;
+    };
+    let header_len = ip_header.header_len();
+    if header_len < size_of::<IpV4Header>() || quoted.len() < header_len {
+        return;
+    }
+    let error = IcmpError {
+        origin: src,
+        icmp_type: header.icmp_type,
+        code: header.code,
+        received: now(),
+    };
+    if ip_header.protocol() == IpV4Protocol::udp() {
+        udp_handle_error(
+            &error,
+            ip_header.src(),
+            ip_header.dst(),
+            &quoted[header_len..],
+        );
+    }
 }
 
 pub fn handle_icmp_packet(src: IpV4Addr, data: &[u8]) {
-    if data.len() < size_of::<IcmpEchoHeader>() {
+    stats_mut().in_msgs += 1;
+    if data.len() < size_of::<IcmpHeader>() {
+        stats_mut().in_errors += 1;
+        return;
+    }
+    let header = unsafe { core::ptr::read_unaligned(data.as_ptr() as *const IcmpHeader) };
+    // チェックサムの欄を除いて計算し、欄の値と比べる
+    let checksum = InternetChecksumGenerator::new()
+        .feed(&data[..2])
+        .feed(&data[4..])
+        .checksum();
+    if checksum != header.checksum {
+        stats_mut().in_errors += 1;
         return;
     }
-
-    let header = unsafe { core::ptr::read_unaligned(data.as_ptr() as *const IcmpEchoHeader) };
 
     match header.icmp_type {
         t if t == IcmpType::echo_request() => {
-            let id = u16::from_be(header.identifier);
-            let seq = u16::from_be(header.sequence);
-            let payload = &data[size_of::<IcmpEchoHeader>()..];
+            let id = header.identifier();
+            let seq = header.sequence();
+            let payload = &data[size_of::<IcmpHeader>()..];
 
             crate::println!(
                 "[icmp] Received Echo Request from {}, id={}, seq={}",
@@ -155,8 +370,8 @@ pub fn handle_icmp_packet(src: IpV4Addr, data: &[u8]) {
             }
         }
         t if t == IcmpType::echo_reply() => {
-            let id = u16::from_be(header.identifier);
-            let seq = u16::from_be(header.sequence);
+            let id = header.identifier();
+            let seq = header.sequence();
 
             crate::println!(
                 "[icmp] Received Echo Reply from {}, id={}, seq={}",
@@ -167,6 +382,7 @@ pub fn handle_icmp_packet(src: IpV4Addr, data: &[u8]) {
 
             // TODO: ユーザープロセスに通知
         }
+        t if t.is_error() => handle_error(src, &header, &data[size_of::<IcmpHeader>()..]),
         _ => {
             crate::println!("[icmp] Unknown ICMP type: {:?}", header.icmp_type);
         }
@@ -1,5 +1,15 @@
 use super::checksum::InternetChecksum;
-use super::loopback::LoopbackInterface;
+use super::icmp::{
+    send_error, IcmpType, ICMP_FRAG_NEEDED, ICMP_FRAG_TIME_EXCEEDED, ICMP_NET_UNREACHABLE,
+    ICMP_PORT_UNREACHABLE, ICMP_PROTOCOL_UNREACHABLE, ICMP_REDIRECT_HOST, ICMP_TTL_EXCEEDED,
+};
+use super::loopback::{InterfaceStats, LoopbackInterface};
+use super::reassembly::{
+    reassembly_datagram, reassembly_expire, reassembly_insert, reassembly_release, FragmentKey,
+};
+use super::udp::{handle_udp_packet, UdpError};
+use crate::random::random_u32;
+use common::{RouteEntry, EEXIST, EINVAL, ENETUNREACH, ENODEV, ENOSPC, ESRCH};
 use core::fmt;
 use core::mem::size_of;
 
@@ -20,12 +30,27 @@ impl IpV4Addr {
     pub fn is_loopback(&self) -> bool {
         self.0[0] == 127 // 127.0.0.0/8
     }
-    pub fn to_be_u32(&self) -> u32 {
+    pub fn to_be_u32(self) -> u32 {
         u32::from_be_bytes(self.0)
     }
     pub fn from_be_u32(n: u32) -> Self {
         Self(n.to_be_bytes())
     }
+    /// 上位`prefix_len`ビットを残したネットワークアドレス
+    pub fn network(&self, prefix_len: u8) -> Self {
+        Self::from_be_u32(self.to_be_u32() & netmask(prefix_len))
+    }
+    /// ネットワークのブロードキャストアドレス
+    pub fn broadcast(&self, prefix_len: u8) -> Self {
+        Self::from_be_u32(self.to_be_u32() | !netmask(prefix_len))
+    }
+    pub fn in_network(&self, network: IpV4Addr, prefix_len: u8) -> bool {
+        self.network(prefix_len) == network
+    }
+}
+
+fn netmask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
 }
 
 impl fmt::Display for IpV4Addr {
@@ -47,15 +72,19 @@ impl IpV4Protocol {
     pub const fn icmp() -> Self {
         Self(1)
     }
-    pub const fn tcp() -> Self {
-        Self(6)
-    }
     pub const fn udp() -> Self {
         Self(17)
     }
+    pub const fn number(&self) -> u8 {
+        self.0
+    }
 }
 
-#[repr(packed)]
+const IP_FLAG_DF: u16 = 0x4000;
+const IP_FLAG_MF: u16 = 0x2000;
+const IP_OFFSET_MASK: u16 = 0x1fff;
+
+#[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct IpV4Header {
     version_and_ihl: u8,        // version (4bit) + IHL (4bit)
@@ -85,12 +114,57 @@ impl IpV4Header {
             dst_addr: dst,
         };
 
+        header.update_checksum();
+        header
+    }
+    /// パケットの先頭のヘッダ。短すぎれば`None`
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < size_of::<Self>() {
+            return None;
+        }
+        Some(unsafe { core::ptr::read_unaligned(packet.as_ptr() as *const Self) })
+    }
+    fn update_checksum(&mut self) {
+        self.checksum = InternetChecksum::default();
         let header_bytes = unsafe {
-            core::slice::from_raw_parts(&header as *const _ as *const u8, size_of::<Self>())
+            core::slice::from_raw_parts(self as *const _ as *const u8, size_of::<Self>())
         };
-        header.checksum = InternetChecksum::calc(header_bytes);
-
-        header
+        self.checksum = InternetChecksum::calc(header_bytes);
+    }
+    /// フラグメントのオフセット (バイト、8の倍数) とMF・DFフラグを設定する
+    pub fn set_fragment(&mut self, offset: usize, more: bool, dont_fragment: bool) {
+        let mut flags_and_offset = (offset / 8) as u16 & IP_OFFSET_MASK;
+        if more {
+            flags_and_offset |= IP_FLAG_MF;
+        }
+        if dont_fragment {
+            flags_and_offset |= IP_FLAG_DF;
+        }
+        self.flags_and_offset = flags_and_offset.to_be();
+        self.update_checksum();
+    }
+    pub fn version(&self) -> u8 {
+        self.version_and_ihl >> 4
+    }
+    /// オプションを含むヘッダの長さ (バイト)
+    pub fn header_len(&self) -> usize {
+        (self.version_and_ihl & 0x0f) as usize * 4
+    }
+    pub fn total_len(&self) -> usize {
+        u16::from_be_bytes(self.total_length) as usize
+    }
+    pub fn identification(&self) -> u16 {
+        u16::from_be(self.identification)
+    }
+    /// フラグメントのオフセット (バイト)
+    pub fn fragment_offset(&self) -> usize {
+        (u16::from_be(self.flags_and_offset) & IP_OFFSET_MASK) as usize * 8
+    }
+    pub fn more_fragments(&self) -> bool {
+        u16::from_be(self.flags_and_offset) & IP_FLAG_MF != 0
+    }
+    pub fn dont_fragment(&self) -> bool {
+        u16::from_be(self.flags_and_offset) & IP_FLAG_DF != 0
     }
     pub fn src(&self) -> IpV4Addr {
         self.src_addr
@@ -103,73 +177,709 @@ impl IpV4Header {
     }
 }
 
-static mut LOOPBACK: Option<LoopbackInterface> = None;
+const IFACES_MAX: usize = 4;
+const ROUTES_MAX: usize = 16;
+
+/// ネットワークインターフェース。今はループバックしかない
+pub struct Interface {
+    pub name: &'static str,
+    /// アドレスとプレフィックス長
+    pub addr: Option<(IpV4Addr, u8)>,
+    pub mtu: usize,
+    device: LoopbackInterface,
+}
+
+/// 経路。宛先のネットワークに届くパケットは`iface`から`gateway`(無ければ宛先そのもの)に送る
+#[derive(Copy, Clone)]
+pub struct Route {
+    pub dst: IpV4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<IpV4Addr>,
+    pub iface: usize,
+    /// インターフェースのアドレスから作った経路。アドレスを変えると張り替える
+    pub connected: bool,
+}
+
+impl Route {
+    /// ユーザー空間の経路を変換する。インターフェースが指定されていなければ`None`
+    pub fn from_entry(entry: &RouteEntry) -> Result<(Self, Option<usize>), i32> {
+        if entry.prefix_len > 32 {
+            return Err(EINVAL);
+        }
+        let iface = match entry.iface() {
+            "" => None,
+            name => Some(iface_index(name).ok_or(ENODEV)?),
+        };
+        let route = Self {
+            dst: IpV4Addr::from_be_u32(entry.dst),
+            prefix_len: entry.prefix_len as u8,
+            gateway: Some(IpV4Addr::from_be_u32(entry.gateway)).filter(|gw| *gw != IpV4Addr::ANY),
+            iface: iface.unwrap_or(0),
+            connected: false,
+        };
+        Ok((route, iface))
+    }
+
+    pub fn to_entry(self) -> RouteEntry {
+        let mut entry = RouteEntry::new();
+        entry.dst = self.dst.to_be_u32();
+        entry.prefix_len = self.prefix_len as u32;
+        entry.gateway = self.gateway.unwrap_or(IpV4Addr::ANY).to_be_u32();
+        entry.src = source_addr(self.iface).unwrap_or(IpV4Addr::ANY).to_be_u32();
+        entry.set_iface(iface(self.iface).map_or("", |i| i.name));
+        entry
+    }
+}
+
+static mut INTERFACES: [Option<Interface>; IFACES_MAX] = [const { None }; IFACES_MAX];
+static mut ROUTES: [Option<Route>; ROUTES_MAX] = [None; ROUTES_MAX];
+
+fn iface(index: usize) -> Option<&'static mut Interface> {
+    unsafe { (*core::ptr::addr_of_mut!(INTERFACES))[index].as_mut() }
+}
+
+fn routes_mut() -> &'static mut [Option<Route>; ROUTES_MAX] {
+    unsafe { &mut *core::ptr::addr_of_mut!(ROUTES) }
+}
+
 pub fn init() {
     unsafe {
-        LOOPBACK = Some(LoopbackInterface::new());
+        INTERFACES[0] = Some(Interface {
+            name: "lo",
+            addr: None,
+            mtu: 1500,
+            device: LoopbackInterface::new(),
+        });
     }
+    set_iface_addr("lo", IpV4Addr::LOOPBACK, 8).unwrap();
+    // 識別子の初期値は推測されにくいよう乱数にする
+    unsafe { NEXT_ID = random_u32() as u16 };
     crate::println!("[net] IP layer initialized");
 }
 
+pub fn iface_index(name: &str) -> Option<usize> {
+    (0..IFACES_MAX).find(|i| iface(*i).is_some_and(|iface| iface.name == name))
+}
+
+/// インターフェースの名前と統計
+pub fn interfaces() -> impl Iterator<Item = (&'static str, InterfaceStats)> {
+    (0..IFACES_MAX).filter_map(|i| iface(i).map(|iface| (iface.name, iface.device.stats)))
+}
+
+/// インターフェースのアドレスを設定し、直結するネットワークへの経路を張り替える
+pub fn set_iface_addr(name: &str, addr: IpV4Addr, prefix_len: u8) -> Result<(), i32> {
+    if prefix_len > 32 {
+        return Err(EINVAL);
+    }
+    let index = iface_index(name).ok_or(ENODEV)?;
+    let route = Route {
+        dst: addr.network(prefix_len),
+        prefix_len,
+        gateway: None,
+        iface: index,
+        connected: true,
+    };
+
+    // 失敗してアドレスと経路が食い違わないよう、先に新しい経路を置けるか確かめる。
+    // 同じ宛先の経路を手で足していても、それは消さない
+    let routes = routes_mut();
+    let old = routes
+        .iter()
+        .position(|r| r.is_some_and(|r| r.connected && r.iface == index));
+    let exists = routes.iter().enumerate().any(|(i, r)| {
+        Some(i) != old && r.is_some_and(|r| r.dst == route.dst && r.prefix_len == prefix_len)
+    });
+    if exists {
+        return Err(EEXIST);
+    }
+    let slot = match old {
+        Some(i) => i,
+        None => routes.iter().position(|r| r.is_none()).ok_or(ENOSPC)?,
+    };
+    routes[slot] = Some(route);
+    iface(index).unwrap().addr = Some((addr, prefix_len));
+    Ok(())
+}
+
+/// 経路を追加する。インターフェースを省いたらゲートウェイへの経路から決める
+pub fn route_add(mut route: Route, iface: Option<usize>) -> Result<(), i32> {
+    if route.dst.network(route.prefix_len) != route.dst {
+        return Err(EINVAL);
+    }
+    match (route.gateway, iface) {
+        (None, None) => return Err(EINVAL),
+        (None, Some(index)) => route.iface = index,
+        (Some(gateway), _) => {
+            // ゲートウェイは直結したネットワークにいなければならない
+            let connected = routes()
+                .filter(|r| r.gateway.is_none() && iface.is_none_or(|i| r.iface == i))
+                .filter(|r| gateway.in_network(r.dst, r.prefix_len))
+                .max_by_key(|r| r.prefix_len)
+                .ok_or(ENETUNREACH)?;
+            route.iface = connected.iface;
+        }
+    }
+    route_insert(route)
+}
+
+fn route_insert(route: Route) -> Result<(), i32> {
+    let routes = routes_mut();
+    if routes
+        .iter()
+        .flatten()
+        .any(|r| r.dst == route.dst && r.prefix_len == route.prefix_len)
+    {
+        return Err(EEXIST);
+    }
+    let slot = routes.iter_mut().find(|r| r.is_none()).ok_or(ENOSPC)?;
+    *slot = Some(route);
+    Ok(())
+}
+
+pub fn route_del(dst: IpV4Addr, prefix_len: u8) -> Result<(), i32> {
+    let slot = routes_mut()
+        .iter_mut()
+        .find(|r| r.is_some_and(|r| r.dst == dst && r.prefix_len == prefix_len))
+        .ok_or(ESRCH)?;
+    *slot = None;
+    Ok(())
+}
+
+/// 経路の一覧
+pub fn routes() -> impl Iterator<Item = Route> {
+    routes_mut().iter().flatten().copied()
+}
+
+/// 宛先が自分のアドレスか
+pub fn is_local(addr: IpV4Addr) -> bool {
+    addr.is_loopback()
+        || (0..IFACES_MAX)
+            .any(|i| iface(i).is_some_and(|iface| iface.addr.is_some_and(|(a, _)| a == addr)))
+}
+
+/// 宛先に最も長く一致する経路を探し、送り出すインターフェースを返す。自分宛てはループバックに回す
+pub fn route_lookup(dst: IpV4Addr) -> Option<usize> {
+    if is_local(dst) {
+        return iface_index("lo");
+    }
+    route_match(dst).map(|r| r.iface)
+}
+
+/// 宛先に最も長く一致する経路
+fn route_match(dst: IpV4Addr) -> Option<Route> {
+    routes()
+        .filter(|r| dst.in_network(r.dst, r.prefix_len))
+        .max_by_key(|r| r.prefix_len)
+}
+
+/// インターフェースから送るときの送信元アドレス
+pub fn source_addr(index: usize) -> Option<IpV4Addr> {
+    iface(index)?.addr.map(|(addr, _)| addr)
+}
+
+/// ヘッダを除いたデータグラムの最大長。これより大きいものは送らず、再構築もしない
+pub const IP_PAYLOAD_MAX: usize = 16384;
+const MAX_IP_PACKET: usize = 1500;
+
+// 送り出すパケットを組み立てるバッファ。カーネルスタックに置くと受信の処理から
+// 送り返すときに溢れるので静的に確保する
+static mut TX_PACKET: [u8; MAX_IP_PACKET] = [0; MAX_IP_PACKET];
+
+fn tx_packet() -> &'static mut [u8; MAX_IP_PACKET] {
+    unsafe { &mut *core::ptr::addr_of_mut!(TX_PACKET) }
+}
+
+/// ソケットごとに変えられる、送るデータグラムのヘッダの値
+#[derive(Copy, Clone)]
+pub struct IpOptions {
+    pub ttl: u8,
+    /// DSCP (上位6bit) とECN (下位2bit)
+    pub tos: u8,
+    pub dont_fragment: bool,
+}
+
+impl IpOptions {
+    pub const DEFAULT: Self = Self {
+        ttl: 64,
+        tos: 0,
+        dont_fragment: false,
+    };
+}
+
+// 次に送るデータグラムの識別子。再構築で別のデータグラムと混ざらないよう、送るたびに増やす
+static mut NEXT_ID: u16 = 0;
+
+fn next_id() -> u16 {
+    unsafe {
+        let id = NEXT_ID;
+        NEXT_ID = NEXT_ID.wrapping_add(1);
+        id
+    }
+}
+
+/// 宛先に送るときの送信元アドレス。自分宛ては宛先のアドレスをそのまま使う
+pub fn source_for(dst: IpV4Addr) -> Option<IpV4Addr> {
+    if is_local(dst) {
+        return Some(dst);
+    }
+    source_addr(route_lookup(dst)?)
+}
+
 pub fn ip_send(dst: IpV4Addr, protocol: IpV4Protocol, data: &[u8]) -> Result<(), super::NetError> {
-    if !dst.is_loopback() {
+    ip_send_with(None, dst, protocol, data, &IpOptions::DEFAULT)
+}
+
+/// 送信元アドレスを省いたら`source_for`で選ぶ。インターフェースのMTUを超えるデータグラムは
+/// フラグメントに分けて送るが、`options.dont_fragment`なら分けずに`MessageTooLong`を返す
+pub fn ip_send_with(
+    src: Option<IpV4Addr>,
+    dst: IpV4Addr,
+    protocol: IpV4Protocol,
+    data: &[u8],
+    options: &IpOptions,
+) -> Result<(), super::NetError> {
+    let Some(index) = route_lookup(dst) else {
+        stats_mut().out_no_routes += 1;
         return Err(super::NetError::NoRoute);
+    };
+    let src = src
+        .or_else(|| source_for(dst))
+        .ok_or(super::NetError::NoRoute)?;
+    let iface = iface(index).unwrap();
+
+    if data.len() > IP_PAYLOAD_MAX {
+        return Err(super::NetError::InvalidPacket);
+    }
+    let header_size = size_of::<IpV4Header>();
+    let mtu = iface.mtu.min(MAX_IP_PACKET);
+    let fragmented = header_size + data.len() > mtu;
+    if fragmented && options.dont_fragment {
+        stats_mut().frag_fails += 1;
+        return Err(super::NetError::MessageTooLong);
     }
 
-    let src = IpV4Addr::LOOPBACK;
-    let header = IpV4Header::new(src, dst, protocol, data.len());
+    let mut header = IpV4Header::new(src, dst, protocol, 0);
+    header.identification = next_id().to_be();
+    header.ttl = options.ttl;
+    header.dscp_and_ecn = options.tos;
+    header.set_fragment(0, false, options.dont_fragment);
+    send_fragments(iface, &header, data)
+}
 
-    const MAX_IP_PACKET: usize = 1500;
-    let mut packet = [0u8; MAX_IP_PACKET];
+/// `template`の送信元・宛先・識別子などを写したヘッダで`data`をMTUに収まるフラグメントに分けて送る。
+/// `template`自体がフラグメントなら、そのオフセットとMFフラグを引き継ぐ。オプションは付けない
+fn send_fragments(
+    iface: &mut Interface,
+    template: &IpV4Header,
+    data: &[u8],
+) -> Result<(), super::NetError> {
     let header_size = size_of::<IpV4Header>();
+    let mtu = iface.mtu.min(MAX_IP_PACKET);
+    let fragmented = header_size + data.len() > mtu;
+    let base = template.fragment_offset();
 
-    if header_size + data.len() > MAX_IP_PACKET {
-        return Err(super::NetError::InvalidPacket);
+    // 最後以外のフラグメントのデータは8バイトの倍数にする
+    let chunk_size = (mtu - header_size) & !7;
+    let packet = tx_packet();
+    let mut offset = 0;
+    loop {
+        let rest = data.len() - offset;
+        let len = if header_size + rest <= mtu {
+            rest
+        } else {
+            chunk_size
+        };
+        let more = offset + len < data.len();
+        let mut header =
+            IpV4Header::new(template.src_addr, template.dst_addr, template.protocol, len);
+        header.identification = template.identification;
+        header.ttl = template.ttl;
+        header.dscp_and_ecn = template.dscp_and_ecn;
+        header.set_fragment(
+            base + offset,
+            more || template.more_fragments(),
+            template.dont_fragment(),
+        );
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                &header as *const _ as *const u8,
+                packet.as_mut_ptr(),
+                header_size,
+            );
+        }
+        packet[header_size..header_size + len].copy_from_slice(&data[offset..offset + len]);
+        if let Err(e) = iface.device.send(&packet[..header_size + len]) {
+            if fragmented {
+                stats_mut().frag_fails += 1;
+            }
+            return Err(e);
+        }
+        if fragmented {
+            stats_mut().frag_creates += 1;
+        }
+
+        offset += len;
+        if !more {
+            break;
+        }
+    }
+    if fragmented {
+        stats_mut().frag_oks += 1;
+    }
+    Ok(())
+}
+
+/// IPの受信の統計。パケットを捨てたら理由ごとに数える
+#[derive(Copy, Clone, Default)]
+pub struct IpStats {
+    pub in_receives: u32,
+    pub in_delivers: u32,
+    /// バージョンが4でない、IHLが5未満、オプションが壊れている
+    pub in_hdr_errors: u32,
+    pub in_csum_errors: u32,
+    /// 全長がヘッダより短いか、受け取ったバイト数より長い
+    pub in_truncated: u32,
+    /// 宛先が自分のアドレスでない
+    pub in_addr_errors: u32,
+    pub in_unknown_protos: u32,
+    /// 時間切れで捨てたデータグラム
+    pub reasm_timeout: u32,
+    /// 受け取ったフラグメント
+    pub reasm_reqds: u32,
+    pub reasm_oks: u32,
+    /// 重なりや大きさの超過で捨てたデータグラム
+    pub reasm_fails: u32,
+    /// フラグメントに分けて送ったデータグラム
+    pub frag_oks: u32,
+    /// DFが立っていたか、送信に失敗したデータグラム
+    pub frag_fails: u32,
+    pub frag_creates: u32,
+    /// 自分宛てでなく、転送したパケット
+    pub forw_datagrams: u32,
+    /// 経路が無くて送れなかった、または転送できなかったパケット
+    pub out_no_routes: u32,
+}
+
+static mut IP_STATS: IpStats = IpStats {
+    in_receives: 0,
+    in_delivers: 0,
+    in_hdr_errors: 0,
+    in_csum_errors: 0,
+    in_truncated: 0,
+    in_addr_errors: 0,
+    in_unknown_protos: 0,
+    reasm_timeout: 0,
+    reasm_reqds: 0,
+    reasm_oks: 0,
+    reasm_fails: 0,
+    frag_oks: 0,
+    frag_fails: 0,
+    frag_creates: 0,
+    forw_datagrams: 0,
+    out_no_routes: 0,
+};
+
+fn stats_mut() -> &'static mut IpStats {
+    unsafe { &mut *core::ptr::addr_of_mut!(IP_STATS) }
+}
+
+pub fn ip_stats() -> IpStats {
+    *stats_mut()
+}
+
+/// 受信したパケットを捨てる理由
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum RxError {
+    Header,
+    /// 壊れたオプション。位置はヘッダの先頭から
+    Options(usize),
+    Checksum,
+    Truncated,
+}
+
+/// 全体へのブロードキャストか、インターフェースのネットワークへのブロードキャストか
+pub fn is_broadcast(addr: IpV4Addr) -> bool {
+    addr == IpV4Addr::new(255, 255, 255, 255)
+        || (0..IFACES_MAX).any(|i| {
+            iface(i)
+                .and_then(|iface| iface.addr)
+                .is_some_and(|(a, len)| len < 31 && addr == a.broadcast(len))
+        })
+}
+
+/// 宛先が自分のアドレスか、ブロードキャストか
+fn accepts(dst: IpV4Addr) -> bool {
+    is_local(dst) || is_broadcast(dst)
+}
+
+/// `addr`がインターフェースに直接繋がったネットワークにいるか
+fn on_link(index: usize, addr: IpV4Addr) -> bool {
+    iface(index)
+        .and_then(|iface| iface.addr)
+        .is_some_and(|(a, len)| addr.in_network(a.network(len), len))
+}
+
+/// オプションが長さの範囲に収まっているか。壊れていたらその位置 (ヘッダの先頭から) を返す
+fn check_options(options: &[u8]) -> Result<(), usize> {
+    let mut i = 0;
+    while i < options.len() {
+        let offset = size_of::<IpV4Header>() + i;
+        match options[i] {
+            // End of Option List
+            0 => return Ok(()),
+            // No Operation
+            1 => i += 1,
+            _ => {
+                let len = *options.get(i + 1).ok_or(offset)? as usize;
+                if len < 2 || i + len > options.len() {
+                    return Err(offset + 1);
+                }
+                i += len;
+            }
+        }
     }
+    Ok(())
+}
 
+/// ヘッダを検査し、ヘッダと(パディングを除いた)ペイロードの範囲を返す
+fn validate(packet: &[u8]) -> Result<(IpV4Header, core::ops::Range<usize>), RxError> {
+    let header = IpV4Header::parse(packet).ok_or(RxError::Truncated)?;
+
+    let header_len = header.header_len();
+    if header.version() != 4 || header_len < size_of::<IpV4Header>() {
+        return Err(RxError::Header);
+    }
+    let total_len = header.total_len();
+    if header_len > packet.len() || total_len < header_len || total_len > packet.len() {
+        return Err(RxError::Truncated);
+    }
+    // チェックサムを含めて計算すると0になる
+    if InternetChecksum::calc(&packet[..header_len]) != InternetChecksum::default() {
+        return Err(RxError::Checksum);
+    }
+    // 最後以外のフラグメントのデータは8バイトの倍数
+    if header.more_fragments() && !(total_len - header_len).is_multiple_of(8) {
+        return Err(RxError::Header);
+    }
+    check_options(&packet[size_of::<IpV4Header>()..header_len]).map_err(RxError::Options)?;
+    // total_lengthより後ろはリンク層のパディング
+    Ok((header, header_len..total_len))
+}
+
+// 自分宛てでないパケットを転送するか (net.ipv4.ip_forward)
+static mut FORWARDING: bool = false;
+
+pub fn ip_forwarding() -> u32 {
+    unsafe { FORWARDING as u32 }
+}
+
+pub fn ip_set_forwarding(value: u32) -> Result<(), i32> {
     unsafe {
-        core::ptr::copy_nonoverlapping(
-            &header as *const _ as *const u8,
-            packet.as_mut_ptr(),
-            header_size,
+        FORWARDING = match value {
+            0 => false,
+            1 => true,
+            _ => return Err(EINVAL),
+        }
+    };
+    Ok(())
+}
+
+/// 自分宛てでないパケットを経路に従って送り出す。`in_iface`は受け取ったインターフェース
+fn forward(in_iface: usize, header: &IpV4Header, packet: &[u8]) {
+    // 転送するとTTLが0になるパケットは捨てて送信元に知らせる
+    let (raw_header, data) = packet.split_at(header.header_len());
+    if header.ttl <= 1 {
+        stats_mut().in_hdr_errors += 1;
+        send_error(
+            IcmpType::time_exceeded(),
+            ICMP_TTL_EXCEEDED,
+            [0; 4],
+            raw_header,
+            data,
+        );
+        return;
+    }
+    let Some(route) = route_match(header.dst_addr) else {
+        stats_mut().out_no_routes += 1;
+        send_error(
+            IcmpType::destination_unreachable(),
+            ICMP_NET_UNREACHABLE,
+            [0; 4],
+            raw_header,
+            data,
+        );
+        return;
+    };
+
+    // 受け取ったインターフェースから送り返すなら、同じネットワークにいる送信元に
+    // 次の転送先へ直接送るよう教える。パケット自体はそのまま転送する
+    let next_hop = route.gateway.unwrap_or(header.dst_addr);
+    if route.iface == in_iface && !is_local(header.src_addr) && on_link(in_iface, header.src_addr) {
+        send_error(
+            IcmpType::redirect(),
+            ICMP_REDIRECT_HOST,
+            next_hop.bytes(),
+            raw_header,
+            data,
         );
     }
-    packet[header_size..header_size + data.len()].copy_from_slice(data);
 
+    let out = iface(route.iface).unwrap();
+    let mtu = out.mtu.min(MAX_IP_PACKET);
+    if packet.len() > mtu && header.dont_fragment() {
+        // 分けられないので、送信元に次の経路のMTUを教える
+        stats_mut().frag_fails += 1;
+        let mtu = (mtu as u16).to_be_bytes();
+        send_error(
+            IcmpType::destination_unreachable(),
+            ICMP_FRAG_NEEDED,
+            [0, 0, mtu[0], mtu[1]],
+            raw_header,
+            data,
+        );
+        return;
+    }
+
+    // TTLを1減らし、チェックサムは変わった語の差分だけ更新する
+    let mut header = *header;
+    let old = u16::from_be_bytes([header.ttl, header.protocol.number()]);
+    header.ttl -= 1;
+    let new = u16::from_be_bytes([header.ttl, header.protocol.number()]);
+    header.checksum = header.checksum.adjust(old, new);
+
+    // 収まらなければフラグメントに分ける。元のパケット自体がフラグメントでも位置を引き継ぐ
+    if packet.len() > mtu {
+        if send_fragments(out, &header, data).is_ok() {
+            stats_mut().forw_datagrams += 1;
+        }
+        return;
+    }
+
+    let copy = tx_packet();
+    copy[..packet.len()].copy_from_slice(packet);
     unsafe {
-        LOOPBACK
-            .as_mut()
-            .unwrap()
-            .send(&packet[..header_size + data.len()])
+        core::ptr::copy_nonoverlapping(
+            &header as *const _ as *const u8,
+            copy.as_mut_ptr(),
+            size_of::<IpV4Header>(),
+        );
+    }
+    if out.device.send(&copy[..packet.len()]).is_ok() {
+        stats_mut().forw_datagrams += 1;
     }
 }
 
-pub fn process_packets() {
-    unsafe {
-        let loopback = match LOOPBACK.as_mut() {
-            Some(lo) => lo,
-            None => return,
-        };
+/// 検査を通ったパケット (`raw_header`はオプションを含むヘッダ) を受け取る。
+/// フラグメントは揃うまで再構築のバッファに貯める
+fn receive(raw_header: &[u8], data: &[u8]) {
+    let header = IpV4Header::parse(raw_header).unwrap();
+    let offset = header.fragment_offset();
+    let more = header.more_fragments();
+    if offset == 0 && !more {
+        deliver(raw_header, data);
+        return;
+    }
 
-        loop {
-            let packet = match loopback.recv() {
-                Some(p) => p,
-                None => return,
-            };
+    stats_mut().reasm_reqds += 1;
+    let key = FragmentKey {
+        src: header.src_addr,
+        dst: header.dst_addr,
+        protocol: header.protocol,
+        id: header.identification(),
+    };
+    match reassembly_insert(key, raw_header, offset, more, data) {
+        Ok(Some(slot)) => {
+            stats_mut().reasm_oks += 1;
+            let (raw_header, data) = reassembly_datagram(slot);
+            deliver(raw_header, data);
+            reassembly_release(slot);
+        }
+        Ok(None) => {}
+        Err(_) => stats_mut().reasm_fails += 1,
+    }
+}
 
-            if packet.len() < size_of::<IpV4Header>() {
-                loopback.consume();
-                continue;
-            }
+/// データグラムを上位のプロトコルに渡す。受け取り手がいなければ送信元に知らせる
+fn deliver(raw_header: &[u8], data: &[u8]) {
+    let header = IpV4Header::parse(raw_header).unwrap();
+    if header.protocol == IpV4Protocol::icmp() {
+        stats_mut().in_delivers += 1;
+        super::icmp::handle_icmp_packet(header.src_addr, data);
+    } else if header.protocol == IpV4Protocol::udp() {
+        stats_mut().in_delivers += 1;
+        if handle_udp_packet(header.src_addr, header.dst_addr, data) == Err(UdpError::NoSocket) {
+            send_error(
+                IcmpType::destination_unreachable(),
+                ICMP_PORT_UNREACHABLE,
+                [0; 4],
+                raw_header,
+                data,
+            );
+        }
+    } else {
+        stats_mut().in_unknown_protos += 1;
+        send_error(
+            IcmpType::destination_unreachable(),
+            ICMP_PROTOCOL_UNREACHABLE,
+            [0; 4],
+            raw_header,
+            data,
+        );
+    }
+}
 
-            let header = core::ptr::read_unaligned(packet.as_ptr() as *const IpV4Header);
-            let data = &packet[size_of::<IpV4Header>()..];
+pub fn process_packets() {
+    // 最初のフラグメントが届いていれば、揃わなかったことを送信元に知らせる
+    stats_mut().reasm_timeout += reassembly_expire(|raw_header, data| {
+        send_error(
+            IcmpType::time_exceeded(),
+            ICMP_FRAG_TIME_EXCEEDED,
+            [0; 4],
+            raw_header,
+            data,
+        )
+    });
+
+    for index in 0..IFACES_MAX {
+        let Some(iface) = iface(index) else {
+            continue;
+        };
+        let device = &mut iface.device;
 
-            if header.protocol == IpV4Protocol::icmp() {
-                super::icmp::handle_icmp_packet(header.src_addr, data);
+        while let Some(packet) = device.recv() {
+            stats_mut().in_receives += 1;
+            match validate(packet) {
+                Ok((header, payload)) if accepts(header.dst_addr) => {
+                    receive(&packet[..payload.start], &packet[payload])
+                }
+                Ok((header, payload)) if ip_forwarding() != 0 => {
+                    forward(index, &header, &packet[..payload.end])
+                }
+                Ok(_) => stats_mut().in_addr_errors += 1,
+                Err(e) => {
+                    let stats = stats_mut();
+                    match e {
+                        RxError::Header => stats.in_hdr_errors += 1,
+                        RxError::Options(pointer) => {
+                            stats.in_hdr_errors += 1;
+                            let header_len = IpV4Header::parse(packet).unwrap().header_len();
+                            let (raw_header, data) = packet.split_at(header_len);
+                            send_error(
+                                IcmpType::parameter_problem(),
+                                0,
+                                [pointer as u8, 0, 0, 0],
+                                raw_header,
+                                data,
+                            );
+                        }
+                        RxError::Checksum => stats.in_csum_errors += 1,
+                        RxError::Truncated => stats.in_truncated += 1,
+                    }
+                }
             }
 
-            loopback.consume();
+            device.consume();
         }
     }
 }
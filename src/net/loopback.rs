@@ -10,12 +10,23 @@
 const MAX_PACKETS: usize = 16;
 const MAX_PACKET_SIZE: usize = 1514; // Ethernet MTU 1500 + Ethernetヘッダ14
 
+/// インターフェースごとの送受信の統計
+#[derive(Copy, Clone, Default)]
+pub struct InterfaceStats {
+    pub rx_packets: u32,
+    pub rx_bytes: u32,
+    pub tx_packets: u32,
+    pub tx_bytes: u32,
+    pub tx_dropped: u32,
+}
+
 pub struct LoopbackInterface {
     packets: [Option<[u8; MAX_PACKET_SIZE]>; MAX_PACKETS],
     packet_lens: [usize; MAX_PACKETS],
     head: usize,
     tail: usize,
     count: usize,
+    pub stats: InterfaceStats,
 }
 impl LoopbackInterface {
     pub fn new() -> Self {
@@ -25,25 +36,30 @@ impl LoopbackInterface {
             head: 0,
             tail: 0,
             count: 0,
+            stats: InterfaceStats::default(),
         }
     }
 
     pub fn send(&mut self, packet: &[u8]) -> Result<(), super::NetError> {
         if self.count >= MAX_PACKETS {
+            self.stats.tx_dropped += 1;
             return Err(super::NetError::Timeout);
         }
 
         if packet.len() > MAX_PACKET_SIZE {
+            self.stats.tx_dropped += 1;
             return Err(super::NetError::InvalidPacket);
         }
 
-        let mut buf = [0u8; MAX_PACKET_SIZE];
+        // 一時バッファを経由するとスタックを大きく使うので、リングの中に直接書き込む
+        let buf = self.packets[self.tail].insert([0; MAX_PACKET_SIZE]);
         buf[..packet.len()].copy_from_slice(packet);
 
-        self.packets[self.tail] = Some(buf);
         self.packet_lens[self.tail] = packet.len();
         self.tail = (self.tail + 1) % MAX_PACKETS;
         self.count += 1;
+        self.stats.tx_packets += 1;
+        self.stats.tx_bytes += packet.len() as u32;
 
         Ok(())
     }
@@ -61,6 +77,8 @@ impl LoopbackInterface {
 
     pub fn consume(&mut self) {
         if self.count > 0 {
+            self.stats.rx_packets += 1;
+            self.stats.rx_bytes += self.packet_lens[self.head] as u32;
             self.packets[self.head] = None;
             self.head = (self.head + 1) % MAX_PACKETS;
             self.count -= 1;
@@ -0,0 +1,182 @@
+// IPv4の再構築: フラグメントを(送信元, 宛先, プロトコル, 識別子)ごとに集め、揃ったら1つのデータグラムにする
+// 既に受け取った範囲と一部だけ重なるフラグメントは、中身の食い違いを避けるためデータグラムごと捨てる
+
+use super::ip::{IpV4Addr, IpV4Protocol, IP_PAYLOAD_MAX};
+use crate::timer::{now, timebase_freq};
+
+const SLOTS: usize = 4;
+// フラグメントのオフセットの単位
+const BLOCK_SIZE: usize = 8;
+const BLOCKS: usize = IP_PAYLOAD_MAX / BLOCK_SIZE;
+// 最初のフラグメントからこの時間で揃わなければ捨てる
+const TIMEOUT_SECS: u64 = 30;
+// オプションを含めたIPヘッダの最大長
+const HEADER_MAX: usize = 60;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct FragmentKey {
+    pub src: IpV4Addr,
+    pub dst: IpV4Addr,
+    pub protocol: IpV4Protocol,
+    pub id: u16,
+}
+
+struct Slot {
+    key: Option<FragmentKey>,
+    started: u64,
+    /// 最後のフラグメントを受け取ったら、データグラムの長さ
+    total_len: Option<usize>,
+    /// 受け取った8バイト単位のブロック
+    received: [u32; BLOCKS / 32],
+    /// オフセットが0のフラグメントのIPヘッダ。ICMPのエラーで引用する
+    header: [u8; HEADER_MAX],
+    /// 0ならまだオフセットが0のフラグメントを受け取っていない
+    header_len: usize,
+    data: [u8; IP_PAYLOAD_MAX],
+}
+
+impl Slot {
+    const EMPTY: Self = Self {
+        key: None,
+        started: 0,
+        total_len: None,
+        received: [0; BLOCKS / 32],
+        header: [0; HEADER_MAX],
+        header_len: 0,
+        data: [0; IP_PAYLOAD_MAX],
+    };
+
+    fn has_block(&self, block: usize) -> bool {
+        self.received[block / 32] & (1 << (block % 32)) != 0
+    }
+
+    fn set_block(&mut self, block: usize) {
+        self.received[block / 32] |= 1 << (block % 32);
+    }
+
+    fn is_complete(&self) -> bool {
+        self.total_len
+            .is_some_and(|len| (0..len.div_ceil(BLOCK_SIZE)).all(|b| self.has_block(b)))
+    }
+
+    fn reset(&mut self) {
+        self.key = None;
+        self.total_len = None;
+        self.received = [0; BLOCKS / 32];
+        self.header_len = 0;
+    }
+}
+
+static mut REASSEMBLY: [Slot; SLOTS] = [const { Slot::EMPTY }; SLOTS];
+
+fn slots() -> &'static mut [Slot; SLOTS] {
+    unsafe { &mut *core::ptr::addr_of_mut!(REASSEMBLY) }
+}
+
+/// 再構築できずに捨てた理由
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReassemblyError {
+    /// 受け取った範囲と一部だけ重なった、または最後のフラグメントと長さが食い違う
+    Overlap,
+    /// `IP_PAYLOAD_MAX`を超える
+    TooBig,
+}
+
+/// `header`(IPヘッダ) を持つフラグメントを加える。データグラムが揃ったら、そのスロットを返す。
+/// 揃ったデータグラムは`reassembly_release`するまで`reassembly_datagram`で読める
+pub fn reassembly_insert(
+    key: FragmentKey,
+    header: &[u8],
+    offset: usize,
+    more: bool,
+    data: &[u8],
+) -> Result<Option<usize>, ReassemblyError> {
+    let end = offset + data.len();
+    if end > IP_PAYLOAD_MAX {
+        if let Some(index) = find(&key) {
+            slots()[index].reset();
+        }
+        return Err(ReassemblyError::TooBig);
+    }
+
+    let index = match find(&key) {
+        Some(index) => index,
+        None => {
+            // 空きが無ければ最も古いものを追い出す
+            let index = slots()
+                .iter()
+                .position(|s| s.key.is_none())
+                .unwrap_or_else(|| (0..SLOTS).min_by_key(|i| slots()[*i].started).unwrap());
+            let slot = &mut slots()[index];
+            slot.reset();
+            slot.key = Some(key);
+            slot.started = now();
+            index
+        }
+    };
+    let slot = &mut slots()[index];
+
+    let first = offset / BLOCK_SIZE;
+    let last = end.div_ceil(BLOCK_SIZE);
+    let seen = (first..last).filter(|b| slot.has_block(*b)).count();
+    let consistent_end = match slot.total_len {
+        Some(len) => end <= len && (more || end == len),
+        None => more || !(last..BLOCKS).any(|b| slot.has_block(b)),
+    };
+    if !consistent_end || (seen != 0 && seen != last - first) {
+        slot.reset();
+        return Err(ReassemblyError::Overlap);
+    }
+    // 全く同じ範囲の再送は無視する
+    if seen == 0 {
+        slot.data[offset..end].copy_from_slice(data);
+        for block in first..last {
+            slot.set_block(block);
+        }
+    }
+    if !more {
+        slot.total_len = Some(end);
+    }
+    if offset == 0 && slot.header_len == 0 {
+        let len = header.len().min(HEADER_MAX);
+        slot.header[..len].copy_from_slice(&header[..len]);
+        slot.header_len = len;
+    }
+
+    Ok(slot.is_complete().then_some(index))
+}
+
+fn find(key: &FragmentKey) -> Option<usize> {
+    slots().iter().position(|s| s.key.as_ref() == Some(key))
+}
+
+/// 揃ったデータグラムと、最初のフラグメントのIPヘッダ
+pub fn reassembly_datagram(index: usize) -> (&'static [u8], &'static [u8]) {
+    let slot = &slots()[index];
+    (
+        &slot.header[..slot.header_len],
+        &slot.data[..slot.total_len.unwrap_or(0)],
+    )
+}
+
+pub fn reassembly_release(index: usize) {
+    slots()[index].reset();
+}
+
+/// 時間切れのデータグラムを捨て、その数を返す。最初のフラグメントを受け取っていたものは、
+/// そのIPヘッダとデータの先頭で`on_expire`を呼ぶ
+pub fn reassembly_expire(mut on_expire: impl FnMut(&[u8], &[u8])) -> u32 {
+    let timeout = TIMEOUT_SECS * timebase_freq();
+    let now = now();
+    let mut expired = 0;
+    for slot in slots().iter_mut() {
+        if slot.key.is_some() && now - slot.started >= timeout {
+            if slot.header_len != 0 {
+                on_expire(&slot.header[..slot.header_len], &slot.data[..BLOCK_SIZE]);
+            }
+            slot.reset();
+            expired += 1;
+        }
+    }
+    expired
+}
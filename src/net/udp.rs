@@ -0,0 +1,388 @@
+// UDP: ソケットをポート番号で区別し、届いたデータグラムをソケットごとのキューに貯める
+// 宛先はconnectで決め、1回のread・writeで1つのデータグラムを受け取る・送る
+
+use common::{
+    SockError, EADDRINUSE, EADDRNOTAVAIL, EDESTADDRREQ, EINVAL, EMSGSIZE, ENETUNREACH, ENOPROTOOPT,
+    ETIMEDOUT, IP_MTU_DISCOVER, IP_PMTUDISC_DO, IP_PMTUDISC_DONT, IP_TOS, IP_TTL,
+};
+
+use super::checksum::{InternetChecksum, InternetChecksumGenerator};
+use super::icmp::IcmpError;
+use super::ip::{
+    ip_send_with, is_local, process_packets, source_for, IpOptions, IpV4Addr, IpV4Protocol,
+    IP_PAYLOAD_MAX,
+};
+use crate::{
+    timer::{now, timebase_freq},
+    PM,
+};
+use core::mem::size_of;
+
+const SOCKETS_MAX: usize = 8;
+// ソケットごとに貯めておけるデータグラムの数。溢れたら捨てる
+const QUEUE_LEN: usize = 4;
+// 貯めるデータグラムの最大長。再構築できる最大のデータグラムがそのまま収まる
+const DATAGRAM_MAX: usize = IP_PAYLOAD_MAX - size_of::<UdpHeader>();
+// bindせずに使ったソケットに割り当てるポートの範囲
+const EPHEMERAL_FIRST: u16 = 49152;
+const EPHEMERAL_LAST: u16 = 65535;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct UdpHeader {
+    src_port: u16,
+    dst_port: u16,
+    length: u16,
+    checksum: InternetChecksum,
+}
+
+#[derive(Copy, Clone)]
+struct Datagram {
+    len: usize,
+    data: [u8; DATAGRAM_MAX],
+}
+
+struct UdpSocket {
+    in_use: bool,
+    refs: usize,
+    /// `ANY`なら全てのアドレスで受け取る
+    local_addr: IpV4Addr,
+    /// 0ならまだポートを割り当てていない
+    local_port: u16,
+    /// connectした相手。そこからのデータグラムだけを受け取る
+    remote: Option<(IpV4Addr, u16)>,
+    options: IpOptions,
+    /// 届いたICMPのエラー。次のread・writeで返す
+    error: Option<IcmpError>,
+    /// 最後にデータグラムを送った時刻。エラーが届くまでの時間を測る
+    sent_at: u64,
+    /// `QUEUES`に貯めたデータグラムの先頭と数
+    head: usize,
+    len: usize,
+}
+
+impl UdpSocket {
+    const fn new() -> Self {
+        Self {
+            in_use: false,
+            refs: 0,
+            local_addr: IpV4Addr::ANY,
+            local_port: 0,
+            remote: None,
+            options: IpOptions::DEFAULT,
+            error: None,
+            sent_at: 0,
+            head: 0,
+            len: 0,
+        }
+    }
+}
+
+static mut SOCKETS: [UdpSocket; SOCKETS_MAX] = [const { UdpSocket::new() }; SOCKETS_MAX];
+// ソケットごとの受信キュー。大きいので、ソケットと分けてゼロで初期化する領域に置く
+static mut QUEUES: [[Datagram; QUEUE_LEN]; SOCKETS_MAX] = [[Datagram {
+    len: 0,
+    data: [0; DATAGRAM_MAX],
+}; QUEUE_LEN]; SOCKETS_MAX];
+static mut NEXT_PORT: u16 = EPHEMERAL_FIRST;
+// 送るデータグラムを組み立てるバッファ
+static mut TX_BUF: [u8; IP_PAYLOAD_MAX] = [0; IP_PAYLOAD_MAX];
+
+fn sockets() -> &'static mut [UdpSocket; SOCKETS_MAX] {
+    unsafe { &mut *core::ptr::addr_of_mut!(SOCKETS) }
+}
+
+fn socket(id: usize) -> &'static mut UdpSocket {
+    &mut sockets()[id]
+}
+
+fn queue(id: usize) -> &'static mut [Datagram; QUEUE_LEN] {
+    unsafe { &mut (*core::ptr::addr_of_mut!(QUEUES))[id] }
+}
+
+/// 新しいソケットを確保する。参照を1つ持った状態で返す
+pub fn udp_alloc() -> Option<usize> {
+    let (id, socket) = sockets().iter_mut().enumerate().find(|(_, s)| !s.in_use)?;
+    *socket = UdpSocket::new();
+    socket.in_use = true;
+    socket.refs = 1;
+    Some(id)
+}
+
+pub fn udp_dup(id: usize) {
+    socket(id).refs += 1;
+}
+
+pub fn udp_close(id: usize) {
+    let socket = socket(id);
+    socket.refs -= 1;
+    if socket.refs == 0 {
+        socket.in_use = false;
+    }
+}
+
+/// `addr`・`port`で受け取るソケットが既にあるか
+fn port_in_use(addr: IpV4Addr, port: u16) -> bool {
+    sockets().iter().any(|s| {
+        s.in_use
+            && s.local_port == port
+            && (s.local_addr == IpV4Addr::ANY || addr == IpV4Addr::ANY || s.local_addr == addr)
+    })
+}
+
+fn ephemeral_port() -> Result<u16, i32> {
+    for _ in EPHEMERAL_FIRST..=EPHEMERAL_LAST {
+        let port = unsafe {
+            let port = NEXT_PORT;
+            NEXT_PORT = if port == EPHEMERAL_LAST {
+                EPHEMERAL_FIRST
+            } else {
+                port + 1
+            };
+            port
+        };
+        if !port_in_use(IpV4Addr::ANY, port) {
+            return Ok(port);
+        }
+    }
+    Err(EADDRINUSE)
+}
+
+/// 受け取るアドレスとポートを決める。ポートが0なら空いているものを割り当てる
+pub fn udp_bind(id: usize, addr: IpV4Addr, port: u16) -> Result<(), i32> {
+    if socket(id).local_port != 0 {
+        return Err(EINVAL);
+    }
+    if addr != IpV4Addr::ANY && !is_local(addr) {
+        return Err(EADDRNOTAVAIL);
+    }
+    let port = match port {
+        0 => ephemeral_port()?,
+        port if port_in_use(addr, port) => return Err(EADDRINUSE),
+        port => port,
+    };
+
+    let socket = socket(id);
+    socket.local_addr = addr;
+    socket.local_port = port;
+    Ok(())
+}
+
+/// 送る相手を決める。まだbindしていなければポートを割り当てる
+pub fn udp_connect(id: usize, addr: IpV4Addr, port: u16) -> Result<(), i32> {
+    if port == 0 {
+        return Err(EINVAL);
+    }
+    if socket(id).local_port == 0 {
+        udp_bind(id, IpV4Addr::ANY, 0)?;
+    }
+    // 前の相手へのデータグラムに届いたエラーは捨てる
+    socket(id).error = None;
+    socket(id).remote = Some((addr, port));
+    Ok(())
+}
+
+pub fn udp_setsockopt(id: usize, option: u32, value: u32) -> Result<(), i32> {
+    let options = &mut socket(id).options;
+    match option {
+        IP_TTL => {
+            options.ttl = u8::try_from(value)
+                .ok()
+                .filter(|ttl| *ttl > 0)
+                .ok_or(EINVAL)?
+        }
+        IP_TOS => options.tos = u8::try_from(value).map_err(|_| EINVAL)?,
+        IP_MTU_DISCOVER => {
+            options.dont_fragment = match value {
+                IP_PMTUDISC_DONT => false,
+                IP_PMTUDISC_DO => true,
+                _ => return Err(EINVAL),
+            }
+        }
+        _ => return Err(ENOPROTOOPT),
+    }
+    Ok(())
+}
+
+/// 疑似ヘッダを含めたチェックサム
+fn checksum(src: IpV4Addr, dst: IpV4Addr, segment: &[u8]) -> InternetChecksum {
+    let mut pseudo = [0u8; 12];
+    pseudo[0..4].copy_from_slice(&src.bytes());
+    pseudo[4..8].copy_from_slice(&dst.bytes());
+    pseudo[9] = IpV4Protocol::udp().number();
+    pseudo[10..12].copy_from_slice(&(segment.len() as u16).to_be_bytes());
+    InternetChecksumGenerator::new()
+        .feed(&pseudo)
+        .feed(segment)
+        .checksum()
+}
+
+/// 1つのデータグラムが届くまで他のプロセスに譲る。`buf`に収まらない分は捨てる。
+/// 先にICMPのエラーが届いていればそれを返す
+pub fn udp_read(id: usize, buf: &mut [u8]) -> Result<usize, i32> {
+    loop {
+        let socket = socket(id);
+        if let Some(error) = socket.error.take() {
+            return Err(error.errno());
+        }
+        if socket.len > 0 {
+            let datagram = &queue(id)[socket.head];
+            let len = datagram.len.min(buf.len());
+            buf[..len].copy_from_slice(&datagram.data[..len]);
+            socket.head = (socket.head + 1) % QUEUE_LEN;
+            socket.len -= 1;
+            return Ok(len);
+        }
+
+        // ループバックに自分で送ったものは、ここで処理しないと届かない
+        process_packets();
+        if socket.len == 0 && socket.error.is_none() {
+            unsafe { PM.yield_() };
+        }
+    }
+}
+
+/// `buf`を1つのデータグラムとしてconnectした相手に送る
+pub fn udp_write(id: usize, buf: &[u8]) -> Result<usize, i32> {
+    let socket = socket(id);
+    if let Some(error) = socket.error.take() {
+        return Err(error.errno());
+    }
+    let (dst, dst_port) = socket.remote.ok_or(EDESTADDRREQ)?;
+    let len = size_of::<UdpHeader>() + buf.len();
+    if len > IP_PAYLOAD_MAX {
+        return Err(EMSGSIZE);
+    }
+    // bindしたアドレスが無ければ、送り出すインターフェースのアドレスを使う
+    let src = match socket.local_addr {
+        IpV4Addr::ANY => source_for(dst).ok_or(ENETUNREACH)?,
+        addr => addr,
+    };
+
+    let packet = unsafe { &mut *core::ptr::addr_of_mut!(TX_BUF) };
+    let packet = &mut packet[..len];
+    let mut header = UdpHeader {
+        src_port: socket.local_port.to_be(),
+        dst_port: dst_port.to_be(),
+        length: (len as u16).to_be(),
+        checksum: InternetChecksum::default(),
+    };
+    write_header(packet, &header);
+    packet[size_of::<UdpHeader>()..].copy_from_slice(buf);
+    header.checksum = checksum(src, dst, packet);
+    // 0は「チェックサムなし」なので、全て1にする
+    if header.checksum == InternetChecksum::default() {
+        header.checksum = InternetChecksum::calc(&[0, 0]);
+    }
+    write_header(packet, &header);
+
+    socket.sent_at = now();
+    ip_send_with(Some(src), dst, IpV4Protocol::udp(), packet, &socket.options)
+        .map_err(|e| e.errno())?;
+    Ok(buf.len())
+}
+
+/// 送ったデータグラムへのICMPのエラーが届くまで、最大`timeout_ms`ミリ秒待つ。
+/// tracerouteのように、TTLを絞って送った応答を受け取るのに使う
+pub fn udp_recverr(id: usize, timeout_ms: u32) -> Result<SockError, i32> {
+    let deadline = now() + timeout_ms as u64 * timebase_freq() / 1000;
+    loop {
+        let socket = socket(id);
+        if let Some(error) = socket.error.take() {
+            let rtt = error.received.saturating_sub(socket.sent_at);
+            return Ok(SockError {
+                origin: error.origin.to_be_u32(),
+                icmp_type: error.icmp_type.number(),
+                code: error.code,
+                rtt_us: (rtt * 1_000_000 / timebase_freq()) as u32,
+            });
+        }
+        if now() >= deadline {
+            return Err(ETIMEDOUT);
+        }
+
+        process_packets();
+        if socket.error.is_none() {
+            unsafe { PM.yield_() };
+        }
+    }
+}
+
+fn write_header(packet: &mut [u8], header: &UdpHeader) {
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            header as *const _ as *const u8,
+            packet.as_mut_ptr(),
+            size_of::<UdpHeader>(),
+        );
+    }
+}
+
+/// 届いたデータグラムを捨てた理由
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UdpError {
+    /// 長さかチェックサムが不正
+    Malformed,
+    /// 宛先のポートで受け取るソケットがない
+    NoSocket,
+    QueueFull,
+}
+
+/// 届いたデータグラムを宛先のポートのソケットのキューに入れる
+pub fn handle_udp_packet(src: IpV4Addr, dst: IpV4Addr, data: &[u8]) -> Result<(), UdpError> {
+    if data.len() < size_of::<UdpHeader>() {
+        return Err(UdpError::Malformed);
+    }
+    let header = unsafe { core::ptr::read_unaligned(data.as_ptr() as *const UdpHeader) };
+    let len = u16::from_be(header.length) as usize;
+    if len < size_of::<UdpHeader>() || len > data.len() {
+        return Err(UdpError::Malformed);
+    }
+    let data = &data[..len];
+    if header.checksum != InternetChecksum::default()
+        && checksum(src, dst, data) != InternetChecksum::default()
+    {
+        return Err(UdpError::Malformed);
+    }
+
+    let src_port = u16::from_be(header.src_port);
+    let dst_port = u16::from_be(header.dst_port);
+    let id = sockets()
+        .iter()
+        .position(|s| {
+            s.in_use
+                && s.local_port == dst_port
+                && (s.local_addr == IpV4Addr::ANY || s.local_addr == dst)
+                && s.remote.is_none_or(|remote| remote == (src, src_port))
+        })
+        .ok_or(UdpError::NoSocket)?;
+    let socket = socket(id);
+    if socket.len == QUEUE_LEN {
+        return Err(UdpError::QueueFull);
+    }
+
+    let payload = &data[size_of::<UdpHeader>()..];
+    let datagram = &mut queue(id)[(socket.head + socket.len) % QUEUE_LEN];
+    datagram.len = payload.len();
+    datagram.data[..datagram.len].copy_from_slice(payload);
+    socket.len += 1;
+    Ok(())
+}
+
+/// 自分が`src`から`dst`に送ったデータグラム (`quoted`はUDPヘッダから) へのICMPのエラーを、
+/// connectしたソケットに知らせる
+pub fn udp_handle_error(error: &IcmpError, src: IpV4Addr, dst: IpV4Addr, quoted: &[u8]) {
+    if quoted.len() < 4 {
+        return;
+    }
+    let src_port = u16::from_be_bytes([quoted[0], quoted[1]]);
+    let dst_port = u16::from_be_bytes([quoted[2], quoted[3]]);
+    if let Some(socket) = sockets().iter_mut().find(|s| {
+        s.in_use
+            && s.local_port == src_port
+            && (s.local_addr == IpV4Addr::ANY || s.local_addr == src)
+            && s.remote == Some((dst, dst_port))
+    }) {
+        socket.error = Some(*error);
+    }
+}
@@ -0,0 +1,769 @@
+// 9P2000.Lのクライアント: virtio-9pで共有されたホストのディレクトリを読み書きする。
+// パスごとにルートのfidからwalkして操作し、ファイルディスクリプタから使うファイルだけ
+// fidを`NODES`に残しておく。データはホストに直接書くので書き戻すものはない
+
+use common::{
+    Stat, DT_BLK, DT_CHR, DT_DIR, DT_LNK, EACCES, EBUSY, EINVAL, EIO, EISDIR, EMFILE, ENODEV,
+    ENOENT, EROFS,
+};
+
+use crate::{
+    path::PathBuf,
+    println,
+    vfs::{DirEntry, FileKind, FileSystem, Node},
+    virtio::p9::{Virtio9p, P9_MSG_SIZE},
+};
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+const VERSION: &str = "9P2000.L";
+const NOTAG: u16 = !0;
+const NOFID: u32 = !0;
+const ROOT_FID: u32 = 0;
+// size[4] type[1] tag[2]
+const HEADER_SIZE: usize = 7;
+// Tread・Twriteでデータの前に付くフィールドの大きさ
+const RREAD_OVERHEAD: usize = HEADER_SIZE + 4;
+const TWRITE_OVERHEAD: usize = HEADER_SIZE + 4 + 8 + 4;
+// 1回のTwalkで辿れる要素の数
+const MAXWELEM: usize = 16;
+const GETATTR_BASIC: u64 = 0x7ff;
+const SETATTR_SIZE: u32 = 0x8;
+const AT_REMOVEDIR: u32 = 0x200;
+// Tlopen・Tlcreateに渡すLinuxのopenフラグ
+const L_O_RDONLY: u32 = 0;
+const L_O_RDWR: u32 = 2;
+const L_O_CREAT: u32 = 0o100;
+const L_O_EXCL: u32 = 0o200;
+const L_O_DIRECTORY: u32 = 0o200000;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+// fidを残しておくファイルの数
+const NODES_MAX: usize = 32;
+// 続きから読めるように開いておくディレクトリの数
+const DIRS_MAX: usize = 4;
+
+static mut DEVICE: Option<Virtio9p> = None;
+// Tversionで決めたメッセージの最大の大きさ
+static mut MSIZE: usize = P9_MSG_SIZE;
+static mut NEXT_FID: u32 = ROOT_FID + 1;
+
+#[derive(Copy, Clone)]
+struct NodeEntry {
+    path: PathBuf,
+    /// 開かれたまま削除されたファイル。同じパスを探しても見つからない
+    stale: bool,
+    fid: u32,
+    /// Tlopenした後なら、書き込めるように開けたか
+    opened: Option<bool>,
+    refs: usize,
+}
+
+static mut NODES: [Option<NodeEntry>; NODES_MAX] = [None; NODES_MAX];
+
+/// 読み進めているディレクトリ。次の要素の番号と、それを読むための9Pのオフセットを覚えておく
+#[derive(Copy, Clone)]
+struct DirCursor {
+    path: PathBuf,
+    fid: u32,
+    index: usize,
+    offset: u64,
+}
+
+static mut DIRS: [Option<DirCursor>; DIRS_MAX] = [None; DIRS_MAX];
+// 空きがないときに次に追い出すカーソル
+static mut NEXT_DIR: usize = 0;
+
+fn nodes() -> &'static mut [Option<NodeEntry>; NODES_MAX] {
+    unsafe { &mut *core::ptr::addr_of_mut!(NODES) }
+}
+
+fn dirs() -> &'static mut [Option<DirCursor>; DIRS_MAX] {
+    unsafe { &mut *core::ptr::addr_of_mut!(DIRS) }
+}
+
+fn node(node: Node) -> &'static mut NodeEntry {
+    nodes()[node].as_mut().unwrap()
+}
+
+fn device() -> Result<&'static mut Virtio9p, i32> {
+    unsafe { (*core::ptr::addr_of_mut!(DEVICE)).as_mut() }.ok_or(ENODEV)
+}
+
+fn msize() -> usize {
+    unsafe { MSIZE }
+}
+
+fn alloc_fid() -> u32 {
+    unsafe {
+        let fid = NEXT_FID;
+        NEXT_FID = if NEXT_FID == NOFID - 1 {
+            ROOT_FID + 1
+        } else {
+            NEXT_FID + 1
+        };
+        fid
+    }
+}
+
+/// メッセージの本体を組み立てる。入り切らなければ`overflow`を立てる
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    overflow: bool,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        if self.len + bytes.len() > self.buf.len() {
+            self.overflow = true;
+            return;
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// 長さ (u16) の後に文字列が続く
+    fn str(&mut self, s: &str) {
+        self.u16(s.len() as u16);
+        self.bytes(s.as_bytes());
+    }
+}
+
+/// 受け取ったメッセージの本体を先頭から読む
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], i32> {
+        let bytes = self.buf.get(self.pos..self.pos + len).ok_or(EIO)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, i32> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, i32> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, i32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, i32> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a [u8], i32> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    /// qid (type[1] version[4] path[8]) を読み飛ばす
+    fn qid(&mut self) -> Result<(), i32> {
+        self.bytes(13).map(|_| ())
+    }
+}
+
+/// `ty`のメッセージを送り、応答の本体を返す。Rlerrorならそのエラー番号を返す。
+/// 応答は次のメッセージを送るまでしか読めない
+fn call(ty: u8, build: impl FnOnce(&mut Writer)) -> Result<Reader<'static>, i32> {
+    let dev = device()?;
+    let mut w = Writer {
+        buf: &mut dev.tx[..msize()],
+        len: HEADER_SIZE,
+        overflow: false,
+    };
+    build(&mut w);
+    if w.overflow {
+        return Err(EINVAL);
+    }
+
+    let len = w.len;
+    let tag = if ty == TVERSION { NOTAG } else { 0 };
+    dev.tx[..4].copy_from_slice(&(len as u32).to_le_bytes());
+    dev.tx[4] = ty;
+    dev.tx[5..7].copy_from_slice(&tag.to_le_bytes());
+    let rx_len = dev.transact(len)?;
+
+    let size = (u32::from_le_bytes(dev.rx[..4].try_into().unwrap()) as usize).min(rx_len);
+    if size < HEADER_SIZE {
+        return Err(EIO);
+    }
+    let buf = unsafe { core::slice::from_raw_parts(dev.rx.as_ptr(), size) };
+    let mut r = Reader {
+        buf,
+        pos: HEADER_SIZE,
+    };
+    match buf[4] {
+        RLERROR => Err(r.u32()? as i32),
+        t if t == ty + 1 => Ok(r),
+        _ => Err(EIO),
+    }
+}
+
+fn clunk(fid: u32) {
+    let _ = call(TCLUNK, |w| w.u32(fid));
+}
+
+/// ルートから`path`まで辿った新しいfidを返す
+fn walk(path: &PathBuf) -> Result<u32, i32> {
+    let fid = alloc_fid();
+    let mut names = path.relative().split('/').filter(|name| !name.is_empty());
+    let mut from = ROOT_FID;
+    loop {
+        let mut chunk = [""; MAXWELEM];
+        let mut n = 0;
+        for name in names.by_ref().take(MAXWELEM) {
+            chunk[n] = name;
+            n += 1;
+        }
+
+        let result = call(TWALK, |w| {
+            w.u32(from);
+            w.u32(fid);
+            w.u16(n as u16);
+            for name in &chunk[..n] {
+                w.str(name);
+            }
+        })
+        .and_then(|mut r| r.u16())
+        .and_then(|nwqid| {
+            if nwqid as usize == n {
+                Ok(())
+            } else {
+                Err(ENOENT)
+            }
+        });
+        if let Err(e) = result {
+            // 途中までしか辿れなければ新しいfidは作られない。2回目以降は作成済みなので解放する
+            if from == fid {
+                clunk(fid);
+            }
+            return Err(e);
+        }
+
+        from = fid;
+        if n < MAXWELEM {
+            return Ok(fid);
+        }
+    }
+}
+
+/// `path`まで辿ったfidで`f`を呼び、fidを解放する
+fn with_walk<T>(path: &PathBuf, f: impl FnOnce(u32) -> Result<T, i32>) -> Result<T, i32> {
+    let fid = walk(path)?;
+    let result = f(fid);
+    clunk(fid);
+    result
+}
+
+struct Attr {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u64,
+    size: u64,
+    mtime: u64,
+}
+
+impl Attr {
+    fn kind(&self) -> FileKind {
+        match self.mode & S_IFMT {
+            S_IFDIR => FileKind::Directory,
+            S_IFLNK => FileKind::Symlink,
+            S_IFCHR => FileKind::CharDevice,
+            S_IFBLK => FileKind::BlockDevice,
+            _ => FileKind::Regular,
+        }
+    }
+}
+
+fn getattr(fid: u32) -> Result<Attr, i32> {
+    let mut r = call(TGETATTR, |w| {
+        w.u32(fid);
+        w.u64(GETATTR_BASIC);
+    })?;
+    r.u64()?; // valid
+    r.qid()?;
+    let mode = r.u32()?;
+    let uid = r.u32()?;
+    let gid = r.u32()?;
+    let nlink = r.u64()?;
+    r.u64()?; // rdev
+    let size = r.u64()?;
+    r.u64()?; // blksize
+    r.u64()?; // blocks
+    r.u64()?; // atime_sec
+    r.u64()?; // atime_nsec
+    let mtime = r.u64()?;
+    Ok(Attr {
+        mode,
+        uid,
+        gid,
+        nlink,
+        size,
+        mtime,
+    })
+}
+
+fn lopen(fid: u32, flags: u32) -> Result<(), i32> {
+    call(TLOPEN, |w| {
+        w.u32(fid);
+        w.u32(flags);
+    })
+    .map(|_| ())
+}
+
+/// 開いたディレクトリのfidから次の要素を読み、カーソルを進める。終わりならNoneを返す
+fn next_entry(dir: &mut DirCursor) -> Result<Option<(&'static [u8], u8)>, i32> {
+    let count = (msize() - RREAD_OVERHEAD) as u32;
+    let mut r = call(TREADDIR, |w| {
+        w.u32(dir.fid);
+        w.u64(dir.offset);
+        w.u32(count);
+    })?;
+    if r.u32()? == 0 {
+        return Ok(None);
+    }
+
+    // 先頭の要素だけを使い、残りは次の呼び出しで読み直す
+    r.qid()?;
+    dir.offset = r.u64()?;
+    let ty = r.u8()?;
+    let name = r.str()?;
+    dir.index += 1;
+    Ok(Some((name, ty)))
+}
+
+/// `dir`の`index`番目の要素 (`.`と`..`を含む) を読むカーソルの位置を返す。
+/// 続きを読んでいるものがなければ開き直し、先頭から読み飛ばす
+fn dir_cursor(dir: &PathBuf, index: usize) -> Result<usize, i32> {
+    let dirs = dirs();
+    if let Some(i) = dirs
+        .iter()
+        .position(|d| d.is_some_and(|d| d.path == *dir && d.index == index))
+    {
+        return Ok(i);
+    }
+
+    let fid = walk(dir)?;
+    if let Err(e) = lopen(fid, L_O_RDONLY | L_O_DIRECTORY) {
+        clunk(fid);
+        return Err(e);
+    }
+    // 空きがなければ順番に追い出す
+    let i = match dirs.iter().position(|d| d.is_none()) {
+        Some(i) => i,
+        None => unsafe {
+            let i = NEXT_DIR;
+            NEXT_DIR = (NEXT_DIR + 1) % DIRS_MAX;
+            close_dir(i);
+            i
+        },
+    };
+    let cursor = dirs[i].insert(DirCursor {
+        path: *dir,
+        fid,
+        index: 0,
+        offset: 0,
+    });
+    while cursor.index < index {
+        match next_entry(cursor) {
+            Ok(Some(_)) => {}
+            Ok(None) => break,
+            Err(e) => {
+                close_dir(i);
+                return Err(e);
+            }
+        }
+    }
+    Ok(i)
+}
+
+/// ディレクトリのカーソルを捨て、fidを解放する
+fn close_dir(i: usize) {
+    if let Some(dir) = dirs()[i].take() {
+        clunk(dir.fid);
+    }
+}
+
+/// パスが変わったファイルを探し直されないようにする。開かれていないものはfidを解放する
+fn forget(path: &PathBuf) {
+    for i in 0..DIRS_MAX {
+        if dirs()[i].is_some_and(|d| d.path == *path) {
+            close_dir(i);
+        }
+    }
+    for slot in nodes().iter_mut() {
+        let Some(entry) = slot.as_mut().filter(|e| !e.stale && e.path == *path) else {
+            continue;
+        };
+        if entry.refs == 0 {
+            clunk(entry.fid);
+            *slot = None;
+        } else {
+            entry.stale = true;
+        }
+    }
+}
+
+/// 読み書きに使うfidを返す。書き込めなければ読み込み専用で開く
+fn io_fid(node: Node, write: bool) -> Result<u32, i32> {
+    let entry = self::node(node);
+    let writable = match entry.opened {
+        Some(writable) => writable,
+        None => {
+            let writable = match lopen(entry.fid, L_O_RDWR) {
+                Ok(()) => true,
+                Err(EACCES) | Err(EROFS) => {
+                    lopen(entry.fid, L_O_RDONLY)?;
+                    false
+                }
+                Err(e) => return Err(e),
+            };
+            entry.opened = Some(writable);
+            writable
+        }
+    };
+    if write && !writable {
+        return Err(EACCES);
+    }
+    Ok(entry.fid)
+}
+
+/// バージョンを決めてホストのディレクトリにattachする。成功すればマウントできる
+pub fn p9fs_init(device: Option<Virtio9p>) -> bool {
+    let Some(device) = device else {
+        return false;
+    };
+    unsafe { DEVICE = Some(device) };
+
+    let result = call(TVERSION, |w| {
+        w.u32(P9_MSG_SIZE as u32);
+        w.str(VERSION);
+    })
+    .and_then(|mut r| {
+        let msize = r.u32()? as usize;
+        if r.str()? != VERSION.as_bytes() || msize <= TWRITE_OVERHEAD {
+            return Err(EIO);
+        }
+        unsafe { MSIZE = msize.min(P9_MSG_SIZE) };
+        Ok(())
+    })
+    .and_then(|()| {
+        call(TATTACH, |w| {
+            w.u32(ROOT_FID);
+            w.u32(NOFID);
+            w.str("root");
+            w.str("");
+            w.u32(0);
+        })
+        .map(|_| ())
+    });
+    if let Err(e) = result {
+        println!("9p: failed to attach: {}", e);
+        unsafe { DEVICE = None };
+        return false;
+    }
+    true
+}
+
+/// virtio-9pの割り込みなら確認済みにする。応答は`Virtio9p::transact`が待つ
+pub fn p9fs_handle_interrupt(irq: u32) {
+    if let Ok(device) = device() {
+        if device.irq() == irq {
+            device.ack_interrupt();
+        }
+    }
+}
+
+/// VFSから見た9P。`Node`は`NODES`の添字
+pub struct P9Fs;
+
+pub static P9FS: P9Fs = P9Fs;
+
+impl FileSystem for P9Fs {
+    fn name(&self) -> &'static str {
+        "9p"
+    }
+
+    fn kind(&self, path: &PathBuf) -> Result<FileKind, i32> {
+        with_walk(path, |fid| Ok(getattr(fid)?.kind()))
+    }
+
+    fn stat(&self, path: &PathBuf) -> Result<Stat, i32> {
+        let attr = with_walk(path, getattr)?;
+        Ok(Stat {
+            kind: attr.kind().dirent_type(),
+            mode: attr.mode & 0o7777,
+            uid: attr.uid,
+            gid: attr.gid,
+            nlink: attr.nlink as u32,
+            size: attr.size,
+            mtime: attr.mtime,
+        })
+    }
+
+    fn readdir(&self, dir: &PathBuf, cursor: &mut usize) -> Option<DirEntry> {
+        // ホストのディレクトリのオフセットは大きな値になりうるので、カーソルは要素の番号にする。
+        // 9Pのオフセットはfidと一緒に覚えておき、次の呼び出しで続きから読む
+        let i = dir_cursor(dir, *cursor).ok()?;
+        let dir = dirs()[i].as_mut().unwrap();
+        loop {
+            let Ok(Some((name, ty))) = next_entry(dir) else {
+                close_dir(i);
+                return None;
+            };
+            if name == b"." || name == b".." {
+                continue;
+            }
+            let kind = match ty as u32 {
+                DT_DIR => FileKind::Directory,
+                DT_LNK => FileKind::Symlink,
+                DT_CHR => FileKind::CharDevice,
+                DT_BLK => FileKind::BlockDevice,
+                _ => FileKind::Regular,
+            };
+            *cursor = dir.index;
+            let name = core::str::from_utf8(name).unwrap_or("?");
+            return Some(DirEntry::new(name, kind));
+        }
+    }
+
+    fn readlink(&self, path: &PathBuf, buf: &mut [u8]) -> Result<usize, i32> {
+        with_walk(path, |fid| {
+            let mut r = call(TREADLINK, |w| w.u32(fid))?;
+            let target = r.str()?;
+            let len = target.len().min(buf.len());
+            buf[..len].copy_from_slice(&target[..len]);
+            Ok(len)
+        })
+    }
+
+    fn lookup(&self, path: &PathBuf) -> Result<Node, i32> {
+        if let Some(i) = nodes()
+            .iter()
+            .position(|e| e.is_some_and(|e| !e.stale && e.path == *path))
+        {
+            return Ok(i);
+        }
+
+        let fid = walk(path)?;
+        let result = getattr(fid).and_then(|attr| {
+            if attr.kind() == FileKind::Directory {
+                return Err(EISDIR);
+            }
+            // 空きがなければ開かれていないファイルのfidを解放して使う
+            let nodes = nodes();
+            let i = nodes
+                .iter()
+                .position(|e| e.is_none())
+                .or_else(|| nodes.iter().position(|e| e.is_some_and(|e| e.refs == 0)))
+                .ok_or(EMFILE)?;
+            if let Some(old) = nodes[i].take() {
+                clunk(old.fid);
+            }
+            nodes[i] = Some(NodeEntry {
+                path: *path,
+                stale: false,
+                fid,
+                opened: None,
+                refs: 0,
+            });
+            Ok(i)
+        });
+        if result.is_err() {
+            clunk(fid);
+        }
+        result
+    }
+
+    fn open(&self, node: Node) {
+        self::node(node).refs += 1;
+    }
+
+    fn close(&self, node: Node) {
+        let entry = self::node(node);
+        entry.refs -= 1;
+        if entry.refs == 0 && entry.stale {
+            clunk(entry.fid);
+            nodes()[node] = None;
+        }
+    }
+
+    fn read(&self, node: Node, offset: usize, buf: &mut [u8]) -> Result<usize, i32> {
+        let fid = io_fid(node, false)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let count = (buf.len() - done).min(msize() - RREAD_OVERHEAD);
+            let mut r = call(TREAD, |w| {
+                w.u32(fid);
+                w.u64((offset + done) as u64);
+                w.u32(count as u32);
+            })?;
+            let len = (r.u32()? as usize).min(count);
+            buf[done..done + len].copy_from_slice(r.bytes(len)?);
+            done += len;
+            if len < count {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn write(&self, node: Node, offset: usize, buf: &[u8]) -> Result<usize, i32> {
+        let fid = io_fid(node, true)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let count = (buf.len() - done).min(msize() - TWRITE_OVERHEAD);
+            let mut r = call(TWRITE, |w| {
+                w.u32(fid);
+                w.u64((offset + done) as u64);
+                w.u32(count as u32);
+                w.bytes(&buf[done..done + count]);
+            })?;
+            let len = (r.u32()? as usize).min(count);
+            done += len;
+            if len < count {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn size(&self, node: Node) -> usize {
+        getattr(self::node(node).fid).map_or(0, |attr| attr.size as usize)
+    }
+
+    fn truncate(&self, node: Node, len: usize) -> Result<(), i32> {
+        call(TSETATTR, |w| {
+            w.u32(self::node(node).fid);
+            w.u32(SETATTR_SIZE);
+            w.u32(0); // mode
+            w.u32(0); // uid
+            w.u32(0); // gid
+            w.u64(len as u64);
+            w.u64(0); // atime_sec
+            w.u64(0); // atime_nsec
+            w.u64(0); // mtime_sec
+            w.u64(0); // mtime_nsec
+        })
+        .map(|_| ())
+    }
+
+    fn create(&self, path: &PathBuf) -> Result<(), i32> {
+        forget(path);
+        // Tlcreateの後、fidは作ったファイルを指す
+        with_walk(&path.parent(), |fid| {
+            call(TLCREATE, |w| {
+                w.u32(fid);
+                w.str(path.file_name());
+                w.u32(L_O_RDWR | L_O_CREAT | L_O_EXCL);
+                w.u32(0o644);
+                w.u32(0); // gid
+            })
+            .map(|_| ())
+        })
+    }
+
+    fn mkdir(&self, path: &PathBuf) -> Result<(), i32> {
+        with_walk(&path.parent(), |fid| {
+            call(TMKDIR, |w| {
+                w.u32(fid);
+                w.str(path.file_name());
+                w.u32(0o755);
+                w.u32(0); // gid
+            })
+            .map(|_| ())
+        })
+    }
+
+    fn unlink(&self, path: &PathBuf) -> Result<(), i32> {
+        with_walk(&path.parent(), |fid| {
+            call(TUNLINKAT, |w| {
+                w.u32(fid);
+                w.str(path.file_name());
+                w.u32(0);
+            })
+            .map(|_| ())
+        })?;
+        forget(path);
+        Ok(())
+    }
+
+    fn rmdir(&self, path: &PathBuf) -> Result<(), i32> {
+        if path.is_root() {
+            return Err(EBUSY);
+        }
+        with_walk(&path.parent(), |fid| {
+            call(TUNLINKAT, |w| {
+                w.u32(fid);
+                w.str(path.file_name());
+                w.u32(AT_REMOVEDIR);
+            })
+            .map(|_| ())
+        })
+    }
+
+    fn symlink(&self, target: &str, path: &PathBuf) -> Result<(), i32> {
+        with_walk(&path.parent(), |fid| {
+            call(TSYMLINK, |w| {
+                w.u32(fid);
+                w.str(path.file_name());
+                w.str(target);
+                w.u32(0); // gid
+            })
+            .map(|_| ())
+        })
+    }
+
+    fn link(&self, old: &PathBuf, path: &PathBuf) -> Result<(), i32> {
+        with_walk(old, |old_fid| {
+            with_walk(&path.parent(), |dfid| {
+                call(TLINK, |w| {
+                    w.u32(dfid);
+                    w.u32(old_fid);
+                    w.str(path.file_name());
+                })
+                .map(|_| ())
+            })
+        })
+    }
+}
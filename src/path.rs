@@ -0,0 +1,122 @@
+use common::{EINVAL, ENAMETOOLONG};
+
+// tarのprefix (155バイト) + name (100バイト) に収まる長さ
+pub const PATH_MAX: usize = 256;
+
+/// `/`で始まり、`.`・`..`・連続した`/`を含まない絶対パス
+#[derive(Copy, Clone)]
+pub struct PathBuf {
+    buf: [u8; PATH_MAX],
+    len: usize,
+}
+
+impl core::fmt::Debug for PathBuf {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl PartialEq for PathBuf {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl PathBuf {
+    pub const fn root() -> Self {
+        let mut buf = [0; PATH_MAX];
+        buf[0] = b'/';
+        Self { buf, len: 1 }
+    }
+
+    /// `base`を起点に`path`を解決する。`path`が`/`で始まる場合は`base`を無視する
+    pub fn resolve(base: &PathBuf, path: &str) -> Result<Self, i32> {
+        if path.is_empty() {
+            return Err(EINVAL);
+        }
+
+        let mut resolved = if path.starts_with('/') {
+            Self::root()
+        } else {
+            *base
+        };
+
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => resolved.pop(),
+                _ => resolved.push(component)?,
+            }
+        }
+        Ok(resolved)
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("/")
+    }
+
+    /// 先頭の`/`を除いたパス。ファイルシステム内部の名前として使う
+    pub fn relative(&self) -> &str {
+        &self.as_str()[1..]
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.len == 1
+    }
+
+    /// 最後の要素を除いたパス
+    pub fn parent(&self) -> Self {
+        let mut parent = *self;
+        parent.pop();
+        parent
+    }
+
+    /// 最後の要素。ルートの場合は空文字列
+    pub fn file_name(&self) -> &str {
+        let s = self.as_str();
+        &s[s.rfind('/').map_or(0, |i| i + 1)..]
+    }
+
+    /// `base`以下にあれば、`base`をルートとみなしたパスを返す
+    pub fn strip_prefix(&self, base: &PathBuf) -> Option<Self> {
+        if base.is_root() {
+            return Some(*self);
+        }
+
+        let rest = self.as_str().strip_prefix(base.as_str())?;
+        if rest.is_empty() {
+            Some(Self::root())
+        } else if rest.starts_with('/') {
+            Self::resolve(&Self::root(), rest).ok()
+        } else {
+            None
+        }
+    }
+
+    fn push(&mut self, component: &str) -> Result<(), i32> {
+        let sep = if self.is_root() { 0 } else { 1 };
+        if self.len + sep + component.len() > PATH_MAX {
+            return Err(ENAMETOOLONG);
+        }
+
+        if sep == 1 {
+            self.buf[self.len] = b'/';
+        }
+        self.buf[self.len + sep..self.len + sep + component.len()]
+            .copy_from_slice(component.as_bytes());
+        self.len += sep + component.len();
+        Ok(())
+    }
+
+    fn pop(&mut self) {
+        if self.is_root() {
+            return;
+        }
+
+        let s = self.as_str();
+        self.len = match s.rfind('/') {
+            Some(0) | None => 1,
+            Some(i) => i,
+        };
+    }
+}
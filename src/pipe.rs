@@ -0,0 +1,118 @@
+// パイプ: カーネル内のリングバッファ
+// 読み込み側・書き込み側の参照数を数え、全ての書き込み側が閉じたらEOF、
+// 全ての読み込み側が閉じたらEPIPEを返す
+
+use common::EPIPE;
+
+use crate::PM;
+
+const PIPES_MAX: usize = 8;
+const PIPE_SIZE: usize = 512;
+
+#[derive(Copy, Clone)]
+struct Pipe {
+    in_use: bool,
+    buf: [u8; PIPE_SIZE],
+    head: usize,
+    len: usize,
+    readers: usize,
+    writers: usize,
+}
+
+impl Pipe {
+    const fn new() -> Self {
+        Self {
+            in_use: false,
+            buf: [0; PIPE_SIZE],
+            head: 0,
+            len: 0,
+            readers: 0,
+            writers: 0,
+        }
+    }
+}
+
+static mut PIPES: [Pipe; PIPES_MAX] = [Pipe::new(); PIPES_MAX];
+
+fn pipe(id: usize) -> &'static mut Pipe {
+    unsafe { &mut (*core::ptr::addr_of_mut!(PIPES))[id] }
+}
+
+/// 新しいパイプを確保する。読み込み側・書き込み側の参照をそれぞれ1つ持った状態で返す
+pub fn pipe_alloc() -> Option<usize> {
+    let pipes = unsafe { &mut *core::ptr::addr_of_mut!(PIPES) };
+    let (id, pipe) = pipes.iter_mut().enumerate().find(|(_, p)| !p.in_use)?;
+    *pipe = Pipe::new();
+    pipe.in_use = true;
+    pipe.readers = 1;
+    pipe.writers = 1;
+    Some(id)
+}
+
+pub fn pipe_dup(id: usize, is_writer: bool) {
+    let pipe = pipe(id);
+    if is_writer {
+        pipe.writers += 1;
+    } else {
+        pipe.readers += 1;
+    }
+}
+
+pub fn pipe_close(id: usize, is_writer: bool) {
+    let pipe = pipe(id);
+    if is_writer {
+        pipe.writers -= 1;
+    } else {
+        pipe.readers -= 1;
+    }
+
+    if pipe.readers == 0 && pipe.writers == 0 {
+        pipe.in_use = false;
+    }
+}
+
+/// 1バイト以上読めるまでブロックする。書き込み側が全て閉じていれば0 (EOF) を返す
+pub fn pipe_read(id: usize, buf: &mut [u8]) -> Result<usize, i32> {
+    loop {
+        let pipe = pipe(id);
+        if pipe.len > 0 {
+            let n = core::cmp::min(buf.len(), pipe.len);
+            for b in buf.iter_mut().take(n) {
+                *b = pipe.buf[pipe.head];
+                pipe.head = (pipe.head + 1) % PIPE_SIZE;
+            }
+            pipe.len -= n;
+            return Ok(n);
+        }
+
+        if pipe.writers == 0 {
+            return Ok(0);
+        }
+
+        unsafe { PM.yield_() };
+    }
+}
+
+/// 全て書き込むまでブロックする。読み込み側が全て閉じていればEPIPEを返す
+pub fn pipe_write(id: usize, buf: &[u8]) -> Result<usize, i32> {
+    let mut written = 0;
+    while written < buf.len() {
+        let pipe = pipe(id);
+        if pipe.readers == 0 {
+            return if written > 0 { Ok(written) } else { Err(EPIPE) };
+        }
+
+        if pipe.len == PIPE_SIZE {
+            unsafe { PM.yield_() };
+            continue;
+        }
+
+        while written < buf.len() && pipe.len < PIPE_SIZE {
+            let tail = (pipe.head + pipe.len) % PIPE_SIZE;
+            pipe.buf[tail] = buf[written];
+            pipe.len += 1;
+            written += 1;
+        }
+    }
+    Ok(written)
+}
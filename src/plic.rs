@@ -0,0 +1,53 @@
+// PLIC: デバイスからの外部割り込みをhart 0のSモードに届ける
+
+use common::{read_csr, write_csr, PAGE_SIZE};
+use core::ptr::{read_volatile, write_volatile};
+
+// 各レジスタのベースアドレスからのオフセット。hart 0のSモードはコンテキスト1
+const PLIC_PRIORITY: usize = 0;
+const PLIC_SENABLE: usize = 0x2080;
+const PLIC_STHRESHOLD: usize = 0x201000;
+const PLIC_SCLAIM: usize = 0x201004;
+const SIE_SEIE: u32 = 1 << 9;
+
+// デバイスツリーで見つけたPLICのアドレス
+static mut PLIC_PADDR: usize = 0;
+
+/// カーネルが使うレジスタを含むページ。各プロセスのページテーブルにマップする
+pub fn plic_pages() -> [usize; 3] {
+    let base = unsafe { PLIC_PADDR };
+    [
+        base + PLIC_PRIORITY,
+        base + (PLIC_SENABLE & !(PAGE_SIZE - 1)),
+        base + PLIC_STHRESHOLD,
+    ]
+}
+
+fn reg_read(offset: usize) -> u32 {
+    unsafe { read_volatile((PLIC_PADDR + offset) as *const u32) }
+}
+
+fn reg_write(offset: usize, value: u32) {
+    unsafe { write_volatile((PLIC_PADDR + offset) as *mut u32, value) }
+}
+
+/// 外部割り込みを受け付ける。割り込みを使うデバイスは`plic_enable`で個別に有効にする
+pub fn plic_init(paddr: usize) {
+    unsafe { PLIC_PADDR = paddr };
+    reg_write(PLIC_STHRESHOLD, 0);
+    write_csr!("sie", read_csr!("sie") | SIE_SEIE);
+}
+
+pub fn plic_enable(irq: u32) {
+    reg_write(PLIC_PRIORITY + irq as usize * 4, 1);
+    reg_write(PLIC_SENABLE, reg_read(PLIC_SENABLE) | (1 << irq));
+}
+
+/// 保留中の割り込みの番号を取得する。なければ0
+pub fn plic_claim() -> u32 {
+    reg_read(PLIC_SCLAIM)
+}
+
+pub fn plic_complete(irq: u32) {
+    reg_write(PLIC_SCLAIM, irq);
+}
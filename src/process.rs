@@ -1,21 +1,77 @@
 use core::arch::{asm, naked_asm};
 use core::ptr;
 
-use common::{println, PAddr, VAddr, PAGE_SIZE, VIRTIO_BLK_PADDR};
+use common::{
+    align_up, println, PAddr, VAddr, ARGS_MAX, EBADF, ECHILD, EMFILE, PAGE_SIZE, USER_ARGS,
+};
 
-use crate::memory::{alloc_pages, map_page, PAGE_R, PAGE_U, PAGE_W, PAGE_X, SATP_SV32};
+use crate::fdt::boot_info;
+use crate::file::OpenFile;
+use crate::memory::{
+    alloc_pages, free_page, free_ram_end, map_page, PAGE_R, PAGE_U, PAGE_V, PAGE_W, PAGE_X,
+    SATP_SV32,
+};
+use crate::path::PathBuf;
+use crate::plic::plic_pages;
+use crate::vfs::vfs_writeback;
 
 extern "C" {
     static mut __kernel_base: u32;
-    static mut __free_ram_end: u32;
 }
 
 const PROCS_MAX: usize = 8;
+pub const FDS_MAX: usize = 8;
 const SSTATUS_SPIE: u32 = 1 << 5;
 const SSTATUS_SUM: u32 = 1 << 18;
 const SSTATUS: u32 = SSTATUS_SPIE | SSTATUS_SUM;
 const USER_BASE: usize = 0x01000000;
 
+/// カーネルのメモリと、カーネルが操作するデバイスのレジスタを恒等写像する
+fn map_kernel(page_table: PAddr) {
+    let mut paddr = ptr::addr_of_mut!(__kernel_base) as usize;
+    while paddr < free_ram_end() {
+        map_page(
+            page_table,
+            paddr as u32,
+            paddr as u32,
+            PAGE_R | PAGE_W | PAGE_X,
+        );
+        paddr += PAGE_SIZE;
+    }
+
+    for device in boot_info().virtio_mmio() {
+        let size = align_up(device.size, PAGE_SIZE);
+        for off in (0..size).step_by(PAGE_SIZE) {
+            let paddr = (device.base & !(PAGE_SIZE - 1)) + off;
+            map_page(page_table, paddr as u32, paddr as u32, PAGE_R | PAGE_W);
+        }
+    }
+    for paddr in plic_pages() {
+        map_page(page_table, paddr as u32, paddr as u32, PAGE_R | PAGE_W);
+    }
+}
+
+/// ユーザー空間のページとページテーブルを解放する。恒等写像したカーネルのページはそのまま残す
+fn free_page_table(table1: PAddr) {
+    let table1 = table1 as *const u32;
+    for vpn1 in 0..PAGE_SIZE / 4 {
+        let pte1 = unsafe { *table1.add(vpn1) };
+        if pte1 & PAGE_V == 0 {
+            continue;
+        }
+
+        let table0 = (pte1 >> 10) * PAGE_SIZE as u32;
+        for vpn0 in 0..PAGE_SIZE / 4 {
+            let pte0 = unsafe { *(table0 as *const u32).add(vpn0) };
+            if pte0 & PAGE_V != 0 && pte0 & PAGE_U != 0 {
+                free_page((pte0 >> 10) * PAGE_SIZE as u32);
+            }
+        }
+        free_page(table0);
+    }
+    free_page(table1 as PAddr);
+}
+
 #[unsafe(naked)]
 extern "C" fn user_entry() {
     naked_asm!(
@@ -41,8 +97,11 @@ enum State {
 struct Process {
     pid: u32,
     state: State,
+    parent: Option<usize>,
     sp: VAddr,
     page_table: PAddr,
+    fds: [Option<OpenFile>; FDS_MAX],
+    cwd: PathBuf,
     stack: [u8; 8192],
 }
 
@@ -51,13 +110,43 @@ impl Process {
         Self {
             pid: 0,
             state: State::Unused,
+            parent: None,
             sp: 0,
             page_table: 0,
+            fds: [None; FDS_MAX],
+            cwd: PathBuf::root(),
             stack: [0; 8192],
         }
     }
 }
 
+/// procfsに見せるプロセスの情報
+pub struct ProcInfo {
+    pub pid: u32,
+    pub state: &'static str,
+    pub parent: Option<u32>,
+    pub cwd: PathBuf,
+    pub fds: usize,
+}
+
+/// 標準入力・標準出力・標準エラー出力をコンソールに繋いだディスクリプタテーブル
+pub fn console_fds() -> [Option<OpenFile>; FDS_MAX] {
+    let mut fds = [None; FDS_MAX];
+    fds[0] = Some(OpenFile::Console);
+    fds[1] = Some(OpenFile::Console);
+    fds[2] = Some(OpenFile::Console);
+    fds
+}
+
+/// 標準入力・標準出力をvirtio-consoleのポートに繋いだディスクリプタテーブル。
+/// カーネルのメッセージを見られるよう、標準エラー出力はコンソールのまま
+pub fn tty_fds(port: usize) -> [Option<OpenFile>; FDS_MAX] {
+    let mut fds = console_fds();
+    fds[0] = Some(OpenFile::Tty(port));
+    fds[1] = Some(OpenFile::Tty(port));
+    fds
+}
+
 pub struct ProcessManager {
     procs: [Process; PROCS_MAX],
     pub current: usize,
@@ -94,16 +183,7 @@ impl ProcessManager {
             *sp.offset(-13) = 0; // ra
                                  //
             let page_table = alloc_pages(1);
-            let mut paddr = ptr::addr_of_mut!(__kernel_base) as *mut u8;
-            while paddr < ptr::addr_of_mut!(__free_ram_end) as *mut u8 {
-                map_page(
-                    page_table,
-                    paddr as u32,
-                    paddr as u32,
-                    PAGE_R | PAGE_W | PAGE_X,
-                );
-                paddr = paddr.add(PAGE_SIZE);
-            }
+            map_kernel(page_table);
 
             proc.pid = u32::MAX;
             proc.state = State::Idle;
@@ -112,7 +192,21 @@ impl ProcessManager {
         }
     }
 
-    pub fn create(&mut self, image: *const u32, image_size: usize) {
+    /// `args`はユーザー空間の`USER_ARGS`に配置され、`fds`はそのまま新しいプロセスのものになる
+    pub fn create(
+        &mut self,
+        image: *const u32,
+        image_size: usize,
+        args: &[u8],
+        fds: [Option<OpenFile>; FDS_MAX],
+    ) -> Result<u32, ()> {
+        // 作業ディレクトリは親から引き継ぐ
+        let cwd = if self.current == 0 {
+            PathBuf::root()
+        } else {
+            self.procs[self.current].cwd
+        };
+
         unsafe {
             if let Some((i, proc)) = self
                 .procs
@@ -136,24 +230,13 @@ impl ProcessManager {
                 *sp.offset(-12) = 0; // s0
                 *sp.offset(-13) = user_entry as usize as u32; // ra
 
-                let page_table = alloc_pages(1);
-                let mut paddr = ptr::addr_of_mut!(__kernel_base) as *mut u8;
-                while paddr < ptr::addr_of_mut!(__free_ram_end) as *mut u8 {
-                    map_page(
-                        page_table,
-                        paddr as u32,
-                        paddr as u32,
-                        PAGE_R | PAGE_W | PAGE_X,
-                    );
-                    paddr = paddr.add(PAGE_SIZE);
+                // 終了したプロセスのページテーブルは切り替わるまで使われているので、
+                // スロットを再利用する時に解放する
+                if proc.page_table != 0 {
+                    free_page_table(proc.page_table);
                 }
-
-                map_page(
-                    page_table,
-                    VIRTIO_BLK_PADDR as u32,
-                    VIRTIO_BLK_PADDR as u32,
-                    PAGE_R | PAGE_W,
-                );
+                let page_table = alloc_pages(1);
+                map_kernel(page_table);
 
                 let mut off = 0;
                 let pimage = image as *const u8;
@@ -174,17 +257,41 @@ impl ProcessManager {
                     off += PAGE_SIZE;
                 }
 
+                let args_page = alloc_pages(1) as *mut u8;
+                let args_len = core::cmp::min(args.len(), ARGS_MAX - 1);
+                ptr::copy_nonoverlapping(args.as_ptr(), args_page, args_len);
+                map_page(
+                    page_table,
+                    USER_ARGS as u32,
+                    args_page as u32,
+                    PAGE_U | PAGE_R,
+                );
+
                 proc.pid = i as u32;
                 proc.state = State::Runnable;
+                proc.parent = if self.current == 0 {
+                    None
+                } else {
+                    Some(self.current)
+                };
                 proc.sp = sp.offset(-13) as VAddr;
                 proc.page_table = page_table;
+                proc.fds = fds;
+                proc.cwd = cwd;
+                Ok(i as u32)
             } else {
-                panic!("no free process slots");
+                for file in fds.iter().flatten() {
+                    file.close();
+                }
+                Err(())
             }
         }
     }
 
     pub fn yield_(&mut self) {
+        // タイマー割り込みがないので、スケジューラが呼ばれるたびに書き戻しの時期かを確認する
+        vfs_writeback();
+
         let mut next: usize = 0;
         for i in 0..PROCS_MAX {
             let idx = (self.current + i + 1) % PROCS_MAX;
@@ -220,9 +327,140 @@ impl ProcessManager {
 
     pub fn exit(&mut self) {
         println!("process {} exited", self.current);
-        self.procs[self.current].state = State::Exited;
+        let current = self.current;
+        for fd in self.procs[current].fds.iter_mut() {
+            if let Some(file) = fd.take() {
+                file.close();
+            }
+        }
+
+        // 子プロセスは親を失う。終了済みのものはここで回収する
+        for proc in self.procs.iter_mut() {
+            if proc.parent == Some(current) {
+                proc.parent = None;
+                if proc.state == State::Exited {
+                    proc.state = State::Unused;
+                }
+            }
+        }
+
+        // 待つ親がいなければスロットをすぐに解放する
+        self.procs[current].state = if self.procs[current].parent.is_some() {
+            State::Exited
+        } else {
+            State::Unused
+        };
         self.yield_();
     }
+
+    /// 子プロセスの終了を待って回収する。`pid`が`u32::MAX`なら任意の子プロセスを待つ
+    pub fn wait(&mut self, pid: u32) -> Result<u32, i32> {
+        loop {
+            let current = self.current;
+            let mut has_child = false;
+            for proc in self.procs.iter_mut() {
+                if proc.parent != Some(current) || proc.state == State::Unused {
+                    continue;
+                }
+                if pid != u32::MAX && proc.pid != pid {
+                    continue;
+                }
+
+                has_child = true;
+                if proc.state == State::Exited {
+                    proc.state = State::Unused;
+                    proc.parent = None;
+                    return Ok(proc.pid);
+                }
+            }
+
+            if !has_child {
+                return Err(ECHILD);
+            }
+
+            self.yield_();
+        }
+    }
+
+    pub fn current_pid(&self) -> u32 {
+        self.procs[self.current].pid
+    }
+
+    /// 使用中のユーザープロセスのpid
+    pub fn pids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.procs
+            .iter()
+            .filter(|p| matches!(p.state, State::Runnable | State::Exited))
+            .map(|p| p.pid)
+    }
+
+    pub fn info(&self, pid: u32) -> Option<ProcInfo> {
+        let proc = self
+            .procs
+            .iter()
+            .find(|p| p.pid == pid && matches!(p.state, State::Runnable | State::Exited))?;
+        Some(ProcInfo {
+            pid,
+            state: match proc.state {
+                State::Exited => "Z (zombie)",
+                _ => "R (running)",
+            },
+            parent: proc.parent.map(|i| self.procs[i].pid),
+            cwd: proc.cwd,
+            fds: proc.fds.iter().flatten().count(),
+        })
+    }
+
+    /// `dir`の下をカレントディレクトリにしているか、その下のディレクトリを開いているプロセスがあるか
+    pub fn uses_dir(&self, dir: &PathBuf) -> bool {
+        self.procs
+            .iter()
+            .filter(|p| p.state == State::Runnable)
+            .any(|p| {
+                p.cwd.strip_prefix(dir).is_some()
+                    || p.fds.iter().flatten().any(|f| {
+                        f.dir_path()
+                            .is_some_and(|path| path.strip_prefix(dir).is_some())
+                    })
+            })
+    }
+
+    pub fn cwd(&self) -> PathBuf {
+        self.procs[self.current].cwd
+    }
+
+    pub fn set_cwd(&mut self, cwd: PathBuf) {
+        self.procs[self.current].cwd = cwd;
+    }
+
+    pub fn fd_get(&mut self, fd: u32) -> Result<&mut OpenFile, i32> {
+        self.procs[self.current]
+            .fds
+            .get_mut(fd as usize)
+            .and_then(|f| f.as_mut())
+            .ok_or(EBADF)
+    }
+
+    pub fn fd_alloc(&mut self, file: OpenFile) -> Result<u32, i32> {
+        let fds = &mut self.procs[self.current].fds;
+        let (fd, slot) = fds
+            .iter_mut()
+            .enumerate()
+            .find(|(_, f)| f.is_none())
+            .ok_or(EMFILE)?;
+        *slot = Some(file);
+        Ok(fd as u32)
+    }
+
+    pub fn fd_close(&mut self, fd: u32) -> Result<(), i32> {
+        let file = self.procs[self.current]
+            .fds
+            .get_mut(fd as usize)
+            .and_then(|f| f.take())
+            .ok_or(EBADF)?;
+        file.close();
+        Ok(())
+    }
 }
 
 #[unsafe(naked)]
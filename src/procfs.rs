@@ -0,0 +1,355 @@
+// procfs: カーネル・プロセス・ネットワークの状態を読み込み専用のファイルとして見せる
+// ファイルの中身は読み込むたびに生成する
+
+use core::fmt::Write;
+
+use common::{Stat, EINVAL, EISDIR, ENOENT, ENOTDIR, PAGE_SIZE};
+
+use crate::{
+    memory::page_stats,
+    net::{icmp, ip},
+    path::PathBuf,
+    timer::{now, timebase_freq},
+    vfs::{vfs_mounts, DirEntry, FileKind, FileSystem, Node},
+    PM,
+};
+
+// 生成するファイルの最大サイズ。超えた分は切り捨てる
+const CONTENT_MAX: usize = 2048;
+// `Node`のうちこの値以上は`/<pid>/status`
+const STATUS_NODE: Node = 0x100;
+
+/// procfs内のファイル
+#[derive(Copy, Clone, PartialEq)]
+enum Entry {
+    Root,
+    Meminfo,
+    Uptime,
+    Mounts,
+    Net,
+    NetDev,
+    NetArp,
+    NetRoute,
+    NetSnmp,
+    SelfLink,
+    Pid(u32),
+    Status(u32),
+}
+
+// ルートとnet/に固定で並ぶファイル
+const ROOT_ENTRIES: [(&str, Entry); 5] = [
+    ("meminfo", Entry::Meminfo),
+    ("uptime", Entry::Uptime),
+    ("mounts", Entry::Mounts),
+    ("net", Entry::Net),
+    ("self", Entry::SelfLink),
+];
+const NET_ENTRIES: [(&str, Entry); 4] = [
+    ("dev", Entry::NetDev),
+    ("arp", Entry::NetArp),
+    ("route", Entry::NetRoute),
+    ("snmp", Entry::NetSnmp),
+];
+
+impl Entry {
+    fn parse(path: &PathBuf) -> Result<Self, i32> {
+        let mut entry = Entry::Root;
+        for component in path.relative().split('/').filter(|c| !c.is_empty()) {
+            entry = match entry {
+                Entry::Root => match ROOT_ENTRIES.iter().find(|(name, _)| *name == component) {
+                    Some((_, e)) => *e,
+                    None => {
+                        let pid = component.parse().map_err(|_| ENOENT)?;
+                        unsafe { PM.info(pid) }.ok_or(ENOENT)?;
+                        Entry::Pid(pid)
+                    }
+                },
+                Entry::Net => {
+                    NET_ENTRIES
+                        .iter()
+                        .find(|(name, _)| *name == component)
+                        .ok_or(ENOENT)?
+                        .1
+                }
+                Entry::Pid(pid) if component == "status" => Entry::Status(pid),
+                Entry::Pid(_) => return Err(ENOENT),
+                _ => return Err(ENOTDIR),
+            };
+        }
+        Ok(entry)
+    }
+
+    fn from_node(node: Node) -> Self {
+        match node {
+            0 => Entry::Meminfo,
+            1 => Entry::Uptime,
+            2 => Entry::Mounts,
+            3 => Entry::NetDev,
+            4 => Entry::NetArp,
+            5 => Entry::NetRoute,
+            6 => Entry::NetSnmp,
+            _ => Entry::Status((node - STATUS_NODE) as u32),
+        }
+    }
+
+    fn node(&self) -> Option<Node> {
+        match self {
+            Entry::Meminfo => Some(0),
+            Entry::Uptime => Some(1),
+            Entry::Mounts => Some(2),
+            Entry::NetDev => Some(3),
+            Entry::NetArp => Some(4),
+            Entry::NetRoute => Some(5),
+            Entry::NetSnmp => Some(6),
+            Entry::Status(pid) => Some(STATUS_NODE + *pid as Node),
+            _ => None,
+        }
+    }
+
+    fn kind(&self) -> FileKind {
+        match self {
+            Entry::Root | Entry::Net | Entry::Pid(_) => FileKind::Directory,
+            Entry::SelfLink => FileKind::Symlink,
+            _ => FileKind::Regular,
+        }
+    }
+}
+
+/// 固定長のバッファに書き込む`core::fmt::Write`。溢れた分は捨てる
+struct Content {
+    buf: [u8; CONTENT_MAX],
+    len: usize,
+}
+
+impl Write for Content {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = s.len().min(CONTENT_MAX - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+// カーネルスタックを圧迫しないよう静的に確保する
+static mut CONTENT: Content = Content {
+    buf: [0; CONTENT_MAX],
+    len: 0,
+};
+
+/// ファイルの中身を生成する。プロセスが既に存在しなければ`ENOENT`
+fn generate(entry: Entry) -> Result<&'static [u8], i32> {
+    let out = unsafe { &mut *core::ptr::addr_of_mut!(CONTENT) };
+    out.len = 0;
+    match entry {
+        Entry::Meminfo => {
+            let (total, free) = page_stats();
+            let _ = writeln!(out, "MemTotal: {} kB", total * PAGE_SIZE / 1024);
+            let _ = writeln!(out, "MemFree:  {} kB", free * PAGE_SIZE / 1024);
+            let _ = writeln!(out, "PageSize: {PAGE_SIZE} B");
+        }
+        Entry::Uptime => {
+            let ticks = now();
+            let freq = timebase_freq();
+            let _ = writeln!(out, "{}.{:02}", ticks / freq, ticks % freq * 100 / freq);
+        }
+        Entry::Mounts => {
+            for (path, fstype) in vfs_mounts() {
+                let _ = writeln!(out, "{} {} {}", fstype, path.as_str(), fstype);
+            }
+        }
+        Entry::NetDev => {
+            let _ = writeln!(
+                out,
+                "Iface rx_packets rx_bytes tx_packets tx_bytes tx_dropped"
+            );
+            for (name, s) in ip::interfaces() {
+                let _ = writeln!(
+                    out,
+                    "{} {} {} {} {} {}",
+                    name, s.rx_packets, s.rx_bytes, s.tx_packets, s.tx_bytes, s.tx_dropped
+                );
+            }
+        }
+        Entry::NetArp => {
+            // ARPを使うインターフェースはまだない
+            let _ = writeln!(out, "IP address HW address Device");
+        }
+        Entry::NetRoute => {
+            let _ = writeln!(out, "Destination Gateway Iface Source");
+            for route in ip::routes() {
+                let entry = route.to_entry();
+                let gateway = ip::IpV4Addr::from_be_u32(entry.gateway);
+                let src = ip::IpV4Addr::from_be_u32(entry.src);
+                let _ = writeln!(
+                    out,
+                    "{}/{} {} {} {}",
+                    route.dst,
+                    route.prefix_len,
+                    gateway,
+                    entry.iface(),
+                    src
+                );
+            }
+        }
+        Entry::NetSnmp => {
+            let s = ip::ip_stats();
+            let _ = writeln!(
+                out,
+                "Ip: InReceives InDelivers InHdrErrors InCsumErrors InTruncatedPkts InAddrErrors InUnknownProtos \
+                 ReasmTimeout ReasmReqds ReasmOKs ReasmFails FragOKs FragFails FragCreates ForwDatagrams OutNoRoutes"
+            );
+            let _ = writeln!(
+                out,
+                "Ip: {} {} {} {} {} {} {} {} {} {} {} {} {} {} {} {}",
+                s.in_receives,
+                s.in_delivers,
+                s.in_hdr_errors,
+                s.in_csum_errors,
+                s.in_truncated,
+                s.in_addr_errors,
+                s.in_unknown_protos,
+                s.reasm_timeout,
+                s.reasm_reqds,
+                s.reasm_oks,
+                s.reasm_fails,
+                s.frag_oks,
+                s.frag_fails,
+                s.frag_creates,
+                s.forw_datagrams,
+                s.out_no_routes
+            );
+            let s = icmp::icmp_stats();
+            let _ = writeln!(
+                out,
+                "Icmp: InMsgs InErrors InDestUnreachs InTimeExcds InParmProbs InRedirects \
+                 OutMsgs OutDestUnreachs OutTimeExcds OutParmProbs OutRedirects OutRateLimited"
+            );
+            let _ = writeln!(
+                out,
+                "Icmp: {} {} {} {} {} {} {} {} {} {} {} {}",
+                s.in_msgs,
+                s.in_errors,
+                s.in_dest_unreachs,
+                s.in_time_excds,
+                s.in_parm_probs,
+                s.in_redirects,
+                s.out_msgs,
+                s.out_dest_unreachs,
+                s.out_time_excds,
+                s.out_parm_probs,
+                s.out_redirects,
+                s.out_rate_limited
+            );
+        }
+        Entry::Status(pid) => {
+            let info = unsafe { PM.info(pid) }.ok_or(ENOENT)?;
+            let _ = writeln!(out, "Pid:   {}", info.pid);
+            let _ = writeln!(out, "State: {}", info.state);
+            let _ = writeln!(out, "PPid:  {}", info.parent.unwrap_or(0));
+            let _ = writeln!(out, "Cwd:   {}", info.cwd.as_str());
+            let _ = writeln!(out, "FDs:   {}", info.fds);
+        }
+        _ => unreachable!(),
+    }
+    Ok(&out.buf[..out.len])
+}
+
+/// VFSから見たprocfs。`Node`は`Entry::node`で割り当てた番号
+pub struct ProcFs;
+
+pub static PROCFS: ProcFs = ProcFs;
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn kind(&self, path: &PathBuf) -> Result<FileKind, i32> {
+        Entry::parse(path).map(|e| e.kind())
+    }
+
+    fn stat(&self, path: &PathBuf) -> Result<Stat, i32> {
+        let entry = Entry::parse(path)?;
+        let kind = entry.kind();
+        Ok(Stat {
+            kind: kind.dirent_type(),
+            mode: match kind {
+                FileKind::Regular => 0o444,
+                FileKind::Directory => 0o555,
+                _ => 0o777,
+            },
+            nlink: 1,
+            ..Stat::new()
+        })
+    }
+
+    fn readdir(&self, dir: &PathBuf, cursor: &mut usize) -> Option<DirEntry> {
+        let (name, entry) = match Entry::parse(dir).ok()? {
+            Entry::Root => {
+                if let Some((name, entry)) = ROOT_ENTRIES.get(*cursor) {
+                    (*name, *entry)
+                } else {
+                    // 固定のファイルの後にプロセスを並べる
+                    let pid = unsafe { PM.pids() }.nth(*cursor - ROOT_ENTRIES.len())?;
+                    *cursor += 1;
+                    let mut name = [0u8; 10];
+                    let len = format_u32(pid, &mut name);
+                    let name = core::str::from_utf8(&name[..len]).unwrap();
+                    return Some(DirEntry::new(name, FileKind::Directory));
+                }
+            }
+            Entry::Net => *NET_ENTRIES.get(*cursor)?,
+            Entry::Pid(pid) if *cursor == 0 => ("status", Entry::Status(pid)),
+            _ => return None,
+        };
+        *cursor += 1;
+        Some(DirEntry::new(name, entry.kind()))
+    }
+
+    fn readlink(&self, path: &PathBuf, buf: &mut [u8]) -> Result<usize, i32> {
+        if Entry::parse(path)? != Entry::SelfLink {
+            return Err(EINVAL);
+        }
+        Ok(format_u32(unsafe { PM.current_pid() }, buf))
+    }
+
+    fn lookup(&self, path: &PathBuf) -> Result<Node, i32> {
+        Entry::parse(path)?.node().ok_or(EISDIR)
+    }
+
+    fn read(&self, node: Node, offset: usize, buf: &mut [u8]) -> Result<usize, i32> {
+        let content = generate(Entry::from_node(node))?;
+        if offset >= content.len() {
+            return Ok(0);
+        }
+
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn size(&self, node: Node) -> usize {
+        generate(Entry::from_node(node)).map_or(0, |c| c.len())
+    }
+}
+
+/// `value`を10進数で`buf`に書き込み、書いた長さを返す
+fn format_u32(value: u32, buf: &mut [u8]) -> usize {
+    let mut digits = [0u8; 10];
+    let mut n = value;
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        len += 1;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+
+    // `buf`が短ければreadlinkと同じく先頭から入る分だけ書く
+    for (i, digit) in digits[..len].iter().rev().take(buf.len()).enumerate() {
+        buf[i] = *digit;
+    }
+    len.min(buf.len())
+}
@@ -0,0 +1,153 @@
+// カーネルの乱数生成器: ChaCha20の鍵にエントロピーを混ぜ、出力のたびに鍵を作り直す
+// (出力から過去の鍵を辿れないようにする)。エントロピーはvirtio-rngから得て、
+// デバイスがなければtimeレジスタの揺らぎで代用する
+
+use crate::{
+    timer::now,
+    virtio::rng::{VirtioRng, RNG_BUF_SIZE},
+};
+
+// この量を出力するたびにデバイスから乱数を取り直す
+const RESEED_BYTES: usize = 64 * 1024;
+// timeレジスタの揺らぎから集めるサンプル数
+const JITTER_SAMPLES: usize = 256;
+
+static mut KEY: [u32; 8] = [0; 8];
+static mut COUNTER: u64 = 0;
+static mut OUTPUT_SINCE_RESEED: usize = 0;
+static mut RNG: Option<VirtioRng> = None;
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// ChaCha20の1ブロック (64バイト) を生成する。ノンスは0
+fn chacha20_block(key: &[u32; 8], counter: u64) -> [u32; 16] {
+    let mut state = [0u32; 16];
+    // "expand 32-byte k"
+    state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    state[4..12].copy_from_slice(key);
+    state[12] = counter as u32;
+    state[13] = (counter >> 32) as u32;
+
+    let mut x = state;
+    for _ in 0..10 {
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 1, 5, 9, 13);
+        quarter_round(&mut x, 2, 6, 10, 14);
+        quarter_round(&mut x, 3, 7, 11, 15);
+        quarter_round(&mut x, 0, 5, 10, 15);
+        quarter_round(&mut x, 1, 6, 11, 12);
+        quarter_round(&mut x, 2, 7, 8, 13);
+        quarter_round(&mut x, 3, 4, 9, 14);
+    }
+    for (x, s) in x.iter_mut().zip(state.iter()) {
+        *x = x.wrapping_add(*s);
+    }
+    x
+}
+
+/// 次のブロックを生成する
+fn next_block() -> [u32; 16] {
+    unsafe {
+        let block = chacha20_block(&*core::ptr::addr_of!(KEY), COUNTER);
+        COUNTER = COUNTER.wrapping_add(1);
+        block
+    }
+}
+
+/// 生成したブロックの前半を新しい鍵にする
+fn rekey() {
+    let block = next_block();
+    let key = unsafe { &mut *core::ptr::addr_of_mut!(KEY) };
+    key.copy_from_slice(&block[..8]);
+}
+
+/// エントロピーを鍵に混ぜる
+pub fn random_add_entropy(data: &[u8]) {
+    for chunk in data.chunks(32) {
+        let key = unsafe { &mut *core::ptr::addr_of_mut!(KEY) };
+        for (i, byte) in chunk.iter().enumerate() {
+            key[i / 4] ^= (*byte as u32) << (i % 4 * 8);
+        }
+        rekey();
+    }
+}
+
+/// timeレジスタが進むまでのループ回数の揺らぎを集める
+fn jitter_entropy() {
+    let mut samples = [0u8; JITTER_SAMPLES];
+    for sample in samples.iter_mut() {
+        let start = now();
+        let mut spins = 0u32;
+        while now() == start {
+            spins = spins.wrapping_add(1);
+        }
+        *sample = spins as u8 ^ now() as u8;
+    }
+    random_add_entropy(&samples);
+}
+
+/// 乱数生成器を初期化する。virtio-rngがあれば以降の再シードにも使う
+pub fn random_init(rng: Option<VirtioRng>) {
+    random_add_entropy(&now().to_le_bytes());
+    match rng {
+        Some(mut rng) => {
+            let mut seed = [0u8; RNG_BUF_SIZE];
+            let len = rng.read_blocking(&mut seed);
+            random_add_entropy(&seed[..len]);
+            unsafe { RNG = Some(rng) };
+        }
+        None => jitter_entropy(),
+    }
+}
+
+/// virtio-rngの割り込みなら処理し、受け取った乱数を混ぜる
+pub fn random_handle_interrupt(irq: u32) {
+    let Some(rng) = (unsafe { (*core::ptr::addr_of_mut!(RNG)).as_mut() }) else {
+        return;
+    };
+    if rng.irq() != irq {
+        return;
+    }
+
+    rng.ack_interrupt();
+    if let Some(data) = rng.take() {
+        random_add_entropy(data);
+    }
+}
+
+/// `buf`を乱数で埋める
+pub fn random_fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(64) {
+        let block = next_block();
+        for (i, byte) in chunk.iter_mut().enumerate() {
+            *byte = (block[i / 4] >> (i % 4 * 8)) as u8;
+        }
+    }
+    rekey();
+
+    unsafe {
+        OUTPUT_SINCE_RESEED += buf.len();
+        if OUTPUT_SINCE_RESEED >= RESEED_BYTES {
+            OUTPUT_SINCE_RESEED = 0;
+            // 受け取った乱数は割り込みで混ぜる
+            if let Some(rng) = (*core::ptr::addr_of_mut!(RNG)).as_mut() {
+                rng.request();
+            }
+        }
+    }
+}
+
+pub fn random_u32() -> u32 {
+    let mut buf = [0u8; 4];
+    random_fill(&mut buf);
+    u32::from_le_bytes(buf)
+}
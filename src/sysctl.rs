@@ -0,0 +1,37 @@
+// sysctl: 名前で指定して読み書きするカーネルの設定値
+
+use common::ENOENT;
+
+use crate::net::{
+    icmp::{icmp_ratelimit, icmp_set_ratelimit},
+    ip::{ip_forwarding, ip_set_forwarding},
+};
+
+struct Sysctl {
+    name: &'static str,
+    get: fn() -> u32,
+    set: fn(u32) -> Result<(), i32>,
+}
+
+const SYSCTLS: [Sysctl; 2] = [
+    Sysctl {
+        name: "net.ipv4.ip_forward",
+        get: ip_forwarding,
+        set: ip_set_forwarding,
+    },
+    Sysctl {
+        name: "net.ipv4.icmp_ratelimit",
+        get: icmp_ratelimit,
+        set: icmp_set_ratelimit,
+    },
+];
+
+/// 設定値を読み、`value`があれば書き換える。書き換える前の値を返す
+pub fn sysctl(name: &str, value: Option<u32>) -> Result<u32, i32> {
+    let entry = SYSCTLS.iter().find(|e| e.name == name).ok_or(ENOENT)?;
+    let old = (entry.get)();
+    if let Some(value) = value {
+        (entry.set)(value)?;
+    }
+    Ok(old)
+}
@@ -0,0 +1,20 @@
+// timeレジスタによる時刻
+
+use crate::fdt::boot_info;
+
+/// timeレジスタが1秒間に進む数。デバイスツリーのtimebase-frequency
+pub fn timebase_freq() -> u64 {
+    boot_info().timebase_freq
+}
+
+/// 起動してからのtick数
+pub fn now() -> u64 {
+    // RV32では上位と下位を別々に読むので、上位が繰り上がった場合は読み直す
+    loop {
+        let hi = common::read_csr!("timeh");
+        let lo = common::read_csr!("time");
+        if hi == common::read_csr!("timeh") {
+            return ((hi as u64) << 32) | lo as u64;
+        }
+    }
+}
@@ -0,0 +1,93 @@
+// 端末: virtio-consoleの各ポートを/dev/hvcNとして読み書きできるようにする。
+// 出力の改行はCRLFに変換する (ONLCR)。エコーや行編集はシェルが行う
+
+use common::{EAGAIN, ENXIO};
+
+use crate::{
+    virtio::console::{VirtioConsole, CONSOLE_PORTS_MAX},
+    PM,
+};
+
+pub const TTYS_MAX: usize = CONSOLE_PORTS_MAX;
+
+static mut CONSOLE: Option<VirtioConsole> = None;
+
+fn console() -> Option<&'static mut VirtioConsole> {
+    unsafe { (*core::ptr::addr_of_mut!(CONSOLE)).as_mut() }
+}
+
+pub fn tty_init(console: Option<VirtioConsole>) {
+    unsafe { CONSOLE = console };
+}
+
+/// virtio-consoleの割り込みなら処理する
+pub fn tty_handle_interrupt(irq: u32) {
+    if let Some(console) = console().filter(|c| c.irq() == irq) {
+        console.handle_interrupt();
+    }
+}
+
+pub fn tty_exists(port: usize) -> bool {
+    console().is_some_and(|c| c.port_present(port))
+}
+
+/// ホスト側でコンソールとして使われているポート。シェルを起動する
+pub fn tty_is_console(port: usize) -> bool {
+    console().is_some_and(|c| c.port_is_console(port))
+}
+
+/// 1バイト以上読めるまで他のプロセスに譲る
+pub fn tty_read(port: usize, buf: &mut [u8]) -> Result<usize, i32> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    let console = console().ok_or(ENXIO)?;
+    loop {
+        if let Some(len) = console.read(port, buf)? {
+            return Ok(len);
+        }
+
+        // 割り込みは他のプロセスがユーザーモードにいる間しか受け取れないので、自分でも確認する
+        console.poll();
+        unsafe { PM.yield_() };
+    }
+}
+
+/// 全て送るまで他のプロセスに譲る
+pub fn tty_write(port: usize, buf: &[u8]) -> Result<usize, i32> {
+    let console = console().ok_or(ENXIO)?;
+    let mut chunk = [0u8; 128];
+    let mut rest = buf;
+    while !rest.is_empty() {
+        // 改行の前にCRを補う
+        let mut len = 0;
+        let mut consumed = 0;
+        for &ch in rest {
+            let need = if ch == b'\n' { 2 } else { 1 };
+            if len + need > chunk.len() {
+                break;
+            }
+            if ch == b'\n' {
+                chunk[len] = b'\r';
+                len += 1;
+            }
+            chunk[len] = ch;
+            len += 1;
+            consumed += 1;
+        }
+
+        let mut sent = 0;
+        while sent < len {
+            match console.write(port, &chunk[sent..len]) {
+                Ok(n) => sent += n,
+                Err(EAGAIN) => {
+                    console.poll();
+                    unsafe { PM.yield_() };
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        rest = &rest[consumed..];
+    }
+    Ok(buf.len())
+}
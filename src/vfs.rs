@@ -0,0 +1,447 @@
+// 仮想ファイルシステム: マウントテーブルと、マウントをまたいだパスの解決
+// システムコールはここを通してファイルシステムを操作し、個々の実装には直接触れない
+
+use common::{
+    Stat, DT_BLK, DT_CHR, DT_DIR, DT_LNK, DT_REG, EBUSY, EEXIST, EINVAL, ELOOP, ENODEV, ENOENT,
+    ENOTDIR, EROFS, EXDEV,
+};
+
+use crate::{
+    bcache::bcache_writeback_due,
+    devfs::DEVFS,
+    ext2::EXT2FS,
+    fat::FATFS,
+    fs::TARFS,
+    p9fs::P9FS,
+    path::{PathBuf, PATH_MAX},
+    procfs::PROCFS,
+    PM,
+};
+
+// シンボリックリンクを辿る回数の上限
+const SYMLINK_MAX: usize = 8;
+const MOUNTS_MAX: usize = 8;
+// readdirのカーソルがこの値以上なら、ファイルシステムのエントリを返し終えてマウントポイントを返している
+const MOUNT_CURSOR: usize = 1 << 30;
+
+/// ファイルシステム内でファイルを指す番号。意味は実装ごとに異なる
+pub type Node = usize;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FileKind {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+impl FileKind {
+    pub fn dirent_type(&self) -> u32 {
+        match self {
+            FileKind::Regular => DT_REG,
+            FileKind::Directory => DT_DIR,
+            FileKind::Symlink => DT_LNK,
+            FileKind::CharDevice => DT_CHR,
+            FileKind::BlockDevice => DT_BLK,
+        }
+    }
+}
+
+/// ディレクトリの要素
+pub struct DirEntry {
+    pub name: [u8; PATH_MAX],
+    pub len: usize,
+    pub kind: FileKind,
+}
+
+impl DirEntry {
+    pub fn new(name: &str, kind: FileKind) -> Self {
+        let len = name.len().min(PATH_MAX);
+        let mut entry = Self {
+            name: [0; PATH_MAX],
+            len,
+            kind,
+        };
+        entry.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        entry
+    }
+}
+
+/// ファイルシステムの実装。パスはシンボリックリンクを辿った後の、ファイルシステムのルートからの絶対パス
+/// 変更を伴う操作は既定では読み込み専用として`EROFS`を返す
+pub trait FileSystem {
+    /// `SYS_MOUNT`で指定する名前
+    fn name(&self) -> &'static str;
+    fn kind(&self, path: &PathBuf) -> Result<FileKind, i32>;
+    /// 最後の要素がシンボリックリンクならリンクそのものの属性を返す
+    fn stat(&self, path: &PathBuf) -> Result<Stat, i32>;
+    /// `dir`の要素を1つ返し、`cursor`を進める
+    fn readdir(&self, dir: &PathBuf, cursor: &mut usize) -> Option<DirEntry>;
+    /// リンク先を`buf`に書き込み、その長さを返す
+    fn readlink(&self, _path: &PathBuf, _buf: &mut [u8]) -> Result<usize, i32> {
+        Err(EINVAL)
+    }
+
+    /// ディレクトリ以外のファイルを探す。参照数は増やさない
+    fn lookup(&self, path: &PathBuf) -> Result<Node, i32>;
+    /// ファイルディスクリプタから参照される間、`open`と`close`が対で呼ばれる
+    fn open(&self, _node: Node) {}
+    fn close(&self, _node: Node) {}
+    fn read(&self, node: Node, offset: usize, buf: &mut [u8]) -> Result<usize, i32>;
+    fn write(&self, _node: Node, _offset: usize, _buf: &[u8]) -> Result<usize, i32> {
+        Err(EROFS)
+    }
+    fn size(&self, node: Node) -> usize;
+    fn truncate(&self, _node: Node, _len: usize) -> Result<(), i32> {
+        Err(EROFS)
+    }
+
+    fn create(&self, _path: &PathBuf) -> Result<(), i32> {
+        Err(EROFS)
+    }
+    fn mkdir(&self, _path: &PathBuf) -> Result<(), i32> {
+        Err(EROFS)
+    }
+    fn unlink(&self, _path: &PathBuf) -> Result<(), i32> {
+        Err(EROFS)
+    }
+    fn rmdir(&self, _path: &PathBuf) -> Result<(), i32> {
+        Err(EROFS)
+    }
+    fn symlink(&self, _target: &str, _path: &PathBuf) -> Result<(), i32> {
+        Err(EROFS)
+    }
+    fn link(&self, _old: &PathBuf, _path: &PathBuf) -> Result<(), i32> {
+        Err(EROFS)
+    }
+
+    /// 変更をディスクなどに書き戻す
+    fn sync(&self) {}
+}
+
+#[derive(Copy, Clone)]
+struct Mount {
+    path: PathBuf,
+    fs: &'static dyn FileSystem,
+    // 開かれているファイルの数
+    refs: usize,
+}
+
+static mut MOUNTS: [Option<Mount>; MOUNTS_MAX] = [None; MOUNTS_MAX];
+
+fn mounts() -> &'static mut [Option<Mount>; MOUNTS_MAX] {
+    unsafe { &mut *core::ptr::addr_of_mut!(MOUNTS) }
+}
+
+fn mount(mnt: usize) -> &'static mut Mount {
+    mounts()[mnt].as_mut().unwrap()
+}
+
+/// `SYS_MOUNT`で指定できるファイルシステム。ディスクを使うものは、起動時にディスクを
+/// 読み込んでルートにマウントしたものだけ。他は初期化されておらず、書き込むとディスクを壊す
+fn filesystem(name: &str) -> Result<&'static dyn FileSystem, i32> {
+    let filesystems: [&'static dyn FileSystem; 3] = [&PROCFS, &DEVFS, &P9FS];
+    if let Some(fs) = filesystems.into_iter().find(|fs| fs.name() == name) {
+        return Ok(fs);
+    }
+
+    let disk_filesystems: [&'static dyn FileSystem; 3] = [&TARFS, &EXT2FS, &FATFS];
+    let fs = disk_filesystems
+        .into_iter()
+        .find(|fs| fs.name() == name)
+        .ok_or(EINVAL)?;
+    let loaded = mounts()
+        .iter()
+        .flatten()
+        .any(|m| m.path.is_root() && core::ptr::addr_eq(m.fs, fs));
+    if loaded {
+        Ok(fs)
+    } else {
+        Err(ENODEV)
+    }
+}
+
+/// `path`を含むマウントと、そのファイルシステム内でのパス
+fn mount_of(path: &PathBuf) -> Result<(usize, PathBuf), i32> {
+    mounts()
+        .iter()
+        .enumerate()
+        .filter_map(|(i, m)| Some((i, m.as_ref()?)))
+        .filter_map(|(i, m)| Some((i, m.path.as_str().len(), path.strip_prefix(&m.path)?)))
+        .max_by_key(|(_, len, _)| *len)
+        .map(|(i, _, rel)| (i, rel))
+        .ok_or(ENOENT)
+}
+
+fn is_mountpoint(path: &PathBuf) -> bool {
+    mounts().iter().flatten().any(|m| m.path == *path)
+}
+
+/// `path`に`fs`をマウントする。最初のマウントはルートでなければならない
+pub fn vfs_mount(path: &PathBuf, fs: &'static dyn FileSystem) -> Result<(), i32> {
+    let mounts = mounts();
+    if mounts
+        .iter()
+        .flatten()
+        .any(|m| core::ptr::addr_eq(m.fs, fs))
+        || is_mountpoint(path)
+    {
+        return Err(EBUSY);
+    }
+    if mounts.iter().flatten().next().is_none() {
+        if !path.is_root() {
+            return Err(EINVAL);
+        }
+    } else {
+        // procfsのように元のファイルシステムにないディレクトリにもマウントできる
+        if vfs_kind(&path.parent())? != FileKind::Directory {
+            return Err(ENOTDIR);
+        }
+        match vfs_kind(path) {
+            Ok(FileKind::Directory) | Err(ENOENT) => {}
+            Ok(_) => return Err(ENOTDIR),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let slot = mounts.iter_mut().find(|m| m.is_none()).ok_or(EBUSY)?;
+    *slot = Some(Mount {
+        path: *path,
+        fs,
+        refs: 0,
+    });
+    Ok(())
+}
+
+/// `fstype`という名前のファイルシステムを`path`にマウントする
+pub fn vfs_mount_by_name(fstype: &str, path: &PathBuf) -> Result<(), i32> {
+    vfs_mount(path, filesystem(fstype)?)
+}
+
+/// ファイルが開かれているか、下をカレントディレクトリにしたり開いたりしているプロセスがあるか、
+/// 下に別のマウントがあれば`EBUSY`
+pub fn vfs_umount(path: &PathBuf) -> Result<(), i32> {
+    let mounts = mounts();
+    let (mnt, _) = mounts
+        .iter()
+        .enumerate()
+        .find(|(_, m)| m.as_ref().is_some_and(|m| m.path == *path))
+        .ok_or(EINVAL)?;
+    let busy = mount(mnt).refs > 0
+        || path.is_root()
+        || unsafe { PM.uses_dir(path) }
+        || mounts
+            .iter()
+            .flatten()
+            .any(|m| m.path != *path && m.path.strip_prefix(path).is_some());
+    if busy {
+        return Err(EBUSY);
+    }
+
+    mount(mnt).fs.sync();
+    mounts[mnt] = None;
+    Ok(())
+}
+
+/// マウントポイントのパスとファイルシステムの名前
+pub fn vfs_mounts() -> impl Iterator<Item = (PathBuf, &'static str)> {
+    mounts().iter().flatten().map(|m| (m.path, m.fs.name()))
+}
+
+/// パスの途中にあるシンボリックリンクを辿る。`follow_last`が真なら最後の要素も辿る
+pub fn vfs_realpath(path: &PathBuf, follow_last: bool) -> Result<PathBuf, i32> {
+    let mut path = *path;
+    'retry: for _ in 0..SYMLINK_MAX {
+        let current = path;
+        let s = current.as_str();
+        let ends = s
+            .char_indices()
+            .filter(|(i, c)| *c == '/' && *i > 0)
+            .map(|(i, _)| i)
+            .chain(if follow_last && !current.is_root() {
+                Some(s.len())
+            } else {
+                None
+            });
+
+        for end in ends {
+            let prefix = PathBuf::resolve(&PathBuf::root(), &s[..end])?;
+            if vfs_kind(&prefix) != Ok(FileKind::Symlink) {
+                continue;
+            }
+
+            // リンク先はシンボリックリンクのあるディレクトリからの相対パス
+            let mut buf = [0; PATH_MAX];
+            let (mnt, rel) = mount_of(&prefix)?;
+            let len = mount(mnt).fs.readlink(&rel, &mut buf)?;
+            let target = core::str::from_utf8(&buf[..len]).map_err(|_| EINVAL)?;
+            let target = PathBuf::resolve(&prefix.parent(), target)?;
+            path = if end < s.len() {
+                PathBuf::resolve(&target, &s[end + 1..])?
+            } else {
+                target
+            };
+            continue 'retry;
+        }
+        return Ok(path);
+    }
+    Err(ELOOP)
+}
+
+/// 以下の関数の`path`は`vfs_realpath`で解決済みのもの
+pub fn vfs_kind(path: &PathBuf) -> Result<FileKind, i32> {
+    if is_mountpoint(path) {
+        return Ok(FileKind::Directory);
+    }
+    let (mnt, rel) = mount_of(path)?;
+    mount(mnt).fs.kind(&rel)
+}
+
+pub fn vfs_stat(path: &PathBuf) -> Result<Stat, i32> {
+    let (mnt, rel) = mount_of(path)?;
+    mount(mnt).fs.stat(&rel)
+}
+
+pub fn vfs_readlink(path: &PathBuf, buf: &mut [u8]) -> Result<usize, i32> {
+    let (mnt, rel) = mount_of(path)?;
+    mount(mnt).fs.readlink(&rel, buf)
+}
+
+/// `dir`の要素を1つ返す。ファイルシステムの要素の後に、直下のマウントポイントを返す
+pub fn vfs_readdir(dir: &PathBuf, cursor: &mut usize) -> Option<DirEntry> {
+    let (mnt, rel) = mount_of(dir).ok()?;
+    let fs = mount(mnt).fs;
+    if *cursor < MOUNT_CURSOR {
+        if let Some(entry) = fs.readdir(&rel, cursor) {
+            return Some(entry);
+        }
+        *cursor = MOUNT_CURSOR;
+    }
+
+    while *cursor - MOUNT_CURSOR < MOUNTS_MAX {
+        let m = mounts()[*cursor - MOUNT_CURSOR];
+        *cursor += 1;
+        let path = match m {
+            Some(m) if !m.path.is_root() && m.path.parent() == *dir => m.path,
+            _ => continue,
+        };
+
+        // ファイルシステム上にも同じ名前のディレクトリがあれば既に返している
+        let name = path.file_name();
+        match PathBuf::resolve(&rel, name) {
+            Ok(shadowed) if fs.kind(&shadowed).is_ok() => continue,
+            _ => return Some(DirEntry::new(name, FileKind::Directory)),
+        }
+    }
+    None
+}
+
+/// ディレクトリ以外のファイルを開き、マウントとノードを返す
+pub fn vfs_open(path: &PathBuf) -> Result<(usize, Node), i32> {
+    let (mnt, rel) = mount_of(path)?;
+    let m = mount(mnt);
+    let node = m.fs.lookup(&rel)?;
+    m.fs.open(node);
+    m.refs += 1;
+    Ok((mnt, node))
+}
+
+/// 開いているファイルへの参照を増やす
+pub fn vfs_dup(mnt: usize, node: Node) {
+    let m = mount(mnt);
+    m.fs.open(node);
+    m.refs += 1;
+}
+
+pub fn vfs_close(mnt: usize, node: Node) {
+    let m = mount(mnt);
+    m.fs.close(node);
+    m.refs -= 1;
+}
+
+/// ファイルを開かずに探す
+pub fn vfs_lookup(path: &PathBuf) -> Result<(usize, Node), i32> {
+    let (mnt, rel) = mount_of(path)?;
+    Ok((mnt, mount(mnt).fs.lookup(&rel)?))
+}
+
+pub fn vfs_read(mnt: usize, node: Node, offset: usize, buf: &mut [u8]) -> Result<usize, i32> {
+    mount(mnt).fs.read(node, offset, buf)
+}
+
+pub fn vfs_write(mnt: usize, node: Node, offset: usize, buf: &[u8]) -> Result<usize, i32> {
+    mount(mnt).fs.write(node, offset, buf)
+}
+
+pub fn vfs_size(mnt: usize, node: Node) -> usize {
+    mount(mnt).fs.size(node)
+}
+
+pub fn vfs_truncate(mnt: usize, node: Node, len: usize) -> Result<(), i32> {
+    mount(mnt).fs.truncate(node, len)
+}
+
+pub fn vfs_create(path: &PathBuf) -> Result<(), i32> {
+    if is_mountpoint(path) {
+        return Err(EEXIST);
+    }
+    let (mnt, rel) = mount_of(path)?;
+    mount(mnt).fs.create(&rel)
+}
+
+pub fn vfs_mkdir(path: &PathBuf) -> Result<(), i32> {
+    if is_mountpoint(path) {
+        return Err(EEXIST);
+    }
+    let (mnt, rel) = mount_of(path)?;
+    mount(mnt).fs.mkdir(&rel)
+}
+
+pub fn vfs_unlink(path: &PathBuf) -> Result<(), i32> {
+    if is_mountpoint(path) {
+        return Err(EBUSY);
+    }
+    let (mnt, rel) = mount_of(path)?;
+    mount(mnt).fs.unlink(&rel)
+}
+
+pub fn vfs_rmdir(path: &PathBuf) -> Result<(), i32> {
+    if is_mountpoint(path) {
+        return Err(EBUSY);
+    }
+    let (mnt, rel) = mount_of(path)?;
+    mount(mnt).fs.rmdir(&rel)
+}
+
+pub fn vfs_symlink(target: &str, path: &PathBuf) -> Result<(), i32> {
+    if is_mountpoint(path) {
+        return Err(EEXIST);
+    }
+    let (mnt, rel) = mount_of(path)?;
+    mount(mnt).fs.symlink(target, &rel)
+}
+
+/// ハードリンクは同じマウント内でしか作れない
+pub fn vfs_link(old: &PathBuf, path: &PathBuf) -> Result<(), i32> {
+    let (old_mnt, old_rel) = mount_of(old)?;
+    let (mnt, rel) = mount_of(path)?;
+    if old_mnt != mnt {
+        return Err(EXDEV);
+    }
+    mount(mnt).fs.link(&old_rel, &rel)
+}
+
+/// 全てのファイルシステムの変更を書き戻す
+pub fn vfs_sync() {
+    for m in mounts().iter().flatten() {
+        m.fs.sync();
+    }
+}
+
+/// 書き戻していない変更が一定時間残っていれば、全てのマウントを書き戻す
+pub fn vfs_writeback() {
+    if bcache_writeback_due() {
+        vfs_sync();
+    }
+}
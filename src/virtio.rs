@@ -1,35 +1,64 @@
-use common::{align_up, PAGE_SIZE, VIRTIO_BLK_PADDR};
+// virtio-mmio: デバイスツリーにある各スロットを調べ、デバイスIDに応じたドライバに渡す
 
-use crate::{memory::alloc_pages, println};
+pub mod blk;
+pub mod console;
+pub mod p9;
+pub mod rng;
+
+use common::{align_up, PAGE_SIZE};
+
+use crate::{fdt::Device, memory::alloc_pages, println};
+use blk::VirtioBlk;
+use console::VirtioConsole;
 use core::{
     arch::asm,
     mem,
     ptr::{self, read_volatile, write_volatile},
 };
+use p9::Virtio9p;
+use rng::VirtioRng;
 
 const VIRTQ_ENTRY_NUM: usize = 16;
+const VIRTIO_MAGIC: u32 = 0x74726976;
+const VIRTIO_DEVICE_NET: u32 = 1;
 const VIRTIO_DEVICE_BLK: u32 = 2;
+const VIRTIO_DEVICE_CONSOLE: u32 = 3;
+const VIRTIO_DEVICE_RNG: u32 = 4;
+const VIRTIO_DEVICE_9P: u32 = 9;
 const VIRTIO_REG_MAGIC: usize = 0x00;
 const VIRTIO_REG_VERSION: usize = 0x04;
 const VIRTIO_REG_DEVICE_ID: usize = 0x08;
+const VIRTIO_REG_DEVICE_FEATURES: usize = 0x10;
+const VIRTIO_REG_DEVICE_FEATURES_SEL: usize = 0x14;
+const VIRTIO_REG_DRIVER_FEATURES: usize = 0x20;
+const VIRTIO_REG_DRIVER_FEATURES_SEL: usize = 0x24;
 const VIRTIO_REG_QUEUE_SEL: usize = 0x30;
-// const VIRTIO_REG_QUEUE_NUM_MAX: usize = 0x34;
+const VIRTIO_REG_QUEUE_NUM_MAX: usize = 0x34;
 const VIRTIO_REG_QUEUE_NUM: usize = 0x38;
+// 以下の2つはレガシー (バージョン1) のみ
 const VIRTIO_REG_QUEUE_ALIGN: usize = 0x3c;
 const VIRTIO_REG_QUEUE_PFN: usize = 0x40;
-// const VIRTIO_REG_QUEUE_READY: u32 = 0x44;
-const VIRTIO_REG_QUEUE_NOTIFY: u32 = 0x50;
+// 以下の4つはバージョン2のみ
+const VIRTIO_REG_QUEUE_READY: usize = 0x44;
+const VIRTIO_REG_QUEUE_DESC: usize = 0x80;
+const VIRTIO_REG_QUEUE_DRIVER: usize = 0x90;
+const VIRTIO_REG_QUEUE_DEVICE: usize = 0xa0;
+const VIRTIO_REG_QUEUE_NOTIFY: usize = 0x50;
+const VIRTIO_REG_INTERRUPT_STATUS: usize = 0x60;
+const VIRTIO_REG_INTERRUPT_ACK: usize = 0x64;
 const VIRTIO_REG_DEVICE_STATUS: usize = 0x70;
+const VIRTIO_REG_CONFIG_GENERATION: usize = 0xfc;
 const VIRTIO_REG_DEVICE_CONFIG: usize = 0x100;
 const VIRTIO_STATUS_ACK: u32 = 1;
 const VIRTIO_STATUS_DRIVER: u32 = 2;
 const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
 const VIRTIO_STATUS_FEAT_OK: u32 = 8;
-const VIRTQ_DESC_F_NEXT: u32 = 1;
-const VIRTQ_DESC_F_WRITE: u32 = 2;
+const VIRTIO_STATUS_FAILED: u32 = 128;
+// バージョン2のデバイスでは必ず受け入れる
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
 // const VIRTQ_AVAIL_F_NO_INTERRUPT: u32 = 1;
-const VIRTIO_BLK_T_IN: u32 = 0;
-const VIRTIO_BLK_T_OUT: u32 = 1;
 
 #[repr(C, packed)]
 struct VirtqDesc {
@@ -78,77 +107,136 @@ struct VirtioVirtq {
     last_used_index: u16,
 }
 
-#[repr(C, packed)]
-struct VirtioBlkReq {
-    type_: u32,
-    reserved: u32,
-    sector: u64,
-    data: [u8; 512],
-    status: u8,
+/// 1つのvirtio-mmioスロットのレジスタ
+pub struct VirtioMmio {
+    base: usize,
+    irq: u32,
+    /// 1はレガシー、2はモダン
+    version: u32,
 }
 
-fn virtio_reg_read32(offset: usize) -> u32 {
-    unsafe { read_volatile((VIRTIO_BLK_PADDR + offset) as *const u32) }
-}
+impl VirtioMmio {
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
 
-fn virtio_reg_read64(offset: usize) -> u64 {
-    unsafe { read_volatile((VIRTIO_BLK_PADDR + offset) as *const u64) }
-}
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
 
-fn virtio_reg_write32(offset: usize, value: u32) {
-    unsafe { write_volatile((VIRTIO_BLK_PADDR + offset) as *mut u32, value) }
-}
+    /// 下位・上位の順に並んだ2つのレジスタに64bitの値を書き込む
+    fn write64(&self, offset: usize, value: u64) {
+        self.write32(offset, value as u32);
+        self.write32(offset + 4, (value >> 32) as u32);
+    }
 
-fn virtio_reg_fetch_and_or32(offset: usize, value: u32) {
-    let current_value = virtio_reg_read32(offset);
-    virtio_reg_write32(offset, current_value | value);
-}
+    fn fetch_and_or32(&self, offset: usize, value: u32) {
+        self.write32(offset, self.read32(offset) | value);
+    }
 
-pub struct Virtio<'a> {
-    blk_request_vq: &'a mut VirtioVirtq,
-    blk_req: &'a mut VirtioBlkReq,
-    blk_req_paddr: u32,
-    blk_capacity: u64,
-}
+    /// デバイス固有の設定領域を読む
+    fn config_read8(&self, offset: usize) -> u8 {
+        unsafe { read_volatile((self.base + VIRTIO_REG_DEVICE_CONFIG + offset) as *const u8) }
+    }
 
-impl<'a> Virtio<'a> {
-    pub const SECTOR_SIZE: u64 = 512;
+    fn config_read32(&self, offset: usize) -> u32 {
+        self.read32(VIRTIO_REG_DEVICE_CONFIG + offset)
+    }
 
-    pub fn new() -> Self {
-        unsafe {
-            if virtio_reg_read32(VIRTIO_REG_MAGIC) != 0x74726976 {
-                panic!("virtio: invalid magic value");
-            }
-            if virtio_reg_read32(VIRTIO_REG_VERSION) != 1 {
-                panic!("virtio: invalid version");
-            }
-            if virtio_reg_read32(VIRTIO_REG_DEVICE_ID) != VIRTIO_DEVICE_BLK {
-                panic!("virtio: invalid device id");
+    /// デバイス固有の設定領域を読む。読んでいる間に更新されたら読み直す
+    fn config_read64(&self, offset: usize) -> u64 {
+        let addr = self.base + VIRTIO_REG_DEVICE_CONFIG + offset;
+        loop {
+            let generation = self.read32(VIRTIO_REG_CONFIG_GENERATION);
+            let lo = unsafe { read_volatile(addr as *const u32) };
+            let hi = unsafe { read_volatile((addr + 4) as *const u32) };
+            // レガシーではconfig generationは常に0
+            if self.version == 1 || generation == self.read32(VIRTIO_REG_CONFIG_GENERATION) {
+                return ((hi as u64) << 32) | lo as u64;
             }
+        }
+    }
 
-            virtio_reg_write32(VIRTIO_REG_DEVICE_STATUS, 0);
-            virtio_reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_ACK);
-            virtio_reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER);
-            virtio_reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_FEAT_OK);
-            let blk_request_vq = Self::virtq_init(0);
-            virtio_reg_write32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER_OK);
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
 
-            let blk_capacity = virtio_reg_read64(VIRTIO_REG_DEVICE_CONFIG) * Self::SECTOR_SIZE;
-            println!("virtio-blk: capacity is {} bytes\n", blk_capacity);
+    fn device_features(&self) -> u64 {
+        self.write32(VIRTIO_REG_DEVICE_FEATURES_SEL, 1);
+        let hi = self.read32(VIRTIO_REG_DEVICE_FEATURES);
+        self.write32(VIRTIO_REG_DEVICE_FEATURES_SEL, 0);
+        let lo = self.read32(VIRTIO_REG_DEVICE_FEATURES);
+        ((hi as u64) << 32) | lo as u64
+    }
 
-            let blk_req_size = align_up(core::mem::size_of::<VirtioBlkReq>(), PAGE_SIZE);
-            let blk_req_paddr = alloc_pages(blk_req_size / PAGE_SIZE);
+    fn set_driver_features(&self, features: u64) {
+        self.write32(VIRTIO_REG_DRIVER_FEATURES_SEL, 1);
+        self.write32(VIRTIO_REG_DRIVER_FEATURES, (features >> 32) as u32);
+        self.write32(VIRTIO_REG_DRIVER_FEATURES_SEL, 0);
+        self.write32(VIRTIO_REG_DRIVER_FEATURES, features as u32);
+    }
 
-            Self {
-                blk_request_vq: blk_request_vq.as_mut().unwrap(),
-                blk_req: (blk_req_paddr as *mut VirtioBlkReq).as_mut().unwrap(),
-                blk_req_paddr,
-                blk_capacity,
+    /// デバイスをリセットし、`wanted`のうちデバイスが対応する機能を有効にして
+    /// キューを設定できる状態にする。有効にした機能を返す
+    fn begin_init(&self, wanted: u64) -> Result<u64, ()> {
+        self.write32(VIRTIO_REG_DEVICE_STATUS, 0);
+        self.fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_ACK);
+        self.fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER);
+
+        let device_features = self.device_features();
+        let mut features = device_features & wanted;
+        if self.version != 1 {
+            if device_features & VIRTIO_F_VERSION_1 == 0 {
+                self.fail();
+                return Err(());
             }
+            features |= VIRTIO_F_VERSION_1;
+        }
+        self.set_driver_features(features);
+
+        self.fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_FEAT_OK);
+        // デバイスが機能の組み合わせを受け入れなければFEATURES_OKが落ちる
+        if self.version != 1 && self.read32(VIRTIO_REG_DEVICE_STATUS) & VIRTIO_STATUS_FEAT_OK == 0 {
+            self.fail();
+            return Err(());
         }
+        Ok(features)
     }
 
-    unsafe fn virtq_init(index: u32) -> *mut VirtioVirtq {
+    /// キューの設定を終え、デバイスを使い始める
+    fn finish_init(&self) {
+        self.fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER_OK);
+    }
+
+    /// 初期化を諦めたことをデバイスに伝える
+    fn fail(&self) {
+        self.fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_FAILED);
+    }
+
+    /// 割り込みの要因を確認済みにする
+    fn ack_interrupt(&self) {
+        let status = self.read32(VIRTIO_REG_INTERRUPT_STATUS);
+        self.write32(VIRTIO_REG_INTERRUPT_ACK, status);
+    }
+}
+
+/// ディスクリプタを空きリストで管理するvirtqueue
+struct Virtqueue {
+    vq: &'static mut VirtioVirtq,
+    // 空きディスクリプタは`next`で繋ぐ
+    free_head: u16,
+    num_free: usize,
+}
+
+impl Virtqueue {
+    /// `index`番目のキューを設定する。デバイスにそのキューがなければエラー
+    unsafe fn new(mmio: &VirtioMmio, index: u32) -> Result<Self, ()> {
+        mmio.write32(VIRTIO_REG_QUEUE_SEL, index);
+        if (mmio.read32(VIRTIO_REG_QUEUE_NUM_MAX) as usize) < VIRTQ_ENTRY_NUM {
+            mmio.fail();
+            return Err(());
+        }
+
         let virtq_size = align_up(core::mem::size_of::<VirtioVirtq>(), PAGE_SIZE);
         let virtq_paddr = alloc_pages(virtq_size / PAGE_SIZE);
         let vq = (virtq_paddr as *mut VirtioVirtq).as_mut().unwrap();
@@ -157,89 +245,184 @@ impl<'a> Virtio<'a> {
         let used_index = (&mut (vq.used) as *const VirtqUsed as *const u8)
             .wrapping_byte_add(core::mem::offset_of!(VirtqUsed, index));
         vq.used_index = used_index as *mut u16;
+        for i in 0..VIRTQ_ENTRY_NUM {
+            vq.descs[i].next = (i + 1) as u16;
+        }
 
-        virtio_reg_write32(VIRTIO_REG_QUEUE_SEL, index);
-        virtio_reg_write32(VIRTIO_REG_QUEUE_NUM, VIRTQ_ENTRY_NUM as u32);
-        virtio_reg_write32(VIRTIO_REG_QUEUE_ALIGN, 0);
-        virtio_reg_write32(VIRTIO_REG_QUEUE_PFN, virtq_paddr);
+        mmio.write32(VIRTIO_REG_QUEUE_NUM, VIRTQ_ENTRY_NUM as u32);
+        if mmio.version == 1 {
+            mmio.write32(VIRTIO_REG_QUEUE_ALIGN, 0);
+            mmio.write32(VIRTIO_REG_QUEUE_PFN, virtq_paddr);
+        } else {
+            // モダンではディスクリプタ・avail・usedのアドレスを別々に渡す
+            let paddr = virtq_paddr as u64;
+            mmio.write64(
+                VIRTIO_REG_QUEUE_DESC,
+                paddr + mem::offset_of!(VirtioVirtq, descs) as u64,
+            );
+            mmio.write64(
+                VIRTIO_REG_QUEUE_DRIVER,
+                paddr + mem::offset_of!(VirtioVirtq, avail) as u64,
+            );
+            mmio.write64(
+                VIRTIO_REG_QUEUE_DEVICE,
+                paddr + mem::offset_of!(VirtioVirtq, used) as u64,
+            );
+            mmio.write32(VIRTIO_REG_QUEUE_READY, 1);
+        }
 
-        vq
+        Ok(Self {
+            vq,
+            free_head: 0,
+            num_free: VIRTQ_ENTRY_NUM,
+        })
     }
 
-    fn virtq_kick(vq: &mut VirtioVirtq, desc_index: u32) {
-        vq.avail.ring[vq.avail.index as usize % VIRTQ_ENTRY_NUM] = desc_index as u16;
-        vq.avail.index += 1;
-        unsafe { asm!("fence") }
-        virtio_reg_write32(VIRTIO_REG_QUEUE_NOTIFY as usize, vq.queue_index);
-        vq.last_used_index += 1;
+    fn num_free(&self) -> usize {
+        self.num_free
     }
 
-    fn virtq_is_busy(vq: &mut VirtioVirtq) -> bool {
-        unsafe { vq.last_used_index != ptr::read_volatile(vq.used_index) }
+    /// 空きディスクリプタを1つ取り出す。呼び出し元が`num_free`で空きを確認する
+    fn alloc_desc(&mut self) -> u16 {
+        let index = self.free_head;
+        self.free_head = self.vq.descs[index as usize].next;
+        self.num_free -= 1;
+        index
     }
 
-    pub fn read_write_disk(&mut self, buf: &mut [u8], sector: u64, is_write: bool) {
-        unsafe {
-            if sector >= self.blk_capacity / Self::SECTOR_SIZE {
-                println!(
-                    "virtio: tried to read/write sector={}, but capacity is {}",
-                    sector,
-                    self.blk_capacity / Self::SECTOR_SIZE
-                );
-                return;
-            }
+    fn desc_mut(&mut self, index: u16) -> &mut VirtqDesc {
+        &mut self.vq.descs[index as usize]
+    }
 
-            self.blk_req.sector = sector;
-            self.blk_req.type_ = if is_write {
-                VIRTIO_BLK_T_OUT
-            } else {
-                VIRTIO_BLK_T_IN
-            };
-            if is_write {
-                ptr::copy(
-                    buf as *mut _ as *mut u8,
-                    &mut self.blk_req.data as *mut [u8] as *mut u8,
-                    Self::SECTOR_SIZE as usize,
-                );
+    /// `head`から`next`で繋がったディスクリプタを空きリストに戻す
+    fn free_chain(&mut self, head: u16) {
+        let mut index = head;
+        loop {
+            let desc = &mut self.vq.descs[index as usize];
+            let flags = desc.flags;
+            let next = desc.next;
+            desc.next = self.free_head;
+            self.free_head = index;
+            self.num_free += 1;
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
             }
+            index = next;
+        }
+    }
 
-            self.blk_request_vq.descs[0].addr = self.blk_req_paddr as u64;
-            self.blk_request_vq.descs[0].len =
-                (mem::size_of::<u32>() * 2 + mem::size_of::<u64>()) as u32;
-            self.blk_request_vq.descs[0].flags = VIRTQ_DESC_F_NEXT as u16;
-            self.blk_request_vq.descs[0].next = 1;
-
-            self.blk_request_vq.descs[1].addr =
-                self.blk_req_paddr as u64 + mem::offset_of!(VirtioBlkReq, data) as u64;
-            self.blk_request_vq.descs[1].len = Self::SECTOR_SIZE as u32;
-            self.blk_request_vq.descs[1].flags =
-                (VIRTQ_DESC_F_NEXT | if is_write { 0 } else { VIRTQ_DESC_F_WRITE }) as u16;
-            self.blk_request_vq.descs[1].next = 2;
-
-            self.blk_request_vq.descs[2].addr =
-                self.blk_req_paddr as u64 + mem::offset_of!(VirtioBlkReq, status) as u64;
-            self.blk_request_vq.descs[2].len = mem::size_of::<u8>() as u32;
-            self.blk_request_vq.descs[2].flags = VIRTQ_DESC_F_WRITE as u16;
-
-            Self::virtq_kick(self.blk_request_vq, 0);
-
-            while Self::virtq_is_busy(self.blk_request_vq) {}
-
-            if self.blk_req.status != 0 {
-                println!(
-                    "virtio: warn: failed to read/write sector={} status={}",
-                    sector, self.blk_req.status,
-                );
-                return;
-            }
+    /// `head`から始まるディスクリプタの連なりをデバイスに渡す
+    fn kick(&mut self, mmio: &VirtioMmio, head: u16) {
+        let vq = &mut *self.vq;
+        vq.avail.ring[vq.avail.index as usize % VIRTQ_ENTRY_NUM] = head;
+        unsafe { asm!("fence") }
+        vq.avail.index = vq.avail.index.wrapping_add(1);
+        unsafe { asm!("fence") }
+        mmio.write32(VIRTIO_REG_QUEUE_NOTIFY, vq.queue_index);
+    }
 
-            if !is_write {
-                ptr::copy(
-                    &self.blk_req.data as *const [u8] as *const u8,
-                    buf as *mut _ as *mut u8,
-                    Self::SECTOR_SIZE as usize,
-                );
+    /// デバイスが処理を終えた連なりの先頭と、書き込まれたバイト数を1つ取り出す
+    fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let vq = &mut *self.vq;
+        if vq.last_used_index == unsafe { ptr::read_volatile(vq.used_index) } {
+            return None;
+        }
+
+        unsafe { asm!("fence") }
+        let elem = &vq.used.ring[vq.last_used_index as usize % VIRTQ_ENTRY_NUM];
+        let (id, len) = (elem.id, elem.len);
+        vq.last_used_index = vq.last_used_index.wrapping_add(1);
+        Some((id as u16, len))
+    }
+}
+
+/// 見つかったデバイスのドライバ
+pub struct VirtioDevices {
+    pub blk: Option<VirtioBlk>,
+    pub rng: Option<VirtioRng>,
+    pub console: Option<VirtioConsole>,
+    pub p9: Option<Virtio9p>,
+}
+
+/// デバイスツリーの各virtio-mmioスロットを調べ、対応するドライバを初期化する
+pub fn virtio_probe(devices: &[Device]) -> VirtioDevices {
+    let mut found = VirtioDevices {
+        blk: None,
+        rng: None,
+        console: None,
+        p9: None,
+    };
+    // QEMUのデバイスツリーはスロットを逆順に並べるので、アドレスの小さい順に調べる
+    let mut last = None;
+    while let Some(device) = devices
+        .iter()
+        .filter(|d| last.is_none_or(|base| d.base > base))
+        .min_by_key(|d| d.base)
+    {
+        last = Some(device.base);
+        let mut mmio = VirtioMmio {
+            base: device.base,
+            irq: device.irq,
+            version: 0,
+        };
+        if mmio.read32(VIRTIO_REG_MAGIC) != VIRTIO_MAGIC {
+            println!("virtio: {:#x}: invalid magic value", device.base);
+            continue;
+        }
+        mmio.version = mmio.read32(VIRTIO_REG_VERSION);
+        if mmio.version != 1 && mmio.version != 2 {
+            println!(
+                "virtio: {:#x}: unsupported version {}",
+                device.base, mmio.version
+            );
+            continue;
+        }
+
+        match mmio.read32(VIRTIO_REG_DEVICE_ID) {
+            // 何も接続されていないスロット
+            0 => {}
+            VIRTIO_DEVICE_BLK if found.blk.is_none() => {
+                found.blk = unsafe { VirtioBlk::new(mmio) }.ok();
+                if found.blk.is_none() {
+                    println!(
+                        "virtio: {:#x}: failed to initialize virtio-blk",
+                        device.base
+                    );
+                }
+            }
+            VIRTIO_DEVICE_RNG if found.rng.is_none() => {
+                found.rng = unsafe { VirtioRng::new(mmio) }.ok();
+                if found.rng.is_none() {
+                    println!(
+                        "virtio: {:#x}: failed to initialize virtio-rng",
+                        device.base
+                    );
+                }
+            }
+            VIRTIO_DEVICE_CONSOLE if found.console.is_none() => {
+                found.console = unsafe { VirtioConsole::new(mmio) }.ok();
+                if found.console.is_none() {
+                    println!(
+                        "virtio: {:#x}: failed to initialize virtio-console",
+                        device.base
+                    );
+                }
+            }
+            VIRTIO_DEVICE_9P if found.p9.is_none() => {
+                found.p9 = unsafe { Virtio9p::new(mmio) }.ok();
+                if found.p9.is_none() {
+                    println!("virtio: {:#x}: failed to initialize virtio-9p", device.base);
+                }
+            }
+            id @ (VIRTIO_DEVICE_NET
+            | VIRTIO_DEVICE_BLK
+            | VIRTIO_DEVICE_CONSOLE
+            | VIRTIO_DEVICE_RNG
+            | VIRTIO_DEVICE_9P) => {
+                println!("virtio: {:#x}: device id {} is not used", device.base, id);
             }
+            id => println!("virtio: {:#x}: unknown device id {}", device.base, id),
         }
     }
+    found
 }
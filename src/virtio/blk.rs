@@ -0,0 +1,207 @@
+// virtio-blk: ブロックデバイスのドライバ
+
+use common::{align_up, EAGAIN, EINVAL, EIO, PAGE_SIZE};
+
+use super::{VirtioMmio, Virtqueue, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE, VIRTQ_ENTRY_NUM};
+use crate::{
+    handle_device_interrupt,
+    memory::alloc_pages,
+    plic::{plic_claim, plic_complete, plic_enable},
+    println,
+};
+use core::{arch::asm, mem};
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+// 要求のヘッダとステータス。データはディスクリプタで呼び出し元のバッファを直接指す
+#[repr(C, packed)]
+struct VirtioBlkReq {
+    type_: u32,
+    reserved: u32,
+    sector: u64,
+    status: u8,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum ReqState {
+    Free,
+    InFlight,
+    Done,
+}
+
+pub struct VirtioBlk {
+    mmio: VirtioMmio,
+    request_vq: Virtqueue,
+    // 先頭のディスクリプタの番号で引く
+    reqs: &'static mut [VirtioBlkReq; VIRTQ_ENTRY_NUM],
+    reqs_paddr: u32,
+    req_states: [ReqState; VIRTQ_ENTRY_NUM],
+    capacity: u64,
+}
+
+impl VirtioBlk {
+    pub const SECTOR_SIZE: u64 = 512;
+    /// 同時に発行できる要求の数。1つの要求でディスクリプタを3つ使う
+    pub const MAX_REQUESTS: usize = VIRTQ_ENTRY_NUM / 3;
+
+    pub(super) unsafe fn new(mmio: VirtioMmio) -> Result<Self, ()> {
+        // 追加の機能は使わない
+        mmio.begin_init(0)?;
+        let request_vq = Virtqueue::new(&mmio, 0)?;
+        mmio.finish_init();
+
+        let capacity = mmio.config_read64(0) * Self::SECTOR_SIZE;
+        println!("virtio-blk: capacity is {} bytes\n", capacity);
+
+        let reqs_size = align_up(
+            core::mem::size_of::<[VirtioBlkReq; VIRTQ_ENTRY_NUM]>(),
+            PAGE_SIZE,
+        );
+        let reqs_paddr = alloc_pages(reqs_size / PAGE_SIZE);
+        plic_enable(mmio.irq);
+
+        Ok(Self {
+            mmio,
+            request_vq,
+            reqs: (reqs_paddr as *mut [VirtioBlkReq; VIRTQ_ENTRY_NUM])
+                .as_mut()
+                .unwrap(),
+            reqs_paddr,
+            req_states: [ReqState::Free; VIRTQ_ENTRY_NUM],
+            capacity,
+        })
+    }
+
+    pub fn blk_capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn irq(&self) -> u32 {
+        self.mmio.irq()
+    }
+
+    /// デバイスが処理を終えた要求を完了済みにする
+    fn collect_used(&mut self) {
+        while let Some((id, _)) = self.request_vq.pop_used() {
+            self.req_states[id as usize] = ReqState::Done;
+        }
+    }
+
+    /// virtio-blkの割り込みを処理する
+    pub fn handle_interrupt(&mut self) {
+        self.mmio.ack_interrupt();
+        self.collect_used();
+    }
+
+    /// `sector`から`len`バイトを読み書きする要求を発行し、完了を待たずに要求の番号を返す。
+    /// キューに空きがなければ`EAGAIN`
+    ///
+    /// # Safety
+    /// `buf`は要求が完了するまで有効な物理アドレスでなければならない
+    pub unsafe fn submit(
+        &mut self,
+        buf: *mut u8,
+        len: usize,
+        sector: u64,
+        is_write: bool,
+    ) -> Result<u16, i32> {
+        if len == 0 || !(len as u64).is_multiple_of(Self::SECTOR_SIZE) {
+            return Err(EINVAL);
+        }
+        if sector + len as u64 / Self::SECTOR_SIZE > self.capacity / Self::SECTOR_SIZE {
+            return Err(EINVAL);
+        }
+        if self.request_vq.num_free() < 3 {
+            return Err(EAGAIN);
+        }
+
+        let head = self.request_vq.alloc_desc();
+        let data = self.request_vq.alloc_desc();
+        let status = self.request_vq.alloc_desc();
+
+        let req = &mut self.reqs[head as usize];
+        req.type_ = if is_write {
+            VIRTIO_BLK_T_OUT
+        } else {
+            VIRTIO_BLK_T_IN
+        };
+        req.reserved = 0;
+        req.sector = sector;
+        req.status = 0xff;
+        let req_paddr =
+            self.reqs_paddr as u64 + (head as usize * mem::size_of::<VirtioBlkReq>()) as u64;
+
+        let desc = self.request_vq.desc_mut(head);
+        desc.addr = req_paddr;
+        desc.len = (mem::size_of::<u32>() * 2 + mem::size_of::<u64>()) as u32;
+        desc.flags = VIRTQ_DESC_F_NEXT;
+        desc.next = data;
+
+        let desc = self.request_vq.desc_mut(data);
+        desc.addr = buf as u64;
+        desc.len = len as u32;
+        desc.flags = VIRTQ_DESC_F_NEXT | if is_write { 0 } else { VIRTQ_DESC_F_WRITE };
+        desc.next = status;
+
+        let desc = self.request_vq.desc_mut(status);
+        desc.addr = req_paddr + mem::offset_of!(VirtioBlkReq, status) as u64;
+        desc.len = mem::size_of::<u8>() as u32;
+        desc.flags = VIRTQ_DESC_F_WRITE;
+
+        self.req_states[head as usize] = ReqState::InFlight;
+        self.request_vq.kick(&self.mmio, head);
+        Ok(head)
+    }
+
+    /// 要求が完了していれば結果を返し、ディスクリプタを解放する
+    pub fn poll(&mut self, id: u16) -> Option<Result<(), i32>> {
+        self.collect_used();
+        if self.req_states[id as usize] != ReqState::Done {
+            return None;
+        }
+
+        let status = self.reqs[id as usize].status;
+        self.req_states[id as usize] = ReqState::Free;
+        self.request_vq.free_chain(id);
+        Some(if status == VIRTIO_BLK_S_OK {
+            Ok(())
+        } else {
+            Err(EIO)
+        })
+    }
+
+    /// 要求が完了するまで割り込みを待つ
+    pub fn wait(&mut self, id: u16) -> Result<(), i32> {
+        loop {
+            if let Some(result) = self.poll(id) {
+                return result;
+            }
+
+            // カーネル内ではsstatus.SIEが0なので、wfiから戻ってもトラップは起きない。
+            // 割り込みはここで受け取って完了させる
+            unsafe { asm!("wfi") };
+            let irq = plic_claim();
+            if irq == self.irq() {
+                self.handle_interrupt();
+            } else if irq != 0 {
+                handle_device_interrupt(irq);
+            }
+            if irq != 0 {
+                plic_complete(irq);
+            }
+        }
+    }
+
+    /// `sector`から`buf`の長さ分 (セクタサイズの倍数) を読み書きし、完了を待つ
+    pub fn read_write_disk(
+        &mut self,
+        buf: &mut [u8],
+        sector: u64,
+        is_write: bool,
+    ) -> Result<(), i32> {
+        let id = unsafe { self.submit(buf.as_mut_ptr(), buf.len(), sector, is_write)? };
+        self.wait(id)
+    }
+}
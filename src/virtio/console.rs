@@ -0,0 +1,351 @@
+// virtio-console: ホストと文字列をやり取りするポートのドライバ。マルチポートに対応していれば
+// 制御キューでポートの追加・削除を知らされる
+
+use common::{EAGAIN, ENXIO, PAGE_SIZE};
+
+use super::{VirtioMmio, Virtqueue, VIRTQ_DESC_F_WRITE, VIRTQ_ENTRY_NUM};
+use crate::{memory::alloc_pages, plic::plic_enable, println};
+use core::mem;
+
+/// 扱うポートの数。デバイスがこれより多くのポートを持っていても使わない
+pub const CONSOLE_PORTS_MAX: usize = 4;
+// 1回の受信・送信で扱う最大のバイト数
+const PORT_BUF_SIZE: usize = PAGE_SIZE / 2;
+// 制御メッセージを受け取るバッファの大きさと数。PORT_NAMEは名前がヘッダの後に続く
+const CONTROL_BUF_SIZE: usize = 64;
+const CONTROL_BUFS: usize = 8;
+const PORT_NAME_MAX: usize = 32;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+// 設定領域のオフセット
+const VIRTIO_CONSOLE_CONFIG_MAX_NR_PORTS: usize = 4;
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct VirtioConsoleControl {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+/// ポートの受信・送信キューの番号。ポート0は0・1、制御キューは2・3、ポート1以降はその後に続く
+fn port_queues(port: usize) -> (u32, u32) {
+    let rx = if port == 0 { 0 } else { 2 * port as u32 + 2 };
+    (rx, rx + 1)
+}
+
+struct Port {
+    present: bool,
+    /// ホスト側でコンソールとして使われているポート
+    is_console: bool,
+    name: [u8; PORT_NAME_MAX],
+    name_len: usize,
+    rx_vq: Virtqueue,
+    tx_vq: Virtqueue,
+    // 受信バッファは先頭の半分、送信バッファは後ろの半分
+    buf: &'static mut [u8; PAGE_SIZE],
+    rx_posted: bool,
+    // 受け取ったがまだ読まれていないデータの範囲
+    rx_pos: usize,
+    rx_len: usize,
+    tx_pending: bool,
+}
+
+impl Port {
+    unsafe fn new(mmio: &VirtioMmio, port: usize) -> Result<Self, ()> {
+        let (rx, tx) = port_queues(port);
+        let rx_vq = Virtqueue::new(mmio, rx)?;
+        let tx_vq = Virtqueue::new(mmio, tx)?;
+        Ok(Self {
+            present: false,
+            is_console: false,
+            name: [0; PORT_NAME_MAX],
+            name_len: 0,
+            rx_vq,
+            tx_vq,
+            buf: (alloc_pages(1) as *mut [u8; PAGE_SIZE]).as_mut().unwrap(),
+            rx_posted: false,
+            rx_pos: 0,
+            rx_len: 0,
+            tx_pending: false,
+        })
+    }
+
+    /// 受信バッファをデバイスに渡す
+    fn post_rx(&mut self, mmio: &VirtioMmio) {
+        if self.rx_posted {
+            return;
+        }
+
+        let head = self.rx_vq.alloc_desc();
+        let desc = self.rx_vq.desc_mut(head);
+        desc.addr = self.buf.as_ptr() as u64;
+        desc.len = PORT_BUF_SIZE as u32;
+        desc.flags = VIRTQ_DESC_F_WRITE;
+        self.rx_posted = true;
+        self.rx_vq.kick(mmio, head);
+    }
+
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    fn collect_used(&mut self, mmio: &VirtioMmio) {
+        if let Some((head, len)) = self.rx_vq.pop_used() {
+            self.rx_vq.free_chain(head);
+            self.rx_posted = false;
+            self.rx_pos = 0;
+            self.rx_len = (len as usize).min(PORT_BUF_SIZE);
+            // 空なら読まれるのを待たずに返す
+            if self.rx_len == 0 {
+                self.post_rx(mmio);
+            }
+        }
+        if let Some((head, _)) = self.tx_vq.pop_used() {
+            self.tx_vq.free_chain(head);
+            self.tx_pending = false;
+        }
+    }
+}
+
+pub struct VirtioConsole {
+    mmio: VirtioMmio,
+    ports: [Option<Port>; CONSOLE_PORTS_MAX],
+    // マルチポートでなければNone
+    control_rx_vq: Option<Virtqueue>,
+    control_tx_vq: Option<Virtqueue>,
+    // 受信用のバッファをディスクリプタの番号の順に並べ、その後ろに送信用のメッセージを置く
+    control_buf: &'static mut [u8; PAGE_SIZE],
+}
+
+impl VirtioConsole {
+    pub(super) unsafe fn new(mmio: VirtioMmio) -> Result<Self, ()> {
+        let features = mmio.begin_init(VIRTIO_CONSOLE_F_MULTIPORT)?;
+        let multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+        let nr_ports = if multiport {
+            (mmio.config_read32(VIRTIO_CONSOLE_CONFIG_MAX_NR_PORTS) as usize)
+                .clamp(1, CONSOLE_PORTS_MAX)
+        } else {
+            1
+        };
+
+        // キューは全てDRIVER_OKの前に設定する
+        let mut ports = [const { None }; CONSOLE_PORTS_MAX];
+        ports[0] = Some(Port::new(&mmio, 0)?);
+        let (control_rx_vq, control_tx_vq) = if multiport {
+            (
+                Some(Virtqueue::new(&mmio, 2)?),
+                Some(Virtqueue::new(&mmio, 3)?),
+            )
+        } else {
+            (None, None)
+        };
+        for (i, port) in ports.iter_mut().enumerate().take(nr_ports).skip(1) {
+            *port = Some(Port::new(&mmio, i)?);
+        }
+        mmio.finish_init();
+        plic_enable(mmio.irq);
+
+        let mut console = Self {
+            mmio,
+            ports,
+            control_rx_vq,
+            control_tx_vq,
+            control_buf: (alloc_pages(1) as *mut [u8; PAGE_SIZE]).as_mut().unwrap(),
+        };
+
+        if multiport {
+            for _ in 0..CONTROL_BUFS {
+                let head = console.control_rx_vq.as_mut().unwrap().alloc_desc();
+                console.post_control_rx(head);
+            }
+            // デバイスがDEVICE_ADDで各ポートを知らせてくる
+            console.send_control(0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+            console.poll();
+        } else {
+            // マルチポートでなければポート0だけが常にある
+            console.add_port(0);
+            console.ports[0].as_mut().unwrap().is_console = true;
+        }
+        Ok(console)
+    }
+
+    pub fn irq(&self) -> u32 {
+        self.mmio.irq()
+    }
+
+    pub fn handle_interrupt(&mut self) {
+        self.mmio.ack_interrupt();
+        self.poll();
+    }
+
+    /// デバイスが処理を終えた受信・送信と、届いた制御メッセージを処理する。
+    /// 割り込みを待たずに呼んでもよい
+    pub fn poll(&mut self) {
+        while let Some((head, len)) = self.control_rx_vq.as_mut().and_then(|vq| vq.pop_used()) {
+            // 応答を送る前にバッファを返し、続くメッセージを受け取れるようにする
+            let mut msg = [0u8; CONTROL_BUF_SIZE];
+            let off = head as usize * CONTROL_BUF_SIZE;
+            let len = (len as usize).min(CONTROL_BUF_SIZE);
+            msg[..len].copy_from_slice(&self.control_buf[off..off + len]);
+            self.post_control_rx(head);
+            self.handle_control(&msg[..len]);
+        }
+
+        for port in self.ports.iter_mut().flatten() {
+            port.collect_used(&self.mmio);
+        }
+    }
+
+    /// 受信用の`head`番目のバッファを制御キューに渡す。バッファはディスクリプタの番号で決まる
+    fn post_control_rx(&mut self, head: u16) {
+        let addr = self.control_buf.as_ptr() as u64 + (head as usize * CONTROL_BUF_SIZE) as u64;
+        let vq = self.control_rx_vq.as_mut().unwrap();
+        let desc = vq.desc_mut(head);
+        desc.addr = addr;
+        desc.len = CONTROL_BUF_SIZE as u32;
+        desc.flags = VIRTQ_DESC_F_WRITE;
+        vq.kick(&self.mmio, head);
+    }
+
+    /// 制御メッセージを送り、デバイスが受け取るまで待つ
+    fn send_control(&mut self, id: u32, event: u16, value: u16) {
+        let off = VIRTQ_ENTRY_NUM * CONTROL_BUF_SIZE;
+        let msg = VirtioConsoleControl { id, event, value };
+        let size = mem::size_of::<VirtioConsoleControl>();
+        unsafe {
+            (self.control_buf.as_mut_ptr().add(off) as *mut VirtioConsoleControl)
+                .write_unaligned(msg)
+        };
+
+        let addr = self.control_buf.as_ptr() as u64 + off as u64;
+        let vq = self.control_tx_vq.as_mut().unwrap();
+        let head = vq.alloc_desc();
+        let desc = vq.desc_mut(head);
+        desc.addr = addr;
+        desc.len = size as u32;
+        desc.flags = 0;
+        vq.kick(&self.mmio, head);
+        loop {
+            if let Some((used, _)) = vq.pop_used() {
+                vq.free_chain(used);
+                break;
+            }
+        }
+    }
+
+    fn handle_control(&mut self, msg: &[u8]) {
+        let size = mem::size_of::<VirtioConsoleControl>();
+        if msg.len() < size {
+            return;
+        }
+        let ctrl = unsafe { (msg.as_ptr() as *const VirtioConsoleControl).read_unaligned() };
+        let id = ctrl.id as usize;
+        if id >= CONSOLE_PORTS_MAX || self.ports[id].is_none() {
+            return;
+        }
+
+        match ctrl.event {
+            VIRTIO_CONSOLE_DEVICE_ADD => {
+                self.add_port(id);
+                self.send_control(ctrl.id, VIRTIO_CONSOLE_PORT_READY, 1);
+                // カーネルが常に受信しているので、ゲスト側は最初から開いていることにする
+                self.send_control(ctrl.id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+            }
+            VIRTIO_CONSOLE_DEVICE_REMOVE => {
+                let port = self.ports[id].as_mut().unwrap();
+                port.present = false;
+                println!("virtio-console: port {} removed", id);
+            }
+            VIRTIO_CONSOLE_CONSOLE_PORT => {
+                self.ports[id].as_mut().unwrap().is_console = true;
+            }
+            VIRTIO_CONSOLE_PORT_NAME => {
+                let port = self.ports[id].as_mut().unwrap();
+                let name = &msg[size..];
+                // 名前はNUL終端されていないことがある
+                let len = name
+                    .iter()
+                    .position(|&c| c == 0)
+                    .unwrap_or(name.len())
+                    .min(PORT_NAME_MAX);
+                port.name[..len].copy_from_slice(&name[..len]);
+                port.name_len = len;
+                println!("virtio-console: port {} is named {}", id, port.name());
+            }
+            // ホスト側の接続・切断 (PORT_OPEN) は気にしない。未接続の間の出力はデバイスが捨てる
+            _ => {}
+        }
+    }
+
+    fn add_port(&mut self, id: usize) {
+        let port = self.ports[id].as_mut().unwrap();
+        port.present = true;
+        port.rx_len = 0;
+        port.post_rx(&self.mmio);
+        println!("virtio-console: port {} added", id);
+    }
+
+    fn port(&self, id: usize) -> Option<&Port> {
+        self.ports.get(id)?.as_ref().filter(|p| p.present)
+    }
+
+    pub fn port_present(&self, id: usize) -> bool {
+        self.port(id).is_some()
+    }
+
+    pub fn port_is_console(&self, id: usize) -> bool {
+        self.port(id).is_some_and(|p| p.is_console)
+    }
+
+    /// 受信したデータを`buf`に移す。まだ何も届いていなければNone
+    pub fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<Option<usize>, i32> {
+        let mmio = &self.mmio;
+        let port = self.ports.get_mut(id).and_then(|p| p.as_mut());
+        let port = port.filter(|p| p.present).ok_or(ENXIO)?;
+        if port.rx_len == 0 {
+            return Ok(None);
+        }
+
+        let len = buf.len().min(port.rx_len);
+        buf[..len].copy_from_slice(&port.buf[port.rx_pos..port.rx_pos + len]);
+        port.rx_pos += len;
+        port.rx_len -= len;
+        // 読み終えたら次の受信のためにバッファを返す
+        if port.rx_len == 0 {
+            port.post_rx(mmio);
+        }
+        Ok(Some(len))
+    }
+
+    /// `buf`の先頭から送信できるだけ送り、送ったバイト数を返す。
+    /// 前の送信が終わっていなければ`EAGAIN`
+    pub fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize, i32> {
+        let mmio = &self.mmio;
+        let port = self.ports.get_mut(id).and_then(|p| p.as_mut());
+        let port = port.filter(|p| p.present).ok_or(ENXIO)?;
+        if port.tx_pending {
+            return Err(EAGAIN);
+        }
+
+        let len = buf.len().min(PORT_BUF_SIZE);
+        port.buf[PORT_BUF_SIZE..PORT_BUF_SIZE + len].copy_from_slice(&buf[..len]);
+        let addr = port.buf.as_ptr() as u64 + PORT_BUF_SIZE as u64;
+        let head = port.tx_vq.alloc_desc();
+        let desc = port.tx_vq.desc_mut(head);
+        desc.addr = addr;
+        desc.len = len as u32;
+        desc.flags = 0;
+        port.tx_pending = true;
+        port.tx_vq.kick(mmio, head);
+        Ok(len)
+    }
+}
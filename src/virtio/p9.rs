@@ -0,0 +1,117 @@
+// virtio-9p: 9Pのメッセージをホストとやり取りするトランスポート
+
+use common::{EIO, PAGE_SIZE};
+
+use super::{VirtioMmio, Virtqueue, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+use crate::{
+    handle_device_interrupt,
+    memory::alloc_pages,
+    plic::{plic_claim, plic_complete, plic_enable},
+    println,
+};
+use core::arch::asm;
+
+/// 1つのメッセージの最大の大きさ。Tversionでこれ以下に決める
+pub const P9_MSG_SIZE: usize = 2 * PAGE_SIZE;
+const TAG_MAX: usize = 32;
+
+const VIRTIO_9P_F_MOUNT_TAG: u64 = 1 << 0;
+
+pub struct Virtio9p {
+    mmio: VirtioMmio,
+    request_vq: Virtqueue,
+    /// 送るメッセージを組み立てるバッファ
+    pub tx: &'static mut [u8; P9_MSG_SIZE],
+    /// 受け取ったメッセージ
+    pub rx: &'static mut [u8; P9_MSG_SIZE],
+    tag: [u8; TAG_MAX],
+    tag_len: usize,
+}
+
+impl Virtio9p {
+    pub(super) unsafe fn new(mmio: VirtioMmio) -> Result<Self, ()> {
+        let features = mmio.begin_init(VIRTIO_9P_F_MOUNT_TAG)?;
+        let request_vq = Virtqueue::new(&mmio, 0)?;
+        mmio.finish_init();
+
+        // 設定領域はタグの長さ (u16) とタグ
+        let mut tag = [0; TAG_MAX];
+        let mut tag_len = 0;
+        if features & VIRTIO_9P_F_MOUNT_TAG != 0 {
+            tag_len =
+                (mmio.config_read8(0) as usize | (mmio.config_read8(1) as usize) << 8).min(TAG_MAX);
+            for (i, byte) in tag.iter_mut().enumerate().take(tag_len) {
+                *byte = mmio.config_read8(2 + i);
+            }
+        }
+
+        let pages = P9_MSG_SIZE / PAGE_SIZE;
+        let tx = alloc_pages(pages);
+        let rx = alloc_pages(pages);
+        plic_enable(mmio.irq);
+
+        let p9 = Self {
+            mmio,
+            request_vq,
+            tx: (tx as *mut [u8; P9_MSG_SIZE]).as_mut().unwrap(),
+            rx: (rx as *mut [u8; P9_MSG_SIZE]).as_mut().unwrap(),
+            tag,
+            tag_len,
+        };
+        println!("virtio-9p: mount tag is \"{}\"", p9.tag());
+        Ok(p9)
+    }
+
+    pub fn tag(&self) -> &str {
+        core::str::from_utf8(&self.tag[..self.tag_len]).unwrap_or("")
+    }
+
+    pub fn irq(&self) -> u32 {
+        self.mmio.irq()
+    }
+
+    pub fn ack_interrupt(&self) {
+        self.mmio.ack_interrupt();
+    }
+
+    /// `tx`の先頭`len`バイトのメッセージを送り、応答が`rx`に届くまで待つ。応答の長さを返す
+    pub fn transact(&mut self, len: usize) -> Result<usize, i32> {
+        let head = self.request_vq.alloc_desc();
+        let reply = self.request_vq.alloc_desc();
+
+        let desc = self.request_vq.desc_mut(head);
+        desc.addr = self.tx.as_ptr() as u64;
+        desc.len = len as u32;
+        desc.flags = VIRTQ_DESC_F_NEXT;
+        desc.next = reply;
+
+        let desc = self.request_vq.desc_mut(reply);
+        desc.addr = self.rx.as_ptr() as u64;
+        desc.len = P9_MSG_SIZE as u32;
+        desc.flags = VIRTQ_DESC_F_WRITE;
+
+        self.request_vq.kick(&self.mmio, head);
+        loop {
+            if let Some((id, len)) = self.request_vq.pop_used() {
+                self.request_vq.free_chain(id);
+                return if (len as usize) < 7 {
+                    Err(EIO)
+                } else {
+                    Ok(len as usize)
+                };
+            }
+
+            // virtio-blkの完了待ちと同じく、割り込みはここで受け取る
+            unsafe { asm!("wfi") };
+            let irq = plic_claim();
+            if irq == self.irq() {
+                self.ack_interrupt();
+            } else if irq != 0 {
+                handle_device_interrupt(irq);
+            }
+            if irq != 0 {
+                plic_complete(irq);
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// virtio-rng (virtio-entropy): ホストの乱数をカーネルの乱数生成器に供給する
+
+use common::PAGE_SIZE;
+
+use super::{VirtioMmio, Virtqueue, VIRTQ_DESC_F_WRITE};
+use crate::{
+    memory::alloc_pages,
+    plic::{plic_claim, plic_complete, plic_enable},
+};
+use core::arch::asm;
+
+/// 1回の要求で受け取る最大のバイト数
+pub const RNG_BUF_SIZE: usize = 64;
+
+pub struct VirtioRng {
+    mmio: VirtioMmio,
+    request_vq: Virtqueue,
+    buf: &'static mut [u8; RNG_BUF_SIZE],
+    // デバイスに渡している要求の先頭ディスクリプタ
+    pending: Option<u16>,
+}
+
+impl VirtioRng {
+    pub(super) unsafe fn new(mmio: VirtioMmio) -> Result<Self, ()> {
+        mmio.begin_init(0)?;
+        let request_vq = Virtqueue::new(&mmio, 0)?;
+        mmio.finish_init();
+
+        let buf = alloc_pages(RNG_BUF_SIZE.div_ceil(PAGE_SIZE));
+        plic_enable(mmio.irq);
+
+        Ok(Self {
+            mmio,
+            request_vq,
+            buf: (buf as *mut [u8; RNG_BUF_SIZE]).as_mut().unwrap(),
+            pending: None,
+        })
+    }
+
+    pub fn irq(&self) -> u32 {
+        self.mmio.irq()
+    }
+
+    /// 乱数を要求する。完了は割り込みで知らされ、`take`で受け取る
+    pub fn request(&mut self) {
+        if self.pending.is_some() {
+            return;
+        }
+
+        let head = self.request_vq.alloc_desc();
+        let desc = self.request_vq.desc_mut(head);
+        desc.addr = self.buf.as_ptr() as u64;
+        desc.len = RNG_BUF_SIZE as u32;
+        desc.flags = VIRTQ_DESC_F_WRITE;
+        self.pending = Some(head);
+        self.request_vq.kick(&self.mmio, head);
+    }
+
+    /// 完了した要求があれば、デバイスが書き込んだ乱数を返す
+    pub fn take(&mut self) -> Option<&[u8]> {
+        let (head, len) = self.request_vq.pop_used()?;
+        self.request_vq.free_chain(head);
+        self.pending = None;
+        Some(&self.buf[..(len as usize).min(RNG_BUF_SIZE)])
+    }
+
+    pub fn ack_interrupt(&self) {
+        self.mmio.ack_interrupt();
+    }
+
+    /// 乱数を要求して完了を待つ。他のデバイスの要求がない起動時に使う
+    pub fn read_blocking(&mut self, out: &mut [u8; RNG_BUF_SIZE]) -> usize {
+        self.request();
+        loop {
+            if let Some(data) = self.take() {
+                out[..data.len()].copy_from_slice(data);
+                return data.len();
+            }
+
+            unsafe { asm!("wfi") };
+            let irq = plic_claim();
+            if irq != 0 {
+                self.ack_interrupt();
+                plic_complete(irq);
+            }
+        }
+    }
+}
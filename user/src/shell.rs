@@ -1,19 +1,48 @@
-use crate::{exit, getchar, ping, putchar, readfile, writefile};
+use common::{
+    CacheStats, Dirent, FsckReport, RouteEntry, SockError, Stat, DT_BLK, DT_CHR, DT_DIR, DT_LNK,
+    FD_STDIN, FD_STDOUT, ICMP_DEST_UNREACH, ICMP_TIME_EXCEEDED, IP_MTU_DISCOVER, IP_PMTUDISC_DO,
+    IP_TOS, IP_TTL, O_APPEND, O_CREAT, O_RDONLY, O_TRUNC, O_WRONLY, SOCK_DGRAM,
+};
+
+use crate::{
+    args, bind, cachestat, chdir, close, connect, exit, fsck, getcwd, getdents, getrandom, ifaddr,
+    link, lstat, mkdir, mount, open, ping, pipe, read, readfile, readlink, recverr, rmdir,
+    route_add, route_del, route_list, setsockopt, socket, spawn, symlink, sync, sysctl, truncate,
+    umount, unlink, wait, write, writefile,
+};
+
+// tracerouteが最初に送るポート。プローブごとに1つずつ増やす
+const TRACEROUTE_PORT: u16 = 33434;
+// 1つのTTLで送るプローブの数
+const TRACEROUTE_PROBES: u32 = 3;
+const TRACEROUTE_TIMEOUT_MS: u32 = 1000;
 
 #[no_mangle]
 fn main() {
+    // spawnされた場合は渡されたコマンドを1つだけ実行して終了する
+    let args = args();
+    if !args.is_empty() {
+        run(args);
+        return;
+    }
+
     loop {
         print("> ");
         let mut cmdline: [u8; 128] = [0; 128];
         let mut count = 0;
         loop {
-            let ch = getchar() as u8;
-            putchar(ch);
-            if ch == b'\r' {
+            // 標準入力が端末のポートに繋がれていることもあるので、ディスクリプタから読む
+            let mut ch = [0u8; 1];
+            if (read(FD_STDIN, &mut ch) as i32) <= 0 {
+                return;
+            }
+            let ch = ch[0];
+            if ch == b'\r' || ch == b'\n' {
                 cmdline[count] = b'\0';
                 print("\n");
                 break;
             } else {
+                write(FD_STDOUT, &[ch]);
                 cmdline[count] = ch;
             }
 
@@ -23,67 +52,691 @@ fn main() {
             }
         }
         match core::str::from_utf8(&cmdline[..count]) {
+            Ok(s) => run(s),
+            Err(_) => print("command not found\n"),
+        }
+    }
+}
+
+fn run(s: &str) {
+    let (cmd, arg) = match s.split_once(' ') {
+        Some((cmd, arg)) => (cmd, arg.trim()),
+        None => (s, ""),
+    };
+
+    if let Some((left, right)) = s.split_once('|') {
+        pipeline(left.trim(), right.trim());
+    } else if s == "hello" {
+        print("Hello world from shell!\n");
+    } else if s == "exit" {
+        exit();
+    } else if cmd == "cat" {
+        let mut path = [0u8; 128];
+        let fd = if arg.is_empty() {
+            FD_STDIN
+        } else {
+            let fd = open(with_nul(arg, &mut path), O_RDONLY);
+            if (fd as i32) < 0 {
+                print("cat: no such file\n");
+                return;
+            }
+            fd
+        };
+
+        let mut buf = [0u8; 128];
+        loop {
+            let len = read(fd, &mut buf);
+            if (len as i32) <= 0 {
+                break;
+            }
+            write(FD_STDOUT, &buf[..len as usize]);
+        }
+
+        if fd != FD_STDIN {
+            close(fd);
+        }
+    } else if cmd == "write" || cmd == "append" {
+        let (file, text) = arg.split_once(' ').unwrap_or((arg, ""));
+        let mode = if cmd == "write" { O_TRUNC } else { O_APPEND };
+        let mut path = [0u8; 128];
+        let fd = open(with_nul(file, &mut path), O_WRONLY | O_CREAT | mode);
+        if (fd as i32) < 0 {
+            print("write: failed to open file\n");
+            return;
+        }
+        write(fd, text.as_bytes());
+        write(fd, b"\n");
+        close(fd);
+    } else if cmd == "ls" {
+        let mut path = [0u8; 128];
+        let fd = open(
+            with_nul(if arg.is_empty() { "." } else { arg }, &mut path),
+            O_RDONLY,
+        );
+        if (fd as i32) < 0 {
+            print("ls: no such directory\n");
+            return;
+        }
+
+        let mut entries = [Dirent::new(); 4];
+        loop {
+            let len = getdents(fd, &mut entries);
+            if (len as i32) <= 0 {
+                break;
+            }
+            for entry in &entries[..len as usize / core::mem::size_of::<Dirent>()] {
+                print(entry.name());
+                if entry.kind == DT_DIR {
+                    print("/");
+                } else if entry.kind == DT_LNK {
+                    print("@");
+                }
+                print("\n");
+            }
+        }
+        close(fd);
+    } else if cmd == "mkdir" {
+        let mut path = [0u8; 128];
+        if (mkdir(with_nul(arg, &mut path)) as i32) < 0 {
+            print("mkdir: failed to create directory\n");
+        }
+    } else if cmd == "rmdir" {
+        let mut path = [0u8; 128];
+        if (rmdir(with_nul(arg, &mut path)) as i32) < 0 {
+            print("rmdir: failed to remove directory\n");
+        }
+    } else if cmd == "cd" {
+        let mut path = [0u8; 128];
+        if (chdir(with_nul(if arg.is_empty() { "/" } else { arg }, &mut path)) as i32) < 0 {
+            print("cd: no such directory\n");
+        }
+    } else if s == "pwd" {
+        let mut buf = [0u8; 256];
+        let len = getcwd(&mut buf);
+        if (len as i32) >= 0 {
+            write(FD_STDOUT, &buf[..len as usize]);
+            print("\n");
+        }
+    } else if cmd == "rm" {
+        let mut path = [0u8; 128];
+        if (unlink(with_nul(arg, &mut path)) as i32) < 0 {
+            print("rm: failed to remove file\n");
+        }
+    } else if cmd == "truncate" {
+        let (file, len) = arg.split_once(' ').unwrap_or((arg, "0"));
+        let mut path = [0u8; 128];
+        match len.trim().parse::<u32>() {
+            Ok(len) if (truncate(with_nul(file, &mut path), len) as i32) >= 0 => {}
+            _ => print("truncate: failed\n"),
+        }
+    } else if cmd == "stat" {
+        let mut path = [0u8; 128];
+        let mut st = Stat::new();
+        if (lstat(with_nul(arg, &mut path), &mut st) as i32) < 0 {
+            print("stat: no such file\n");
+            return;
+        }
+
+        print(match st.kind {
+            DT_DIR => "directory",
+            DT_LNK => "symlink",
+            DT_CHR => "character device",
+            DT_BLK => "block device",
+            _ => "file",
+        });
+        print(" mode=0");
+        print_octal(st.mode);
+        print(" size=");
+        print_num(st.size as u32);
+        print(" nlink=");
+        print_num(st.nlink);
+        print(" uid=");
+        print_num(st.uid);
+        print(" gid=");
+        print_num(st.gid);
+        print(" mtime=");
+        print_num(st.mtime as u32);
+        print("\n");
+    } else if cmd == "ln" {
+        // ln [-s] <target> <path>
+        let (is_symlink, arg) = match arg.strip_prefix("-s ") {
+            Some(arg) => (true, arg.trim()),
+            None => (false, arg),
+        };
+        let (target, file) = arg.split_once(' ').unwrap_or((arg, ""));
+        let mut target_buf = [0u8; 128];
+        let mut path = [0u8; 128];
+        let target = with_nul(target, &mut target_buf);
+        let path = with_nul(file.trim(), &mut path);
+        let ret = if is_symlink {
+            symlink(target, path)
+        } else {
+            link(target, path)
+        };
+        if (ret as i32) < 0 {
+            print("ln: failed to create link\n");
+        }
+    } else if cmd == "readlink" {
+        let mut path = [0u8; 128];
+        let mut buf = [0u8; 128];
+        let len = readlink(with_nul(arg, &mut path), &mut buf);
+        if (len as i32) < 0 {
+            print("readlink: not a symlink\n");
+            return;
+        }
+        write(FD_STDOUT, &buf[..len as usize]);
+        print("\n");
+    } else if s == "fsck" {
+        let mut report = FsckReport::new();
+        fsck(&mut report);
+        print("entries=");
+        print_num(report.entries);
+        print(" corrupt=");
+        print_num(report.corrupt);
+        print(" quarantined=");
+        print_num(report.quarantined);
+        print(" skipped=");
+        print_num(report.skipped);
+        print("\n");
+        if report.truncated != 0 {
+            print("archive is truncated\n");
+        }
+        if report.end_marker == 0 {
+            print("end-of-archive marker not found\n");
+        }
+    } else if cmd == "mount" {
+        // mount <fstype> <path>
+        let (fstype, dir) = arg.split_once(' ').unwrap_or((arg, ""));
+        let mut fstype_buf = [0u8; 128];
+        let mut path = [0u8; 128];
+        if (mount(
+            with_nul(fstype, &mut fstype_buf),
+            with_nul(dir.trim(), &mut path),
+        ) as i32)
+            < 0
+        {
+            print("mount: failed\n");
+        }
+    } else if cmd == "umount" {
+        let mut path = [0u8; 128];
+        if (umount(with_nul(arg, &mut path)) as i32) < 0 {
+            print("umount: failed\n");
+        }
+    } else if s == "sync" {
+        sync();
+    } else if s == "cachestat" {
+        let mut stats = CacheStats::new();
+        cachestat(&mut stats);
+        print("hits=");
+        print_num(stats.hits);
+        print(" misses=");
+        print_num(stats.misses);
+        print(" writebacks=");
+        print_num(stats.writebacks);
+        print(" syncs=");
+        print_num(stats.syncs);
+        print(" dirty=");
+        print_num(stats.dirty);
+        print("\n");
+    } else if cmd == "hexdump" {
+        // 先頭の1セクタ分を16進数で表示する
+        let mut path = [0u8; 128];
+        let fd = open(with_nul(arg, &mut path), O_RDONLY);
+        if (fd as i32) < 0 {
+            print("hexdump: no such file\n");
+            return;
+        }
+
+        let mut buf = [0u8; 512];
+        let mut len = 0;
+        while len < buf.len() {
+            let n = read(fd, &mut buf[len..]);
+            if (n as i32) <= 0 {
+                break;
+            }
+            len += n as usize;
+        }
+        close(fd);
+
+        for (i, line) in buf[..len].chunks(16).enumerate() {
+            print_hex((i * 16) as u32, 8);
+            print(":");
+            for byte in line {
+                print(" ");
+                print_hex(*byte as u32, 2);
+            }
+            print("\n");
+        }
+    } else if cmd == "random" {
+        // random [バイト数]: カーネルの乱数生成器から得た値を16進数で表示する
+        let mut buf = [0u8; 64];
+        let len = if arg.is_empty() {
+            16
+        } else {
+            arg.parse::<usize>().unwrap_or(0).min(buf.len())
+        };
+        getrandom(&mut buf[..len]);
+        for byte in &buf[..len] {
+            print_hex(*byte as u32, 2);
+        }
+        print("\n");
+    } else if s == "readfile" {
+        let mut buf: [u8; 128] = [0; 128];
+        readfile("./lorem.txt\0", &mut buf, 128);
+        match core::str::from_utf8(&buf) {
             Ok(s) => {
-                if s == "hello" {
-                    print("Hello world from shell!\n");
-                } else if s == "exit" {
-                    exit();
-                } else if s == "readfile" {
-                    let mut buf: [u8; 128] = [0; 128];
-                    readfile("./lorem.txt\0", &mut buf, 128);
-                    match core::str::from_utf8(&buf) {
-                        Ok(s) => {
-                            print(s);
-                            print("\n");
-                        }
-                        Err(_) => print("error"),
+                print(s);
+                print("\n");
+            }
+            Err(_) => print("error"),
+        }
+    } else if s == "writefile" {
+        let data = b"Hello from virtio\n";
+        writefile("./lorem.txt\0", data, data.len() as u32);
+    } else if cmd == "ifconfig" {
+        // ifconfig <iface> <addr>/<prefix>
+        let (name, cidr) = arg.split_once(' ').unwrap_or((arg, ""));
+        let Some((addr, prefix_len)) = parse_cidr(cidr.trim()) else {
+            print("usage: ifconfig <iface> <addr>/<prefix>\n");
+            return;
+        };
+        let mut name_buf = [0u8; 128];
+        if (ifaddr(with_nul(name, &mut name_buf), addr, prefix_len) as i32) < 0 {
+            print("ifconfig: failed\n");
+        }
+    } else if s == "route" {
+        let mut routes = [RouteEntry::new(); 16];
+        let count = route_list(&mut routes);
+        print("Destination Gateway Iface Source\n");
+        for route in &routes[..count as usize] {
+            print_ip(route.dst);
+            print("/");
+            print_num(route.prefix_len);
+            print(" ");
+            print_ip(route.gateway);
+            print(" ");
+            print(route.iface());
+            print(" ");
+            print_ip(route.src);
+            print("\n");
+        }
+    } else if cmd == "route" {
+        // route add <dst>/<prefix> [via <gateway>] [dev <iface>] | route del <dst>/<prefix>
+        // "default"は0.0.0.0/0
+        let mut words = arg.split(' ').filter(|w| !w.is_empty());
+        let op = words.next().unwrap_or("");
+        let dst = match words.next() {
+            Some("default") => Some((0, 0)),
+            Some(dst) => parse_cidr(dst),
+            None => None,
+        };
+        let Some((dst, prefix_len)) = dst else {
+            print("usage: route add|del <dst>/<prefix> [via <gateway>] [dev <iface>]\n");
+            return;
+        };
+        let mut route = RouteEntry::new();
+        route.dst = dst;
+        route.prefix_len = prefix_len;
+        while let Some(key) = words.next() {
+            match (key, words.next()) {
+                ("via", Some(gateway)) => match parse_ip(gateway) {
+                    Some(gateway) => route.gateway = gateway,
+                    None => {
+                        print("route: invalid gateway\n");
+                        return;
+                    }
+                },
+                ("dev", Some(iface)) => route.set_iface(iface),
+                _ => {
+                    print("route: invalid argument\n");
+                    return;
+                }
+            }
+        }
+        let ret = match op {
+            "add" => route_add(&route),
+            "del" => route_del(&route),
+            _ => {
+                print("route: unknown operation\n");
+                return;
+            }
+        };
+        if (ret as i32) < 0 {
+            print("route: failed\n");
+        }
+    } else if cmd == "sysctl" {
+        // sysctl <name> | sysctl <name>=<value>
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => match value.trim().parse::<u32>() {
+                Ok(value) => (name.trim(), Some(value)),
+                Err(_) => {
+                    print("sysctl: invalid value\n");
+                    return;
+                }
+            },
+            None => (arg, None),
+        };
+        let mut name_buf = [0u8; 128];
+        let ret = sysctl(with_nul(name, &mut name_buf), value);
+        if (ret as i32) < 0 {
+            print("sysctl: failed\n");
+            return;
+        }
+        print(name);
+        print(" = ");
+        print_num(value.unwrap_or(ret));
+        print("\n");
+    } else if cmd == "udp" {
+        // udp listen <port> | udp send <addr> <port> [ttl=<n>] [tos=<n>] [df] <message>
+        let (op, rest) = next_word(arg);
+        let fd = socket(SOCK_DGRAM);
+        if (fd as i32) < 0 {
+            print("udp: failed to create socket\n");
+            return;
+        }
+        match op {
+            "listen" => match rest.parse::<u16>() {
+                Ok(port) if (bind(fd, 0, port) as i32) >= 0 => {
+                    let mut buf = [0u8; 512];
+                    let len = read(fd, &mut buf);
+                    if (len as i32) >= 0 {
+                        write(FD_STDOUT, &buf[..len as usize]);
+                        print("\n");
                     }
-                } else if s == "writefile" {
-                    writefile("./lorem.txt\0", b"Hello from virtio\n\0", 128);
-                } else if s == "ping" {
-                    print("PING 127.0.0.1 (32 bytes of data)\n");
-
-                    let dst_ip: u32 = u32::from_be_bytes([127, 0, 0, 1]);
-
-                    for seq in 0..3 {
-                        // TODO: タイマー実装後、送信時刻を記録してRTTを計測する
-                        let ret = ping(dst_ip, seq);
-
-                        if ret == 0 {
-                            print("Reply from 127.0.0.1: seq=");
-                            print_num(seq);
-                            // TODO: 実際のRTT（ミリ秒）を表示する
-                            print(" time=0.0098ms\n");
-                        } else {
-                            print("Request timeout for seq=");
-                            print_num(seq);
-                            print("\n");
-                        }
-
-                        // TODO: タイマー割り込み実装後、sleep()システムコールに置き換える
-                        // 現在はNOPループによる簡易的なsleep（約1秒）
-                        for _ in 0..2000000000 {
-                            unsafe { core::arch::asm!("nop") };
-                        }
+                }
+                _ => print("udp: failed to bind\n"),
+            },
+            "send" => {
+                let (addr, rest) = next_word(rest);
+                let (port, mut message) = next_word(rest);
+                let (Some(addr), Ok(port)) = (parse_ip(addr), port.parse::<u16>()) else {
+                    print("usage: udp send <addr> <port> [ttl=<n>] [tos=<n>] [df] <message>\n");
+                    close(fd);
+                    return;
+                };
+                // メッセージの前にあるオプションをソケットに設定する
+                loop {
+                    let (word, rest) = next_word(message);
+                    let ret = if let Some(ttl) = word.strip_prefix("ttl=") {
+                        setsockopt(fd, IP_TTL, ttl.parse().unwrap_or(0))
+                    } else if let Some(tos) = word.strip_prefix("tos=") {
+                        setsockopt(fd, IP_TOS, tos.parse().unwrap_or(u32::MAX))
+                    } else if word == "df" {
+                        setsockopt(fd, IP_MTU_DISCOVER, IP_PMTUDISC_DO)
+                    } else {
+                        break;
+                    };
+                    if (ret as i32) < 0 {
+                        print("udp: invalid option\n");
+                        close(fd);
+                        return;
                     }
-                } else {
-                    print("command not found\n");
+                    message = rest;
+                }
+                if (connect(fd, addr, port) as i32) < 0
+                    || (write(fd, message.as_bytes()) as i32) < 0
+                {
+                    print("udp: failed to send\n");
                 }
             }
-            Err(_) => print("command not found\n"),
+            _ => print("usage: udp listen|send ...\n"),
+        }
+        close(fd);
+    } else if cmd == "ping" {
+        // ping [-s <size>] [addr]
+        let (size, arg) = match arg.strip_prefix("-s ") {
+            Some(rest) => {
+                let (size, rest) = rest.trim().split_once(' ').unwrap_or((rest.trim(), ""));
+                match size.parse::<u32>() {
+                    Ok(size) => (size, rest.trim()),
+                    Err(_) => {
+                        print("ping: invalid size\n");
+                        return;
+                    }
+                }
+            }
+            None => (32, arg),
+        };
+        let dst_ip = if arg.is_empty() {
+            u32::from_be_bytes([127, 0, 0, 1])
+        } else {
+            match parse_ip(arg) {
+                Some(ip) => ip,
+                None => {
+                    print("ping: invalid address\n");
+                    return;
+                }
+            }
+        };
+        print("PING ");
+        print_ip(dst_ip);
+        print(" (");
+        print_num(size);
+        print(" bytes of data)\n");
+
+        for seq in 0..3 {
+            // TODO: タイマー実装後、送信時刻を記録してRTTを計測する
+            let ret = ping(dst_ip, seq, size);
+
+            if ret == 0 {
+                print("Reply from ");
+                print_ip(dst_ip);
+                print(": seq=");
+                print_num(seq);
+                // TODO: 実際のRTT（ミリ秒）を表示する
+                print(" time=0.0098ms\n");
+            } else {
+                print("Request timeout for seq=");
+                print_num(seq);
+                print("\n");
+            }
+
+            // TODO: タイマー割り込み実装後、sleep()システムコールに置き換える
+            // 現在はNOPループによる簡易的なsleep（約1秒）
+            for _ in 0..2000000000 {
+                unsafe { core::arch::asm!("nop") };
+            }
+        }
+    } else if cmd == "traceroute" {
+        // traceroute [-m <max_hops>] <addr>
+        let (max_hops, arg) = match arg.strip_prefix("-m ") {
+            Some(rest) => {
+                let (max_hops, rest) = next_word(rest);
+                match max_hops.parse::<u32>() {
+                    Ok(max_hops) if (1..=255).contains(&max_hops) => (max_hops, rest),
+                    _ => {
+                        print("traceroute: invalid max hops\n");
+                        return;
+                    }
+                }
+            }
+            None => (30, arg),
+        };
+        let Some(dst_ip) = parse_ip(arg) else {
+            print("usage: traceroute [-m <max_hops>] <addr>\n");
+            return;
+        };
+        let fd = socket(SOCK_DGRAM);
+        if (fd as i32) < 0 {
+            print("traceroute: failed to create socket\n");
+            return;
         }
+        print("traceroute to ");
+        print_ip(dst_ip);
+        print(", ");
+        print_num(max_hops);
+        print(" hops max\n");
+
+        // TTLを1つずつ増やして使われていないはずのポートに送り、途中のルーターからの
+        // Time Exceededか、宛先からのPort Unreachableを待つ。
+        // プローブごとにポートを変え、届いたエラーを送ったプローブと対応付ける
+        let mut port = TRACEROUTE_PORT;
+        for ttl in 1..=max_hops {
+            print(" ");
+            print_num(ttl);
+            let mut last_origin = None;
+            let mut reached = false;
+            for _ in 0..TRACEROUTE_PROBES {
+                let sent = (setsockopt(fd, IP_TTL, ttl) as i32) >= 0
+                    && (connect(fd, dst_ip, port) as i32) >= 0
+                    && (write(fd, &[0; 32]) as i32) >= 0;
+                port = port.wrapping_add(1);
+                let mut error = SockError::default();
+                if !sent || (recverr(fd, &mut error, TRACEROUTE_TIMEOUT_MS) as i32) < 0 {
+                    print("  *");
+                    continue;
+                }
+                if last_origin != Some(error.origin) {
+                    print("  ");
+                    print_ip(error.origin);
+                    last_origin = Some(error.origin);
+                }
+                print("  ");
+                print_ms(error.rtt_us);
+                // Time Exceeded以外は宛先に着いたか、そこから先に進めない
+                if error.icmp_type != ICMP_TIME_EXCEEDED {
+                    reached = true;
+                    if error.icmp_type == ICMP_DEST_UNREACH {
+                        print(match error.code {
+                            0 => " !N",
+                            1 => " !H",
+                            2 => " !P",
+                            3 => "",
+                            4 => " !F",
+                            _ => " !X",
+                        });
+                    }
+                }
+            }
+            print("\n");
+            if reached {
+                break;
+            }
+        }
+        close(fd);
+    } else {
+        print("command not found\n");
+    }
+}
+
+/// `left | right`を2つの子プロセスで実行し、両方の終了を待つ
+fn pipeline(left: &str, right: &str) {
+    let mut fds = [0u32; 2];
+    if pipe(&mut fds) != 0 {
+        print("pipe: failed\n");
+        return;
+    }
+
+    let mut cmd = [0u8; 128];
+    let left_pid = spawn(with_nul(left, &mut cmd).as_bytes(), FD_STDIN, fds[1]);
+    let mut cmd = [0u8; 128];
+    let right_pid = spawn(with_nul(right, &mut cmd).as_bytes(), fds[0], FD_STDOUT);
+
+    // 自分が持っている端を閉じないと右側のプロセスにEOFが届かない
+    close(fds[0]);
+    close(fds[1]);
+
+    if (left_pid as i32) < 0 || (right_pid as i32) < 0 {
+        print("spawn: failed\n");
+    }
+    if (left_pid as i32) >= 0 {
+        wait(left_pid);
+    }
+    if (right_pid as i32) >= 0 {
+        wait(right_pid);
+    }
+}
+
+/// システムコールに渡すためにNUL終端した文字列を`buf`に作る
+fn with_nul<'a>(s: &str, buf: &'a mut [u8; 128]) -> &'a str {
+    let len = s.len().min(buf.len() - 1);
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+    buf[len] = b'\0';
+    core::str::from_utf8(&buf[..len + 1]).unwrap_or("\0")
+}
+
+/// 先頭の単語と、その後の空白を除いた残り
+fn next_word(s: &str) -> (&str, &str) {
+    match s.split_once(' ') {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (s, ""),
+    }
+}
+
+/// ドット区切りのIPv4アドレスをビッグエンディアンの値にする
+fn parse_ip(s: &str) -> Option<u32> {
+    let mut bytes = [0u8; 4];
+    let mut parts = s.split('.');
+    for byte in bytes.iter_mut() {
+        *byte = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(u32::from_be_bytes(bytes))
+}
+
+/// `<addr>/<prefix>`。プレフィックス長を省いたら/32
+fn parse_cidr(s: &str) -> Option<(u32, u32)> {
+    let (addr, prefix_len) = match s.split_once('/') {
+        Some((addr, prefix_len)) => (addr, prefix_len.parse().ok().filter(|len| *len <= 32)?),
+        None => (s, 32),
+    };
+    Some((parse_ip(addr)?, prefix_len))
+}
+
+fn print_ip(addr_be: u32) {
+    for (i, byte) in addr_be.to_be_bytes().iter().enumerate() {
+        if i > 0 {
+            print(".");
+        }
+        print_num(*byte as u32);
     }
 }
 
 fn print(s: &str) {
-    for c in s.bytes() {
-        putchar(c);
+    write(FD_STDOUT, s.as_bytes());
+}
+
+fn print_octal(mut n: u32) {
+    let mut buf = [b'0'; 11];
+    let mut i = 0;
+    while n > 0 || i < 3 {
+        buf[i] = (n % 8) as u8 + b'0';
+        n /= 8;
+        i += 1;
+    }
+
+    buf[..i].reverse();
+    write(FD_STDOUT, &buf[..i]);
+}
+
+fn print_hex(n: u32, digits: usize) {
+    let mut buf = [0u8; 8];
+    for (i, ch) in buf[..digits].iter_mut().enumerate() {
+        *ch = b"0123456789abcdef"[(n >> ((digits - 1 - i) * 4)) as usize & 0xf];
+    }
+    write(FD_STDOUT, &buf[..digits]);
+}
+
+/// マイクロ秒をミリ秒で小数点以下3桁まで表示する
+fn print_ms(us: u32) {
+    print_num(us / 1000);
+    print(".");
+    let frac = us % 1000;
+    if frac < 100 {
+        print("0");
     }
+    if frac < 10 {
+        print("0");
+    }
+    print_num(frac);
+    print(" ms");
 }
 
 fn print_num(mut n: u32) {
     if n == 0 {
-        putchar(b'0');
+        print("0");
         return;
     }
 
@@ -96,8 +749,6 @@ fn print_num(mut n: u32) {
         i += 1;
     }
 
-    while i > 0 {
-        i -= 1;
-        putchar(buf[i]);
-    }
+    buf[..i].reverse();
+    write(FD_STDOUT, &buf[..i]);
 }
@@ -3,7 +3,15 @@
 
 mod shell;
 
-use common::{SYS_EXIT, SYS_GETCHAR, SYS_PING, SYS_PUTCHAR, SYS_READFILE, SYS_WRITEFILE};
+use common::{
+    ascii_len, CacheStats, Dirent, FsckReport, RouteEntry, SockError, Stat, ARGS_MAX, SYS_BIND,
+    SYS_CACHESTAT, SYS_CHDIR, SYS_CLOSE, SYS_CONNECT, SYS_EXIT, SYS_FSCK, SYS_GETCWD, SYS_GETDENTS,
+    SYS_GETRANDOM, SYS_IFADDR, SYS_LINK, SYS_LSTAT, SYS_MKDIR, SYS_MOUNT, SYS_OPEN, SYS_PING,
+    SYS_PIPE, SYS_READ, SYS_READFILE, SYS_READLINK, SYS_RECVERR, SYS_RMDIR, SYS_ROUTE_ADD,
+    SYS_ROUTE_DEL, SYS_ROUTE_LIST, SYS_SETSOCKOPT, SYS_SOCKET, SYS_SPAWN, SYS_STAT, SYS_SYMLINK,
+    SYS_SYNC, SYS_SYSCTL, SYS_TRUNCATE, SYS_UMOUNT, SYS_UNLINK, SYS_WAIT, SYS_WRITE, SYS_WRITEFILE,
+    USER_ARGS,
+};
 use core::arch::{asm, naked_asm};
 use core::panic::PanicInfo;
 
@@ -49,16 +57,6 @@ unsafe fn syscall(sysno: u32, arg0: u32, arg1: u32, arg2: u32) -> u32 {
     result
 }
 
-pub fn putchar(ch: u8) {
-    unsafe {
-        syscall(SYS_PUTCHAR, ch as u32, 0, 0);
-    }
-}
-
-pub fn getchar() -> u32 {
-    unsafe { syscall(SYS_GETCHAR, 0, 0, 0) }
-}
-
 pub fn readfile(filename: &str, buf: &mut [u8], len: u32) -> u32 {
     unsafe {
         syscall(
@@ -81,6 +79,236 @@ pub fn writefile(filename: &str, buf: &[u8], len: u32) -> u32 {
     }
 }
 
-pub fn ping(dst_ip_be: u32, seq: u32) -> u32 {
-    unsafe { syscall(SYS_PING, dst_ip_be, seq, 0) }
+pub fn ping(dst_ip_be: u32, seq: u32, size: u32) -> u32 {
+    unsafe { syscall(SYS_PING, dst_ip_be, seq, size) }
+}
+
+pub fn pipe(fds: &mut [u32; 2]) -> u32 {
+    unsafe { syscall(SYS_PIPE, fds.as_mut_ptr() as u32, 0, 0) }
+}
+
+pub fn read(fd: u32, buf: &mut [u8]) -> u32 {
+    unsafe { syscall(SYS_READ, fd, buf.as_mut_ptr() as u32, buf.len() as u32) }
+}
+
+pub fn write(fd: u32, buf: &[u8]) -> u32 {
+    unsafe { syscall(SYS_WRITE, fd, buf.as_ptr() as u32, buf.len() as u32) }
+}
+
+pub fn close(fd: u32) -> u32 {
+    unsafe { syscall(SYS_CLOSE, fd, 0, 0) }
+}
+
+pub fn open(filename: &str, flags: u32) -> u32 {
+    unsafe { syscall(SYS_OPEN, filename as *const _ as *const u8 as u32, flags, 0) }
+}
+
+pub fn unlink(filename: &str) -> u32 {
+    unsafe { syscall(SYS_UNLINK, filename as *const _ as *const u8 as u32, 0, 0) }
+}
+
+pub fn truncate(filename: &str, len: u32) -> u32 {
+    unsafe {
+        syscall(
+            SYS_TRUNCATE,
+            filename as *const _ as *const u8 as u32,
+            len,
+            0,
+        )
+    }
+}
+
+pub fn mkdir(path: &str) -> u32 {
+    unsafe { syscall(SYS_MKDIR, path as *const _ as *const u8 as u32, 0, 0) }
+}
+
+pub fn rmdir(path: &str) -> u32 {
+    unsafe { syscall(SYS_RMDIR, path as *const _ as *const u8 as u32, 0, 0) }
+}
+
+/// 読み込んだバイト数を返す。`buf`には`Dirent`が詰められる
+pub fn getdents(fd: u32, buf: &mut [Dirent]) -> u32 {
+    unsafe {
+        syscall(
+            SYS_GETDENTS,
+            fd,
+            buf.as_mut_ptr() as u32,
+            core::mem::size_of_val(buf) as u32,
+        )
+    }
+}
+
+pub fn chdir(path: &str) -> u32 {
+    unsafe { syscall(SYS_CHDIR, path as *const _ as *const u8 as u32, 0, 0) }
+}
+
+pub fn getcwd(buf: &mut [u8]) -> u32 {
+    unsafe { syscall(SYS_GETCWD, buf.as_mut_ptr() as u32, buf.len() as u32, 0) }
+}
+
+pub fn stat(path: &str, stat: &mut Stat) -> u32 {
+    unsafe {
+        syscall(
+            SYS_STAT,
+            path as *const _ as *const u8 as u32,
+            stat as *mut Stat as u32,
+            0,
+        )
+    }
+}
+
+/// `stat`と同じだが、シンボリックリンクを辿らない
+pub fn lstat(path: &str, stat: &mut Stat) -> u32 {
+    unsafe {
+        syscall(
+            SYS_LSTAT,
+            path as *const _ as *const u8 as u32,
+            stat as *mut Stat as u32,
+            0,
+        )
+    }
+}
+
+/// `target`を指すシンボリックリンクを`path`に作る
+pub fn symlink(target: &str, path: &str) -> u32 {
+    unsafe {
+        syscall(
+            SYS_SYMLINK,
+            target as *const _ as *const u8 as u32,
+            path as *const _ as *const u8 as u32,
+            0,
+        )
+    }
+}
+
+pub fn link(old: &str, new: &str) -> u32 {
+    unsafe {
+        syscall(
+            SYS_LINK,
+            old as *const _ as *const u8 as u32,
+            new as *const _ as *const u8 as u32,
+            0,
+        )
+    }
+}
+
+/// リンク先の長さを返す。`buf`はNUL終端されない
+pub fn readlink(path: &str, buf: &mut [u8]) -> u32 {
+    unsafe {
+        syscall(
+            SYS_READLINK,
+            path as *const _ as *const u8 as u32,
+            buf.as_mut_ptr() as u32,
+            buf.len() as u32,
+        )
+    }
+}
+
+/// 起動時にファイルシステムを読み込んだ結果
+pub fn fsck(report: &mut FsckReport) -> u32 {
+    unsafe { syscall(SYS_FSCK, report as *mut FsckReport as u32, 0, 0) }
+}
+
+/// `fstype`のファイルシステムを`path`にマウントする
+pub fn mount(fstype: &str, path: &str) -> u32 {
+    unsafe {
+        syscall(
+            SYS_MOUNT,
+            fstype as *const _ as *const u8 as u32,
+            path as *const _ as *const u8 as u32,
+            0,
+        )
+    }
+}
+
+pub fn umount(path: &str) -> u32 {
+    unsafe { syscall(SYS_UMOUNT, path as *const _ as *const u8 as u32, 0, 0) }
+}
+
+/// 変更をディスクに書き戻す
+pub fn sync() -> u32 {
+    unsafe { syscall(SYS_SYNC, 0, 0, 0) }
+}
+
+pub fn cachestat(stats: &mut CacheStats) -> u32 {
+    unsafe { syscall(SYS_CACHESTAT, stats as *mut CacheStats as u32, 0, 0) }
+}
+
+/// `args`はNUL終端したコマンドライン。子プロセスの標準入力・標準出力を`stdin`・`stdout`に繋ぐ
+pub fn spawn(args: &[u8], stdin: u32, stdout: u32) -> u32 {
+    unsafe { syscall(SYS_SPAWN, args.as_ptr() as u32, stdin, stdout) }
+}
+
+pub fn wait(pid: u32) -> u32 {
+    unsafe { syscall(SYS_WAIT, pid, 0, 0) }
+}
+
+/// spawn時に渡されたコマンドライン
+pub fn args() -> &'static str {
+    let args = USER_ARGS as *const u8;
+    let len = ascii_len(args) - 1;
+    unsafe {
+        core::str::from_utf8(core::slice::from_raw_parts(args, len.min(ARGS_MAX))).unwrap_or("")
+    }
+}
+
+pub fn getrandom(buf: &mut [u8]) -> u32 {
+    unsafe { syscall(SYS_GETRANDOM, buf.as_mut_ptr() as u32, buf.len() as u32, 0) }
+}
+
+pub fn ifaddr(name: &str, addr_be: u32, prefix_len: u32) -> u32 {
+    unsafe { syscall(SYS_IFADDR, name.as_ptr() as u32, addr_be, prefix_len) }
+}
+
+pub fn route_add(route: &RouteEntry) -> u32 {
+    unsafe { syscall(SYS_ROUTE_ADD, route as *const _ as u32, 0, 0) }
+}
+
+pub fn route_del(route: &RouteEntry) -> u32 {
+    unsafe { syscall(SYS_ROUTE_DEL, route as *const _ as u32, 0, 0) }
+}
+
+/// 経路の数を返す
+pub fn route_list(routes: &mut [RouteEntry]) -> u32 {
+    unsafe {
+        syscall(
+            SYS_ROUTE_LIST,
+            routes.as_mut_ptr() as u32,
+            routes.len() as u32,
+            0,
+        )
+    }
+}
+
+pub fn socket(kind: u32) -> u32 {
+    unsafe { syscall(SYS_SOCKET, kind, 0, 0) }
+}
+
+pub fn bind(fd: u32, addr_be: u32, port: u16) -> u32 {
+    unsafe { syscall(SYS_BIND, fd, addr_be, port as u32) }
+}
+
+pub fn connect(fd: u32, addr_be: u32, port: u16) -> u32 {
+    unsafe { syscall(SYS_CONNECT, fd, addr_be, port as u32) }
+}
+
+pub fn setsockopt(fd: u32, option: u32, value: u32) -> u32 {
+    unsafe { syscall(SYS_SETSOCKOPT, fd, option, value) }
+}
+
+/// 送ったデータグラムへのICMPのエラーを最大`timeout_ms`ミリ秒待つ
+pub fn recverr(fd: u32, error: &mut SockError, timeout_ms: u32) -> u32 {
+    unsafe { syscall(SYS_RECVERR, fd, error as *mut SockError as u32, timeout_ms) }
+}
+
+/// `value`が`Some`なら書き換える。書き換える前の値を返す
+pub fn sysctl(name: &str, value: Option<u32>) -> u32 {
+    unsafe {
+        syscall(
+            SYS_SYSCTL,
+            name.as_ptr() as u32,
+            value.unwrap_or(0),
+            value.is_some() as u32,
+        )
+    }
 }